@@ -5,15 +5,108 @@
 
 use crate::*;
 
+// 16-bit to 8-bit component, rounded
+fn to8(v: u16) -> u8 {
+    (((v as u32) * 255 + 32895) >> 16) as u8
+}
+
+// 8-bit to 16-bit component
+fn to16(v: u8) -> u16 {
+    (v as u16) * 257
+}
+
+// normalized float to 16-bit component, clamped
+fn f_to16(v: f32) -> u16 {
+    if v >= 1.0 {
+        65535
+    }
+    else if v > 0.0 {
+        (v * 65535.0 + 0.5) as u16
+    }
+    else {
+        0
+    }
+}
+
+/// Pixel format that images can be decoded into and encoded from.
+///
+/// Every format offers the 8-bit path `set`/`get`. The 16-bit path `set16`/`get16` and the normalized float path `setf`/`getf` default to the narrower paths, and are overridden by formats that store more precision. Decoders should write through the widest path their data has.
 pub trait Pixel: Copy + Clone + Zero {
+    /// Whether or not the format stores alpha.
+    const ALPHA: bool;
+
+    /// Bits per channel, 32 for float formats. Encoders that can write wider samples use this to stay lossless.
+    const BITS: u32 = 8;
+
+    /// Set from 8-bit components.
     fn set(&mut self,r: u8,g: u8,b: u8,a: u8);
+
+    /// Get as 8-bit components.
     fn get(&self) -> (u8,u8,u8,u8);
+
+    /// Set from 16-bit components.
+    fn set16(&mut self,r: u16,g: u16,b: u16,a: u16) {
+        self.set(to8(r),to8(g),to8(b),to8(a));
+    }
+
+    /// Get as 16-bit components.
+    fn get16(&self) -> (u16,u16,u16,u16) {
+        let (r,g,b,a) = self.get();
+        (to16(r),to16(g),to16(b),to16(a))
+    }
+
+    /// Set from normalized components, 0.0 to 1.0. Float formats also accept values above 1.0.
+    fn setf(&mut self,r: f32,g: f32,b: f32,a: f32) {
+        self.set16(f_to16(r),f_to16(g),f_to16(b),f_to16(a));
+    }
+
+    /// Get as normalized components.
+    fn getf(&self) -> (f32,f32,f32,f32) {
+        let (r,g,b,a) = self.get16();
+        ((r as f32) / 65535.0,(g as f32) / 65535.0,(b as f32) / 65535.0,(a as f32) / 65535.0)
+    }
+}
+
+// pack non-negative float into an unsigned small float with 5 exponent bits and mantissa_bits mantissa bits, rounding to nearest
+fn pack_ufloat(v: f32,mantissa_bits: u32) -> u32 {
+    if !(v > 0.0) {
+        return 0;
+    }
+    let bits = v.to_bits();
+    let exp = ((bits >> 23) & 255) as i32 - 127 + 15;
+    let mantissa = bits & 0x007FFFFF;
+    let packed = if exp <= 0 {
+        let shift = (1 - exp) as u32 + 23 - mantissa_bits;
+        if shift > 24 { 0 } else { ((mantissa | 0x00800000) + (1 << (shift - 1))) >> shift }
+    }
+    else {
+        let shift = 23 - mantissa_bits;
+        ((((exp as u32) << 23) | mantissa) + (1 << (shift - 1))) >> shift
+    };
+    let max = (31 << mantissa_bits) - 1;
+    if packed > max { max } else { packed }
+}
+
+fn unpack_ufloat(d: u32,mantissa_bits: u32) -> f32 {
+    let exp = (d >> mantissa_bits) as i32;
+    let mantissa = (d & ((1 << mantissa_bits) - 1)) as f32;
+    let scale = (1 << mantissa_bits) as f32;
+    if exp == 0 {
+        (mantissa / scale) * 2.0f32.powi(-14)
+    }
+    else if exp == 31 {
+        if mantissa == 0.0 { f32::INFINITY } else { f32::NAN }
+    }
+    else {
+        (1.0 + mantissa / scale) * 2.0f32.powi(exp - 15)
+    }
 }
 
 #[derive(Copy,Clone)]
 pub struct R5G6B5UN { d: u16, }
 impl Zero for R5G6B5UN { fn zero() -> Self { R5G6B5UN { d: 0x0000 } } }
 impl Pixel for R5G6B5UN {
+    const ALPHA: bool = false;
     fn set(&mut self,r: u8,g: u8,b: u8,a: u8) {
         let r = (r >> 3) as u16;
         let g = (g >> 2) as u16;
@@ -35,6 +128,7 @@ impl Pixel for R5G6B5UN {
 pub struct A1RGB5UN { d: u16, }
 impl Zero for A1RGB5UN { fn zero() -> Self { A1RGB5UN { d: 0x0000 } } }
 impl Pixel for A1RGB5UN {
+    const ALPHA: bool = true;
     fn set(&mut self,r: u8,g: u8,b: u8,a: u8) {
         let r = (r >> 3) as u16;
         let g = (g >> 3) as u16;
@@ -67,6 +161,7 @@ pub struct RG8I { r: i8,g: i8, }
 pub struct RGB8UN { r: u8,g: u8,b: u8, }
 impl Zero for RGB8UN { fn zero() -> Self { RGB8UN { r: 0x00,g: 0x00,b: 0x00, } } }
 impl Pixel for RGB8UN {
+    const ALPHA: bool = false;
     fn set(&mut self,r: u8,g: u8,b: u8,a: u8) { self.r = r; self.g = g; self.b = b; }
     fn get(&self) -> (u8,u8,u8,u8) { (self.r,self.g,self.b,0xFF) }
 }
@@ -79,6 +174,7 @@ pub struct RGB8I { r: i8,g: i8,b: i8, }
 pub struct BGR8UN { b: u8,g: u8,r: u8, }
 impl Zero for BGR8UN { fn zero() -> Self { BGR8UN { b: 0x00,g: 0x00,r: 0x00, } } }
 impl Pixel for BGR8UN {
+    const ALPHA: bool = false;
     fn set(&mut self,r: u8,g: u8,b: u8,a: u8) { self.r = r; self.g = g; self.b = b; }
     fn get(&self) -> (u8,u8,u8,u8) { (self.r,self.g,self.b,0xFF) }
 }
@@ -91,6 +187,7 @@ pub struct BGR8I { b: i8,g: i8,r: i8, }
 pub struct RGBA8UN { r: u8,g: u8,b: u8,a: u8, }
 impl Zero for RGBA8UN { fn zero() -> Self { RGBA8UN { r: 0x00,g: 0x00,b: 0x00,a: 0x00, } } }
 impl Pixel for RGBA8UN {
+    const ALPHA: bool = true;
     fn set(&mut self,r: u8,g: u8,b: u8,a: u8) { self.r = r; self.g = g; self.b = b; self.a = a; }
     fn get(&self) -> (u8,u8,u8,u8) { (self.r,self.g,self.b,self.a) }
 }
@@ -103,7 +200,8 @@ pub struct RGBA8I { r: i8,g: i8,b: i8,a: i8, }
 pub struct BGRA8UN { b: u8,g: u8,r: u8,a: u8, }
 impl Zero for BGRA8UN { fn zero() -> Self { BGRA8UN { b: 0x00,g: 0x00,r: 0x00,a: 0x00, } } }
 impl Pixel for BGRA8UN {
-    fn set(&mut self,r: u8,g: u8,b: u8,a: u8) { self.r = r; self.g = g; self.b = b; }
+    const ALPHA: bool = true;
+    fn set(&mut self,r: u8,g: u8,b: u8,a: u8) { self.r = r; self.g = g; self.b = b; self.a = a; }
     fn get(&self) -> (u8,u8,u8,u8) { (self.r,self.g,self.b,self.a) }
 }
 
@@ -115,7 +213,8 @@ pub struct BGRA8I { b: i8,g: i8,r: i8,a: i8, }
 pub struct ABGR8UN { a: u8,b: u8,g: u8,r: u8, }
 impl Zero for ABGR8UN { fn zero() -> Self { ABGR8UN { a: 0x00,b: 0x00,g: 0x00,r: 0x00, } } }
 impl Pixel for ABGR8UN {
-    fn set(&mut self,r: u8,g: u8,b: u8,a: u8) { self.r = r; self.g = g; self.b = b; }
+    const ALPHA: bool = true;
+    fn set(&mut self,r: u8,g: u8,b: u8,a: u8) { self.r = r; self.g = g; self.b = b; self.a = a; }
     fn get(&self) -> (u8,u8,u8,u8) { (self.r,self.g,self.b,self.a) }
 }
 
@@ -127,6 +226,8 @@ pub struct ABGR8I { a: i8,b: i8,g: i8,r: i8, }
 pub struct A2RGB10UN { d: u32, }
 impl Zero for A2RGB10UN { fn zero() -> Self { A2RGB10UN { d: 0x00000000, } } }
 impl Pixel for A2RGB10UN {
+    const ALPHA: bool = true;
+    const BITS: u32 = 10;
     fn set(&mut self,r: u8,g: u8,b: u8,a: u8) {
         let r = ((r << 2) | (r >> 6)) as u32;
         let g = ((g << 2) | (g >> 6)) as u32;
@@ -142,6 +243,34 @@ impl Pixel for A2RGB10UN {
         let a = (a << 6) | (a << 4) | (a << 2) | a;
         (r as u8,g as u8,b as u8,a as u8)
     }
+    fn set16(&mut self,r: u16,g: u16,b: u16,a: u16) {
+        let r = ((r as u32) * 1023 + 32767) / 65535;
+        let g = ((g as u32) * 1023 + 32767) / 65535;
+        let b = ((b as u32) * 1023 + 32767) / 65535;
+        let a = ((a as u32) * 3 + 32767) / 65535;
+        self.d = (a << 30) | (r << 20) | (g << 10) | b;
+    }
+    fn get16(&self) -> (u16,u16,u16,u16) {
+        let r = (self.d >> 20) & 1023;
+        let g = (self.d >> 10) & 1023;
+        let b = self.d & 1023;
+        let a = self.d >> 30;
+        (((r << 6) | (r >> 4)) as u16,((g << 6) | (g >> 4)) as u16,((b << 6) | (b >> 4)) as u16,(a * 0x5555) as u16)
+    }
+    fn setf(&mut self,r: f32,g: f32,b: f32,a: f32) {
+        let r = ((r.max(0.0).min(1.0) * 1023.0) + 0.5) as u32;
+        let g = ((g.max(0.0).min(1.0) * 1023.0) + 0.5) as u32;
+        let b = ((b.max(0.0).min(1.0) * 1023.0) + 0.5) as u32;
+        let a = ((a.max(0.0).min(1.0) * 3.0) + 0.5) as u32;
+        self.d = (a << 30) | (r << 20) | (g << 10) | b;
+    }
+    fn getf(&self) -> (f32,f32,f32,f32) {
+        let r = ((self.d >> 20) & 1023) as f32 / 1023.0;
+        let g = ((self.d >> 10) & 1023) as f32 / 1023.0;
+        let b = (self.d & 1023) as f32 / 1023.0;
+        let a = (self.d >> 30) as f32 / 3.0;
+        (r,g,b,a)
+    }
 }
 
 pub struct R16UN { r: u16, }
@@ -152,13 +281,132 @@ pub struct RG16UN { r: u16,g: u16, }
 pub struct RG16IN { r: i16,g: i16, }
 pub struct RG16U { r: u16,g: u16, }
 pub struct RG16I { r: i16,g: i16, }
+#[derive(Copy,Clone)]
 pub struct RGB16UN { r: u16,g: u16,b: u16, }
+impl Zero for RGB16UN { fn zero() -> Self { RGB16UN { r: 0x0000,g: 0x0000,b: 0x0000, } } }
+impl Pixel for RGB16UN {
+    const ALPHA: bool = false;
+    const BITS: u32 = 16;
+    fn set(&mut self,r: u8,g: u8,b: u8,_a: u8) { self.r = to16(r); self.g = to16(g); self.b = to16(b); }
+    fn get(&self) -> (u8,u8,u8,u8) { (to8(self.r),to8(self.g),to8(self.b),0xFF) }
+    fn set16(&mut self,r: u16,g: u16,b: u16,_a: u16) { self.r = r; self.g = g; self.b = b; }
+    fn get16(&self) -> (u16,u16,u16,u16) { (self.r,self.g,self.b,0xFFFF) }
+}
+
 pub struct RGB16IN { r: i16,g: i16,b: i16, }
 pub struct RGB16U { r: u16,g: u16,b: u16, }
 pub struct RGB16I { r: i16,g: i16,b: i16, }
+#[derive(Copy,Clone)]
 pub struct RGBA16UN { r: u16,g: u16,b: u16,a: u16, }
+impl Zero for RGBA16UN { fn zero() -> Self { RGBA16UN { r: 0x0000,g: 0x0000,b: 0x0000,a: 0x0000, } } }
+impl Pixel for RGBA16UN {
+    const ALPHA: bool = true;
+    const BITS: u32 = 16;
+    fn set(&mut self,r: u8,g: u8,b: u8,a: u8) { self.r = to16(r); self.g = to16(g); self.b = to16(b); self.a = to16(a); }
+    fn get(&self) -> (u8,u8,u8,u8) { (to8(self.r),to8(self.g),to8(self.b),to8(self.a)) }
+    fn set16(&mut self,r: u16,g: u16,b: u16,a: u16) { self.r = r; self.g = g; self.b = b; self.a = a; }
+    fn get16(&self) -> (u16,u16,u16,u16) { (self.r,self.g,self.b,self.a) }
+}
+
 pub struct RGBA16IN { r: i16,g: i16,b: i16,a: i16, }
 pub struct RGBA16U { r: u16,g: u16,b: u16,a: u16, }
 pub struct RGBA16I { r: i16,g: i16,b: i16,a: i16, }
+#[derive(Copy,Clone)]
 pub struct RG11B10F { d: u32, }
+impl Zero for RG11B10F { fn zero() -> Self { RG11B10F { d: 0x00000000, } } }
+impl Pixel for RG11B10F {
+    const ALPHA: bool = false;
+    const BITS: u32 = 32;
+    fn set(&mut self,r: u8,g: u8,b: u8,_a: u8) {
+        self.setf((r as f32) / 255.0,(g as f32) / 255.0,(b as f32) / 255.0,1.0);
+    }
+    fn get(&self) -> (u8,u8,u8,u8) {
+        let (r,g,b,_) = self.get16();
+        (to8(r),to8(g),to8(b),0xFF)
+    }
+    fn set16(&mut self,r: u16,g: u16,b: u16,_a: u16) {
+        self.setf((r as f32) / 65535.0,(g as f32) / 65535.0,(b as f32) / 65535.0,1.0);
+    }
+    fn get16(&self) -> (u16,u16,u16,u16) {
+        let (r,g,b,_) = self.getf();
+        (f_to16(r),f_to16(g),f_to16(b),0xFFFF)
+    }
+    fn setf(&mut self,r: f32,g: f32,b: f32,_a: f32) {
+        self.d = (pack_ufloat(b,5) << 22) | (pack_ufloat(g,6) << 11) | pack_ufloat(r,6);
+    }
+    fn getf(&self) -> (f32,f32,f32,f32) {
+        (unpack_ufloat(self.d & 0x7FF,6),unpack_ufloat((self.d >> 11) & 0x7FF,6),unpack_ufloat(self.d >> 22,5),1.0)
+    }
+}
+
+#[derive(Copy,Clone)]
 pub struct RGB9E5F { d: u32, }
+impl Zero for RGB9E5F { fn zero() -> Self { RGB9E5F { d: 0x00000000, } } }
+impl Pixel for RGB9E5F {
+    const ALPHA: bool = false;
+    const BITS: u32 = 32;
+    fn set(&mut self,r: u8,g: u8,b: u8,_a: u8) {
+        self.setf((r as f32) / 255.0,(g as f32) / 255.0,(b as f32) / 255.0,1.0);
+    }
+    fn get(&self) -> (u8,u8,u8,u8) {
+        let (r,g,b,_) = self.get16();
+        (to8(r),to8(g),to8(b),0xFF)
+    }
+    fn set16(&mut self,r: u16,g: u16,b: u16,_a: u16) {
+        self.setf((r as f32) / 65535.0,(g as f32) / 65535.0,(b as f32) / 65535.0,1.0);
+    }
+    fn get16(&self) -> (u16,u16,u16,u16) {
+        let (r,g,b,_) = self.getf();
+        (f_to16(r),f_to16(g),f_to16(b),0xFFFF)
+    }
+    fn setf(&mut self,r: f32,g: f32,b: f32,_a: f32) {
+        // shared exponent encoding as in EXT_texture_shared_exponent
+        const MAX: f32 = 65408.0;
+        let r = if r > 0.0 { r.min(MAX) } else { 0.0 };
+        let g = if g > 0.0 { g.min(MAX) } else { 0.0 };
+        let b = if b > 0.0 { b.min(MAX) } else { 0.0 };
+        let max = r.max(g).max(b);
+        let mut exp = (max.log2().floor() as i32).max(-16) + 1 + 15;
+        let mut scale = 2.0f32.powi(exp - 15 - 9);
+        if ((max / scale) + 0.5).floor() as u32 == 512 {
+            scale *= 2.0;
+            exp += 1;
+        }
+        let r = ((r / scale) + 0.5).floor() as u32;
+        let g = ((g / scale) + 0.5).floor() as u32;
+        let b = ((b / scale) + 0.5).floor() as u32;
+        self.d = ((exp as u32) << 27) | (b << 18) | (g << 9) | r;
+    }
+    fn getf(&self) -> (f32,f32,f32,f32) {
+        let scale = 2.0f32.powi((self.d >> 27) as i32 - 15 - 9);
+        ((self.d & 511) as f32 * scale,((self.d >> 9) & 511) as f32 * scale,((self.d >> 18) & 511) as f32 * scale,1.0)
+    }
+}
+
+#[derive(Copy,Clone)]
+pub struct RGB32F { r: f32,g: f32,b: f32, }
+impl Zero for RGB32F { fn zero() -> Self { RGB32F { r: 0.0,g: 0.0,b: 0.0, } } }
+impl Pixel for RGB32F {
+    const ALPHA: bool = false;
+    const BITS: u32 = 32;
+    fn set(&mut self,r: u8,g: u8,b: u8,_a: u8) { self.setf((r as f32) / 255.0,(g as f32) / 255.0,(b as f32) / 255.0,1.0); }
+    fn get(&self) -> (u8,u8,u8,u8) { let (r,g,b,_) = self.get16(); (to8(r),to8(g),to8(b),0xFF) }
+    fn set16(&mut self,r: u16,g: u16,b: u16,_a: u16) { self.setf((r as f32) / 65535.0,(g as f32) / 65535.0,(b as f32) / 65535.0,1.0); }
+    fn get16(&self) -> (u16,u16,u16,u16) { (f_to16(self.r),f_to16(self.g),f_to16(self.b),0xFFFF) }
+    fn setf(&mut self,r: f32,g: f32,b: f32,_a: f32) { self.r = r; self.g = g; self.b = b; }
+    fn getf(&self) -> (f32,f32,f32,f32) { (self.r,self.g,self.b,1.0) }
+}
+
+#[derive(Copy,Clone)]
+pub struct RGBA32F { r: f32,g: f32,b: f32,a: f32, }
+impl Zero for RGBA32F { fn zero() -> Self { RGBA32F { r: 0.0,g: 0.0,b: 0.0,a: 0.0, } } }
+impl Pixel for RGBA32F {
+    const ALPHA: bool = true;
+    const BITS: u32 = 32;
+    fn set(&mut self,r: u8,g: u8,b: u8,a: u8) { self.setf((r as f32) / 255.0,(g as f32) / 255.0,(b as f32) / 255.0,(a as f32) / 255.0); }
+    fn get(&self) -> (u8,u8,u8,u8) { let (r,g,b,a) = self.get16(); (to8(r),to8(g),to8(b),to8(a)) }
+    fn set16(&mut self,r: u16,g: u16,b: u16,a: u16) { self.setf((r as f32) / 65535.0,(g as f32) / 65535.0,(b as f32) / 65535.0,(a as f32) / 65535.0); }
+    fn get16(&self) -> (u16,u16,u16,u16) { (f_to16(self.r),f_to16(self.g),f_to16(self.b),f_to16(self.a)) }
+    fn setf(&mut self,r: f32,g: f32,b: f32,a: f32) { self.r = r; self.g = g; self.b = b; self.a = a; }
+    fn getf(&self) -> (f32,f32,f32,f32) { (self.r,self.g,self.b,self.a) }
+}
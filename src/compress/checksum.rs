@@ -0,0 +1,96 @@
+// E - compress - Checksums
+// Desmond Germans, 2020
+
+const fn make_crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if (c & 1) != 0 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+const CRC_TABLE: [u32; 256] = make_crc_table();
+
+/// Running CRC-32, as used by GZIP and PNG.
+#[derive(Copy,Clone)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        Crc32 {
+            crc: 0xFFFFFFFF,
+        }
+    }
+
+    /// Add data to the checksum.
+    pub fn update(&mut self,src: &[u8]) {
+        let mut crc = self.crc;
+        for &d in src {
+            crc = CRC_TABLE[((crc ^ (d as u32)) & 255) as usize] ^ (crc >> 8);
+        }
+        self.crc = crc;
+    }
+
+    /// Current value of the checksum.
+    pub fn value(&self) -> u32 {
+        self.crc ^ 0xFFFFFFFF
+    }
+}
+
+/// Running Adler-32, as used by ZLIB.
+#[derive(Copy,Clone)]
+pub struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+impl Adler32 {
+    pub fn new() -> Adler32 {
+        Adler32 {
+            a: 1,
+            b: 0,
+        }
+    }
+
+    /// Add data to the checksum.
+    pub fn update(&mut self,src: &[u8]) {
+        // 5552 is the largest run that cannot overflow b
+        for chunk in src.chunks(5552) {
+            for &d in chunk {
+                self.a += d as u32;
+                self.b += self.a;
+            }
+            self.a %= 65521;
+            self.b %= 65521;
+        }
+    }
+
+    /// Current value of the checksum.
+    pub fn value(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
+
+/// CRC-32 of a buffer.
+pub fn crc32(src: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(src);
+    crc.value()
+}
+
+/// Adler-32 of a buffer.
+pub fn adler32(src: &[u8]) -> u32 {
+    let mut adler = Adler32::new();
+    adler.update(src);
+    adler.value()
+}
@@ -0,0 +1,633 @@
+// E - compress - Deflate
+// Desmond Germans, 2020
+
+use {
+    super::*,
+    std::io,
+};
+
+const WINDOW_MASK: usize = WINDOW_SIZE - 1;
+const HASH_BITS: u32 = 15;
+const HASH_SIZE: usize = 1 << HASH_BITS;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const BLOCK_TOKENS: usize = 16384;
+const STORED_SIZE: usize = 65535;
+const CHUNK_SIZE: usize = 4 * WINDOW_SIZE;
+const NO_POS: usize = usize::MAX;
+
+// search depth, good enough match length and lazy matching, per compression level
+const LEVELS: [(usize,usize,bool); 10] = [
+    (0,0,false),
+    (4,8,false),
+    (8,16,false),
+    (16,32,false),
+    (16,32,true),
+    (32,64,true),
+    (64,128,true),
+    (128,128,true),
+    (512,MAX_MATCH,true),
+    (4096,MAX_MATCH,true),
+];
+
+#[derive(Copy,Clone)]
+enum Token {
+    Literal(u8),
+    Match(usize,usize),
+}
+
+struct ZipWriter {
+    data: Vec<u8>,
+    cache: u64,
+    bit: u32,
+}
+
+impl ZipWriter {
+    fn new() -> ZipWriter {
+        ZipWriter {
+            data: Vec::new(),
+            cache: 0,
+            bit: 0,
+        }
+    }
+
+    fn write_bits(&mut self,value: u32,n: u32) {
+        self.cache |= (value as u64) << self.bit;
+        self.bit += n;
+        while self.bit >= 8 {
+            self.data.push(self.cache as u8);
+            self.cache >>= 8;
+            self.bit -= 8;
+        }
+    }
+
+    fn align(&mut self) {
+        if self.bit > 0 {
+            self.data.push(self.cache as u8);
+            self.cache = 0;
+            self.bit = 0;
+        }
+    }
+}
+
+fn length_code(length: usize) -> usize {
+    let mut code = 28;
+    while LITLEN_LENGTH[code] as usize > length {
+        code -= 1;
+    }
+    code
+}
+
+fn dist_code(dist: usize) -> usize {
+    let mut code = 29;
+    while DIST_DIST[code] as usize > dist {
+        code -= 1;
+    }
+    code
+}
+
+// optimal code lengths, limited to max_length bits
+fn huffman_lengths(freqs: &[u32],max_length: usize) -> Vec<u8> {
+    let mut lengths = vec![0u8; freqs.len()];
+    let mut symbols: Vec<usize> = (0..freqs.len()).filter(|&i| freqs[i] > 0).collect();
+    if symbols.is_empty() {
+        return lengths;
+    }
+    if symbols.len() == 1 {
+        lengths[symbols[0]] = 1;
+        return lengths;
+    }
+
+    // build the tree, leaves are 0..n, internal nodes follow
+    symbols.sort_by(|&a,&b| freqs[a].cmp(&freqs[b]).then(a.cmp(&b)));
+    let n = symbols.len();
+    let mut weight: Vec<u64> = symbols.iter().map(|&s| freqs[s] as u64).collect();
+    let mut parent = vec![0usize; 2 * n - 1];
+    let mut leaf = 0usize;
+    let mut node = n;
+    for _i in 0..n - 1 {
+        let mut pick = [0usize; 2];
+        for k in 0..2 {
+            // both queues are sorted, so take the lighter head
+            if (leaf < n) && ((node >= weight.len()) || (weight[leaf] <= weight[node])) {
+                pick[k] = leaf;
+                leaf += 1;
+            }
+            else {
+                pick[k] = node;
+                node += 1;
+            }
+        }
+        let index = weight.len();
+        weight.push(weight[pick[0]] + weight[pick[1]]);
+        parent[pick[0]] = index;
+        parent[pick[1]] = index;
+    }
+
+    // count leaves per depth
+    let root = 2 * n - 2;
+    let mut depth = vec![0usize; 2 * n - 1];
+    let mut counts = vec![0usize; 2 * n];
+    for i in (0..root).rev() {
+        depth[i] = depth[parent[i]] + 1;
+    }
+    for i in 0..n {
+        counts[depth[i]] += 1;
+    }
+
+    // move overly long codes up, keeping the Kraft sum at one
+    let mut bl_count = vec![0usize; max_length + 1];
+    for d in 1..counts.len() {
+        if d > max_length {
+            bl_count[max_length] += counts[d];
+        }
+        else {
+            bl_count[d] += counts[d];
+        }
+    }
+    let mut total: usize = 0;
+    for d in 1..max_length + 1 {
+        total += bl_count[d] << (max_length - d);
+    }
+    while total > (1 << max_length) {
+        bl_count[max_length] -= 1;
+        for d in (1..max_length).rev() {
+            if bl_count[d] != 0 {
+                bl_count[d] -= 1;
+                bl_count[d + 1] += 2;
+                break;
+            }
+        }
+        total -= 1;
+    }
+
+    // most frequent symbols get the shortest codes
+    let mut i = n;
+    for d in 1..max_length + 1 {
+        for _k in 0..bl_count[d] {
+            i -= 1;
+            lengths[symbols[i]] = d as u8;
+        }
+    }
+    lengths
+}
+
+// canonical codes, bit-reversed so they can be written LSB-first
+fn huffman_codes(lengths: &[u8]) -> Vec<u16> {
+    let mut bl_count = [0u32; 16];
+    for &length in lengths {
+        bl_count[length as usize] += 1;
+    }
+    bl_count[0] = 0;
+    let mut next_code = [0u32; 16];
+    let mut code = 0u32;
+    for bits in 1..16 {
+        code = (code + bl_count[bits - 1]) << 1;
+        next_code[bits] = code;
+    }
+    let mut codes = vec![0u16; lengths.len()];
+    for i in 0..lengths.len() {
+        let length = lengths[i] as usize;
+        if length != 0 {
+            codes[i] = bit_reverse(next_code[length],length as u32) as u16;
+            next_code[length] += 1;
+        }
+    }
+    codes
+}
+
+// run-length encode the code lengths into (symbol,extra) pairs
+fn encode_lengths(lengths: &[u8]) -> Vec<(u8,u8)> {
+    let mut result: Vec<(u8,u8)> = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        let length = lengths[i];
+        let mut run = 1;
+        while (i + run < lengths.len()) && (lengths[i + run] == length) {
+            run += 1;
+        }
+        i += run;
+        if length == 0 {
+            while run >= 11 {
+                let n = if run > 138 { 138 } else { run };
+                result.push((18,(n - 11) as u8));
+                run -= n;
+            }
+            if run >= 3 {
+                result.push((17,(run - 3) as u8));
+                run = 0;
+            }
+        }
+        else {
+            result.push((length,0));
+            run -= 1;
+            while run >= 3 {
+                let n = if run > 6 { 6 } else { run };
+                result.push((16,(n - 3) as u8));
+                run -= n;
+            }
+        }
+        for _k in 0..run {
+            result.push((length,0));
+        }
+    }
+    result
+}
+
+fn write_tokens(writer: &mut ZipWriter,tokens: &[Token],litlen_lengths: &[u8],litlen_codes: &[u16],dist_lengths: &[u8],dist_codes: &[u16]) {
+    for token in tokens {
+        match *token {
+            Token::Literal(value) => {
+                writer.write_bits(litlen_codes[value as usize] as u32,litlen_lengths[value as usize] as u32);
+            },
+            Token::Match(length,dist) => {
+                let code = length_code(length);
+                writer.write_bits(litlen_codes[257 + code] as u32,litlen_lengths[257 + code] as u32);
+                if LITLEN_EXTRA[code] > 0 {
+                    writer.write_bits((length - LITLEN_LENGTH[code] as usize) as u32,LITLEN_EXTRA[code] as u32);
+                }
+                let code = dist_code(dist);
+                writer.write_bits(dist_codes[code] as u32,dist_lengths[code] as u32);
+                if DIST_EXTRA[code] > 0 {
+                    writer.write_bits((dist - DIST_DIST[code] as usize) as u32,DIST_EXTRA[code] as u32);
+                }
+            },
+        }
+    }
+    writer.write_bits(litlen_codes[256] as u32,litlen_lengths[256] as u32);
+}
+
+fn write_stored(writer: &mut ZipWriter,src: &[u8],is_final: bool) {
+    let mut sp = 0;
+    loop {
+        let length = if src.len() - sp > STORED_SIZE { STORED_SIZE } else { src.len() - sp };
+        let last = sp + length == src.len();
+        writer.write_bits(if is_final && last { 1 } else { 0 },1);
+        writer.write_bits(0,2);
+        writer.align();
+        writer.data.push((length & 255) as u8);
+        writer.data.push((length >> 8) as u8);
+        writer.data.push((!length & 255) as u8);
+        writer.data.push(((!length >> 8) & 255) as u8);
+        writer.data.extend_from_slice(&src[sp..sp + length]);
+        sp += length;
+        if last {
+            break;
+        }
+    }
+}
+
+// write one block, picking whichever of stored, fixed or dynamic Huffman is smallest
+fn write_block(writer: &mut ZipWriter,tokens: &[Token],src: &[u8],is_final: bool) {
+    let mut litlen_freqs = [0u32; 286];
+    let mut dist_freqs = [0u32; 30];
+    for token in tokens {
+        match *token {
+            Token::Literal(value) => { litlen_freqs[value as usize] += 1; },
+            Token::Match(length,dist) => {
+                litlen_freqs[257 + length_code(length)] += 1;
+                dist_freqs[dist_code(dist)] += 1;
+            },
+        }
+    }
+    litlen_freqs[256] = 1;
+
+    // dynamic tables
+    let litlen_lengths = huffman_lengths(&litlen_freqs,15);
+    let mut dist_lengths = huffman_lengths(&dist_freqs,15);
+    if dist_lengths.iter().all(|&l| l == 0) {
+        dist_lengths[0] = 1;
+    }
+    let mut hlit = 286;
+    while (hlit > 257) && (litlen_lengths[hlit - 1] == 0) {
+        hlit -= 1;
+    }
+    let mut hdist = 30;
+    while (hdist > 1) && (dist_lengths[hdist - 1] == 0) {
+        hdist -= 1;
+    }
+    let mut all_lengths: Vec<u8> = Vec::new();
+    all_lengths.extend_from_slice(&litlen_lengths[0..hlit]);
+    all_lengths.extend_from_slice(&dist_lengths[0..hdist]);
+    let encoded = encode_lengths(&all_lengths);
+    let mut hc_freqs = [0u32; 19];
+    for &(symbol,_) in &encoded {
+        hc_freqs[symbol as usize] += 1;
+    }
+    let hc_lengths = huffman_lengths(&hc_freqs,7);
+    let mut hclen = 19;
+    while (hclen > 4) && (hc_lengths[HCORD[hclen - 1]] == 0) {
+        hclen -= 1;
+    }
+
+    // fixed tables
+    let fixed_litlen_lengths = fixed_litlen_lengths();
+    let fixed_dist_lengths = [5u8; 30];
+
+    // estimate sizes in bits
+    let mut dynamic_size = 3 + 5 + 5 + 4 + 3 * hclen;
+    for &(symbol,_) in &encoded {
+        dynamic_size += hc_lengths[symbol as usize] as usize + match symbol { 16 => 2,17 => 3,18 => 7,_ => 0, };
+    }
+    let mut fixed_size = 3;
+    for i in 0..286 {
+        dynamic_size += litlen_freqs[i] as usize * litlen_lengths[i] as usize;
+        fixed_size += litlen_freqs[i] as usize * fixed_litlen_lengths[i] as usize;
+        if i >= 257 {
+            dynamic_size += litlen_freqs[i] as usize * LITLEN_EXTRA[i - 257] as usize;
+            fixed_size += litlen_freqs[i] as usize * LITLEN_EXTRA[i - 257] as usize;
+        }
+    }
+    for i in 0..30 {
+        dynamic_size += dist_freqs[i] as usize * (dist_lengths[i] as usize + DIST_EXTRA[i] as usize);
+        fixed_size += dist_freqs[i] as usize * (5 + DIST_EXTRA[i] as usize);
+    }
+    let stored_size = (src.len() + 5 * (src.len() / STORED_SIZE + 1)) * 8 + 7;
+
+    if (stored_size <= dynamic_size) && (stored_size <= fixed_size) {
+        write_stored(writer,src,is_final);
+    }
+    else if fixed_size <= dynamic_size {
+        writer.write_bits(if is_final { 1 } else { 0 },1);
+        writer.write_bits(1,2);
+        let litlen_codes = huffman_codes(&fixed_litlen_lengths);
+        let dist_codes = huffman_codes(&fixed_dist_lengths);
+        write_tokens(writer,tokens,&fixed_litlen_lengths,&litlen_codes,&fixed_dist_lengths,&dist_codes);
+    }
+    else {
+        writer.write_bits(if is_final { 1 } else { 0 },1);
+        writer.write_bits(2,2);
+        writer.write_bits((hlit - 257) as u32,5);
+        writer.write_bits((hdist - 1) as u32,5);
+        writer.write_bits((hclen - 4) as u32,4);
+        for i in 0..hclen {
+            writer.write_bits(hc_lengths[HCORD[i]] as u32,3);
+        }
+        let hc_codes = huffman_codes(&hc_lengths);
+        for &(symbol,extra) in &encoded {
+            writer.write_bits(hc_codes[symbol as usize] as u32,hc_lengths[symbol as usize] as u32);
+            match symbol {
+                16 => { writer.write_bits(extra as u32,2); },
+                17 => { writer.write_bits(extra as u32,3); },
+                18 => { writer.write_bits(extra as u32,7); },
+                _ => { },
+            }
+        }
+        let litlen_codes = huffman_codes(&litlen_lengths);
+        let dist_codes = huffman_codes(&dist_lengths);
+        write_tokens(writer,tokens,&litlen_lengths,&litlen_codes,&dist_lengths,&dist_codes);
+    }
+}
+
+fn hash(src: &[u8],p: usize) -> usize {
+    let d = ((src[p] as u32) << 16) | ((src[p + 1] as u32) << 8) | (src[p + 2] as u32);
+    (d.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+// find the longest match for position p, returns (length,distance)
+fn find_match(src: &[u8],p: usize,head: &[usize],prev: &[usize],max_chain: usize,nice: usize) -> (usize,usize) {
+    let mut best_length = 0;
+    let mut best_dist = 0;
+    if p + MIN_MATCH > src.len() {
+        return (0,0);
+    }
+    let max_length = if src.len() - p > MAX_MATCH { MAX_MATCH } else { src.len() - p };
+    let mut candidate = head[hash(src,p)];
+    let mut chain = max_chain;
+    while (candidate != NO_POS) && (candidate < p) && (p - candidate <= WINDOW_SIZE) && (chain > 0) {
+        if src[candidate + best_length] == src[p + best_length] {
+            let mut length = 0;
+            while (length < max_length) && (src[candidate + length] == src[p + length]) {
+                length += 1;
+            }
+            if length > best_length {
+                best_length = length;
+                best_dist = p - candidate;
+                if (length >= nice) || (length >= max_length) {
+                    break;
+                }
+            }
+        }
+        let next = prev[candidate & WINDOW_MASK];
+        if (next == NO_POS) || (next >= candidate) {
+            break;
+        }
+        candidate = next;
+        chain -= 1;
+    }
+    if best_length >= MIN_MATCH {
+        (best_length,best_dist)
+    }
+    else {
+        (0,0)
+    }
+}
+
+/// Incremental compressor, taking data through `Write` and writing the compressed stream to `W`.
+pub struct Deflater<W: Write> {
+    inner: Option<W>,
+    format: Format,
+    level: usize,
+    writer: ZipWriter,
+    buffer: Vec<u8>,
+    done: usize,
+    head: Vec<usize>,
+    prev: Vec<usize>,
+    header_written: bool,
+    adler: Adler32,
+    crc: Crc32,
+    total: u64,
+}
+
+impl<W: Write> Deflater<W> {
+    /// Create new compressor.
+    /// # Arguments
+    /// * `inner` - Destination of the compressed data.
+    /// * `format` - Framing of the compressed data.
+    /// * `level` - Compression level, 0 (store only) to 9 (best).
+    pub fn new(inner: W,format: Format,level: u32) -> Deflater<W> {
+        Deflater {
+            inner: Some(inner),
+            format: format,
+            level: if level > 9 { 9 } else { level as usize },
+            writer: ZipWriter::new(),
+            buffer: Vec::new(),
+            done: 0,
+            head: vec![NO_POS; HASH_SIZE],
+            prev: vec![NO_POS; WINDOW_SIZE],
+            header_written: false,
+            adler: Adler32::new(),
+            crc: Crc32::new(),
+            total: 0,
+        }
+    }
+
+    fn write_header(&mut self) {
+        match self.format {
+            Format::Raw => { },
+            Format::Zlib => {
+                let flevel = if self.level < 2 { 0 } else if self.level < 6 { 1 } else if self.level == 6 { 2 } else { 3 };
+                let cmf = 0x78u16;
+                let mut flg = flevel << 6;
+                flg += 31 - (((cmf << 8) | flg) % 31);
+                self.writer.data.push(cmf as u8);
+                self.writer.data.push(flg as u8);
+            },
+            Format::Gzip => {
+                let xfl = if self.level == 9 { 2 } else if self.level == 1 { 4 } else { 0 };
+                self.writer.data.extend_from_slice(&[0x1F,0x8B,8,0,0,0,0,0,xfl,255]);
+            },
+        }
+        self.header_written = true;
+    }
+
+    // compress everything that is pending in the buffer
+    fn compress(&mut self,is_final: bool) {
+        if !self.header_written {
+            self.write_header();
+        }
+        let src = &self.buffer;
+        let (max_chain,nice,lazy) = LEVELS[self.level];
+        if self.level == 0 {
+            if is_final || (self.done < src.len()) {
+                write_stored(&mut self.writer,&src[self.done..],is_final);
+            }
+            self.done = src.len();
+            return;
+        }
+        let head = &mut self.head;
+        let prev = &mut self.prev;
+        let mut tokens: Vec<Token> = Vec::new();
+        let mut block_start = self.done;
+        let mut p = self.done;
+        let mut pending: Option<(usize,usize)> = None;
+        while p < src.len() {
+            let current = match pending.take() {
+                Some(m) => m,
+                None => find_match(src,p,head,prev,max_chain,nice),
+            };
+            if p + MIN_MATCH <= src.len() {
+                let h = hash(src,p);
+                prev[p & WINDOW_MASK] = head[h];
+                head[h] = p;
+            }
+            if current.0 == 0 {
+                tokens.push(Token::Literal(src[p]));
+                p += 1;
+            }
+            else {
+                // see if the next position gives a better match
+                if lazy && (current.0 < nice) && (p + 1 < src.len()) {
+                    let next = find_match(src,p + 1,head,prev,max_chain,nice);
+                    if next.0 > current.0 {
+                        tokens.push(Token::Literal(src[p]));
+                        pending = Some(next);
+                        p += 1;
+                        continue;
+                    }
+                }
+                tokens.push(Token::Match(current.0,current.1));
+                for i in p + 1..p + current.0 {
+                    if i + MIN_MATCH <= src.len() {
+                        let h = hash(src,i);
+                        prev[i & WINDOW_MASK] = head[h];
+                        head[h] = i;
+                    }
+                }
+                p += current.0;
+            }
+            if tokens.len() >= BLOCK_TOKENS {
+                write_block(&mut self.writer,&tokens,&src[block_start..p],false);
+                tokens.clear();
+                block_start = p;
+            }
+        }
+        if is_final || !tokens.is_empty() {
+            write_block(&mut self.writer,&tokens,&src[block_start..p],is_final);
+        }
+        self.done = p;
+    }
+
+    // drop everything but the last window from the buffer, keeping the hash chains consistent
+    fn slide(&mut self) {
+        if self.done < 2 * WINDOW_SIZE {
+            return;
+        }
+        let drop = ((self.done - WINDOW_SIZE) / WINDOW_SIZE) * WINDOW_SIZE;
+        self.buffer.drain(0..drop);
+        self.done -= drop;
+        for p in self.head.iter_mut().chain(self.prev.iter_mut()) {
+            *p = if (*p != NO_POS) && (*p >= drop) { *p - drop } else { NO_POS };
+        }
+    }
+
+    fn emit(&mut self) -> io::Result<()> {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.write_all(&self.writer.data)?;
+        }
+        self.writer.data.clear();
+        Ok(())
+    }
+
+    fn finish_stream(&mut self) -> io::Result<()> {
+        self.compress(true);
+        self.writer.align();
+        match self.format {
+            Format::Raw => { },
+            Format::Zlib => {
+                let adler = self.adler.value();
+                self.writer.data.extend_from_slice(&adler.to_be_bytes());
+            },
+            Format::Gzip => {
+                let crc = self.crc.value();
+                self.writer.data.extend_from_slice(&crc.to_le_bytes());
+                self.writer.data.extend_from_slice(&(self.total as u32).to_le_bytes());
+            },
+        }
+        self.emit()
+    }
+
+    /// Finish the stream and take back the destination.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.finish_stream()?;
+        Ok(self.inner.take().unwrap())
+    }
+}
+
+impl<W: Write> Write for Deflater<W> {
+    fn write(&mut self,buf: &[u8]) -> io::Result<usize> {
+        match self.format {
+            Format::Raw => { },
+            Format::Zlib => { self.adler.update(buf); },
+            Format::Gzip => { self.crc.update(buf); },
+        }
+        self.total += buf.len() as u64;
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() - self.done >= CHUNK_SIZE {
+            self.compress(false);
+            self.slide();
+            self.emit()?;
+        }
+        Ok(buf.len())
+    }
+
+    // sync flush: compress all pending data and byte-align the stream with an empty stored block
+    fn flush(&mut self) -> io::Result<()> {
+        self.compress(false);
+        self.slide();
+        write_stored(&mut self.writer,&[],false);
+        self.emit()?;
+        match self.inner.as_mut() {
+            Some(inner) => inner.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Drop for Deflater<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.finish_stream();
+        }
+    }
+}
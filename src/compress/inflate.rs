@@ -0,0 +1,472 @@
+// E - compress - Inflate
+// Desmond Germans, 2020
+
+use {
+    super::*,
+    std::io,
+};
+
+const TABLE: usize = 8;  // 8 seems to be a good balance
+const TABLE_SIZE: usize = 1 << TABLE;
+const INPUT_SIZE: usize = 65536;
+const STEP_SIZE: usize = 32768;
+
+fn insert_code(tables: &mut Vec<[i16; TABLE_SIZE]>,ofs: u32,code: u16,length: u8) -> u32 {
+    let shift = 32 - TABLE;
+    if (length as usize) > TABLE {
+        let pos: usize = ((ofs >> shift) & ((TABLE_SIZE - 1) as u32)) as usize;
+        let p = bit_reverse(pos as u32,TABLE as u32) as usize;
+        let mut n: i16 = tables.len() as i16;
+        if tables[0][p] == 0 {
+            tables.push([0i16; TABLE_SIZE]);
+            tables[0][p] = -n;
+        }
+        else {
+            n = -tables[0][p];
+        }
+        let shift = 32 - TABLE - TABLE;
+        let pos = ((ofs >> shift) & ((TABLE_SIZE - 1) as u32)) as usize;
+        let count = TABLE_SIZE >> (length - TABLE as u8) as usize;
+        for i in pos..pos + count {
+            let p = bit_reverse(i as u32,TABLE as u32) as usize;
+            tables[n as usize][p] = ((code << 5) | (length as u16)) as i16;
+        }
+        (count << shift) as u32
+    }
+    else {
+        let pos = ((ofs >> shift) & ((TABLE_SIZE - 1) as u32)) as usize;
+        let count = TABLE_SIZE >> length as usize;
+        for i in pos..pos + count {
+            let p = bit_reverse(i as u32,TABLE as u32) as usize;
+            tables[0][p] = ((code << 5) | (length as u16)) as i16;
+        }
+        (count << shift) as u32
+    }
+}
+
+fn create_huffman_tables(lengths: &[u8]) -> Option<Vec<[i16; TABLE_SIZE]>> {
+    let mut tables: Vec<[i16; TABLE_SIZE]> = Vec::new();
+    tables.push([0i16; TABLE_SIZE]);
+    let mut ofs: u64 = 0;
+    for i in 1..16 {
+        for k in 0..lengths.len() {
+            if lengths[k] == i {
+                // oversubscribed code
+                if ofs >= (1 << 32) {
+                    return None;
+                }
+                let size = insert_code(&mut tables,ofs as u32,k as u16,lengths[k]);
+                ofs += size as u64;
+            }
+        }
+    }
+    Some(tables)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,message)
+}
+
+fn unexpected_eof() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof,"compressed stream ended early")
+}
+
+#[derive(Copy,Clone,PartialEq)]
+enum State {
+    Header,
+    Block,
+    Stored(usize),
+    Huffman,
+    Trailer,
+    Done,
+}
+
+/// Incremental decompressor, reading compressed data from `R` and handing out decompressed data through `Read`.
+pub struct Inflater<R: Read> {
+    inner: R,
+    format: Format,
+    input: Vec<u8>,
+    ip: usize,
+    il: usize,
+    eof: bool,
+    cache: u64,
+    bit: u32,
+    state: State,
+    is_final: bool,
+    hlitlen_tables: Vec<[i16; TABLE_SIZE]>,
+    hdist_tables: Vec<[i16; TABLE_SIZE]>,
+    history: Vec<u8>,
+    hp: usize,
+    adler: Adler32,
+    crc: Crc32,
+    total: u64,
+}
+
+impl<R: Read> Inflater<R> {
+    /// Create new decompressor.
+    /// # Arguments
+    /// * `inner` - Source of the compressed data.
+    /// * `format` - Framing of the compressed data.
+    pub fn new(inner: R,format: Format) -> Inflater<R> {
+        Inflater {
+            inner: inner,
+            format: format,
+            input: vec![0; INPUT_SIZE],
+            ip: 0,
+            il: 0,
+            eof: false,
+            cache: 0,
+            bit: 0,
+            state: State::Header,
+            is_final: false,
+            hlitlen_tables: Vec::new(),
+            hdist_tables: Vec::new(),
+            history: Vec::new(),
+            hp: 0,
+            adler: Adler32::new(),
+            crc: Crc32::new(),
+            total: 0,
+        }
+    }
+
+    /// Look at the source.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Take back the source. Any data read ahead of the end of the stream is lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn refill(&mut self) -> io::Result<()> {
+        while self.bit <= 56 {
+            if self.ip >= self.il {
+                if self.eof {
+                    break;
+                }
+                self.il = loop {
+                    match self.inner.read(&mut self.input) {
+                        Ok(n) => break n,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => { },
+                        Err(e) => { return Err(e); },
+                    }
+                };
+                self.ip = 0;
+                if self.il == 0 {
+                    self.eof = true;
+                    break;
+                }
+            }
+            self.cache |= (self.input[self.ip] as u64) << self.bit;
+            self.ip += 1;
+            self.bit += 8;
+        }
+        Ok(())
+    }
+
+    fn read_bits(&mut self,n: u32) -> io::Result<u32> {
+        if self.bit < n {
+            self.refill()?;
+            if self.bit < n {
+                return Err(unexpected_eof());
+            }
+        }
+        let result = (self.cache & ((1 << n) - 1)) as u32;
+        self.cache >>= n;
+        self.bit -= n;
+        Ok(result)
+    }
+
+    fn read_symbol(&mut self,hlitlen: bool) -> io::Result<usize> {
+        if self.bit < 15 {
+            self.refill()?;
+        }
+        let tables = if hlitlen { &self.hlitlen_tables } else { &self.hdist_tables };
+        let mut stuff = tables[0][(self.cache & (TABLE_SIZE - 1) as u64) as usize];
+        if stuff < 0 {
+            stuff = tables[(-stuff) as usize][((self.cache >> TABLE) & (TABLE_SIZE - 1) as u64) as usize];
+        }
+        if stuff == 0 {
+            return Err(invalid("invalid Huffman code"));
+        }
+        let length = (stuff & 31) as u32;
+        if length > self.bit {
+            return Err(unexpected_eof());
+        }
+        self.cache >>= length;
+        self.bit -= length;
+        Ok((stuff >> 5) as usize)
+    }
+
+    fn align(&mut self) {
+        let rest = self.bit & 7;
+        self.cache >>= rest;
+        self.bit -= rest;
+    }
+
+    fn read_header(&mut self) -> io::Result<()> {
+        match self.format {
+            Format::Raw => { },
+            Format::Zlib => {
+                let cmf = self.read_bits(8)?;
+                let flg = self.read_bits(8)?;
+                if ((cmf & 15) != 8) || ((cmf >> 4) > 7) || ((((cmf << 8) | flg) % 31) != 0) {
+                    return Err(invalid("invalid ZLIB header"));
+                }
+                if (flg & 0x20) != 0 {
+                    return Err(invalid("ZLIB preset dictionaries are not supported"));
+                }
+            },
+            Format::Gzip => {
+                let id1 = self.read_bits(8)?;
+                let id2 = self.read_bits(8)?;
+                let cm = self.read_bits(8)?;
+                let flg = self.read_bits(8)?;
+                if (id1 != 0x1F) || (id2 != 0x8B) || (cm != 8) {
+                    return Err(invalid("invalid GZIP header"));
+                }
+                // skip MTIME, XFL and OS
+                for _i in 0..6 {
+                    self.read_bits(8)?;
+                }
+                if (flg & 0x04) != 0 {  // FEXTRA
+                    let xlen = self.read_bits(16)?;
+                    for _i in 0..xlen {
+                        self.read_bits(8)?;
+                    }
+                }
+                if (flg & 0x08) != 0 {  // FNAME
+                    while self.read_bits(8)? != 0 { }
+                }
+                if (flg & 0x10) != 0 {  // FCOMMENT
+                    while self.read_bits(8)? != 0 { }
+                }
+                if (flg & 0x02) != 0 {  // FHCRC
+                    self.read_bits(16)?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn read_trailer(&mut self) -> io::Result<()> {
+        self.align();
+        match self.format {
+            Format::Raw => { },
+            Format::Zlib => {
+                let mut adler = 0u32;
+                for _i in 0..4 {
+                    adler = (adler << 8) | self.read_bits(8)?;
+                }
+                if adler != self.adler.value() {
+                    return Err(invalid("Adler-32 mismatch"));
+                }
+            },
+            Format::Gzip => {
+                let crc = self.read_bits(16)? | (self.read_bits(16)? << 16);
+                let size = self.read_bits(16)? | (self.read_bits(16)? << 16);
+                if crc != self.crc.value() {
+                    return Err(invalid("CRC-32 mismatch"));
+                }
+                if size != (self.total as u32) {
+                    return Err(invalid("GZIP size mismatch"));
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn read_block_header(&mut self) -> io::Result<()> {
+        self.is_final = self.read_bits(1)? == 1;
+        match self.read_bits(2)? {
+            0 => {
+                self.align();
+                let length = self.read_bits(16)?;
+                let nlength = self.read_bits(16)?;
+                if length != (!nlength & 0xFFFF) {
+                    return Err(invalid("stored block length mismatch"));
+                }
+                self.state = State::Stored(length as usize);
+            },
+            1 => {
+                self.hlitlen_tables = create_huffman_tables(&fixed_litlen_lengths()).unwrap();
+                self.hdist_tables = create_huffman_tables(&[5u8; 30]).unwrap();
+                self.state = State::Huffman;
+            },
+            2 => {
+                // get table metrics
+                let hlit = self.read_bits(5)? as usize + 257;
+                let hdist = self.read_bits(5)? as usize + 1;
+                let hclen = self.read_bits(4)? as usize + 4;
+
+                // get length codes
+                let mut lengths: [u8; 19] = [0; 19];
+                for i in 0..hclen {
+                    lengths[HCORD[i]] = self.read_bits(3)? as u8;
+                }
+                self.hlitlen_tables = match create_huffman_tables(&lengths) {
+                    Some(tables) => tables,
+                    None => { return Err(invalid("invalid code length code")); },
+                };
+
+                // no really, get length codes
+                let mut lengths: [u8; 320] = [0; 320];
+                let mut ll: usize = 0;
+                while ll < hlit + hdist {
+                    let code = self.read_symbol(true)?;
+                    let (value,count) = match code {
+                        16 => {
+                            if ll == 0 {
+                                return Err(invalid("repeat without previous length"));
+                            }
+                            (lengths[ll - 1],self.read_bits(2)? + 3)
+                        },
+                        17 => (0,self.read_bits(3)? + 3),
+                        18 => (0,self.read_bits(7)? + 11),
+                        _ => (code as u8,1),
+                    };
+                    if ll + count as usize > hlit + hdist {
+                        return Err(invalid("too many code lengths"));
+                    }
+                    for _i in 0..count {
+                        lengths[ll] = value;
+                        ll += 1;
+                    }
+                }
+                if lengths[256] == 0 {
+                    return Err(invalid("missing end-of-block code"));
+                }
+                self.hlitlen_tables = match create_huffman_tables(&lengths[0..hlit]) {
+                    Some(tables) => tables,
+                    None => { return Err(invalid("invalid literal/length code")); },
+                };
+                self.hdist_tables = match create_huffman_tables(&lengths[hlit..hlit + hdist]) {
+                    Some(tables) => tables,
+                    None => { return Err(invalid("invalid distance code")); },
+                };
+                self.state = State::Huffman;
+            },
+            _ => {
+                return Err(invalid("invalid block type"));
+            },
+        }
+        Ok(())
+    }
+
+    fn read_codes(&mut self) -> io::Result<()> {
+        let start = self.history.len();
+        while self.history.len() - start < STEP_SIZE {
+            let code = self.read_symbol(true)?;
+            if code < 256 {
+                self.history.push(code as u8);
+            }
+            else if code == 256 {
+                self.state = State::Block;
+                break;
+            }
+            else {
+                // get lit/len length and extra bit entries
+                let code = code - 257;
+                if code >= 29 {
+                    return Err(invalid("invalid length code"));
+                }
+                let mut length = LITLEN_LENGTH[code] as usize;
+                let extra = LITLEN_EXTRA[code] as u32;
+                if extra > 0 {
+                    length += self.read_bits(extra)? as usize;
+                }
+
+                // get dist length and extra bit entries
+                let code = self.read_symbol(false)?;
+                if code >= 30 {
+                    return Err(invalid("invalid distance code"));
+                }
+                let mut dist = DIST_DIST[code] as usize;
+                let extra = DIST_EXTRA[code] as u32;
+                if extra > 0 {
+                    dist += self.read_bits(extra)? as usize;
+                }
+
+                // copy block
+                if dist > self.history.len() {
+                    return Err(invalid("distance too large"));
+                }
+                let sp = self.history.len() - dist;
+                for i in 0..length {
+                    let d = self.history[sp + i];
+                    self.history.push(d);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // decompress the next piece of the stream into the history
+    fn step(&mut self) -> io::Result<()> {
+        let start = self.history.len();
+        match self.state {
+            State::Header => {
+                self.read_header()?;
+                self.state = State::Block;
+            },
+            State::Block => {
+                if self.is_final {
+                    self.state = State::Trailer;
+                }
+                else {
+                    self.read_block_header()?;
+                }
+            },
+            State::Stored(length) => {
+                let n = if length > STEP_SIZE { STEP_SIZE } else { length };
+                for _i in 0..n {
+                    let d = self.read_bits(8)? as u8;
+                    self.history.push(d);
+                }
+                self.state = if n == length { State::Block } else { State::Stored(length - n) };
+            },
+            State::Huffman => {
+                self.read_codes()?;
+            },
+            State::Trailer => {
+                self.read_trailer()?;
+                self.state = State::Done;
+            },
+            State::Done => { },
+        }
+        let produced = &self.history[start..];
+        self.total += produced.len() as u64;
+        match self.format {
+            Format::Raw => { },
+            Format::Zlib => { self.adler.update(produced); },
+            Format::Gzip => { self.crc.update(produced); },
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Inflater<R> {
+    fn read(&mut self,buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.hp < self.history.len() {
+                let available = self.history.len() - self.hp;
+                let n = if buf.len() < available { buf.len() } else { available };
+                buf[0..n].copy_from_slice(&self.history[self.hp..self.hp + n]);
+                self.hp += n;
+                return Ok(n);
+            }
+            if (self.state == State::Done) || buf.is_empty() {
+                return Ok(0);
+            }
+
+            // keep one window of history around for back references
+            if self.history.len() > 3 * WINDOW_SIZE {
+                let drop = self.history.len() - WINDOW_SIZE;
+                self.history.drain(0..drop);
+                self.hp -= drop;
+            }
+            self.step()?;
+        }
+    }
+}
@@ -0,0 +1,161 @@
+// E - compress
+// Desmond Germans, 2020
+
+//! Compression algorithms.
+
+mod checksum;
+pub use checksum::*;
+
+mod inflate;
+pub use inflate::*;
+
+mod deflate;
+pub use deflate::*;
+
+use std::io::prelude::*;
+
+/// Framing around a deflate stream.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Format {
+    /// Bare deflate blocks (RFC 1951).
+    Raw,
+    /// ZLIB header and Adler-32 trailer (RFC 1950).
+    Zlib,
+    /// GZIP header and CRC-32 trailer (RFC 1952).
+    Gzip,
+}
+
+const LITLEN_LENGTH: [u16; 29] = [3,4,5,6,7,8,9,10,11,13,15,17,19,23,27,31,35,43,51,59,67,83,99,115,131,163,195,227,258];
+const LITLEN_EXTRA: [u8; 29] = [0,0,0,0,0,0,0,0,1,1,1,1,2,2,2,2,3,3,3,3,4,4,4,4,5,5,5,5,0];
+const DIST_DIST: [u16; 30] = [1,2,3,4,5,7,9,13,17,25,33,49,65,97,129,193,257,385,513,769,1025,1537,2049,3073,4097,6145,8193,12289,16385,24577];
+const DIST_EXTRA: [u8; 30] = [0,0,0,0,1,1,2,2,3,3,4,4,5,5,6,6,7,7,8,8,9,9,10,10,11,11,12,12,13,13];
+const HCORD: [usize; 19] = [16,17,18,0,8,7,9,6,10,5,11,4,12,3,13,2,14,1,15];
+
+const WINDOW_SIZE: usize = 32768;
+
+fn bit_reverse(value: u32,width: u32) -> u32 {
+    let mut result: u32 = 0;
+    for i in 0..width {
+        let bit: u32 = (value >> i) & 1;
+        result |= bit << (width - i - 1);
+    }
+    result
+}
+
+fn fixed_litlen_lengths() -> [u8; 288] {
+    let mut lengths = [0u8; 288];
+    for i in 0..288 {
+        lengths[i] = if i < 144 { 8 } else if i < 256 { 9 } else if i < 280 { 7 } else { 8 };
+    }
+    lengths
+}
+
+/// Decompress a complete stream.
+/// # Arguments
+/// * `src` - Compressed data.
+/// * `format` - Framing of the compressed data.
+/// # Returns
+/// * `None` - The data is corrupt or the checksum does not match.
+/// * `Some(data)` - The decompressed data.
+pub fn inflate(src: &[u8],format: Format) -> Option<Vec<u8>> {
+    let mut inflater = Inflater::new(src,format);
+    let mut dst: Vec<u8> = Vec::new();
+    match inflater.read_to_end(&mut dst) {
+        Ok(_) => Some(dst),
+        Err(_) => None,
+    }
+}
+
+/// Compress a complete buffer.
+/// # Arguments
+/// * `src` - Data to compress.
+/// * `format` - Framing of the compressed data.
+/// * `level` - Compression level, 0 (store only) to 9 (best).
+/// # Returns
+/// The compressed data.
+pub fn deflate(src: &[u8],format: Format,level: u32) -> Vec<u8> {
+    let mut deflater = Deflater::new(Vec::new(),format,level);
+    // writing into a Vec cannot fail
+    deflater.write_all(src).unwrap();
+    deflater.finish().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // text, runs, noise and a repeat further back than the window
+    fn sample() -> Vec<u8> {
+        let mut data: Vec<u8> = Vec::new();
+        for i in 0..2000 {
+            data.extend_from_slice(format!("line {} of the sample text\n",i % 37).as_bytes());
+        }
+        data.extend(std::iter::repeat(0xAA).take(5000));
+        let mut seed = 12345u32;
+        for _ in 0..40000 {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            data.push((seed >> 24) as u8);
+        }
+        let head = data[0..1000].to_vec();
+        data.extend_from_slice(&head);
+        data
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"123456789"),0xCBF43926);
+        assert_eq!(adler32(b"Wikipedia"),0x11E60398);
+    }
+
+    #[test]
+    fn inflate_reference() {
+        // produced by zlib and gzip
+        let zlib = [120,218,203,72,205,201,201,87,200,64,39,117,20,82,82,211,114,18,75,82,1,196,0,11,210];
+        let gzip = [31,139,8,0,0,0,0,0,2,3,203,72,205,201,201,87,200,64,39,117,20,82,82,211,114,18,75,82,1,214,62,203,245,32,0,0,0];
+        let expected = b"hello hello hello hello, deflate";
+        assert_eq!(inflate(&zlib,Format::Zlib).unwrap(),expected);
+        assert_eq!(inflate(&gzip,Format::Gzip).unwrap(),expected);
+        assert_eq!(inflate(&zlib[2..zlib.len() - 4],Format::Raw).unwrap(),expected);
+    }
+
+    #[test]
+    fn inflate_rejects_bad_checksum() {
+        let mut zlib = deflate(b"checksum",Format::Zlib,6);
+        let last = zlib.len() - 1;
+        zlib[last] ^= 1;
+        assert!(inflate(&zlib,Format::Zlib).is_none());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = sample();
+        for format in [Format::Raw,Format::Zlib,Format::Gzip] {
+            for level in 0..10 {
+                assert_eq!(inflate(&deflate(&data,format,level),format).unwrap(),data);
+                assert_eq!(inflate(&deflate(&[],format,level),format).unwrap(),Vec::<u8>::new());
+            }
+        }
+    }
+
+    #[test]
+    fn roundtrip_streaming() {
+        let data = sample();
+        let mut deflater = Deflater::new(Vec::new(),Format::Zlib,6);
+        for chunk in data.chunks(1000) {
+            deflater.write_all(chunk).unwrap();
+        }
+        let compressed = deflater.finish().unwrap();
+        assert!(compressed.len() < data.len());
+        let mut inflater = Inflater::new(&compressed[..],Format::Zlib);
+        let mut result: Vec<u8> = Vec::new();
+        let mut buffer = [0u8; 77];
+        loop {
+            let n = inflater.read(&mut buffer).unwrap();
+            if n == 0 {
+                break;
+            }
+            result.extend_from_slice(&buffer[0..n]);
+        }
+        assert_eq!(result,data);
+    }
+}
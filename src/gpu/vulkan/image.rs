@@ -104,6 +104,8 @@ impl InternalFormat for Vec4<i64> { const VK_FORMAT: VkFormat = VK_FORMAT_R64G64
 impl InternalFormat for Vec4<f64> { const VK_FORMAT: VkFormat = VK_FORMAT_R64G64B64A64_SFLOAT; }
 impl InternalFormat for pixel::RG11B10F { const VK_FORMAT: VkFormat = VK_FORMAT_B10G11R11_UFLOAT_PACK32; }
 impl InternalFormat for pixel::RGB9E5F { const VK_FORMAT: VkFormat = VK_FORMAT_E5B9G9R9_UFLOAT_PACK32; }
+impl InternalFormat for pixel::RGB32F { const VK_FORMAT: VkFormat = VK_FORMAT_R32G32B32_SFLOAT; }
+impl InternalFormat for pixel::RGBA32F { const VK_FORMAT: VkFormat = VK_FORMAT_R32G32B32A32_SFLOAT; }
 
 pub struct Image {
     pub session: Rc<Session>,
@@ -117,6 +119,26 @@ pub struct Image {
 
 // TODO: implement creation from session, like for texture mapping and such
 
+impl<T: pixel::Pixel> MipChain<T> {
+
+    /// Copy regions for `vkCmdCopyBufferToImage`, one per mip level, when `data` is uploaded to a staging buffer as is.
+    pub fn vk_buffer_image_copies(&self) -> Vec<VkBufferImageCopy> {
+        self.levels.iter().enumerate().map(|(i,level)| VkBufferImageCopy {
+            bufferOffset: (level.offset * std::mem::size_of::<T>()) as VkDeviceSize,
+            bufferRowLength: 0,  // rows are tightly packed
+            bufferImageHeight: 0,
+            imageSubresource: VkImageSubresourceLayers {
+                aspectMask: VK_IMAGE_ASPECT_COLOR_BIT as VkImageAspectFlags,
+                mipLevel: i as u32,
+                baseArrayLayer: 0,
+                layerCount: 1,
+            },
+            imageOffset: VkOffset3D { x: 0,y: 0,z: 0, },
+            imageExtent: VkExtent3D { width: level.size.x as u32,height: level.size.y as u32,depth: 1, },
+        }).collect()
+    }
+}
+
 impl SwapChain {
 
     pub fn get_images(&self) -> Vec<Rc<Image>> {
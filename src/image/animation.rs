@@ -0,0 +1,205 @@
+// E - image - Animation
+// Desmond Germans, 2020
+
+use crate::*;
+
+/// What happens to a frame's area before the next frame is rendered.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Disposal {
+    /// Leave the canvas as it is.
+    None,
+    /// Clear the frame's area to transparent.
+    Background,
+    /// Restore the frame's area to what it was before the frame was rendered.
+    Previous,
+}
+
+/// How a frame is combined with the canvas.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum FrameBlend {
+    /// Replace the canvas.
+    Source,
+    /// Alpha-blend over the canvas.
+    Over,
+}
+
+/// Single composited frame of an animation.
+#[derive(Clone)]
+pub struct Frame<T: pixel::Pixel> {
+    /// The full canvas after rendering this frame.
+    pub image: Mat<T>,
+    /// How long to show this frame, in milliseconds.
+    pub delay: u32,
+}
+
+/// Decoded animation.
+#[derive(Clone)]
+pub struct Animation<T: pixel::Pixel> {
+    /// Size of the canvas.
+    pub size: Vec2<usize>,
+    /// The composited frames.
+    pub frames: Vec<Frame<T>>,
+    /// Number of times to play the animation, 0 means forever.
+    pub loops: u32,
+}
+
+impl<T: pixel::Pixel> Animation<T> {
+    /// Total running time of one loop, in milliseconds, saturating at `u32::MAX`.
+    pub fn duration(&self) -> u32 {
+        self.frames.iter().fold(0u32,|duration,frame| duration.saturating_add(frame.delay))
+    }
+}
+
+// source-over compositing of straight alpha pixels, in float so 16-bit and float pixels keep their precision
+fn blend_over<T: pixel::Pixel>(dst: T,src: T) -> T {
+    let (sr,sg,sb,sa) = src.getf();
+    if sa >= 1.0 {
+        return src;
+    }
+    if sa <= 0.0 {
+        return dst;
+    }
+    let (dr,dg,db,da) = dst.getf();
+    let da = da * (1.0 - sa);
+    let a = sa + da;
+    let mix = |s: f32,d: f32| (s * sa + d * da) / a;
+    let mut result = T::zero();
+    result.setf(mix(sr,dr),mix(sg,dg),mix(sb,db),a);
+    result
+}
+
+// Builds the frame sequence of an animation. Decoders call begin() with the frame rectangle, render into canvas (or draw() a decoded image), and then call end().
+pub(crate) struct Compositor<T: pixel::Pixel> {
+    pub canvas: Mat<T>,
+    pub frames: Vec<Frame<T>>,
+    previous: Option<Mat<T>>,
+    rect: Rect<usize>,
+    disposal: Disposal,
+}
+
+impl<T: pixel::Pixel> Compositor<T> {
+    pub fn new(size: Vec2<usize>) -> Compositor<T> {
+        Compositor {
+            canvas: Mat::new(size),
+            frames: Vec::new(),
+            previous: None,
+            rect: rect!(0,0,0,0),
+            disposal: Disposal::None,
+        }
+    }
+
+    // start a new frame covering rect, clipped to the canvas
+    pub fn begin(&mut self,rect: Rect<usize>,disposal: Disposal) {
+        let x0 = if rect.o.x > self.canvas.size.x { self.canvas.size.x } else { rect.o.x };
+        let y0 = if rect.o.y > self.canvas.size.y { self.canvas.size.y } else { rect.o.y };
+        let x1 = if rect.o.x + rect.s.x > self.canvas.size.x { self.canvas.size.x } else { rect.o.x + rect.s.x };
+        let y1 = if rect.o.y + rect.s.y > self.canvas.size.y { self.canvas.size.y } else { rect.o.y + rect.s.y };
+        self.rect = rect!(x0,y0,x1 - x0,y1 - y0);
+        self.disposal = disposal;
+        self.previous = if disposal == Disposal::Previous { Some(self.canvas.clone()) } else { None };
+    }
+
+    // draw image over the frame rectangle
+    pub fn draw(&mut self,image: &Mat<T>,blend: FrameBlend) {
+        for y in 0..self.rect.s.y {
+            for x in 0..self.rect.s.x {
+                let p = (self.rect.o.x + x,self.rect.o.y + y);
+                self.canvas[p] = match blend {
+                    FrameBlend::Source => image[(x,y)],
+                    FrameBlend::Over => blend_over(self.canvas[p],image[(x,y)]),
+                };
+            }
+        }
+    }
+
+    // finish the frame, store the result and dispose
+    pub fn end(&mut self,delay: u32) {
+        self.frames.push(Frame {
+            image: self.canvas.clone(),
+            delay: delay,
+        });
+        match self.disposal {
+            Disposal::None => { },
+            Disposal::Background => {
+                for y in self.rect.o.y..self.rect.o.y + self.rect.s.y {
+                    for x in self.rect.o.x..self.rect.o.x + self.rect.s.x {
+                        self.canvas[(x,y)] = T::zero();
+                    }
+                }
+            },
+            Disposal::Previous => {
+                if let Some(previous) = self.previous.take() {
+                    for y in self.rect.o.y..self.rect.o.y + self.rect.s.y {
+                        for x in self.rect.o.x..self.rect.o.x + self.rect.s.x {
+                            self.canvas[(x,y)] = previous[(x,y)];
+                        }
+                    }
+                }
+            },
+        }
+    }
+
+    pub fn finish(self,loops: u32) -> Animation<T> {
+        Animation {
+            size: self.canvas.size,
+            frames: self.frames,
+            loops: loops,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        pixel::Pixel,
+    };
+
+    #[test]
+    fn duration_saturates() {
+        let frame = Frame { image: Mat::<pixel::RGBA8UN>::new(vec2!(1usize,1)),delay: u32::MAX - 10, };
+        let mut animation = Animation {
+            size: vec2!(1usize,1),
+            frames: vec![frame.clone()],
+            loops: 0,
+        };
+        assert_eq!(animation.duration(),u32::MAX - 10);
+        animation.frames.push(frame);
+        assert_eq!(animation.duration(),u32::MAX);
+    }
+
+    #[test]
+    fn blend_16bit() {
+        let mut dst = pixel::RGBA16UN::zero();
+        dst.set16(0xFFFF,0x0000,0x1000,0xFFFF);
+        let mut src = pixel::RGBA16UN::zero();
+        src.set16(0x0000,0x8000,0x1001,0x4000);
+        // the 8-bit path would round every channel to a multiple of 257
+        let sa = 0x4000 as f64 / 65535.0;
+        let expected = |s: u16,d: u16| ((s as f64) * sa + (d as f64) * (1.0 - sa)).round() as i32;
+        let (r,g,b,a) = blend_over(dst,src).get16();
+        assert!((r as i32 - expected(0x0000,0xFFFF)).abs() <= 1);
+        assert!((g as i32 - expected(0x8000,0x0000)).abs() <= 1);
+        assert!((b as i32 - expected(0x1001,0x1000)).abs() <= 1);
+        assert_eq!(a,0xFFFF);
+    }
+
+    #[test]
+    fn blend_float() {
+        let mut dst = pixel::RGBA32F::zero();
+        dst.setf(4.0,0.0,0.25,1.0);
+        let mut src = pixel::RGBA32F::zero();
+        src.setf(2.0,1.0,0.0,0.5);
+        let (r,g,b,a) = blend_over(dst,src).getf();
+        assert!((r - 3.0).abs() < 1e-5);
+        assert!((g - 0.5).abs() < 1e-5);
+        assert!((b - 0.125).abs() < 1e-5);
+        assert!((a - 1.0).abs() < 1e-5);
+
+        // translucent over translucent
+        dst.setf(1.0,1.0,1.0,0.5);
+        let (r,_,_,a) = blend_over(dst,src).getf();
+        assert!((a - 0.75).abs() < 1e-5);
+        assert!((r - (2.0 * 0.5 + 1.0 * 0.25) / 0.75).abs() < 1e-5);
+    }
+}
@@ -67,25 +67,26 @@ impl Component {
         }
     }
 
-    pub fn get(&self,c: u32,def: u8) -> u8 {
+    // component scaled to 16 bits by replicating its bits
+    pub fn get(&self,c: u32,def: u16) -> u16 {
         if self.size == 0 {
             return def;
         }
         let d = (c & self.mask) >> self.shift;
-        match self.size {
-            1 => if d != 0 { 255 } else { 0 },
-            2 => ((d << 6) | (d << 4) | (d << 2) | d) as u8,
-            3 => ((d << 5) | (d << 2) | (d >> 1)) as u8,
-            4 => ((d << 4) | d) as u8,
-            5 => ((d << 3) | (d >> 2)) as u8,
-            6 => ((d << 2) | (d >> 4)) as u8,
-            7 => ((d << 1) | (d >> 6)) as u8,
-            _ => (d >> (self.size - 8)) as u8,
+        if self.size >= 16 {
+            return (d >> (self.size - 16)) as u16;
         }
+        let mut result = 0u32;
+        let mut bits = 0;
+        while bits < 16 {
+            result = (result << self.size) | d;
+            bits += self.size;
+        }
+        (result >> (bits - 16)) as u16
     }
 }
 
-fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,height: usize,bottom_up: bool,itype: Type,palette: &[T; 256],redmask: u32,greenmask: u32,bluemask: u32,alphamask: u32) {
+fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,height: usize,bottom_up: bool,itype: Type,palette: &[T; 256],redmask: u32,greenmask: u32,bluemask: u32,alphamask: u32) -> Result<(),usize> {
     let red = Component::new(redmask);
     let green = Component::new(greenmask);
     let blue = Component::new(bluemask);
@@ -166,7 +167,7 @@ fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,heigh
                 if (width & 1) != 0 {
                     let d = src[sp];
                     sp += 1;
-                    dst[dp] = palette[(d & 15) as usize];
+                    dst[dp] = palette[(d >> 4) as usize];
                 }
                 let rest = ((width + 1) / 2) & 3;
                 if rest > 0 {
@@ -177,7 +178,7 @@ fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,heigh
         },
         Type::C4RLE => {
             let mut x = 0usize;
-            while sp < src.len() {
+            while sp + 1 < src.len() {
                 let code: u16 = from_le16(&src[sp..sp+2]);
                 sp += 2;
                 match code {
@@ -189,6 +190,9 @@ fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,heigh
                         break;
                     },
                     0x0200 => {
+                        if sp + 2 > src.len() {
+                            return Err(sp);
+                        }
                         x += src[sp] as usize;
                         y = ((y as isize) + (src[sp + 1] as isize) * dy) as usize;
                         sp += 2;
@@ -196,7 +200,7 @@ fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,heigh
                     _ => {
                         if (code & 255) != 0 {
                             let count = code & 255;
-                            if x + (count as usize) > width {
+                            if (y >= height) || (x + (count as usize) > width) {
                                 break;
                             }
                             let c0 = palette[(code >> 12) as usize];
@@ -213,9 +217,12 @@ fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,heigh
                         }
                         else {
                             let count = code >> 8;
-                            if x + (count as usize) > width {
+                            if (y >= height) || (x + (count as usize) > width) {
                                 break;
                             }
+                            if sp + ((count as usize + 3) / 4) * 2 > src.len() {
+                                return Err(sp);
+                            }
                             for _i in 0..count / 4 {
                                 let c = from_le16(&src[sp..sp+2]);
                                 sp += 2;
@@ -263,7 +270,7 @@ fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,heigh
         },
         Type::C8RLE => {
             let mut x = 0usize;
-            while sp < src.len() {
+            while sp + 1 < src.len() {
                 let code: u16 = from_le16(&src[sp..sp+2]);
                 sp += 2;
                 match code {
@@ -275,6 +282,9 @@ fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,heigh
                         break;
                     },
                     0x0200 => {
+                        if sp + 2 > src.len() {
+                            return Err(sp);
+                        }
                         x += src[sp] as usize;
                         y = ((y as isize) + (src[sp + 1] as isize) * dy) as usize;
                         sp += 2;
@@ -282,7 +292,7 @@ fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,heigh
                     _ => {
                         if (code & 255) != 0 {
                             let count = code & 255;
-                            if x + count as usize > width {
+                            if (y >= height) || (x + count as usize > width) {
                                 break;
                             }
                             let c = palette[(code >> 8) as usize];
@@ -293,9 +303,12 @@ fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,heigh
                         }
                         else {
                             let count = code >> 8;
-                            if x + count as usize > width {
+                            if (y >= height) || (x + count as usize > width) {
                                 break;
                             }
+                            if sp + ((count as usize + 1) / 2) * 2 > src.len() {
+                                return Err(sp);
+                            }
                             for _i in 0..count / 2 {
                                 let c = from_le16(&src[sp..sp + 2]);
                                 sp += 2;
@@ -347,8 +360,8 @@ fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,heigh
                     let r = red.get(d,0);
                     let g = green.get(d,0);
                     let b = blue.get(d,0);
-                    let a = if alphamask == 0 { 255 } else { alpha.get(d,255) };
-                    dst[dp].set(r,g,b,a);
+                    let a = if alphamask == 0 { 0xFFFF } else { alpha.get(d,0xFFFF) };
+                    dst[dp].set16(r,g,b,a);
                     dp += 1;
                 }
                 let rest = (width * 2) & 3;
@@ -401,18 +414,22 @@ fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,heigh
                     let r = red.get(d,0);
                     let g = green.get(d,0);
                     let b = blue.get(d,0);
-                    let a = if alphamask == 0 { 255 } else { alpha.get(d,255) };
-                    dst[dp].set(r,g,b,a);
+                    let a = if alphamask == 0 { 0xFFFF } else { alpha.get(d,0xFFFF) };
+                    dst[dp].set16(r,g,b,a);
                     dp += 1;
                 }
                 line = (line as isize + dline) as usize;
             }
         },
     }
+    Ok(())
 }
 
 
-pub fn test(src: &[u8]) -> Option<(u32,u32)> {
+pub fn info(src: &[u8]) -> Result<ImageInfo,ImageError> {
+    if src.len() < 2 {
+        return Err(ImageError::UnknownFormat);
+    }
     let tag = from_le16(&src[0..2]);
     if (tag == 0x4D42) ||   // BM (Windows BMP)
         (tag == 0x4142) ||  // BA (OS/2 bitmap)
@@ -420,11 +437,17 @@ pub fn test(src: &[u8]) -> Option<(u32,u32)> {
         (tag == 0x5043) ||  // CP (OS/2 color pointer) 
         (tag == 0x4349) ||  // IC (OS/2 icon)
         (tag == 0x5450) {    // PT (OS/2 pointer)
+        if src.len() < 18 {
+            return Err(ImageError::Corrupt(src.len(),"truncated header"));
+        }
         let filesize = from_le32(&src[2..6]);
         let offset = from_le32(&src[10..14]);
         let headersize = from_le32(&src[14..18]);
-        if (headersize > filesize) || (offset > filesize) || (headersize > offset) || (filesize != src.len() as u32) {
-            return None;
+        if filesize != src.len() as u32 {
+            return Err(ImageError::Corrupt(src.len(),"file size mismatch"));
+        }
+        if (offset > filesize) || headersize.checked_add(14).map_or(true,|end| end > offset) {
+            return Err(ImageError::Corrupt(10,"inconsistent header offsets"));
         }
         if (headersize != 12) &&
            (headersize != 40) &&
@@ -432,53 +455,58 @@ pub fn test(src: &[u8]) -> Option<(u32,u32)> {
            (headersize != 56) &&
            (headersize != 108) &&
            (headersize != 124) {
-            return None;
+            return Err(ImageError::Unsupported("BMP header version"));
         }
         if headersize == 12 {
             let width = from_le16(&src[18..20]) as usize;
             let mut height = from_le16(&src[20..22]) as usize;
             if (height as i16) < 0 {
-                height = -(height as i16) as usize;
+                height = (height as i16).unsigned_abs() as usize;
             }
             if (width > 32768) || (height > 32768) || (width == 0) || (height == 0) {
-                return None;
+                return Err(ImageError::Dimensions(width,height));
             }
             let planes = from_le16(&src[22..24]);
             let itype_code = from_le16(&src[24..26]);
             if planes != 1 {
-                return None;
+                return Err(ImageError::Corrupt(22,"invalid plane count"));
             }
             let itype = match itype_code {
                 0x0001 => Type::C1,
                 0x0004 => Type::C4,
                 0x0008 => Type::C8,
                 0x0018 => Type::RGB8,
-                _ => { return None; },
+                _ => { return Err(ImageError::Unsupported("BMP pixel format")); },
             };
             let mut line = match itype {
                 Type::C1 => (width + 7) / 8,
                 Type::C4 => (width + 1) / 2,
                 Type::C8 => width,
                 Type::RGB8 => width * 3,
-                _ => { return None; },
+                _ => { return Err(ImageError::Unsupported("BMP pixel format")); },
             };
             let rest = line & 3;
             if rest > 0 {
                 line += 4 - rest;
             }
             if offset as usize + height * line > src.len() {
-                return None;
+                return Err(ImageError::Corrupt(src.len(),"truncated pixel data"));
             }
-            return Some((width as u32,height as u32));
+            let mut info = ImageInfo::new(ImageFormat::Bmp,width,height,itype_code as u32,ChannelLayout::Rgb);
+            match itype {
+                Type::RGB8 => { info.bit_depth = 8; },
+                _ => { info.palette = true; },
+            }
+            return Ok(info);
         }
         else {
             let width = from_le32(&src[18..22]) as usize;
             let mut height = from_le32(&src[22..26]) as usize;
             if (height as i32) < 0 {
-                height = -(height as i32) as usize;
+                height = (height as i32).unsigned_abs() as usize;
             }
             if (width > 32768) || (height > 32768) || (width == 0) || (height == 0) {
-                return None;
+                return Err(ImageError::Dimensions(width,height));
             }
             //let planes = from_le16(&src[26..28]);
             let bpp = from_le16(&src[28..30]);
@@ -496,7 +524,7 @@ pub fn test(src: &[u8]) -> Option<(u32,u32)> {
                 0x0018 => Type::RGB8,
                 0x0020 => Type::ARGB8,
                 0x0320 => Type::B32,
-                _ => { return None; },
+                _ => { return Err(ImageError::Unsupported("BMP pixel format")); },
             };
             let mut line = match itype {
                 Type::C1 => (width + 7) / 8,
@@ -514,15 +542,72 @@ pub fn test(src: &[u8]) -> Option<(u32,u32)> {
                 line += 4 - rest;
             }
             if (line != 0) && (offset as usize + height * line > src.len()) {
-                return None;
+                return Err(ImageError::Corrupt(src.len(),"truncated pixel data"));
+            }
+            let mut info = ImageInfo::new(ImageFormat::Bmp,width,height,8,ChannelLayout::Rgb);
+            match itype {
+                Type::C1 | Type::C2 | Type::C4 | Type::C4RLE | Type::C8 | Type::C8RLE => {
+                    info.bit_depth = bpp as u32;
+                    info.palette = true;
+                },
+                Type::A1RGB5 => {
+                    info.bit_depth = 5;
+                    if headersize >= 56 {
+                        info.channels = ChannelLayout::Rgba;
+                    }
+                },
+                Type::B16 | Type::B32 => {
+                    if offset < 66 {
+                        return Err(ImageError::Corrupt(14,"missing bit fields"));
+                    }
+                    let mut masks = vec![from_le32(&src[54..58]),from_le32(&src[58..62]),from_le32(&src[62..66])];
+                    if (headersize >= 56) || ((offset - headersize - 14) >= 16) {
+                        masks.push(from_le32(&src[66..70]));
+                    }
+                    info.bit_depth = masks.iter().map(|mask| mask.count_ones()).max().unwrap_or(0);
+                    if (masks.len() == 4) && (masks[3] != 0) {
+                        info.channels = ChannelLayout::Rgba;
+                    }
+                },
+                Type::ARGB8 => {
+                    if headersize >= 56 {
+                        info.channels = ChannelLayout::Rgba;
+                    }
+                },
+                Type::RGB8 => { },
+            }
+
+            // resolution in pixels per meter
+            let x = from_le32(&src[38..42]);
+            let y = from_le32(&src[42..46]);
+            if (x != 0) && (y != 0) {
+                info.dpi = Some(vec2!((x as f32) * 0.0254,(y as f32) * 0.0254));
+            }
+
+            // V5 header can point to an embedded profile, relative to the header
+            if (headersize == 124) && (from_le32(&src[70..74]) == 0x4D424544) {  // MBED
+                let start = 14 + from_le32(&src[126..130]) as usize;
+                let size = from_le32(&src[130..134]) as usize;
+                if start + size > src.len() {
+                    return Err(ImageError::Corrupt(126,"invalid ICC profile"));
+                }
+                info.icc_profile = Some(src[start..start + size].to_vec());
             }
-            return Some((width as u32,height as u32));
+            return Ok(info);
         }
     }
-    None
+    Err(ImageError::UnknownFormat)
 }
 
-pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Option<Mat<T>> {
+pub fn test(src: &[u8]) -> Result<(u32,u32),ImageError> {
+    let info = info(src)?;
+    Ok((info.size.x as u32,info.size.y as u32))
+}
+
+pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Result<Mat<T>,ImageError> {
+    if src.len() < 2 {
+        return Err(ImageError::UnknownFormat);
+    }
     let tag = from_le16(&src[0..2]);
     if (tag != 0x4D42) &&
         (tag != 0x4142) &&
@@ -530,13 +615,19 @@ pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Option<Mat<T>> {
         (tag != 0x5043) && 
         (tag != 0x4349) &&
         (tag != 0x5450) {
-        return None;
+        return Err(ImageError::UnknownFormat);
+    }
+    if src.len() < 18 {
+        return Err(ImageError::Corrupt(src.len(),"truncated header"));
     }
     let filesize = from_le32(&src[2..6]);
     let offset = from_le32(&src[10..14]);
     let headersize = from_le32(&src[14..18]);
-    if (headersize > filesize) || (offset > filesize) || (headersize > offset) || (filesize != src.len() as u32) {
-        return None;
+    if filesize != src.len() as u32 {
+        return Err(ImageError::Corrupt(src.len(),"file size mismatch"));
+    }
+    if (offset > filesize) || headersize.checked_add(14).map_or(true,|end| end > offset) {
+        return Err(ImageError::Corrupt(10,"inconsistent header offsets"));
     }
     if (headersize != 12) &&
         (headersize != 40) &&
@@ -544,7 +635,7 @@ pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Option<Mat<T>> {
         (headersize != 56) &&
         (headersize != 108) &&
         (headersize != 124) {
-        return None;
+        return Err(ImageError::Unsupported("BMP header version"));
     }
     #[allow(unused_assignments)]
     let mut width = 0usize;
@@ -561,44 +652,46 @@ pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Option<Mat<T>> {
     if headersize == 12 {
         width = from_le16(&src[18..20]) as usize;
         let pheight = from_le16(&src[20..22]) as i16;
-        height = if pheight < 0 { bottom_up = false; -pheight as usize } else { pheight as usize };
-        if (width > 32768) || (height > 32768) || (width == 0) || (height == 0) {
-            return None;
+        height = if pheight < 0 { bottom_up = false; pheight.unsigned_abs() as usize } else { pheight as usize };
+        if (width > 32768) || (height > 32768) {
+            return Err(ImageError::Dimensions(width,height));
         }
+        check_dimensions(width,height)?;
         let planes = from_le16(&src[22..24]);
         let itype_code = from_le16(&src[24..26]);
         if planes != 1 {
-            return None;
+            return Err(ImageError::Corrupt(22,"invalid plane count"));
         }
         itype = match itype_code {
             0x0001 => Type::C1,
             0x0004 => Type::C4,
             0x0008 => Type::C8,
             0x0018 => Type::RGB8,
-            _ => { return None; },
+            _ => { return Err(ImageError::Unsupported("BMP pixel format")); },
         };
         let mut line = match itype {
             Type::C1 => (width + 7) / 8,
             Type::C4 => (width + 1) / 2,
             Type::C8 => width,
             Type::RGB8 => width * 3,
-            _ => { return None; },
+            _ => { return Err(ImageError::Unsupported("BMP pixel format")); },
         };
         let rest = line & 3;
         if rest > 0 {
             line += 4 - rest;
         }
         if offset as usize + (height * line) as usize > src.len() {
-            return None;
+            return Err(ImageError::Corrupt(src.len(),"truncated pixel data"));
         }
     }
     else {
         width = from_le32(&src[18..22]) as usize;
         let pheight = from_le32(&src[22..26]) as i32;
-        height = if pheight < 0 { bottom_up = false; -pheight as usize } else { pheight as usize };
-        if (width > 32768) || (height > 32768) || (width == 0) || (height == 0) {
-            return None;
+        height = if pheight < 0 { bottom_up = false; pheight.unsigned_abs() as usize } else { pheight as usize };
+        if (width > 32768) || (height > 32768) {
+            return Err(ImageError::Dimensions(width,height));
         }
+        check_dimensions(width,height)?;
         //let planes = from_le16(&src[26..28]);
         let bpp = from_le16(&src[28..30]);
         let compression = from_le32(&src[30..34]) as u16;
@@ -615,7 +708,7 @@ pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Option<Mat<T>> {
             0x0018 => Type::RGB8,
             0x0020 => Type::ARGB8,
             0x0320 => Type::B32,
-            _ => { return None; },
+            _ => { return Err(ImageError::Unsupported("BMP pixel format")); },
         };
         let mut line = match itype {
             Type::C1 => (width + 7) / 8,
@@ -633,11 +726,11 @@ pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Option<Mat<T>> {
             line += 4 - rest;
         }
         if (line != 0) && (offset as usize + (height * line) as usize > src.len()) {
-            return None;
+            return Err(ImageError::Corrupt(src.len(),"truncated pixel data"));
         }
         let imagesize = from_le32(&src[34..38]);
         if (compression == 0) && (imagesize > filesize - offset) {
-            return None;
+            return Err(ImageError::Corrupt(34,"invalid image size"));
         }
         // 38..46: resolution
         let mut colors = from_le32(&src[46..50]);
@@ -646,8 +739,9 @@ pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Option<Mat<T>> {
             Type::C1 | Type::C2 | Type::C4 | Type::C4RLE | Type::C8 | Type::C8RLE => {
                 if colors == 0 {
                     colors = 1 << bpp;
-                } else if colors > 256 {
-                    return None;
+                }
+                if (colors > 256) || (14 + headersize + colors * 4 > offset) {
+                    return Err(ImageError::Corrupt(46,"invalid palette size"));
                 }
                 for i in 0..colors {
                     let sp = (14 + headersize + i * 4) as usize;
@@ -658,6 +752,9 @@ pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Option<Mat<T>> {
                 }
             },
             Type::B16 | Type::B32 => {
+                if offset < 66 {
+                    return Err(ImageError::Corrupt(14,"missing bit fields"));
+                }
                 redmask = from_le32(&src[54..58]);
                 greenmask = from_le32(&src[58..62]);
                 bluemask = from_le32(&src[62..66]);
@@ -675,15 +772,16 @@ pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Option<Mat<T>> {
         }
     }
     let mut image = Mat::<T>::new(vec2!(width,height));
-    decode_pixels(&mut image,&src[offset as usize..],width,height,bottom_up,itype,&palette,redmask,greenmask,bluemask,alphamask);
-    Some(image)
+    if let Err(sp) = decode_pixels(&mut image,&src[offset as usize..],width,height,bottom_up,itype,&palette,redmask,greenmask,bluemask,alphamask) {
+        return Err(ImageError::Corrupt(offset as usize + sp,"truncated RLE data"));
+    }
+    Ok(image)
 }
 
 trait WriteTypes {
     fn push16(&mut self,d: u16);
     fn push16b(&mut self,d: u16);
     fn push32(&mut self,d: u32);
-    fn push32b(&mut self,d: u32);
 }
 
 impl WriteTypes for Vec<u8> {
@@ -701,69 +799,230 @@ impl WriteTypes for Vec<u8> {
         self.push(((d >> 16) & 255) as u8);
         self.push((d >> 24) as u8);
     }
-    fn push32b(&mut self,d: u32) {
-        self.push((d >> 24) as u8);
-        self.push(((d >> 16) & 255) as u8);
-        self.push(((d >> 8) & 255) as u8);
-        self.push((d & 255) as u8);
+}
+
+/// BMP encoder settings.
+#[derive(Copy,Clone,Debug)]
+pub struct EncodeOptions {
+    /// Bits per pixel: 1, 4 or 8 for palette images (encoding fails if the image has more colors than fit), 16 for 5-6-5 RGB, 24 for RGB, or 32 for RGB with alpha.
+    pub bits_per_pixel: u16,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> EncodeOptions {
+        EncodeOptions {
+            bits_per_pixel: 32,
+        }
     }
 }
 
-pub fn encode<T: pixel::Pixel>(image: &Mat<T>) -> Option<Vec<u8>> {
-    let headersize = 108;
-    let stride = image.size.x * 4;
-    let palettesize = 0;
-    let bpp = 32;
-    let compression = 3;
-    let colors = 0;
-    let redmask: u32 = 0x00FF0000;
-    let greenmask: u32 = 0x0000FF00;
-    let bluemask: u32 = 0x000000FF;
-    let alphamask: u32 = 0xFF000000;
-    let imagesize = stride * image.size.y;
-    let offset = 14 + headersize + palettesize;
-    let filesize = offset + imagesize;
+pub fn encode<T: pixel::Pixel>(image: &Mat<T>) -> Result<Vec<u8>,ImageError> {
+    encode_with_options(image,&EncodeOptions::default())
+}
+
+pub fn encode_with_options<T: pixel::Pixel>(image: &Mat<T>,options: &EncodeOptions) -> Result<Vec<u8>,ImageError> {
+    let width = image.size.x;
+    let height = image.size.y;
+    if (width == 0) || (height == 0) || (width > 32768) || (height > 32768) {
+        return Err(ImageError::Dimensions(width,height));
+    }
+    let bpp = options.bits_per_pixel;
+    let (headersize,compression,masks) = match bpp {
+        1 | 4 | 8 | 24 => (40,0,Vec::new()),
+        16 => (40,3,vec![0xF800,0x07E0,0x001F]),
+        32 => (108,3,vec![0x00FF0000,0x0000FF00,0x000000FF,0xFF000000]),
+        _ => { return Err(ImageError::Unsupported("BMP bit depth")); },
+    };
+
+    // rows, padded to 32 bits
+    let mut palette: Vec<(u8,u8,u8)> = Vec::new();
+    let mut pixels: Vec<u8> = Vec::new();
+    for y in 0..height {
+        let start = pixels.len();
+        let mut bits = 0u32;
+        let mut count = 0u16;
+        for x in 0..width {
+            let (r,g,b,a) = image[(x,y)].get();
+            match bpp {
+                1 | 4 | 8 => {
+                    let index = match palette.iter().position(|&c| c == (r,g,b)) {
+                        Some(index) => index,
+                        None => {
+                            if palette.len() == 1 << bpp {
+                                return Err(ImageError::Unsupported("more colors than fit in the BMP bit depth"));
+                            }
+                            palette.push((r,g,b));
+                            palette.len() - 1
+                        },
+                    };
+                    bits = (bits << bpp) | (index as u32);
+                    count += bpp;
+                    if count == 8 {
+                        pixels.push(bits as u8);
+                        bits = 0;
+                        count = 0;
+                    }
+                },
+                16 => {
+                    let (r,g,b,_) = image[(x,y)].get16();
+                    pixels.push16(((r >> 11) << 11) | ((g >> 10) << 5) | (b >> 11));
+                },
+                24 => {
+                    pixels.push(b);
+                    pixels.push(g);
+                    pixels.push(r);
+                },
+                _ => {
+                    pixels.push(b);
+                    pixels.push(g);
+                    pixels.push(r);
+                    pixels.push(a);
+                },
+            }
+        }
+        if count > 0 {
+            pixels.push((bits << (8 - count)) as u8);
+        }
+        while ((pixels.len() - start) & 3) != 0 {
+            pixels.push(0);
+        }
+    }
+
+    let offset = 14 + headersize + if headersize == 40 { masks.len() * 4 } else { 0 } + palette.len() * 4;
+    let filesize = offset + pixels.len();
     let mut dst: Vec<u8> = Vec::new();
     dst.push16b(0x424D);  // 0
     dst.push32(filesize as u32);  // 2
     dst.push32(0);  // 6
     dst.push32(offset as u32);  // 10
     dst.push32(headersize as u32);  // 14
-    dst.push32(image.size.x as u32);  // 18
-    dst.push32(-(image.size.y as i32) as u32);  // 22
+    dst.push32(width as u32);  // 18
+    dst.push32(-(height as i32) as u32);  // 22
     dst.push16(1);  // 26
     dst.push16(bpp);  // 28
     dst.push32(compression);  // 30
-    dst.push32(imagesize as u32);  // 34
-    dst.push32(1);  // 38
-    dst.push32(1);  // 42
-    dst.push32(colors);  // 46
-    dst.push32(colors);  // 50
-    dst.push32(redmask);  // 54
-    dst.push32(greenmask);  // 58
-    dst.push32(bluemask);  // 62
-    dst.push32(alphamask);  // 66
-    dst.push32(0x57696E20);  // 70
-    dst.push32(0);  // 74
-    dst.push32(0);  // 78
-    dst.push32(0);  // 82
-    dst.push32(0);  // 86
-    dst.push32(0);  // 90
-    dst.push32(0);  // 94
-    dst.push32(0);  // 98
-    dst.push32(0);  // 102
-    dst.push32(0);  // 106
-    dst.push32(0);  // 110
-    dst.push32(0);  // 114
-    dst.push32(0);  // 118
-    for y in 0..image.size.y {
-        for x in 0..image.size.x {
-            let (r,g,b,a) = image[(x,y)].get();
-            dst.push(r);
-            dst.push(g);
-            dst.push(b);
-            dst.push(a);
+    dst.push32(pixels.len() as u32);  // 34
+    dst.push32(0);  // 38
+    dst.push32(0);  // 42
+    dst.push32(palette.len() as u32);  // 46
+    dst.push32(palette.len() as u32);  // 50
+    for mask in masks.iter() {
+        dst.push32(*mask);  // 54
+    }
+    if headersize == 108 {
+        dst.push32(0x57696E20);  // 70
+        for _i in 0..12 {
+            dst.push32(0);  // 74
         }
     }
-    Some(dst)
+    for (r,g,b) in palette.iter() {
+        dst.push(*b);
+        dst.push(*g);
+        dst.push(*r);
+        dst.push(0);
+    }
+    dst.extend_from_slice(&pixels);
+    Ok(dst)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        pixel::Pixel,
+        crate::image::fixture,
+    };
+
+    fn roundtrip(image: &Mat<pixel::RGBA8UN>,bits_per_pixel: u16,alpha: bool) {
+        let data = encode_with_options(image,&EncodeOptions { bits_per_pixel: bits_per_pixel, }).unwrap();
+        let expected = if alpha { image.clone() } else { fixture::opaque(image) };
+        fixture::assert_same(&expected,&decode::<pixel::RGBA8UN>(&data).unwrap());
+    }
+
+    #[test]
+    fn roundtrip_truecolor() {
+        // odd width, so every row needs padding
+        let image = fixture::pattern(vec2!(13,5),256);
+        roundtrip(&image,32,true);
+        roundtrip(&image,24,false);
+    }
+
+    #[test]
+    fn roundtrip_palette() {
+        roundtrip(&fixture::pattern(vec2!(13,5),2),1,false);
+        roundtrip(&fixture::pattern(vec2!(13,5),16),4,false);
+        roundtrip(&fixture::pattern(vec2!(13,5),13),8,false);
+        assert!(encode_with_options(&fixture::pattern::<pixel::RGBA8UN>(vec2!(13,5),3),&EncodeOptions { bits_per_pixel: 1, }).is_err());
+    }
+
+    #[test]
+    fn encode_bytes() {
+        let mut image = Mat::<pixel::RGB8UN>::new(vec2!(2,1));
+        image[(0,0)].set(255,128,0,255);
+        image[(1,0)].set(1,2,3,255);
+
+        // file header and BITMAPINFOHEADER, top-down
+        let header = |filesize: u32,offset: u32,bpp: u16,compression: u32,size: u32,colors: u32| {
+            let mut data = b"BM".to_vec();
+            for v in [filesize,0,offset,40,2,0xFFFFFFFF] {
+                data.extend_from_slice(&v.to_le_bytes());
+            }
+            data.extend_from_slice(&1u16.to_le_bytes());
+            data.extend_from_slice(&bpp.to_le_bytes());
+            for v in [compression,size,0,0,colors,colors] {
+                data.extend_from_slice(&v.to_le_bytes());
+            }
+            data
+        };
+        let encoded = |bits_per_pixel: u16| encode_with_options(&image,&EncodeOptions { bits_per_pixel: bits_per_pixel, }).unwrap();
+
+        // BGR, padded to 4 bytes
+        let mut expected = header(62,54,24,0,8,0);
+        expected.extend_from_slice(&[0,128,255,3,2,1,0,0]);
+        assert_eq!(encoded(24),expected);
+
+        // palette in order of appearance, then the indices from the high bit down
+        let mut expected = header(66,62,1,0,4,2);
+        expected.extend_from_slice(&[0,128,255,0,3,2,1,0]);
+        expected.extend_from_slice(&[0x40,0,0,0]);
+        assert_eq!(encoded(1),expected);
+
+        // 5-6-5 bit fields
+        let mut expected = header(70,66,16,3,4,0);
+        for mask in [0xF800u32,0x07E0,0x001F] {
+            expected.extend_from_slice(&mask.to_le_bytes());
+        }
+        expected.extend_from_slice(&[0x00,0xFC,0x00,0x00]);
+        assert_eq!(encoded(16),expected);
+    }
+
+    #[test]
+    fn roundtrip_565() {
+        let options = EncodeOptions { bits_per_pixel: 16, };
+        let image = fixture::pattern::<pixel::RGBA8UN>(vec2!(13,5),256);
+        let decoded = decode::<pixel::RGBA8UN>(&encode_with_options(&image,&options).unwrap()).unwrap();
+        for (a,b) in image.data().iter().zip(decoded.data().iter()) {
+            let (r,g,b_,_) = a.get();
+            let (dr,dg,db,da) = b.get();
+            assert!(((r as i32 - dr as i32).abs() < 8) && ((g as i32 - dg as i32).abs() < 4) && ((b_ as i32 - db as i32).abs() < 8) && (da == 255));
+        }
+
+        // once quantized, the colors survive
+        let again = decode::<pixel::RGBA8UN>(&encode_with_options(&decoded,&options).unwrap()).unwrap();
+        for (a,b) in decoded.data().iter().zip(again.data().iter()) {
+            assert_eq!(a.get(),b.get());
+        }
+    }
+
+    #[test]
+    fn decode_header_overflow() {
+        // header size that wraps around when the file header is added
+        let mut src = vec![0u8; 54];
+        src[0..2].copy_from_slice(b"BM");
+        src[2..6].copy_from_slice(&54u32.to_le_bytes());
+        src[10..14].copy_from_slice(&54u32.to_le_bytes());
+        src[14..18].copy_from_slice(&0xFFFFFFF8u32.to_le_bytes());
+        assert!(matches!(decode::<pixel::RGBA8UN>(&src),Err(ImageError::Corrupt(_,_))));
+        assert!(matches!(info(&src),Err(ImageError::Corrupt(_,_))));
+    }
 }
@@ -0,0 +1,537 @@
+// E - image - Codec
+// Desmond Germans, 2020
+
+use {
+    crate::*,
+    std::sync::{
+        Arc,
+        OnceLock,
+        RwLock,
+    },
+};
+
+/// Image format that can be plugged into a `Registry`.
+///
+/// Codecs decode into and encode from `pixel::RGBA16UN`, or `pixel::RGBA32F` for float pixel formats, so high dynamic range images keep their range. The registry converts to and from the pixel format the caller asks for.
+pub trait ImageCodec: Send + Sync {
+    /// Short lowercase name of the format, like `"png"`.
+    fn name(&self) -> &'static str;
+
+    /// File extensions, lowercase and without the dot.
+    fn extensions(&self) -> &'static [&'static str];
+
+    /// Check if a slice is in this format, from its signature or, for formats without one, a plausible header.
+    fn detect(&self,src: &[u8]) -> bool;
+
+    /// Probe the header of a slice, without decoding the pixels.
+    fn info(&self,src: &[u8]) -> Result<ImageInfo,ImageError>;
+
+    /// Decode a slice.
+    fn decode(&self,src: &[u8]) -> Result<Mat<pixel::RGBA16UN>,ImageError>;
+
+    /// Built-in format of the codec, which the registry decodes straight into the pixel format the caller asks for. Application codecs keep the default.
+    #[doc(hidden)]
+    fn builtin(&self) -> Option<ImageFormat> {
+        None
+    }
+
+    /// Decode a slice into float pixels. The default converts the result of `decode`.
+    fn decode_float(&self,src: &[u8]) -> Result<Mat<pixel::RGBA32F>,ImageError> {
+        Ok(convert(&self.decode(src)?))
+    }
+
+    /// Decode a slice as animation. Formats without animation keep this default, which makes a single frame animation from `decode`.
+    fn decode_animation(&self,src: &[u8]) -> Result<Animation<pixel::RGBA16UN>,ImageError> {
+        let image = self.decode(src)?;
+        Ok(Animation {
+            size: image.size,
+            frames: vec![Frame { image: image,delay: 0, }],
+            loops: 1,
+        })
+    }
+
+    /// Read the EXIF metadata of a slice. Formats without EXIF keep this default.
+    fn exif(&self,_src: &[u8]) -> Option<Exif> {
+        None
+    }
+
+    /// Encode an image. Formats that can only be read keep this default.
+    fn encode(&self,_image: &Mat<pixel::RGBA16UN>) -> Result<Vec<u8>,ImageError> {
+        Err(ImageError::Unsupported("encoding"))
+    }
+
+    /// Encode an image with encoder settings. `channels` (`Rgb` or `Rgba`) and `bit_depth` describe the pixel format of the image before it was converted, so the codec can store it as it is. The default ignores them and calls `encode`.
+    fn encode_with_options(&self,image: &Mat<pixel::RGBA16UN>,_channels: ChannelLayout,_bit_depth: u32,_options: &EncodeOptions) -> Result<Vec<u8>,ImageError> {
+        self.encode(image)
+    }
+
+    /// Encode an image with float pixels, like `encode_with_options`. The default converts the image to `pixel::RGBA16UN`, which clamps it to 0..1.
+    fn encode_float(&self,image: &Mat<pixel::RGBA32F>,channels: ChannelLayout,bit_depth: u32,options: &EncodeOptions) -> Result<Vec<u8>,ImageError> {
+        self.encode_with_options(&convert(image),channels,bit_depth,options)
+    }
+}
+
+// encode through the pixel format that matches the channels and bit depth
+macro_rules! with_pixel_format {
+    ($channels:expr,$bit_depth:expr,$pixel:ident => $encode:expr) => {
+        match ($channels,$bit_depth) {
+            (ChannelLayout::Rgb,0..=8) => { type $pixel = pixel::RGB8UN; $encode },
+            (_,0..=8) => { type $pixel = pixel::RGBA8UN; $encode },
+            (ChannelLayout::Rgb,9..=16) => { type $pixel = pixel::RGB16UN; $encode },
+            (_,9..=16) => { type $pixel = pixel::RGBA16UN; $encode },
+            (ChannelLayout::Rgb,_) => { type $pixel = pixel::RGB32F; $encode },
+            (_,_) => { type $pixel = pixel::RGBA32F; $encode },
+        }
+    };
+}
+
+// codec that forwards to one of the format modules, with the field of EncodeOptions for that module
+macro_rules! builtin_codec {
+    ($codec:ident,$module:ident,$name:expr,$format:expr,$detect:expr) => {
+        builtin_codec!($codec,$module,$name,$format,$detect,|image,_options| $module::encode(image));
+    };
+    ($codec:ident,$module:ident,$name:expr,$format:expr,$detect:expr,$options:ident) => {
+        builtin_codec!($codec,$module,$name,$format,$detect,|image,options| $module::encode_with_options(image,&options.$options));
+    };
+    ($codec:ident,$module:ident,$name:expr,$format:expr,$detect:expr,|$image:ident,$opts:ident| $encode:expr) => {
+        struct $codec;
+
+        impl ImageCodec for $codec {
+            fn name(&self) -> &'static str {
+                $name
+            }
+
+            fn extensions(&self) -> &'static [&'static str] {
+                $format.extensions()
+            }
+
+            fn detect(&self,src: &[u8]) -> bool {
+                let detect: fn(&[u8]) -> bool = $detect;
+                detect(src)
+            }
+
+            fn info(&self,src: &[u8]) -> Result<ImageInfo,ImageError> {
+                $module::info(src)
+            }
+
+            fn builtin(&self) -> Option<ImageFormat> {
+                Some($format)
+            }
+
+            fn decode(&self,src: &[u8]) -> Result<Mat<pixel::RGBA16UN>,ImageError> {
+                $module::decode::<pixel::RGBA16UN>(src)
+            }
+
+            fn decode_float(&self,src: &[u8]) -> Result<Mat<pixel::RGBA32F>,ImageError> {
+                $module::decode::<pixel::RGBA32F>(src)
+            }
+
+            fn decode_animation(&self,src: &[u8]) -> Result<Animation<pixel::RGBA16UN>,ImageError> {
+                decode_animation_builtin::<pixel::RGBA16UN>($format,src)
+            }
+
+            fn exif(&self,src: &[u8]) -> Option<Exif> {
+                exif_builtin($format,src)
+            }
+
+            fn encode(&self,image: &Mat<pixel::RGBA16UN>) -> Result<Vec<u8>,ImageError> {
+                self.encode_with_options(image,ChannelLayout::Rgba,16,&EncodeOptions::default())
+            }
+
+            fn encode_with_options(&self,image: &Mat<pixel::RGBA16UN>,channels: ChannelLayout,bit_depth: u32,$opts: &EncodeOptions) -> Result<Vec<u8>,ImageError> {
+                with_pixel_format!(channels,bit_depth,P => {
+                    let $image = &convert::<pixel::RGBA16UN,P>(image);
+                    $encode
+                })
+            }
+
+            fn encode_float(&self,image: &Mat<pixel::RGBA32F>,channels: ChannelLayout,bit_depth: u32,$opts: &EncodeOptions) -> Result<Vec<u8>,ImageError> {
+                with_pixel_format!(channels,bit_depth,P => {
+                    let $image = &convert_float::<pixel::RGBA32F,P>(image);
+                    $encode
+                })
+            }
+        }
+    };
+}
+
+builtin_codec!(BmpCodec,bmp,"bmp",ImageFormat::Bmp,|src| (src.len() >= 2) && [b"BM",b"BA",b"CI",b"CP",b"IC",b"PT"].iter().any(|tag| src[0..2] == tag[..]),bmp);
+builtin_codec!(PngCodec,png,"png",ImageFormat::Png,|src| src.starts_with(&[0x89,0x50,0x4E,0x47,0x0D,0x0A,0x1A,0x0A]),png);
+builtin_codec!(JpegCodec,jpeg,"jpeg",ImageFormat::Jpeg,|src| src.starts_with(&[0xFF,0xD8]),jpeg);
+builtin_codec!(GifCodec,gif,"gif",ImageFormat::Gif,|src| src.starts_with(b"GIF87a") || src.starts_with(b"GIF89a"));
+builtin_codec!(TiffCodec,tiff,"tiff",ImageFormat::Tiff,|src| src.starts_with(b"II\x2A\0") || src.starts_with(b"MM\0\x2A"),tiff);
+builtin_codec!(PbmCodec,pbm,"pbm",ImageFormat::Pbm,|src| (src.len() >= 3) && (src[0] == b'P') && (src[1] >= b'1') && (src[1] <= b'7') && src[2].is_ascii_whitespace(),pbm);
+builtin_codec!(WebpCodec,webp,"webp",ImageFormat::Webp,|src| (src.len() >= 12) && (&src[0..4] == b"RIFF") && (&src[8..12] == b"WEBP"));
+builtin_codec!(HdrCodec,hdr,"hdr",ImageFormat::Hdr,|src| src.starts_with(b"#?"),hdr);
+
+// no signature, so the header has to make sense
+builtin_codec!(XbmCodec,xbm,"xbm",ImageFormat::Xbm,|src| xbm::test(src).is_ok(),xbm);
+builtin_codec!(TgaCodec,tga,"tga",ImageFormat::Tga,|src| tga::test(src).is_ok(),tga);
+
+// decode with a format module, in any pixel format
+fn decode_builtin<T: pixel::Pixel>(format: ImageFormat,src: &[u8]) -> Result<Mat<T>,ImageError> {
+    match format {
+        ImageFormat::Bmp => bmp::decode::<T>(src),
+        ImageFormat::Png => png::decode::<T>(src),
+        ImageFormat::Jpeg => jpeg::decode::<T>(src),
+        ImageFormat::Gif => gif::decode::<T>(src),
+        ImageFormat::Tiff => tiff::decode::<T>(src),
+        ImageFormat::Pbm => pbm::decode::<T>(src),
+        ImageFormat::Xbm => xbm::decode::<T>(src),
+        ImageFormat::Webp => webp::decode::<T>(src),
+        ImageFormat::Tga => tga::decode::<T>(src),
+        ImageFormat::Hdr => hdr::decode::<T>(src),
+        ImageFormat::Other(_) => Err(ImageError::UnknownFormat),
+    }
+}
+
+// decode an animation with a format module, formats without animation give a single frame
+fn decode_animation_builtin<T: pixel::Pixel>(format: ImageFormat,src: &[u8]) -> Result<Animation<T>,ImageError> {
+    match format {
+        ImageFormat::Gif => gif::decode_animation::<T>(src),
+        ImageFormat::Png => png::decode_animation::<T>(src),
+        ImageFormat::Webp => webp::decode_animation::<T>(src),
+        _ => {
+            let image = decode_builtin::<T>(format,src)?;
+            Ok(Animation {
+                size: image.size,
+                frames: vec![Frame { image: image,delay: 0, }],
+                loops: 1,
+            })
+        },
+    }
+}
+
+// EXIF metadata from a format module
+fn exif_builtin(format: ImageFormat,src: &[u8]) -> Option<Exif> {
+    match format {
+        ImageFormat::Jpeg => jpeg::exif(src),
+        ImageFormat::Png => png::exif(src),
+        _ => None,
+    }
+}
+
+fn convert<S: pixel::Pixel,D: pixel::Pixel>(image: &Mat<S>) -> Mat<D> {
+    let mut result = Mat::<D>::new(image.size);
+    for (d,s) in result.data_mut().iter_mut().zip(image.data().iter()) {
+        let (r,g,b,a) = s.get16();
+        d.set16(r,g,b,a);
+    }
+    result
+}
+
+// same, without going through 16 bits
+fn convert_float<S: pixel::Pixel,D: pixel::Pixel>(image: &Mat<S>) -> Mat<D> {
+    let mut result = Mat::<D>::new(image.size);
+    for (d,s) in result.data_mut().iter_mut().zip(image.data().iter()) {
+        let (r,g,b,a) = s.getf();
+        d.setf(r,g,b,a);
+    }
+    result
+}
+
+/// Ordered collection of codecs.
+#[derive(Clone)]
+pub struct Registry {
+    codecs: Vec<Arc<dyn ImageCodec>>,  // the last one registered is tried first
+}
+
+impl Registry {
+    /// Create an empty registry.
+    pub fn new() -> Registry {
+        Registry {
+            codecs: Vec::new(),
+        }
+    }
+
+    /// Create a registry with the built-in codecs.
+    pub fn builtin() -> Registry {
+        let mut registry = Registry::new();
+
+        // formats without a signature are tried last, so register them first
+        registry.register(Arc::new(TgaCodec));
+        registry.register(Arc::new(XbmCodec));
+        registry.register(Arc::new(WebpCodec));
+        registry.register(Arc::new(HdrCodec));
+        registry.register(Arc::new(PbmCodec));
+        registry.register(Arc::new(TiffCodec));
+        registry.register(Arc::new(GifCodec));
+        registry.register(Arc::new(JpegCodec));
+        registry.register(Arc::new(PngCodec));
+        registry.register(Arc::new(BmpCodec));
+        registry
+    }
+
+    /// Add a codec. It is tried before the codecs that are already registered, so it overrides any codec with the same signature, name or extension.
+    pub fn register(&mut self,codec: Arc<dyn ImageCodec>) {
+        self.codecs.push(codec);
+    }
+
+    /// Remove all codecs with a name.
+    pub fn unregister(&mut self,name: &str) {
+        self.codecs.retain(|codec| codec.name() != name);
+    }
+
+    /// All codecs, in the order they are tried.
+    pub fn codecs(&self) -> Vec<Arc<dyn ImageCodec>> {
+        self.codecs.iter().rev().cloned().collect()
+    }
+
+    /// Find a codec by name.
+    pub fn by_name(&self,name: &str) -> Option<Arc<dyn ImageCodec>> {
+        self.codecs.iter().rev().find(|codec| codec.name() == name).cloned()
+    }
+
+    /// Find a codec by file extension.
+    /// # Arguments
+    /// * `extension` - The extension, with or without the dot, in any case.
+    /// # Returns
+    /// * `None` - No codec handles this extension.
+    /// * `Some(codec)` - The codec.
+    pub fn by_extension(&self,extension: &str) -> Option<Arc<dyn ImageCodec>> {
+        let extension = extension.trim_start_matches('.').to_lowercase();
+        self.codecs.iter().rev().find(|codec| codec.extensions().contains(&extension.as_str())).cloned()
+    }
+
+    /// Find the codec that can read a slice.
+    pub fn detect(&self,src: &[u8]) -> Option<Arc<dyn ImageCodec>> {
+        self.codecs.iter().rev().find(|codec| codec.detect(src)).cloned()
+    }
+
+    /// Probe the header of a slice, without decoding the pixels.
+    pub fn info(&self,src: &[u8]) -> Result<ImageInfo,ImageError> {
+        self.detect(src).ok_or(ImageError::UnknownFormat)?.info(src)
+    }
+
+    /// Decode a slice with the codec that detects it. The built-in codecs decode straight into `T`, other codecs through `pixel::RGBA16UN`, or `pixel::RGBA32F` for float pixel formats, so they keep values above 1.
+    pub fn decode<T: pixel::Pixel>(&self,src: &[u8]) -> Result<Mat<T>,ImageError> {
+        let codec = self.detect(src).ok_or(ImageError::UnknownFormat)?;
+        if let Some(format) = codec.builtin() {
+            decode_builtin::<T>(format,src)
+        }
+        else if T::BITS > 16 {
+            Ok(convert_float(&codec.decode_float(src)?))
+        }
+        else {
+            Ok(convert(&codec.decode(src)?))
+        }
+    }
+
+    /// Decode a slice as animation with the codec that detects it. The built-in codecs decode straight into `T`, other codecs through `pixel::RGBA16UN`.
+    pub fn decode_animation<T: pixel::Pixel>(&self,src: &[u8]) -> Result<Animation<T>,ImageError> {
+        let codec = self.detect(src).ok_or(ImageError::UnknownFormat)?;
+        if let Some(format) = codec.builtin() {
+            decode_animation_builtin::<T>(format,src)
+        }
+        else {
+            let animation = codec.decode_animation(src)?;
+            Ok(Animation {
+                size: animation.size,
+                frames: animation.frames.iter().map(|frame| Frame { image: convert(&frame.image),delay: frame.delay, }).collect(),
+                loops: animation.loops,
+            })
+        }
+    }
+
+    /// Read the EXIF metadata of a slice with the codec that detects it.
+    pub fn exif(&self,src: &[u8]) -> Option<Exif> {
+        self.detect(src)?.exif(src)
+    }
+
+    /// Encode an image.
+    /// # Arguments
+    /// * `name` - Name of the codec.
+    /// * `image` - Image to encode.
+    /// # Returns
+    /// * `Ok(data)` - The encoded image.
+    /// * `Err(ImageError)` - There is no codec with this name, or it could not encode the image.
+    pub fn encode<T: pixel::Pixel>(&self,name: &str,image: &Mat<T>) -> Result<Vec<u8>,ImageError> {
+        self.encode_with_options(name,image,&EncodeOptions::default())
+    }
+
+    /// Encode an image with encoder settings.
+    /// # Arguments
+    /// * `name` - Name of the codec.
+    /// * `image` - Image to encode.
+    /// * `options` - Encoder settings, the codec picks the ones for its format.
+    /// # Returns
+    /// * `Ok(data)` - The encoded image.
+    /// * `Err(ImageError)` - There is no codec with this name, or it could not encode the image.
+    pub fn encode_with_options<T: pixel::Pixel>(&self,name: &str,image: &Mat<T>,options: &EncodeOptions) -> Result<Vec<u8>,ImageError> {
+        let codec = self.by_name(name).ok_or(ImageError::Unsupported("unknown codec"))?;
+        let channels = if T::ALPHA { ChannelLayout::Rgba } else { ChannelLayout::Rgb };
+        if T::BITS > 16 {
+            codec.encode_float(&convert_float(image),channels,T::BITS,options)
+        }
+        else {
+            codec.encode_with_options(&convert(image),channels,T::BITS,options)
+        }
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry::builtin()
+    }
+}
+
+// the registry behind test, info, decode and load
+fn global() -> &'static RwLock<Registry> {
+    static GLOBAL: OnceLock<RwLock<Registry>> = OnceLock::new();
+    GLOBAL.get_or_init(|| RwLock::new(Registry::builtin()))
+}
+
+/// Add a codec to the registry used by `test`, `info`, `decode` and `load`. It overrides the codecs that are already registered.
+pub fn register_codec(codec: Arc<dyn ImageCodec>) {
+    global().write().unwrap().register(codec);
+}
+
+/// Remove codecs by name from the registry used by `test`, `info`, `decode` and `load`.
+pub fn unregister_codec(name: &str) {
+    global().write().unwrap().unregister(name);
+}
+
+/// Snapshot of the registry used by `test`, `info`, `decode` and `load`.
+pub fn registry() -> Registry {
+    global().read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        pixel::Pixel,
+    };
+
+    // stands in for an application codec that replaces PNG
+    struct FakePng;
+
+    impl ImageCodec for FakePng {
+        fn name(&self) -> &'static str {
+            "png"
+        }
+
+        fn extensions(&self) -> &'static [&'static str] {
+            &["png"]
+        }
+
+        fn detect(&self,src: &[u8]) -> bool {
+            src.starts_with(&[0x89,b'P',b'N',b'G'])
+        }
+
+        fn info(&self,_src: &[u8]) -> Result<ImageInfo,ImageError> {
+            Err(ImageError::Unsupported("fake"))
+        }
+
+        fn decode(&self,_src: &[u8]) -> Result<Mat<pixel::RGBA16UN>,ImageError> {
+            Err(ImageError::Unsupported("fake"))
+        }
+
+        fn exif(&self,_src: &[u8]) -> Option<Exif> {
+            Some(Exif {
+                orientation: Orientation::Rotate90,
+                date_time: None,
+                make: Some("fake".to_string()),
+                model: None,
+                resolution: None,
+                resolution_unit: ResolutionUnit::Inch,
+                photometric: None,
+            })
+        }
+
+        fn encode(&self,image: &Mat<pixel::RGBA16UN>) -> Result<Vec<u8>,ImageError> {
+            Ok(vec![image.size.x as u8,image.size.y as u8])
+        }
+    }
+
+    fn gradient() -> Mat<pixel::RGB8UN> {
+        let mut image = Mat::<pixel::RGB8UN>::new(vec2!(9,4));
+        for y in 0..4 {
+            for x in 0..9 {
+                image[(x,y)].set((x * 28) as u8,(y * 80) as u8,128,255);
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn encode_keeps_pixel_format() {
+        let image = gradient();
+        let registry = Registry::builtin();
+        let mut options = EncodeOptions::default();
+        for level in [0,9] {
+            options.png.level = level;
+            assert_eq!(registry.encode_with_options("png",&image,&options).unwrap(),png::encode_with_options(&image,&options.png).unwrap());
+        }
+        assert_eq!(encode_as(&image,ImageFormat::Png,&options).unwrap(),png::encode_with_options(&image,&options.png).unwrap());
+        options.pbm.image_type = pbm::ImageType::Pixmap;
+        assert_eq!(registry.encode_with_options("pbm",&image,&options).unwrap(),pbm::encode_with_options(&image,&options.pbm).unwrap());
+    }
+
+    #[test]
+    fn decode_keeps_pixel_format() {
+        let data = png::encode(&gradient()).unwrap();
+        let registry = Registry::builtin();
+        let image = registry.decode::<pixel::RGB8UN>(&data).unwrap();
+        let expected = png::decode::<pixel::RGB8UN>(&data).unwrap();
+        assert_eq!(image.size,expected.size);
+        for (a,b) in image.data().iter().zip(expected.data().iter()) {
+            assert_eq!(a.get(),b.get());
+        }
+        let image = registry.decode::<pixel::RGBA32F>(&data).unwrap();
+        let expected = png::decode::<pixel::RGBA32F>(&data).unwrap();
+        for (a,b) in image.data().iter().zip(expected.data().iter()) {
+            assert_eq!(a.getf(),b.getf());
+        }
+    }
+
+    #[test]
+    fn decode_override() {
+        let data = png::encode(&gradient()).unwrap();
+        let mut registry = Registry::builtin();
+        registry.register(Arc::new(FakePng));
+        assert!(matches!(registry.decode::<pixel::RGB8UN>(&data),Err(ImageError::Unsupported("fake"))));
+        registry.unregister("png");
+        assert!(registry.decode::<pixel::RGB8UN>(&data).is_err());
+    }
+
+    #[test]
+    fn animation_exif_override() {
+        let data = png::encode(&gradient()).unwrap();
+        let mut registry = Registry::builtin();
+        let animation = registry.decode_animation::<pixel::RGB8UN>(&data).unwrap();
+        assert_eq!(animation.frames.len(),1);
+        assert_eq!(animation.size,vec2!(9,4));
+        assert!(registry.exif(&data).is_none());
+        registry.register(Arc::new(FakePng));
+        assert!(matches!(registry.decode_animation::<pixel::RGB8UN>(&data),Err(ImageError::Unsupported("fake"))));
+        let exif = registry.exif(&data).unwrap();
+        assert_eq!(exif.orientation,Orientation::Rotate90);
+        assert_eq!(exif.make.as_deref(),Some("fake"));
+    }
+
+    #[test]
+    fn detect_headerless() {
+        let registry = Registry::builtin();
+        let data = tga::encode(&gradient()).unwrap();
+        assert_eq!(registry.detect(&data).unwrap().name(),"tga");
+        // a plausible header that does not decode is not claimed
+        let mut header = data[0..18].to_vec();
+        header[2] = 2;
+        assert!(registry.detect(&header).is_none());
+        let data = xbm::encode(&gradient()).unwrap();
+        assert_eq!(registry.detect(&data).unwrap().name(),"xbm");
+        let data = b"#define a_width 0\n#define a_height 3\nstatic char a_bits[] = {\n0x00 };\n";
+        assert!(registry.detect(data).is_none());
+    }
+
+    #[test]
+    fn encode_override() {
+        let mut registry = Registry::builtin();
+        registry.register(Arc::new(FakePng));
+        assert_eq!(registry.encode("png",&gradient()).unwrap(),vec![9,4]);
+        assert_eq!(registry.by_extension("PNG").unwrap().name(),"png");
+        registry.unregister("png");
+        assert!(registry.encode("png",&gradient()).is_err());
+    }
+}
@@ -0,0 +1,451 @@
+// E - image - Color
+// Desmond Germans, 2020
+
+use crate::*;
+
+/// Tone response curve of a channel, from encoded value to linear light.
+#[derive(Clone,Debug)]
+pub enum Curve {
+    /// The values are linear.
+    Linear,
+    /// Power function.
+    Gamma(f32),
+    /// ICC parametric curve, parameters `[g,a,b,c,d,e,f]`: `(a * x + b)^g + e` from `d` up, `c * x + f` below.
+    Parametric([f32; 7]),
+    /// Samples spaced evenly over 0..1, interpolated linearly.
+    Table(Vec<f32>),
+}
+
+impl Curve {
+    /// The sRGB curve.
+    pub fn srgb() -> Curve {
+        Curve::Parametric([2.4,1.0 / 1.055,0.055 / 1.055,1.0 / 12.92,0.04045,0.0,0.0])
+    }
+
+    /// Linear light value of an encoded value.
+    pub fn eval(&self,x: f32) -> f32 {
+        let x = if x < 0.0 { 0.0 } else if x > 1.0 { 1.0 } else { x };
+        match self {
+            Curve::Linear => x,
+            Curve::Gamma(g) => x.powf(*g),
+            Curve::Parametric([g,a,b,c,d,e,f]) => {
+                if x >= *d {
+                    let v = a * x + b;
+                    (if v > 0.0 { v.powf(*g) } else { 0.0 }) + e
+                }
+                else {
+                    c * x + f
+                }
+            },
+            Curve::Table(table) => {
+                if table.is_empty() {
+                    return x;
+                }
+                let p = x * ((table.len() - 1) as f32);
+                let i = p as usize;
+                if i + 1 >= table.len() {
+                    table[table.len() - 1]
+                }
+                else {
+                    let t = p - (i as f32);
+                    table[i] + (table[i + 1] - table[i]) * t
+                }
+            },
+        }
+    }
+
+    /// Encoded value of a linear light value. Assumes the curve is increasing.
+    pub fn invert(&self,y: f32) -> f32 {
+        let x = match self {
+            Curve::Linear => y,
+            Curve::Gamma(g) => if (y > 0.0) && (*g != 0.0) { y.powf(1.0 / g) } else { 0.0 },
+            Curve::Parametric([g,a,b,c,d,e,f]) => {
+                if y >= self.eval(*d) {
+                    let v = y - e;
+                    if (v > 0.0) && (*g != 0.0) && (*a != 0.0) { (v.powf(1.0 / g) - b) / a } else { *d }
+                }
+                else if *c != 0.0 {
+                    (y - f) / c
+                }
+                else {
+                    0.0
+                }
+            },
+            Curve::Table(table) => {
+                if table.len() < 2 {
+                    return y;
+                }
+
+                // first sample at or above y, then interpolate back
+                let i = table.partition_point(|&v| v < y);
+                if i == 0 {
+                    0.0
+                }
+                else if i >= table.len() {
+                    1.0
+                }
+                else {
+                    let d = table[i] - table[i - 1];
+                    let t = if d > 0.0 { (y - table[i - 1]) / d } else { 0.0 };
+                    ((i - 1) as f32 + t) / ((table.len() - 1) as f32)
+                }
+            },
+        };
+        if x < 0.0 { 0.0 } else if x > 1.0 { 1.0 } else { x }
+    }
+}
+
+/// Matrix/TRC color profile: tone response curves to linear light, followed by a matrix to CIE XYZ relative to D50.
+#[derive(Clone,Debug)]
+pub struct ColorProfile {
+    /// Tone response curves of red, green and blue.
+    pub curves: [Curve; 3],
+    /// Linear red, green and blue to XYZ (D50). The columns are the primaries.
+    pub to_xyz: Mat3x3<f32>,
+}
+
+const D50: Vec3<f32> = Vec3 { x: 0.9642,y: 1.0,z: 0.8249, };
+
+const SRGB_CHROMATICITIES: Chromaticities = Chromaticities {
+    white: Vec2 { x: 0.3127,y: 0.3290, },
+    red: Vec2 { x: 0.64,y: 0.33, },
+    green: Vec2 { x: 0.30,y: 0.60, },
+    blue: Vec2 { x: 0.15,y: 0.06, },
+};
+
+fn xyz(c: Vec2<f32>) -> Vec3<f32> {
+    vec3!(c.x / c.y,1.0,(1.0 - c.x - c.y) / c.y)
+}
+
+// primaries and white point to XYZ, adapted to D50 (Bradford)
+fn primaries_to_xyz(chromaticities: &Chromaticities) -> Mat3x3<f32> {
+    let c = chromaticities;
+    let c = if (c.white.y > 0.0) && (c.red.y > 0.0) && (c.green.y > 0.0) && (c.blue.y > 0.0) { c } else { &SRGB_CHROMATICITIES };
+    let m = Mat3x3 { x: xyz(c.red),y: xyz(c.green),z: xyz(c.blue), };
+    let white = xyz(c.white);
+    let s = m.inverse() * white;
+    let m = Mat3x3 { x: m.x * s.x,y: m.y * s.y,z: m.z * s.z, };
+    let bradford = Mat3x3::new(0.8951,-0.7502,0.0389,0.2664,1.7135,-0.0685,-0.1614,0.0367,1.0296);
+    let src = bradford * white;
+    let dst = bradford * D50;
+    let adapt = bradford.inverse() * Mat3x3::scale(vec3!(dst.x / src.x,dst.y / src.y,dst.z / src.z)) * bradford;
+    adapt * m
+}
+
+fn from_be16(src: &[u8]) -> u16 {
+    ((src[0] as u16) << 8) | (src[1] as u16)
+}
+
+fn from_be32(src: &[u8]) -> u32 {
+    ((src[0] as u32) << 24) | ((src[1] as u32) << 16) | ((src[2] as u32) << 8) | (src[3] as u32)
+}
+
+fn s15fixed16(src: &[u8]) -> f32 {
+    (from_be32(src) as i32 as f32) / 65536.0
+}
+
+impl ColorProfile {
+    /// The sRGB profile.
+    pub fn srgb() -> ColorProfile {
+        ColorProfile {
+            curves: [Curve::srgb(),Curve::srgb(),Curve::srgb()],
+            to_xyz: primaries_to_xyz(&SRGB_CHROMATICITIES),
+        }
+    }
+
+    /// sRGB primaries with linear curves.
+    pub fn linear_srgb() -> ColorProfile {
+        ColorProfile {
+            curves: [Curve::Linear,Curve::Linear,Curve::Linear],
+            to_xyz: primaries_to_xyz(&SRGB_CHROMATICITIES),
+        }
+    }
+
+    /// Profile from PNG gAMA and cHRM.
+    /// # Arguments
+    /// * `gamma` - Encoding gamma, if known. Without it, the sRGB curve is assumed.
+    /// * `chromaticities` - White point and primaries, if known. Without them, the sRGB primaries are assumed.
+    /// # Returns
+    /// The profile.
+    pub fn from_gamma_chromaticities(gamma: Option<f32>,chromaticities: Option<&Chromaticities>) -> ColorProfile {
+        let curve = match gamma {
+            Some(gamma) if gamma > 0.0 => Curve::Gamma(1.0 / gamma),
+            _ => Curve::srgb(),
+        };
+        ColorProfile {
+            curves: [curve.clone(),curve.clone(),curve],
+            to_xyz: primaries_to_xyz(chromaticities.unwrap_or(&SRGB_CHROMATICITIES)),
+        }
+    }
+
+    /// Parse an ICC profile.
+    /// # Arguments
+    /// * `src` - The profile, as embedded in the image.
+    /// # Returns
+    /// * `Ok(profile)` - The profile.
+    /// * `Err(ImageError)` - The profile is invalid, or it is not an RGB or gray matrix/TRC profile (v2 or v4).
+    pub fn from_icc(src: &[u8]) -> Result<ColorProfile,ImageError> {
+        if (src.len() < 132) || (&src[36..40] != b"acsp") {
+            return Err(ImageError::Corrupt(0,"invalid ICC profile"));
+        }
+        let gray = match &src[16..20] {
+            b"RGB " => false,
+            b"GRAY" => true,
+            _ => { return Err(ImageError::Unsupported("ICC profile color space")); },
+        };
+        if &src[20..24] != b"XYZ " {
+            return Err(ImageError::Unsupported("ICC profile connection space"));
+        }
+
+        // tag table
+        let count = from_be32(&src[128..132]) as usize;
+        if 132 + count * 12 > src.len() {
+            return Err(ImageError::Corrupt(128,"invalid ICC tag table"));
+        }
+        let tag = |signature: &[u8]| -> Result<Option<(usize,&[u8])>,ImageError> {
+            for i in 0..count {
+                let entry = &src[132 + i * 12..144 + i * 12];
+                if &entry[0..4] == signature {
+                    let offset = from_be32(&entry[4..8]) as usize;
+                    let size = from_be32(&entry[8..12]) as usize;
+                    if (size < 8) || (offset > src.len()) || (size > src.len() - offset) {
+                        return Err(ImageError::Corrupt(132 + i * 12,"invalid ICC tag"));
+                    }
+                    return Ok(Some((offset,&src[offset..offset + size])));
+                }
+            }
+            Ok(None)
+        };
+        let curve = |signature: &[u8]| -> Result<Curve,ImageError> {
+            let (offset,data) = tag(signature)?.ok_or(ImageError::Unsupported("ICC profile without TRC"))?;
+            match &data[0..4] {
+                b"curv" => {
+                    if data.len() < 12 {
+                        return Err(ImageError::Corrupt(offset,"invalid ICC curve"));
+                    }
+                    let entries = from_be32(&data[8..12]) as usize;
+                    if data.len() < 12 + entries * 2 {
+                        return Err(ImageError::Corrupt(offset,"invalid ICC curve"));
+                    }
+                    match entries {
+                        0 => Ok(Curve::Linear),
+                        1 => Ok(Curve::Gamma((from_be16(&data[12..14]) as f32) / 256.0)),
+                        _ => Ok(Curve::Table((0..entries).map(|i| (from_be16(&data[12 + i * 2..14 + i * 2]) as f32) / 65535.0).collect())),
+                    }
+                },
+                b"para" => {
+                    if data.len() < 12 {
+                        return Err(ImageError::Corrupt(offset,"invalid ICC curve"));
+                    }
+                    let function = from_be16(&data[8..10]);
+                    let length = match function {
+                        0 => 1,
+                        1 => 3,
+                        2 => 4,
+                        3 => 5,
+                        4 => 7,
+                        _ => { return Err(ImageError::Unsupported("ICC parametric curve")); },
+                    };
+                    if data.len() < 12 + length * 4 {
+                        return Err(ImageError::Corrupt(offset,"invalid ICC curve"));
+                    }
+                    let p: Vec<f32> = (0..length).map(|i| s15fixed16(&data[12 + i * 4..16 + i * 4])).collect();
+
+                    // map all functions onto the most general one
+                    Ok(match function {
+                        0 => Curve::Gamma(p[0]),
+                        1 => Curve::Parametric([p[0],p[1],p[2],0.0,if p[1] != 0.0 { -p[2] / p[1] } else { 0.0 },0.0,0.0]),
+                        2 => Curve::Parametric([p[0],p[1],p[2],0.0,if p[1] != 0.0 { -p[2] / p[1] } else { 0.0 },p[3],p[3]]),
+                        3 => Curve::Parametric([p[0],p[1],p[2],p[3],p[4],0.0,0.0]),
+                        _ => Curve::Parametric([p[0],p[1],p[2],p[3],p[4],p[5],p[6]]),
+                    })
+                },
+                _ => Err(ImageError::Unsupported("ICC curve type")),
+            }
+        };
+
+        if gray {
+            let k = curve(b"kTRC")?;
+            let column = D50 / 3.0;
+            return Ok(ColorProfile {
+                curves: [k.clone(),k.clone(),k],
+                to_xyz: Mat3x3 { x: column,y: column,z: column, },
+            });
+        }
+        let column = |signature: &[u8]| -> Result<Vec3<f32>,ImageError> {
+            let (offset,data) = tag(signature)?.ok_or(ImageError::Unsupported("ICC profile without colorants"))?;
+            if (&data[0..4] != b"XYZ ") || (data.len() < 20) {
+                return Err(ImageError::Corrupt(offset,"invalid ICC colorant"));
+            }
+            Ok(vec3!(s15fixed16(&data[8..12]),s15fixed16(&data[12..16]),s15fixed16(&data[16..20])))
+        };
+        Ok(ColorProfile {
+            curves: [curve(b"rTRC")?,curve(b"gTRC")?,curve(b"bTRC")?],
+            to_xyz: Mat3x3 { x: column(b"rXYZ")?,y: column(b"gXYZ")?,z: column(b"bXYZ")?, },
+        })
+    }
+}
+
+/// Color space of image data.
+#[derive(Clone,Debug)]
+pub enum ColorSpace {
+    /// sRGB.
+    Srgb,
+    /// sRGB primaries, linear light.
+    LinearSrgb,
+    /// Any other color space.
+    Profile(ColorProfile),
+}
+
+impl ColorSpace {
+    /// The color space an image declares: its ICC profile, else PNG sRGB, else PNG gAMA and cHRM, else sRGB.
+    /// Profiles that cannot be used (CMYK, Lab or LUT-based) are ignored.
+    pub fn from_info(info: &ImageInfo) -> ColorSpace {
+        if let Some(icc) = &info.icc_profile {
+            if let Ok(profile) = ColorProfile::from_icc(icc) {
+                return ColorSpace::Profile(profile);
+            }
+        }
+        if info.srgb || ((info.gamma == None) && info.chromaticities.is_none()) {
+            return ColorSpace::Srgb;
+        }
+        ColorSpace::Profile(ColorProfile::from_gamma_chromaticities(info.gamma,info.chromaticities.as_ref()))
+    }
+
+    /// The profile of the color space.
+    pub fn profile(&self) -> ColorProfile {
+        match self {
+            ColorSpace::Srgb => ColorProfile::srgb(),
+            ColorSpace::LinearSrgb => ColorProfile::linear_srgb(),
+            ColorSpace::Profile(profile) => profile.clone(),
+        }
+    }
+}
+
+/// Image with the color space of its pixels.
+pub struct ManagedImage<T: pixel::Pixel> {
+    /// The image.
+    pub image: Mat<T>,
+    /// Color space of the pixels.
+    pub color_space: ColorSpace,
+}
+
+const TABLE_SIZE: usize = 65536;
+
+fn lookup(table: &[f32],v: f32) -> f32 {
+    let v = if v < 0.0 { 0.0 } else if v > 1.0 { 1.0 } else { v };
+    table[(v * 65535.0 + 0.5) as usize]
+}
+
+/// Convert an image from one color space to another.
+/// # Generic
+/// * `S` - The pixel format of the image.
+/// * `D` - The resulting pixel format.
+/// # Arguments
+/// * `image` - Image to convert.
+/// * `from` - Color space of the image.
+/// * `to` - Color space to convert to.
+/// # Returns
+/// The converted image. Colors outside the target gamut are clipped, alpha is copied.
+pub fn convert_color<S: pixel::Pixel,D: pixel::Pixel>(image: &Mat<S>,from: &ColorSpace,to: &ColorSpace) -> Mat<D> {
+    let mut result = Mat::<D>::new(image.size);
+    let same = match (from,to) {
+        (ColorSpace::Srgb,ColorSpace::Srgb) | (ColorSpace::LinearSrgb,ColorSpace::LinearSrgb) => true,
+        _ => false,
+    };
+    if same {
+        for (d,s) in result.data_mut().iter_mut().zip(image.data().iter()) {
+            let (r,g,b,a) = s.getf();
+            d.setf(r,g,b,a);
+        }
+        return result;
+    }
+
+    // source curves sampled at 16 bits, unless the source is float, and the matrix from source to target through XYZ
+    let from = from.profile();
+    let to = to.profile();
+    let to_linear: Vec<Vec<f32>> = if S::BITS <= 16 {
+        from.curves.iter().map(|curve| (0..TABLE_SIZE).map(|i| curve.eval((i as f32) / 65535.0)).collect()).collect()
+    }
+    else {
+        Vec::new()
+    };
+    let eval = |i: usize,v: f32| if to_linear.is_empty() { from.curves[i].eval(v) } else { lookup(&to_linear[i],v) };
+    let m = to.to_xyz.inverse() * from.to_xyz;
+
+    // the target curves are inverted directly, a table spaced evenly in linear light has too few samples in the darks
+    for (d,s) in result.data_mut().iter_mut().zip(image.data().iter()) {
+        let (r,g,b,a) = s.getf();
+        let c = m * vec3!(eval(0,r),eval(1,g),eval(2,b));
+        d.setf(to.curves[0].invert(c.x),to.curves[1].invert(c.y),to.curves[2].invert(c.z),a);
+    }
+    result
+}
+
+/// Decode a slice into a color space.
+/// # Generic
+/// * `T` - The resulting pixel format.
+/// # Arguments
+/// * `src` - Slice to decode.
+/// * `target` - Color space to convert to.
+/// # Returns
+/// * `Ok(image)` - Slice is decoded and converted from the color space it declares (see `ColorSpace::from_info`) to `target`.
+/// * `Err(ImageError)` - Slice could not be decoded.
+pub fn decode_managed<T: pixel::Pixel>(src: &[u8],target: &ColorSpace) -> Result<ManagedImage<T>,ImageError> {
+    let source = ColorSpace::from_info(&info(src)?);
+
+    // decode at 16 bits, so the conversion does not add banding
+    let image = decode::<pixel::RGBA16UN>(src)?;
+    Ok(ManagedImage {
+        image: convert_color(&image,&source,target),
+        color_space: target.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        pixel::Pixel,
+    };
+
+    #[test]
+    fn convert_darks() {
+        // a ramp of dark linear values must come out as distinct sRGB values
+        let mut image = Mat::<pixel::RGBA32F>::new(vec2!(64usize,1));
+        for (i,p) in image.data_mut().iter_mut().enumerate() {
+            let v = (i as f32) * 0.00002;
+            p.setf(v,v,v,1.0);
+        }
+        let result: Mat<pixel::RGBA32F> = convert_color(&image,&ColorSpace::LinearSrgb,&ColorSpace::Srgb);
+        let srgb = Curve::srgb();
+        for (s,d) in image.data().iter().zip(result.data().iter()) {
+            let (v,_,_,_) = s.getf();
+            let (r,g,b,a) = d.getf();
+            let expected = srgb.invert(v);
+            assert!((r - expected).abs() < 2e-5);
+            assert!((g - expected).abs() < 2e-5);
+            assert!((b - expected).abs() < 2e-5);
+            assert_eq!(a,1.0);
+        }
+    }
+
+    #[test]
+    fn convert_roundtrip() {
+        let mut image = Mat::<pixel::RGBA16UN>::new(vec2!(256usize,1));
+        for (i,p) in image.data_mut().iter_mut().enumerate() {
+            let v = (i * 257) as u16;
+            p.set16(v,v / 2,65535 - v,v);
+        }
+        let linear: Mat<pixel::RGBA32F> = convert_color(&image,&ColorSpace::Srgb,&ColorSpace::LinearSrgb);
+        let result: Mat<pixel::RGBA16UN> = convert_color(&linear,&ColorSpace::LinearSrgb,&ColorSpace::Srgb);
+        for (s,d) in image.data().iter().zip(result.data().iter()) {
+            let (r0,g0,b0,a0) = s.get16();
+            let (r1,g1,b1,a1) = d.get16();
+            assert!(((r0 as i32) - (r1 as i32)).abs() <= 2);
+            assert!(((g0 as i32) - (g1 as i32)).abs() <= 2);
+            assert!(((b0 as i32) - (b1 as i32)).abs() <= 2);
+            assert_eq!(a0,a1);
+        }
+    }
+}
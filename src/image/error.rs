@@ -0,0 +1,60 @@
+// E - image - Error
+// Desmond Germans, 2020
+
+use std::{
+    fmt,
+    io,
+};
+
+/// Image decoding and encoding error.
+#[derive(Debug)]
+pub enum ImageError {
+    /// Opening, reading or writing a file failed.
+    Io(io::Error),
+    /// The data is not in any recognized format.
+    UnknownFormat,
+    /// The format is recognized, but uses a feature that is not supported.
+    Unsupported(&'static str),
+    /// The data is damaged or truncated, at byte offset (first) in the source.
+    Corrupt(usize,&'static str),
+    /// The image is empty or too large (width,height).
+    Dimensions(usize,usize),
+}
+
+/// Largest image the decoders accept, in pixels. Headers that claim more are rejected with `ImageError::Dimensions` before anything is allocated.
+pub const MAX_PIXELS: usize = 16384 * 16384;
+
+// reject empty and oversized images, returns the number of pixels
+pub(crate) fn check_dimensions(width: usize,height: usize) -> Result<usize,ImageError> {
+    match width.checked_mul(height) {
+        Some(pixels) if (width > 0) && (height > 0) && (pixels <= MAX_PIXELS) => Ok(pixels),
+        _ => Err(ImageError::Dimensions(width,height)),
+    }
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Io(error) => { write!(f,"I/O error: {}",error) },
+            ImageError::UnknownFormat => { write!(f,"unknown image format") },
+            ImageError::Unsupported(feature) => { write!(f,"unsupported: {}",feature) },
+            ImageError::Corrupt(offset,reason) => { write!(f,"corrupt data at offset {}: {}",offset,reason) },
+            ImageError::Dimensions(width,height) => { write!(f,"invalid image dimensions {}x{}",width,height) },
+        }
+    }
+}
+
+impl std::error::Error for ImageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ImageError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ImageError {
+    fn from(error: io::Error) -> ImageError {
+        ImageError::Io(error)
+    }
+}
@@ -0,0 +1,444 @@
+// E - image - EXIF
+// Desmond Germans, 2020
+
+use crate::*;
+
+/// How the stored image has to be transformed to show it upright (EXIF tag 0x0112).
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Orientation {
+    /// Stored upright (1).
+    Normal,
+    /// Mirrored left to right (2).
+    FlipHorizontal,
+    /// Upside down (3).
+    Rotate180,
+    /// Mirrored top to bottom (4).
+    FlipVertical,
+    /// Mirrored along the top-left to bottom-right diagonal (5).
+    Transpose,
+    /// Needs to be rotated 90 degrees clockwise (6).
+    Rotate90,
+    /// Mirrored along the top-right to bottom-left diagonal (7).
+    Transverse,
+    /// Needs to be rotated 90 degrees counterclockwise (8).
+    Rotate270,
+}
+
+impl Orientation {
+    fn from_tag(value: u32) -> Orientation {
+        match value {
+            2 => Orientation::FlipHorizontal,
+            3 => Orientation::Rotate180,
+            4 => Orientation::FlipVertical,
+            5 => Orientation::Transpose,
+            6 => Orientation::Rotate90,
+            7 => Orientation::Transverse,
+            8 => Orientation::Rotate270,
+            _ => Orientation::Normal,
+        }
+    }
+
+    /// Transform an image so it shows upright.
+    /// # Arguments
+    /// * `image` - Image as it was stored.
+    /// # Returns
+    /// The upright image. Width and height are swapped for the orientations that rotate by 90 degrees.
+    pub fn apply<T: pixel::Pixel>(&self,image: &Mat<T>) -> Mat<T> {
+        let width = image.size.x;
+        let height = image.size.y;
+        let swap = match self {
+            Orientation::Transpose | Orientation::Rotate90 | Orientation::Transverse | Orientation::Rotate270 => true,
+            _ => false,
+        };
+        let mut result = Mat::<T>::new(if swap { vec2!(height,width) } else { vec2!(width,height) });
+        for y in 0..result.size.y {
+            for x in 0..result.size.x {
+                let (sx,sy) = match self {
+                    Orientation::Normal => (x,y),
+                    Orientation::FlipHorizontal => (width - 1 - x,y),
+                    Orientation::Rotate180 => (width - 1 - x,height - 1 - y),
+                    Orientation::FlipVertical => (x,height - 1 - y),
+                    Orientation::Transpose => (y,x),
+                    Orientation::Rotate90 => (y,height - 1 - x),
+                    Orientation::Transverse => (width - 1 - y,height - 1 - x),
+                    Orientation::Rotate270 => (width - 1 - y,x),
+                };
+                result[(x,y)] = image[(sx,sy)];
+            }
+        }
+        result
+    }
+}
+
+/// Unit of the resolution (EXIF tag 0x0128).
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum ResolutionUnit {
+    /// No absolute unit, only the aspect ratio is known.
+    None,
+    /// Pixels per inch.
+    Inch,
+    /// Pixels per centimeter.
+    Centimeter,
+}
+
+/// Metadata from an EXIF block.
+#[derive(Clone,Debug)]
+pub struct Exif {
+    /// Orientation of the stored image.
+    pub orientation: Orientation,
+    /// Capture time as `YYYY:MM:DD HH:MM:SS`, falls back to the modification time.
+    pub date_time: Option<String>,
+    /// Camera manufacturer.
+    pub make: Option<String>,
+    /// Camera model.
+    pub model: Option<String>,
+    /// Horizontal and vertical resolution.
+    pub resolution: Option<Vec2<f32>>,
+    /// Unit of `resolution`.
+    pub resolution_unit: ResolutionUnit,
+    pub(crate) photometric: Option<u32>,
+}
+
+// one IFD entry
+#[derive(Copy,Clone)]
+pub(crate) struct Entry {
+    pub tag: u16,
+    pub format: u16,
+    pub count: u32,
+    pub offset: usize,  // where the value is, either in the entry or elsewhere in the block
+}
+
+// reader for TIFF structured data
+pub(crate) struct TiffReader<'a> {
+    pub src: &'a [u8],
+    pub le: bool,
+}
+
+impl<'a> TiffReader<'a> {
+    pub fn new(src: &'a [u8]) -> Option<TiffReader<'a>> {
+        if src.len() < 8 {
+            return None;
+        }
+        let le = match (src[0],src[1]) {
+            (0x49,0x49) => true,
+            (0x4D,0x4D) => false,
+            _ => { return None; },
+        };
+        let reader = TiffReader {
+            src: src,
+            le: le,
+        };
+        if reader.u16(2)? != 42 {
+            return None;
+        }
+        Some(reader)
+    }
+
+    pub fn u16(&self,offset: usize) -> Option<u16> {
+        if offset + 2 > self.src.len() {
+            return None;
+        }
+        let b = &self.src[offset..offset + 2];
+        Some(if self.le { (b[1] as u16) << 8 | b[0] as u16 } else { (b[0] as u16) << 8 | b[1] as u16 })
+    }
+
+    pub fn u32(&self,offset: usize) -> Option<u32> {
+        if offset + 4 > self.src.len() {
+            return None;
+        }
+        let b = &self.src[offset..offset + 4];
+        Some(if self.le {
+            (b[3] as u32) << 24 | (b[2] as u32) << 16 | (b[1] as u32) << 8 | b[0] as u32
+        }
+        else {
+            (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32
+        })
+    }
+
+    // offset of the first IFD
+    pub fn first_ifd(&self) -> Option<usize> {
+        Some(self.u32(4)? as usize)
+    }
+
+    // entries of the IFD at offset, and the offset of the next IFD (0 if none)
+    pub fn ifd(&self,offset: usize) -> Option<(Vec<Entry>,usize)> {
+        let count = self.u16(offset)? as usize;
+        let mut entries: Vec<Entry> = Vec::new();
+        let mut sp = offset + 2;
+        for _i in 0..count {
+            let tag = self.u16(sp)?;
+            let format = self.u16(sp + 2)?;
+            let count = self.u32(sp + 4)?;
+            let size = match format {
+                1 | 2 | 6 | 7 => 1,
+                3 | 8 => 2,
+                4 | 9 | 11 | 13 => 4,
+                5 | 10 | 12 => 8,
+                _ => 0,  // unknown, skip
+            };
+            if size != 0 {
+                let total = size * (count as usize);
+                let offset = if total <= 4 { sp + 8 } else { self.u32(sp + 8)? as usize };
+                if offset + total <= self.src.len() {
+                    entries.push(Entry {
+                        tag: tag,
+                        format: format,
+                        count: count,
+                        offset: offset,
+                    });
+                }
+            }
+            sp += 12;
+        }
+        let next = self.u32(sp).unwrap_or(0) as usize;
+        Some((entries,next))
+    }
+
+    // element of an unsigned integer entry
+    pub fn uint(&self,entry: &Entry,index: usize) -> Option<u32> {
+        if index >= entry.count as usize {
+            return None;
+        }
+        match entry.format {
+            1 | 7 => Some(self.src[entry.offset + index] as u32),
+            3 => Some(self.u16(entry.offset + index * 2)? as u32),
+            4 | 13 => self.u32(entry.offset + index * 4),
+            _ => None,
+        }
+    }
+
+    // all elements of an unsigned integer entry
+    pub fn uints(&self,entry: &Entry) -> Option<Vec<u32>> {
+        let mut result: Vec<u32> = Vec::new();
+        for i in 0..entry.count as usize {
+            result.push(self.uint(entry,i)?);
+        }
+        Some(result)
+    }
+
+    pub fn rational(&self,entry: &Entry) -> Option<f32> {
+        if (entry.format != 5) || (entry.count < 1) {
+            return None;
+        }
+        let num = self.u32(entry.offset)?;
+        let den = self.u32(entry.offset + 4)?;
+        if den == 0 {
+            return None;
+        }
+        Some((num as f32) / (den as f32))
+    }
+
+    pub fn ascii(&self,entry: &Entry) -> Option<String> {
+        if entry.format != 2 {
+            return None;
+        }
+        let bytes = &self.src[entry.offset..entry.offset + entry.count as usize];
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        let text = String::from_utf8_lossy(&bytes[0..end]).trim().to_string();
+        if text.len() == 0 {
+            None
+        }
+        else {
+            Some(text)
+        }
+    }
+}
+
+impl Exif {
+    /// Parse an EXIF block.
+    /// # Arguments
+    /// * `src` - TIFF structured EXIF data, starting with the byte order mark. A leading `Exif\0\0` is skipped.
+    /// # Returns
+    /// * `None` - The data is not valid EXIF.
+    /// * `Some(exif)` - The metadata.
+    pub fn parse(src: &[u8]) -> Option<Exif> {
+        let src = if (src.len() >= 6) && (&src[0..6] == b"Exif\0\0") { &src[6..] } else { src };
+        let reader = TiffReader::new(src)?;
+        let mut exif = Exif {
+            orientation: Orientation::Normal,
+            date_time: None,
+            make: None,
+            model: None,
+            resolution: None,
+            resolution_unit: ResolutionUnit::Inch,
+            photometric: None,
+        };
+        let (entries,_) = reader.ifd(reader.first_ifd()?)?;
+        let mut x_resolution: Option<f32> = None;
+        let mut y_resolution: Option<f32> = None;
+        let mut exif_ifd: Option<usize> = None;
+        for entry in entries.iter() {
+            match entry.tag {
+                0x0106 => { exif.photometric = reader.uint(entry,0); },  // photometric interpretation
+                0x010F => { exif.make = reader.ascii(entry); },
+                0x0110 => { exif.model = reader.ascii(entry); },
+                0x0112 => { exif.orientation = Orientation::from_tag(reader.uint(entry,0).unwrap_or(1)); },
+                0x011A => { x_resolution = reader.rational(entry); },
+                0x011B => { y_resolution = reader.rational(entry); },
+                0x0128 => {
+                    exif.resolution_unit = match reader.uint(entry,0) {
+                        Some(1) => ResolutionUnit::None,
+                        Some(3) => ResolutionUnit::Centimeter,
+                        _ => ResolutionUnit::Inch,
+                    };
+                },
+                0x0132 => { exif.date_time = reader.ascii(entry); },  // modification time
+                0x8769 => { exif_ifd = reader.uint(entry,0).map(|o| o as usize); },  // EXIF sub-IFD
+                _ => { },
+            }
+        }
+        if let (Some(x),Some(y)) = (x_resolution,y_resolution) {
+            exif.resolution = Some(vec2!(x,y));
+        }
+
+        // capture time is in the EXIF sub-IFD
+        if let Some(offset) = exif_ifd {
+            if let Some((entries,_)) = reader.ifd(offset) {
+                for entry in entries.iter() {
+                    if entry.tag == 0x9003 {  // DateTimeOriginal
+                        if let Some(date_time) = reader.ascii(entry) {
+                            exif.date_time = Some(date_time);
+                        }
+                    }
+                }
+            }
+        }
+        Some(exif)
+    }
+
+    // resolution in dots per inch, if it has an absolute unit
+    pub(crate) fn dpi(&self) -> Option<Vec2<f32>> {
+        let resolution = self.resolution?;
+        match self.resolution_unit {
+            ResolutionUnit::Inch => Some(resolution),
+            ResolutionUnit::Centimeter => Some(vec2!(resolution.x * 2.54,resolution.y * 2.54)),
+            ResolutionUnit::None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        pixel::Pixel,
+    };
+
+    // APP1 payload with orientation, make and a resolution in centimeters
+    fn app1(le: bool,orientation: u16) -> Vec<u8> {
+        let u16b = |v: u16| if le { v.to_le_bytes() } else { v.to_be_bytes() };
+        let u32b = |v: u32| if le { v.to_le_bytes() } else { v.to_be_bytes() };
+        let mut data = b"Exif\0\0".to_vec();
+        data.extend_from_slice(if le { b"II" } else { b"MM" });
+        data.extend_from_slice(&u16b(42));
+        data.extend_from_slice(&u32b(8));
+        data.extend_from_slice(&u16b(5));
+        let mut entry = |tag: u16,format: u16,count: u32,value: [u8; 4]| {
+            data.extend_from_slice(&u16b(tag));
+            data.extend_from_slice(&u16b(format));
+            data.extend_from_slice(&u32b(count));
+            data.extend_from_slice(&value);
+        };
+        let short = |v: u16| { let b = u16b(v); [b[0],b[1],0,0] };
+        entry(0x010F,2,4,*b"Cam\0");
+        entry(0x0112,3,1,short(orientation));
+        entry(0x011A,5,1,u32b(74));
+        entry(0x011B,5,1,u32b(82));
+        entry(0x0128,3,1,short(3));
+        data.extend_from_slice(&u32b(0));
+        for v in [118,1,59,1] {
+            data.extend_from_slice(&u32b(v));
+        }
+        data
+    }
+
+    // 16x8 JPEG, black on the left and white on the right, with an APP1 segment
+    fn jpeg_with(app1: &[u8]) -> Vec<u8> {
+        let mut image = Mat::<pixel::RGB8UN>::new(vec2!(16usize,8));
+        for y in 0..8 {
+            for x in 8..16 {
+                image[(x,y)].set(255,255,255,255);
+            }
+        }
+        let encoded = jpeg::encode(&image).unwrap();
+        let mut data = vec![0xFF,0xD8,0xFF,0xE1];
+        data.extend_from_slice(&((app1.len() + 2) as u16).to_be_bytes());
+        data.extend_from_slice(app1);
+        data.extend_from_slice(&encoded[2..]);
+        data
+    }
+
+    #[test]
+    fn parse_byte_orders() {
+        for le in [true,false] {
+            let exif = Exif::parse(&app1(le,6)).unwrap();
+            assert_eq!(exif.orientation,Orientation::Rotate90);
+            assert_eq!(exif.make.as_deref(),Some("Cam"));
+            assert_eq!(exif.resolution_unit,ResolutionUnit::Centimeter);
+            assert_eq!(exif.resolution.unwrap(),vec2!(118.0,59.0));
+            let dpi = exif.dpi().unwrap();
+            assert!(((dpi.x - 299.72).abs() < 0.01) && ((dpi.y - 149.86).abs() < 0.01));
+            let data = jpeg_with(&app1(le,6));
+            assert_eq!(jpeg::exif(&data).unwrap().orientation,Orientation::Rotate90);
+            assert_eq!(jpeg::info(&data).unwrap().dpi,Some(dpi));
+        }
+        let mut data = app1(true,6);
+        data[6] = b'X';
+        assert!(Exif::parse(&data).is_none());
+    }
+
+    #[test]
+    fn apply_all() {
+        // 3x2, numbered row by row
+        let mut image = Mat::<pixel::RGBA8UN>::new(vec2!(3usize,2));
+        for y in 0..2 {
+            for x in 0..3 {
+                image[(x,y)].set((y * 3 + x) as u8,0,0,255);
+            }
+        }
+        let cases: [(Orientation,&[&[u8]]); 8] = [
+            (Orientation::Normal,&[&[0,1,2],&[3,4,5]]),
+            (Orientation::FlipHorizontal,&[&[2,1,0],&[5,4,3]]),
+            (Orientation::Rotate180,&[&[5,4,3],&[2,1,0]]),
+            (Orientation::FlipVertical,&[&[3,4,5],&[0,1,2]]),
+            (Orientation::Transpose,&[&[0,3],&[1,4],&[2,5]]),
+            (Orientation::Rotate90,&[&[3,0],&[4,1],&[5,2]]),
+            (Orientation::Transverse,&[&[5,2],&[4,1],&[3,0]]),
+            (Orientation::Rotate270,&[&[2,5],&[1,4],&[0,3]]),
+        ];
+        for (tag,(orientation,rows)) in cases.iter().enumerate() {
+            assert_eq!(Orientation::from_tag(tag as u32 + 1),*orientation);
+            let result = orientation.apply(&image);
+            assert_eq!(result.size,vec2!(rows[0].len(),rows.len()));
+            for (y,row) in rows.iter().enumerate() {
+                for (x,value) in row.iter().enumerate() {
+                    assert_eq!(result[(x,y)].get().0,*value,"{:?} at ({},{})",orientation,x,y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decode_oriented() {
+        for tag in 1..9 {
+            let data = jpeg_with(&app1(tag & 1 == 0,tag));
+            let stored = decode::<pixel::RGB8UN>(&data).unwrap();
+            assert_eq!(stored.size,vec2!(16,8));
+            let plain = decode_with_options::<pixel::RGB8UN>(&data,&DecodeOptions::default()).unwrap();
+            assert_eq!(plain.size,vec2!(16,8));
+            let upright = decode_with_options::<pixel::RGB8UN>(&data,&DecodeOptions { apply_orientation: true, }).unwrap();
+            let expected = Orientation::from_tag(tag as u32).apply(&stored);
+            assert_eq!(upright.size,expected.size);
+            for (a,b) in upright.data().iter().zip(expected.data().iter()) {
+                assert_eq!(a.get(),b.get());
+            }
+        }
+
+        // rotated clockwise, the left half ends up on top
+        let data = jpeg_with(&app1(true,6));
+        let upright = decode_with_options::<pixel::RGB8UN>(&data,&DecodeOptions { apply_orientation: true, }).unwrap();
+        assert_eq!(upright.size,vec2!(8,16));
+        assert!(upright[(4,4)].get().0 < 16);
+        assert!(upright[(4,12)].get().0 > 240);
+    }
+}
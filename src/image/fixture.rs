@@ -0,0 +1,46 @@
+// E - image - Fixture
+// Desmond Germans, 2020
+
+use crate::*;
+
+// test image with runs on the left and noise on the right, so both kinds of RLE packets and predictor rows appear
+// colors (at least 2) limits the number of different colors for palette formats, alpha ramps from left to right
+pub fn pattern<T: pixel::Pixel>(size: Vec2<usize>,colors: usize) -> Mat<T> {
+    let spread = |n: usize| ((n * 65535) / (colors - 1)) as u16;
+    let mut image = Mat::<T>::new(size);
+    for y in 0..size.y {
+        for x in 0..size.x {
+            let n = if x < size.x / 3 { (y * 3) % colors } else { (x * 7919 + y * 104729) % colors };
+            image[(x,y)].set16(spread(n),spread((n * 3) % colors),spread(colors - 1 - n),((x * 65535) / (size.x - 1)) as u16);
+        }
+    }
+    image
+}
+
+// same image with red copied to green and blue
+pub fn grayscale<T: pixel::Pixel>(image: &Mat<T>) -> Mat<T> {
+    let mut result = image.clone();
+    for p in result.data_mut().iter_mut() {
+        let (r,_,_,a) = p.get16();
+        p.set16(r,r,r,a);
+    }
+    result
+}
+
+// same image without alpha
+pub fn opaque<T: pixel::Pixel>(image: &Mat<T>) -> Mat<T> {
+    let mut result = image.clone();
+    for p in result.data_mut().iter_mut() {
+        let (r,g,b,_) = p.get16();
+        p.set16(r,g,b,0xFFFF);
+    }
+    result
+}
+
+// size and pixels have to match exactly
+pub fn assert_same<S: pixel::Pixel,D: pixel::Pixel>(expected: &Mat<S>,actual: &Mat<D>) {
+    assert_eq!(actual.size,expected.size);
+    for (i,(a,b)) in expected.data().iter().zip(actual.data().iter()).enumerate() {
+        assert_eq!(b.get16(),a.get16(),"pixel ({},{})",i % expected.size.x,i / expected.size.x);
+    }
+}
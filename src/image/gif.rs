@@ -5,14 +5,470 @@
 
 use crate::*;
 
-pub fn test(_src: &[u8]) -> Option<(u32,u32)> {
-    None
+const MAX_CODES: usize = 4096;
+
+fn from_le16(src: &[u8]) -> u16 {
+    ((src[1] as u16) << 8) | (src[0] as u16)
+}
+
+// LSB-first bit reader over the concatenated image data sub-blocks
+struct Reader<'a> {
+    block: &'a [u8],
+    rp: usize,
+    bit: u32,
+    cache: u32,
+}
+
+impl<'a> Reader<'a> {
+    fn new(block: &'a [u8]) -> Reader<'a> {
+        Reader {
+            block: block,
+            rp: 0,
+            bit: 0,
+            cache: 0,
+        }
+    }
+
+    fn read_bits(&mut self,n: u32) -> Option<u32> {
+        while self.bit < n {
+            if self.rp >= self.block.len() {
+                return None;
+            }
+            self.cache |= (self.block[self.rp] as u32) << self.bit;
+            self.rp += 1;
+            self.bit += 8;
+        }
+        let result = self.cache & ((1 << n) - 1);
+        self.cache >>= n;
+        self.bit -= n;
+        Some(result)
+    }
+}
+
+// collect the data sub-blocks starting at sp, returns the data and the position after the terminator
+fn read_sub_blocks(src: &[u8],mut sp: usize) -> Option<(Vec<u8>,usize)> {
+    let mut data: Vec<u8> = Vec::new();
+    loop {
+        if sp >= src.len() {
+            return None;
+        }
+        let length = src[sp] as usize;
+        sp += 1;
+        if length == 0 {
+            break;
+        }
+        if sp + length > src.len() {
+            return None;
+        }
+        data.extend_from_slice(&src[sp..sp + length]);
+        sp += length;
+    }
+    Some((data,sp))
+}
+
+// skip the data sub-blocks starting at sp, returns the position after the terminator
+fn skip_sub_blocks(src: &[u8],mut sp: usize) -> Option<usize> {
+    loop {
+        if sp >= src.len() {
+            return None;
+        }
+        let length = src[sp] as usize;
+        sp += 1;
+        if length == 0 {
+            break;
+        }
+        sp += length;
+    }
+    Some(sp)
+}
+
+// decompress LZW data into count color indices; truncated streams leave the remaining indices at 0
+fn decompress(src: &[u8],min_size: u32,count: usize) -> Option<Vec<u8>> {
+    if (min_size < 1) || (min_size > 11) {
+        return None;
+    }
+    let mut dst = vec![0u8; count];
+    let mut reader = Reader::new(src);
+    let mut prefix = [0u16; MAX_CODES];
+    let mut suffix = [0u8; MAX_CODES];
+    let mut first = [0u8; MAX_CODES];
+    let mut length = [0u16; MAX_CODES];
+    let clear = 1usize << min_size;
+    let end = clear + 1;
+    for i in 0..clear {
+        suffix[i] = i as u8;
+        first[i] = i as u8;
+        length[i] = 1;
+    }
+    let mut size = min_size + 1;
+    let mut next = clear + 2;
+    let mut prev: Option<usize> = None;
+    let mut dp = 0usize;
+    while dp < count {
+        let code = match reader.read_bits(size) {
+            Some(code) => code as usize,
+            None => { break; },
+        };
+        if code == clear {
+            size = min_size + 1;
+            next = clear + 2;
+            prev = None;
+            continue;
+        }
+        if code == end {
+            break;
+        }
+
+        // add new entry to the table, this also covers the KwKwK case where code == next
+        match prev {
+            None => {
+                if code >= clear {
+                    return None;
+                }
+            },
+            Some(prev) => {
+                if code > next {
+                    return None;
+                }
+                if next < MAX_CODES {
+                    prefix[next] = prev as u16;
+                    suffix[next] = if code == next { first[prev] } else { first[code] };
+                    first[next] = first[prev];
+                    length[next] = length[prev] + 1;
+                    next += 1;
+                    if (next == (1 << size)) && (size < 12) {
+                        size += 1;
+                    }
+                }
+                else if code == next {
+                    return None;
+                }
+            },
+        }
+
+        // write string backwards into the output
+        let n = length[code] as usize;
+        let mut c = code;
+        for i in (0..n).rev() {
+            if dp + i < count {
+                dst[dp + i] = suffix[c];
+            }
+            c = prefix[c] as usize;
+        }
+        dp += n;
+        prev = Some(code);
+    }
+    Some(dst)
+}
+
+// map line number to row in the image, for interlaced images
+fn interlaced_row(line: usize,height: usize) -> usize {
+    let pass1 = (height + 7) / 8;
+    let pass2 = (height + 3) / 8;
+    let pass3 = (height + 1) / 4;
+    if line < pass1 {
+        line * 8
+    }
+    else if line < pass1 + pass2 {
+        (line - pass1) * 8 + 4
+    }
+    else if line < pass1 + pass2 + pass3 {
+        (line - pass1 - pass2) * 4 + 2
+    }
+    else {
+        (line - pass1 - pass2 - pass3) * 2 + 1
+    }
+}
+
+fn read_palette<T: pixel::Pixel>(src: &[u8],sp: usize,palette: &mut [T; 256],count: usize) -> Option<usize> {
+    if sp + count * 3 > src.len() {
+        return None;
+    }
+    for i in 0..count {
+        let r = src[sp + i * 3];
+        let g = src[sp + i * 3 + 1];
+        let b = src[sp + i * 3 + 2];
+        palette[i].set(r,g,b,255);
+    }
+    Some(sp + count * 3)
+}
+
+pub fn test(src: &[u8]) -> Result<(u32,u32),ImageError> {
+    if (src.len() < 6) || ((&src[0..6] != b"GIF87a") && (&src[0..6] != b"GIF89a")) {
+        return Err(ImageError::UnknownFormat);
+    }
+    if src.len() < 13 {
+        return Err(ImageError::Corrupt(src.len(),"truncated header"));
+    }
+    let width = from_le16(&src[6..8]) as u32;
+    let height = from_le16(&src[8..10]) as u32;
+    check_dimensions(width as usize,height as usize)?;
+    Ok((width,height))
+}
+
+// walk the blocks without decompressing anything
+pub fn info(src: &[u8]) -> Result<ImageInfo,ImageError> {
+    let (width,height) = test(src)?;
+    let flags = src[10];
+    let global = (flags & 0x80) != 0;
+    let mut info = ImageInfo::new(ImageFormat::Gif,width as usize,height as usize,((flags & 7) + 1) as u32,ChannelLayout::Rgb);
+    info.palette = true;
+    info.frames = 0;
+    let mut sp = 13;
+    if global {
+        sp += 3 * (2 << (flags & 7));
+    }
+    while sp < src.len() {
+        let b = src[sp];
+        sp += 1;
+        match b {
+            0x21 => {  // extension
+                if sp >= src.len() {
+                    return Err(ImageError::Corrupt(sp,"truncated extension"));
+                }
+                let label = src[sp];
+                sp += 1;
+                if (label == 0xF9) && (sp + 5 < src.len()) && (src[sp] >= 4) && ((src[sp + 1] & 1) != 0) {  // graphic control extension with transparency
+                    info.channels = ChannelLayout::Rgba;
+                }
+                else if (label == 0xFF) && (sp + 12 <= src.len()) && (src[sp] == 11) && (&src[sp + 1..sp + 12] == b"ICCRGBG1012") {  // ICC profile extension
+                    let (profile,_) = read_sub_blocks(src,sp + 12).ok_or(ImageError::Corrupt(sp,"truncated extension"))?;
+                    info.icc_profile = Some(profile);
+                }
+                sp = skip_sub_blocks(src,sp).ok_or(ImageError::Corrupt(sp,"truncated extension"))?;
+            },
+            0x2C => {  // image descriptor
+                if sp + 9 > src.len() {
+                    return Err(ImageError::Corrupt(sp,"truncated image descriptor"));
+                }
+                let fflags = src[sp + 8];
+                sp += 9;
+                if info.frames == 0 {
+                    info.interlaced = (fflags & 0x40) != 0;
+                    if !global && ((fflags & 0x80) != 0) {
+                        info.bit_depth = ((fflags & 7) + 1) as u32;
+                    }
+                }
+                if (fflags & 0x80) != 0 {
+                    sp += 3 * (2 << (fflags & 7));
+                }
+                if sp >= src.len() {
+                    return Err(ImageError::Corrupt(src.len(),"truncated image data"));
+                }
+                sp = skip_sub_blocks(src,sp + 1).ok_or(ImageError::Corrupt(sp,"truncated image data"))?;
+                info.frames += 1;
+            },
+            0x3B => {  // trailer
+                break;
+            },
+            _ => {
+                return Err(ImageError::Corrupt(sp - 1,"invalid block"));
+            },
+        }
+    }
+    if info.frames == 0 {
+        return Err(ImageError::Corrupt(sp,"no images"));
+    }
+    Ok(info)
+}
+
+// image as found in the stream
+struct Descriptor {
+    rect: Rect<usize>,
+    interlaced: bool,
+    delay: u32,
+    disposal: Disposal,
+    transparent: Option<usize>,
+}
+
+// walk the blocks and call process for each image, until it returns false; returns the loop count
+fn parse<T: pixel::Pixel,F: FnMut(&Descriptor,&[u8],&[T; 256]) -> bool>(src: &[u8],mut process: F) -> Result<u32,ImageError> {
+    test(src)?;
+    let flags = src[10];
+    let mut sp = 13;
+    let mut global_palette = [T::zero(); 256];
+    if (flags & 0x80) != 0 {
+        sp = read_palette(src,sp,&mut global_palette,2 << (flags & 7)).ok_or(ImageError::Corrupt(sp,"truncated palette"))?;
+    }
+    let mut loops = 1u32;
+    let mut delay = 0u32;
+    let mut disposal = Disposal::None;
+    let mut transparent: Option<usize> = None;
+    let mut found = false;
+    while sp < src.len() {
+        let b = src[sp];
+        sp += 1;
+        match b {
+            0x21 => {  // extension
+                if sp >= src.len() {
+                    return Err(ImageError::Corrupt(sp,"truncated extension"));
+                }
+                let label = src[sp];
+                sp += 1;
+                if (label == 0xF9) && (sp + 5 < src.len()) && (src[sp] >= 4) {  // graphic control extension
+                    let gflags = src[sp + 1];
+                    disposal = match (gflags >> 2) & 7 {
+                        2 => Disposal::Background,
+                        3 => Disposal::Previous,
+                        _ => Disposal::None,
+                    };
+                    delay = (from_le16(&src[sp + 2..sp + 4]) as u32) * 10;
+                    transparent = if (gflags & 1) != 0 { Some(src[sp + 4] as usize) } else { None };
+                }
+                else if (label == 0xFF) && (sp + 16 < src.len()) && (src[sp] == 11) {  // application extension
+                    let id = &src[sp + 1..sp + 12];
+                    if ((id == b"NETSCAPE2.0") || (id == b"ANIMEXTS1.0")) && (src[sp + 12] >= 3) && (src[sp + 13] == 1) {
+                        loops = from_le16(&src[sp + 14..sp + 16]) as u32;
+                    }
+                }
+                sp = skip_sub_blocks(src,sp).ok_or(ImageError::Corrupt(sp,"truncated extension"))?;
+            },
+            0x2C => {  // image descriptor
+                if sp + 9 > src.len() {
+                    return Err(ImageError::Corrupt(sp,"truncated image descriptor"));
+                }
+                let x0 = from_le16(&src[sp..sp + 2]) as usize;
+                let y0 = from_le16(&src[sp + 2..sp + 4]) as usize;
+                let width = from_le16(&src[sp + 4..sp + 6]) as usize;
+                let height = from_le16(&src[sp + 6..sp + 8]) as usize;
+                let fflags = src[sp + 8];
+                sp += 9;
+                let mut palette = global_palette;
+                if (fflags & 0x80) != 0 {
+                    sp = read_palette(src,sp,&mut palette,2 << (fflags & 7)).ok_or(ImageError::Corrupt(sp,"truncated palette"))?;
+                }
+                if sp >= src.len() {
+                    return Err(ImageError::Corrupt(sp,"truncated image data"));
+                }
+                let min_size = src[sp] as u32;
+                sp += 1;
+                let (data,next) = read_sub_blocks(src,sp).ok_or(ImageError::Corrupt(sp,"truncated image data"))?;
+                let count = check_dimensions(width,height)?;
+                let indices = decompress(&data,min_size,count).ok_or(ImageError::Corrupt(sp,"invalid LZW data"))?;
+                sp = next;
+                let descriptor = Descriptor {
+                    rect: rect!(x0,y0,width,height),
+                    interlaced: (fflags & 0x40) != 0,
+                    delay: delay,
+                    disposal: disposal,
+                    transparent: transparent,
+                };
+                found = true;
+                if !process(&descriptor,&indices,&palette) {
+                    break;
+                }
+
+                // graphic control extension only applies to the next image
+                delay = 0;
+                disposal = Disposal::None;
+                transparent = None;
+            },
+            0x3B => {  // trailer
+                break;
+            },
+            _ => {
+                return Err(ImageError::Corrupt(sp - 1,"invalid block"));
+            },
+        }
+    }
+    if found { Ok(loops) } else { Err(ImageError::Corrupt(sp,"no images")) }
+}
+
+// render image into the compositor canvas, transparent pixels leave the canvas untouched
+fn render<T: pixel::Pixel>(compositor: &mut Compositor<T>,descriptor: &Descriptor,indices: &[u8],palette: &[T; 256]) {
+    let size = compositor.canvas.size;
+    let rect = descriptor.rect;
+    compositor.begin(rect,descriptor.disposal);
+    for line in 0..rect.s.y {
+        let y = rect.o.y + if descriptor.interlaced { interlaced_row(line,rect.s.y) } else { line };
+        if y >= size.y {
+            continue;
+        }
+        for x in 0..rect.s.x {
+            if rect.o.x + x >= size.x {
+                break;
+            }
+            let index = indices[line * rect.s.x + x] as usize;
+            if Some(index) != descriptor.transparent {
+                compositor.canvas[(rect.o.x + x,y)] = palette[index];
+            }
+        }
+    }
+    compositor.end(descriptor.delay);
 }
 
-pub fn decode<T: pixel::Pixel>(_src: &[u8]) -> Option<Mat<T>> {
-    None
+pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Result<Mat<T>,ImageError> {
+    let (width,height) = test(src)?;
+    let mut compositor = Compositor::<T>::new(vec2!(width as usize,height as usize));
+    parse(src,|descriptor,indices,palette| {
+        render(&mut compositor,descriptor,indices,palette);
+        false
+    })?;
+    compositor.frames.pop().map(|frame| frame.image).ok_or(ImageError::Corrupt(13,"no images"))
 }
 
-pub fn encode<T: pixel::Pixel>(_src: &Mat<T>) -> Option<Vec<u8>> {
-    None
+pub fn decode_animation<T: pixel::Pixel>(src: &[u8]) -> Result<Animation<T>,ImageError> {
+    let (width,height) = test(src)?;
+    let mut compositor = Compositor::<T>::new(vec2!(width as usize,height as usize));
+    let loops = parse(src,|descriptor,indices,palette| {
+        render(&mut compositor,descriptor,indices,palette);
+        true
+    })?;
+    Ok(compositor.finish(loops))
+}
+
+pub fn encode<T: pixel::Pixel>(_src: &Mat<T>) -> Result<Vec<u8>,ImageError> {
+    Err(ImageError::Unsupported("GIF encoding"))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        pixel::Pixel,
+    };
+
+    // 6x5 interlaced, palette red, green, blue, white with white transparent, index (x + 2 * y) % 4
+    const INTERLACED_6X5: [u8; 56] = [
+        71,73,70,56,57,97,6,0,5,0,241,0,0,255,0,0,0,255,0,0,0,255,255,255,255,33,249,4,1,0,0,3,
+        0,44,0,0,0,0,6,0,5,0,64,2,9,68,52,102,168,151,190,6,115,5,0,59,
+    ];
+
+    #[test]
+    fn decode_interlaced_transparent() {
+        let image = decode::<pixel::RGBA8UN>(&INTERLACED_6X5).unwrap();
+        assert_eq!(image.size,vec2!(6usize,5));
+        let colors = [(255,0,0,255),(0,255,0,255),(0,0,255,255),(0,0,0,0)];
+        for y in 0..5 {
+            for x in 0..6 {
+                let (r,g,b,a) = image[(x,y)].get();
+                let expected = colors[(x + 2 * y) % 4];
+                if expected.3 == 0 {
+                    assert_eq!(a,0);
+                }
+                else {
+                    assert_eq!((r,g,b,a),expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decode_truncated() {
+        for length in 0..INTERLACED_6X5.len() - 1 {
+            let _ = decode::<pixel::RGBA8UN>(&INTERLACED_6X5[0..length]);
+        }
+    }
+
+    #[test]
+    fn decode_oversized() {
+        // 65535x65535 canvas
+        let src: [u8; 29] = [71,73,70,56,57,97,255,255,255,255,0,0,0,44,0,0,0,0,255,255,255,255,0,2,2,76,1,0,59];
+        assert!(matches!(decode::<pixel::RGBA8UN>(&src),Err(ImageError::Dimensions(65535,65535))));
+
+        // 65535x65535 image on a 1x1 canvas
+        let mut src = src;
+        src[6..10].copy_from_slice(&[1,0,1,0]);
+        assert!(matches!(decode::<pixel::RGBA8UN>(&src),Err(ImageError::Dimensions(65535,65535))));
+    }
 }
@@ -0,0 +1,454 @@
+// E - image - HDR
+// Desmond Germans, 2020
+
+//! Radiance HDR (RGBE)
+
+use crate::*;
+
+struct Header {
+    width: usize,
+    height: usize,
+    transposed: bool,  // scanlines are columns
+    flip_x: bool,
+    flip_y: bool,
+    exposure: f32,
+    sp: usize,  // start of the pixel data
+}
+
+fn parse_header(src: &[u8]) -> Result<Header,ImageError> {
+    if !src.starts_with(b"#?") {
+        return Err(ImageError::UnknownFormat);
+    }
+
+    // variables, up to an empty line
+    let mut sp = 0;
+    let mut exposure = 1.0;
+    let mut first = true;
+    loop {
+        let start = sp;
+        while (sp < src.len()) && (src[sp] != b'\n') {
+            sp += 1;
+        }
+        if sp >= src.len() {
+            return Err(ImageError::Corrupt(start,"missing resolution"));
+        }
+        let line = String::from_utf8_lossy(&src[start..sp]);
+        let line = line.trim();
+        sp += 1;
+        if first {
+            first = false;
+            continue;
+        }
+        if line.len() == 0 {
+            break;
+        }
+        if let Some(format) = line.strip_prefix("FORMAT=") {
+            if format.trim() != "32-bit_rle_rgbe" {
+                return Err(ImageError::Unsupported("HDR format"));
+            }
+        }
+        else if let Some(value) = line.strip_prefix("EXPOSURE=") {
+            // exposures accumulate
+            let value = value.trim().parse::<f32>().map_err(|_| ImageError::Corrupt(start,"invalid EXPOSURE"))?;
+            if value > 0.0 {
+                exposure *= value;
+            }
+        }
+    }
+
+    // resolution string, like -Y 512 +X 768
+    let start = sp;
+    while (sp < src.len()) && (src[sp] != b'\n') {
+        sp += 1;
+    }
+    if sp >= src.len() {
+        return Err(ImageError::Corrupt(start,"missing resolution"));
+    }
+    let invalid = || ImageError::Corrupt(start,"invalid resolution");
+    let line = String::from_utf8_lossy(&src[start..sp]);
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if words.len() != 4 {
+        return Err(invalid());
+    }
+    let first = words[1].parse::<usize>().map_err(|_| invalid())?;
+    let second = words[3].parse::<usize>().map_err(|_| invalid())?;
+    let (transposed,flip_x,flip_y,width,height) = match (words[0],words[2]) {
+        ("-Y","+X") => (false,false,false,second,first),
+        ("-Y","-X") => (false,true,false,second,first),
+        ("+Y","+X") => (false,false,true,second,first),
+        ("+Y","-X") => (false,true,true,second,first),
+        ("+X","-Y") => (true,false,false,first,second),
+        ("-X","-Y") => (true,true,false,first,second),
+        ("+X","+Y") => (true,false,true,first,second),
+        ("-X","+Y") => (true,true,true,first,second),
+        _ => { return Err(invalid()); },
+    };
+    if (width == 0) || (height == 0) {
+        return Err(ImageError::Dimensions(width,height));
+    }
+    Ok(Header {
+        width: width,
+        height: height,
+        transposed: transposed,
+        flip_x: flip_x,
+        flip_y: flip_y,
+        exposure: exposure,
+        sp: sp + 1,
+    })
+}
+
+// one scanline of RGBE quadruples, flat or run length encoded
+fn read_scanline(src: &[u8],sp: &mut usize,line: &mut [[u8; 4]]) -> Result<(),ImageError> {
+    let length = line.len();
+    if *sp + 4 > src.len() {
+        return Err(ImageError::Corrupt(*sp,"truncated pixel data"));
+    }
+
+    // new style: 2, 2, length, then each component separately
+    if (length >= 8) && (length < 0x8000) && (src[*sp] == 2) && (src[*sp + 1] == 2) && (src[*sp + 2] & 0x80 == 0) {
+        if (((src[*sp + 2] as usize) << 8) | (src[*sp + 3] as usize)) != length {
+            return Err(ImageError::Corrupt(*sp,"scanline length mismatch"));
+        }
+        *sp += 4;
+        for c in 0..4 {
+            let mut x = 0;
+            while x < length {
+                if *sp >= src.len() {
+                    return Err(ImageError::Corrupt(*sp,"truncated pixel data"));
+                }
+                let count = src[*sp] as usize;
+                *sp += 1;
+                if count > 128 {
+                    let count = count - 128;
+                    if (x + count > length) || (*sp >= src.len()) {
+                        return Err(ImageError::Corrupt(*sp,"invalid run"));
+                    }
+                    for p in line[x..x + count].iter_mut() {
+                        p[c] = src[*sp];
+                    }
+                    *sp += 1;
+                    x += count;
+                }
+                else {
+                    if (count == 0) || (x + count > length) || (*sp + count > src.len()) {
+                        return Err(ImageError::Corrupt(*sp,"invalid run"));
+                    }
+                    for (p,&b) in line[x..x + count].iter_mut().zip(src[*sp..*sp + count].iter()) {
+                        p[c] = b;
+                    }
+                    *sp += count;
+                    x += count;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    // flat, with old style runs of 1, 1, 1, count repeating the previous pixel
+    let mut x = 0;
+    let mut shift = 0;
+    while x < length {
+        if *sp + 4 > src.len() {
+            return Err(ImageError::Corrupt(*sp,"truncated pixel data"));
+        }
+        let p = [src[*sp],src[*sp + 1],src[*sp + 2],src[*sp + 3]];
+        *sp += 4;
+        if (p[0] == 1) && (p[1] == 1) && (p[2] == 1) {
+            if (x == 0) || (shift > 24) {
+                return Err(ImageError::Corrupt(*sp - 4,"invalid run"));
+            }
+            let count = (p[3] as usize) << shift;
+            if x + count > length {
+                return Err(ImageError::Corrupt(*sp - 4,"invalid run"));
+            }
+            let previous = line[x - 1];
+            for q in line[x..x + count].iter_mut() {
+                *q = previous;
+            }
+            x += count;
+            shift += 8;
+        }
+        else {
+            line[x] = p;
+            x += 1;
+            shift = 0;
+        }
+    }
+    Ok(())
+}
+
+fn rgbe_to_float(p: [u8; 4]) -> (f32,f32,f32) {
+    if p[3] == 0 {
+        return (0.0,0.0,0.0);
+    }
+    let f = 2.0f32.powi(p[3] as i32 - 136);
+    (((p[0] as f32) + 0.5) * f,((p[1] as f32) + 0.5) * f,((p[2] as f32) + 0.5) * f)
+}
+
+fn float_to_rgbe(r: f32,g: f32,b: f32) -> [u8; 4] {
+    let r = if r > 0.0 { r } else { 0.0 };
+    let g = if g > 0.0 { g } else { 0.0 };
+    let b = if b > 0.0 { b } else { 0.0 };
+    let max = r.max(g).max(b);
+    if !(max >= 1e-32) || !max.is_finite() {
+        return [0,0,0,0];
+    }
+
+    // max = m * 2^e with m in [0.5,1)
+    let mut e = max.log2().floor() as i32 + 1;
+    if max / 2.0f32.powi(e) >= 1.0 {
+        e += 1;
+    }
+    else if max / 2.0f32.powi(e) < 0.5 {
+        e -= 1;
+    }
+    if e > 127 {
+        return [255,255,255,255];
+    }
+    let scale = 256.0 / 2.0f32.powi(e);
+    [(r * scale) as u8,(g * scale) as u8,(b * scale) as u8,(e + 128) as u8]
+}
+
+pub fn test(src: &[u8]) -> Result<(u32,u32),ImageError> {
+    let header = parse_header(src)?;
+    Ok((header.width as u32,header.height as u32))
+}
+
+pub fn info(src: &[u8]) -> Result<ImageInfo,ImageError> {
+    let header = parse_header(src)?;
+    Ok(ImageInfo::new(ImageFormat::Hdr,header.width,header.height,32,ChannelLayout::Rgb))
+}
+
+pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Result<Mat<T>,ImageError> {
+    let header = parse_header(src)?;
+    let width = header.width;
+    let height = header.height;
+
+    let pixels = check_dimensions(width,height)?;
+
+    // runs of 127 in two bytes per component is the best the encoding does
+    if pixels > (src.len() - header.sp).saturating_mul(32) {
+        return Err(ImageError::Corrupt(src.len(),"truncated pixel data"));
+    }
+    let (count,length) = if header.transposed { (width,height) } else { (height,width) };
+    let scale = 1.0 / header.exposure;  // undo the exposure, so the pixels are radiance again
+    let mut image = Mat::<T>::new(vec2!(width,height));
+    let mut line: Vec<[u8; 4]> = vec![[0; 4]; length];
+    let mut sp = header.sp;
+    for s in 0..count {
+        read_scanline(src,&mut sp,&mut line)?;
+        for (i,p) in line.iter().enumerate() {
+            let (x,y) = if header.transposed { (s,i) } else { (i,s) };
+            let x = if header.flip_x { width - 1 - x } else { x };
+            let y = if header.flip_y { height - 1 - y } else { y };
+            let (r,g,b) = rgbe_to_float(*p);
+            image[(x,y)].setf(r * scale,g * scale,b * scale,1.0);
+        }
+    }
+    Ok(image)
+}
+
+/// Radiance HDR encoder settings.
+#[derive(Copy,Clone,Debug)]
+pub struct EncodeOptions {
+    /// Compress the scanlines with new style run length encoding.
+    pub rle: bool,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> EncodeOptions {
+        EncodeOptions {
+            rle: true,
+        }
+    }
+}
+
+// one component of a scanline, as runs and literal spans
+fn encode_component(dst: &mut Vec<u8>,data: &[u8]) {
+    let mut x = 0;
+    while x < data.len() {
+        // find the next run of at least 4
+        let mut start = x;
+        let mut run = 0;
+        while start < data.len() {
+            run = 1;
+            while (start + run < data.len()) && (run < 127) && (data[start + run] == data[start]) {
+                run += 1;
+            }
+            if run >= 4 {
+                break;
+            }
+            start += run;
+        }
+
+        // literals up to the run
+        while x < start {
+            let count = if start - x > 128 { 128 } else { start - x };
+            dst.push(count as u8);
+            dst.extend_from_slice(&data[x..x + count]);
+            x += count;
+        }
+        if (start < data.len()) && (run >= 4) {
+            dst.push((128 + run) as u8);
+            dst.push(data[start]);
+            x = start + run;
+        }
+    }
+}
+
+pub fn encode<T: pixel::Pixel>(image: &Mat<T>) -> Result<Vec<u8>,ImageError> {
+    encode_with_options(image,&EncodeOptions::default())
+}
+
+pub fn encode_with_options<T: pixel::Pixel>(image: &Mat<T>,options: &EncodeOptions) -> Result<Vec<u8>,ImageError> {
+    let width = image.size.x;
+    let height = image.size.y;
+    if (width == 0) || (height == 0) {
+        return Err(ImageError::Dimensions(width,height));
+    }
+    let mut dst: Vec<u8> = Vec::new();
+    dst.extend_from_slice(format!("#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",height,width).as_bytes());
+
+    // new style runs only fit scanlines of 8 to 32767 pixels
+    let rle = options.rle && (width >= 8) && (width < 0x8000);
+    let mut components: Vec<u8> = vec![0; width];
+    for y in 0..height {
+        let line: Vec<[u8; 4]> = (0..width).map(|x| {
+            let (r,g,b,_) = image[(x,y)].getf();
+            float_to_rgbe(r,g,b)
+        }).collect();
+        if rle {
+            dst.extend_from_slice(&[2,2,(width >> 8) as u8,(width & 255) as u8]);
+            for c in 0..4 {
+                for (d,p) in components.iter_mut().zip(line.iter()) {
+                    *d = p[c];
+                }
+                encode_component(&mut dst,&components);
+            }
+        }
+        else {
+            for p in line.iter() {
+                dst.extend_from_slice(p);
+            }
+        }
+    }
+    Ok(dst)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        pixel::Pixel,
+    };
+
+    // exactly representable radiance of the test pattern
+    fn rgbe(x: usize,y: usize) -> [u8; 4] {
+        [(x * 40 + 8) as u8,(y * 60 + 8) as u8,100,(129 + (x & 1)) as u8]
+    }
+
+    // flat scanlines in the order given by the resolution string
+    fn build(extra: &str,resolution: &str,order: &[(usize,usize)]) -> Vec<u8> {
+        let mut src: Vec<u8> = format!("#?RADIANCE\n{}FORMAT=32-bit_rle_rgbe\n\n{}\n",extra,resolution).into_bytes();
+        for &(x,y) in order.iter() {
+            src.extend_from_slice(&rgbe(x,y));
+        }
+        src
+    }
+
+    fn check(image: &Mat<pixel::RGBA32F>,scale: f32) {
+        for y in 0..image.size.y {
+            for x in 0..image.size.x {
+                let (r,g,b) = rgbe_to_float(rgbe(x,y));
+                assert_eq!(image[(x,y)].getf(),(r * scale,g * scale,b * scale,1.0),"({},{})",x,y);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_orientations() {
+        let rows: Vec<(usize,usize)> = (0..2).flat_map(|y| (0..3).map(move |x| (x,y))).collect();
+        let flipped: Vec<(usize,usize)> = (0..2).rev().flat_map(|y| (0..3).rev().map(move |x| (x,y))).collect();
+        let columns: Vec<(usize,usize)> = (0..3).flat_map(|x| (0..2).map(move |y| (x,y))).collect();
+        let columns_flipped: Vec<(usize,usize)> = (0..3).rev().flat_map(|x| (0..2).rev().map(move |y| (x,y))).collect();
+        for (resolution,order) in [("-Y 2 +X 3",&rows),("+Y 2 -X 3",&flipped),("+X 3 -Y 2",&columns),("-X 3 +Y 2",&columns_flipped)] {
+            let image = decode::<pixel::RGBA32F>(&build("",resolution,order)).unwrap();
+            assert_eq!(image.size,vec2!(3usize,2),"{}",resolution);
+            check(&image,1.0);
+        }
+    }
+
+    #[test]
+    fn decode_exposure() {
+        let rows: Vec<(usize,usize)> = (0..2).flat_map(|y| (0..3).map(move |x| (x,y))).collect();
+        let image = decode::<pixel::RGBA32F>(&build("EXPOSURE=2\nEXPOSURE=  4.0\n","-Y 2 +X 3",&rows)).unwrap();
+        check(&image,0.125);
+    }
+
+    #[test]
+    fn decode_old_rle() {
+        // one pixel, repeated 43 times, then 256 more times by a second repeat that shifts the count
+        let mut src: Vec<u8> = b"#?RADIANCE\n\n-Y 2 +X 300\n".to_vec();
+        for _ in 0..2 {
+            src.extend_from_slice(&[128,64,32,129,1,1,1,43,1,1,1,1]);
+        }
+        let image = decode::<pixel::RGBA32F>(&src).unwrap();
+        let expected = rgbe_to_float([128,64,32,129]);
+        for p in image.data().iter() {
+            assert_eq!(p.getf(),(expected.0,expected.1,expected.2,1.0));
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut image = Mat::<pixel::RGBA32F>::new(vec2!(13usize,7));
+        for y in 0..7 {
+            for x in 0..13 {
+                image[(x,y)].setf((x as f32) * 0.5 + 0.01,(y as f32) * 3.0 + 0.02,((x + y) as f32) * 0.001 + 0.003,1.0);
+            }
+        }
+        for rle in [false,true] {
+            let data = encode_with_options(&image,&EncodeOptions { rle: rle, }).unwrap();
+            let result = decode::<pixel::RGBA32F>(&data).unwrap();
+            assert_eq!(result.size,image.size);
+            for (s,d) in image.data().iter().zip(result.data().iter()) {
+                let (r0,g0,b0,_) = s.getf();
+                let (r1,g1,b1,_) = d.getf();
+
+                // 8 bits of mantissa, shared by the largest component
+                let tolerance = r0.max(g0).max(b0) / 128.0;
+                assert!((r0 - r1).abs() <= tolerance);
+                assert!((g0 - g1).abs() <= tolerance);
+                assert!((b0 - b1).abs() <= tolerance);
+            }
+
+            // decoded values are exact in RGBE, so a second round trip is lossless
+            let again = decode::<pixel::RGBA32F>(&encode_with_options(&result,&EncodeOptions { rle: rle, }).unwrap()).unwrap();
+            assert!(result.data().iter().zip(again.data().iter()).all(|(a,b)| a.getf() == b.getf()));
+        }
+    }
+
+    #[test]
+    fn registry_keeps_range() {
+        let mut image = Mat::<pixel::RGBA32F>::new(vec2!(9usize,2));
+        for (i,p) in image.data_mut().iter_mut().enumerate() {
+            let v = 2.0f32.powi(i as i32 - 4);
+            p.setf(v,v * 0.5,v * 0.25,1.0);
+        }
+        let data = registry().encode("hdr",&image).unwrap();
+        assert_eq!(data,encode(&image).unwrap());
+        let result = registry().decode::<pixel::RGBA32F>(&data).unwrap();
+        for (s,d) in image.data().iter().zip(result.data().iter()) {
+            let (r0,g0,b0,_) = s.getf();
+            let (r1,g1,b1,_) = d.getf();
+            assert!((r0 - r1).abs() <= r0 / 128.0);
+            assert!((g0 - g1).abs() <= r0 / 128.0);
+            assert!((b0 - b1).abs() <= r0 / 128.0);
+        }
+        assert!(result.data().iter().any(|p| p.getf().0 > 100.0));
+
+        // integer formats still clamp
+        let clamped = registry().decode::<pixel::RGBA16UN>(&data).unwrap();
+        assert_eq!(clamped[(8,0)].get16().0,65535);
+    }
+}
@@ -0,0 +1,173 @@
+// E - image - Info
+// Desmond Germans, 2020
+
+use crate::*;
+
+/// Image file format.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum ImageFormat {
+    /// Windows or OS/2 bitmap.
+    Bmp,
+    /// Portable Network Graphics, including APNG.
+    Png,
+    /// JPEG/JFIF.
+    Jpeg,
+    /// Graphics Interchange Format.
+    Gif,
+    /// Tagged Image File Format.
+    Tiff,
+    /// Netpbm (PBM, PGM, PPM, PAM).
+    Pbm,
+    /// X11 bitmap.
+    Xbm,
+    /// WebP, lossy or lossless.
+    Webp,
+    /// Truevision TGA.
+    Tga,
+    /// Radiance HDR (RGBE).
+    Hdr,
+    /// Format of a codec added by the application, by name.
+    Other(&'static str),
+}
+
+impl ImageFormat {
+    /// Name of the codec that handles the format in the registry.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ImageFormat::Bmp => "bmp",
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpeg",
+            ImageFormat::Gif => "gif",
+            ImageFormat::Tiff => "tiff",
+            ImageFormat::Pbm => "pbm",
+            ImageFormat::Xbm => "xbm",
+            ImageFormat::Webp => "webp",
+            ImageFormat::Tga => "tga",
+            ImageFormat::Hdr => "hdr",
+            ImageFormat::Other(name) => name,
+        }
+    }
+
+    /// File extensions of the format, lowercase and without the dot.
+    pub fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            ImageFormat::Bmp => &["bmp","dib"],
+            ImageFormat::Png => &["png","apng"],
+            ImageFormat::Jpeg => &["jpg","jpeg","jpe","jfif"],
+            ImageFormat::Gif => &["gif"],
+            ImageFormat::Tiff => &["tif","tiff"],
+            ImageFormat::Pbm => &["pbm","pgm","ppm","pnm","pam"],
+            ImageFormat::Xbm => &["xbm"],
+            ImageFormat::Webp => &["webp"],
+            ImageFormat::Tga => &["tga","tpic"],
+            ImageFormat::Hdr => &["hdr","rgbe","pic"],
+            ImageFormat::Other(_) => &[],
+        }
+    }
+
+    /// Find the built-in format for a file extension.
+    /// # Arguments
+    /// * `extension` - The extension, with or without the dot, in any case.
+    /// # Returns
+    /// * `None` - No built-in format uses this extension.
+    /// * `Some(format)` - The format.
+    pub fn from_extension(extension: &str) -> Option<ImageFormat> {
+        let extension = extension.trim_start_matches('.').to_lowercase();
+        let formats = [
+            ImageFormat::Bmp,
+            ImageFormat::Png,
+            ImageFormat::Jpeg,
+            ImageFormat::Gif,
+            ImageFormat::Tiff,
+            ImageFormat::Pbm,
+            ImageFormat::Xbm,
+            ImageFormat::Webp,
+            ImageFormat::Tga,
+            ImageFormat::Hdr,
+        ];
+        formats.iter().find(|format| format.extensions().contains(&extension.as_str())).cloned()
+    }
+}
+
+/// Channels as they are stored in the file.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum ChannelLayout {
+    /// Single gray level.
+    Gray,
+    /// Gray level and alpha.
+    GrayAlpha,
+    /// Red, green and blue.
+    Rgb,
+    /// Red, green, blue and alpha.
+    Rgba,
+    /// Cyan, magenta, yellow and black.
+    Cmyk,
+}
+
+/// CIE 1931 xy chromaticity coordinates of the white point and the primaries.
+#[derive(Copy,Clone,Debug)]
+pub struct Chromaticities {
+    /// White point.
+    pub white: Vec2<f32>,
+    /// Red primary.
+    pub red: Vec2<f32>,
+    /// Green primary.
+    pub green: Vec2<f32>,
+    /// Blue primary.
+    pub blue: Vec2<f32>,
+}
+
+/// Everything that can be learned about an image without decoding the pixels.
+#[derive(Clone,Debug)]
+pub struct ImageInfo {
+    /// File format.
+    pub format: ImageFormat,
+    /// Size of the image (or the canvas, for animations).
+    pub size: Vec2<usize>,
+    /// Bits per channel, or bits per index for palette images.
+    pub bit_depth: u32,
+    /// Stored channels. For palette images, the channels of the palette entries.
+    pub channels: ChannelLayout,
+    /// The pixels are indices into a palette.
+    pub palette: bool,
+    /// The rows are stored interlaced (PNG Adam7, GIF).
+    pub interlaced: bool,
+    /// The image is stored progressively (JPEG).
+    pub progressive: bool,
+    /// The image declares the sRGB color space (PNG sRGB).
+    pub srgb: bool,
+    /// Encoding gamma (PNG gAMA).
+    pub gamma: Option<f32>,
+    /// White point and primaries (PNG cHRM).
+    pub chromaticities: Option<Chromaticities>,
+    /// Embedded ICC profile.
+    pub icc_profile: Option<Vec<u8>>,
+    /// Horizontal and vertical resolution in dots per inch.
+    pub dpi: Option<Vec2<f32>>,
+    /// Background color to show the image on, as 16-bit red, green and blue (PNG bKGD).
+    pub background: Option<(u16,u16,u16)>,
+    /// Number of frames or pages.
+    pub frames: usize,
+}
+
+impl ImageInfo {
+    // info for a single frame image without metadata
+    pub(crate) fn new(format: ImageFormat,width: usize,height: usize,bit_depth: u32,channels: ChannelLayout) -> ImageInfo {
+        ImageInfo {
+            format: format,
+            size: vec2!(width,height),
+            bit_depth: bit_depth,
+            channels: channels,
+            palette: false,
+            interlaced: false,
+            progressive: false,
+            srgb: false,
+            gamma: None,
+            chromaticities: None,
+            icc_profile: None,
+            dpi: None,
+            background: None,
+            frames: 1,
+        }
+    }
+}
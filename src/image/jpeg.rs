@@ -3,9 +3,15 @@
 
 //! JPEG
 
-use crate::*;
+use {
+	crate::*,
+	std::{
+		io::Read,
+		mem,
+	},
+};
 
-#[derive(Copy,Clone)]
+#[derive(Copy,Clone,PartialEq)]
 enum Type {
 	Y,
 	YUV420,
@@ -13,16 +19,20 @@ enum Type {
 	YUV440,
 	YUV444,
 	RGB444,
+	CMYK444,
+	YCCK444,
+	YCCK420,
 }
 
-fn type_order(t: Type) -> u16 {
+// component of each block in a macroblock
+fn type_order(t: Type) -> &'static [usize] {
 	match t {
-		Type::Y => 0x000C,
-		Type::YUV420 => 0x3900,
-		Type::YUV422 => 0x0390,
-		Type::YUV440 => 0x1390,
-		Type::YUV444 => 0x00E4,
-		Type::RGB444 => 0x01E4,
+		Type::Y => &[0],
+		Type::YUV420 => &[0,0,0,0,1,2],
+		Type::YUV422 | Type::YUV440 => &[0,0,1,2],
+		Type::YUV444 | Type::RGB444 => &[0,1,2],
+		Type::CMYK444 | Type::YCCK444 => &[0,1,2,3],
+		Type::YCCK420 => &[0,0,0,0,1,2,3,3,3,3],
 	}
 }
 
@@ -65,14 +75,6 @@ const C0S: i32 = C0 >> 1;
 const C6PC2: i32 = C6 + C2;
 const C6MC2: i32 = C6 - C2;
 
-fn from_le16(src: &[u8]) -> u16 {
-    ((src[1] as u16) << 8) | (src[0] as u16)
-}
-
-fn from_le32(src: &[u8]) -> u32 {
-    ((src[3] as u32) << 24) | ((src[2] as u32) << 16) | ((src[1] as u32) << 8) | (src[0] as u32)
-}
-
 fn from_be16(src: &[u8]) -> u16 {
     ((src[0] as u16) << 8) | (src[1] as u16)
 }
@@ -151,25 +153,24 @@ struct Reader<'a> {
 
 impl<'a> Reader<'a> {
 	pub fn new(block: &'a [u8]) -> Reader<'a> {
-		let mut rp = 0usize;
-		let mut bit = 0u32;
-		let mut cache = 0u32;
-		while bit <= 24 {
-			let b = jpeg_get8(block,&mut rp);
-			cache |= (b as u32) << (24 - bit);
-			bit += 8;
-		}
-		Reader {
+		let mut reader = Reader {
 			block: block,
-			rp: rp,
-			bit: bit,
-			cache: cache,
-		}
+			rp: 0,
+			bit: 0,
+			cache: 0,
+		};
+		reader.restock();
+		reader
+	}
+
+	// stop in front of a marker, and fill with 0 from there
+	fn at_marker(&self) -> bool {
+		(self.rp >= self.block.len()) || ((self.block[self.rp] == 0xFF) && ((self.rp + 1 >= self.block.len()) || (self.block[self.rp + 1] != 0x00)))
 	}
 
 	fn restock(&mut self) {
 		while self.bit <= 24 {
-			let b = if self.rp >= self.block.len() { 0 } else { jpeg_get8(self.block,&mut self.rp) };  // fill with 0 if at end of block
+			let b = if self.at_marker() { 0 } else { jpeg_get8(self.block,&mut self.rp) };
 			self.cache |= (b as u32) << (24 - self.bit);
 			self.bit += 8;
 		}
@@ -217,35 +218,19 @@ impl<'a> Reader<'a> {
 	}
 
 	pub fn leave(&mut self) -> usize {
-		//println!("leave: bit = {}, rp = {}",self.bit,self.rp);
-		// superspecial case: no JPEG data read at all (only during initial programming)
-		if (self.bit == 32) && (self.rp == 4) {
-			return 0;
-		}
-		/*// first search FFD9 to elimiate stray FFs past end of buffer
-		if (self.block[self.rp - 5] == 0xFF) && (self.block[self.rp - 4] == 0xD9) {
-			return self.rp - 5;
-		}
-		if (self.block[self.rp - 4] == 0xFF) && (self.block[self.rp - 3] == 0xD9) {
-			return self.rp - 4;
-		}
-		if (self.block[self.rp - 3] == 0xFF) && (self.block[self.rp - 2] == 0xD9) {
-			return self.rp - 3;
-		}
-		if (self.block[self.rp - 2] == 0xFF) && (self.block[self.rp - 1] == 0xD9) {
-			return self.rp - 2;
-		}
-		if (self.block[self.rp - 1] == 0xFF) && (self.block[self.rp] == 0xD9) {
-			return self.rp - 1;
-		}*/
-		// anything else
-		for _i in 0..((self.bit + 7) / 8) - 2 {
-			if (self.block[self.rp - 1] == 0x00) && (self.block[self.rp - 2] == 0xFF) {
+		if self.at_marker() {
+			return self.rp;
+		}
+		// not at a marker yet, give back the whole bytes still in the cache
+		for _i in 0..self.bit / 8 {
+			if self.rp == 0 {  // corrupt data read more bits than there were
+				break;
+			}
+			if (self.rp >= 2) && (self.block[self.rp - 1] == 0x00) && (self.block[self.rp - 2] == 0xFF) {
 				self.rp -= 1;
 			}
 			self.rp -= 1;
 		}
-		//println!("and leaving with rp = {} ({:02X} {:02X})",self.rp,self.block[self.rp],self.block[self.rp + 1]);
 		self.rp
 	}
 }
@@ -267,6 +252,9 @@ fn unpack_sequential(reader: &mut Reader,coeffs: &mut [i32],dcht: &Table,acht: &
 			let code = reader.getn(cat as usize);
 			let coeff = make_coeff(cat,code as isize) as i32;
 			i += run;
+			if i >= 64 {
+				break;
+			}
 			coeffs[FOLDING[i as usize] as usize] = coeff;
 			//println!("coeffs[{}] = {}",i,coeff);
 		}
@@ -308,6 +296,9 @@ fn unpack_progressive_start_ac(reader: &mut Reader,coeffs: &mut[i32],acht: &Tabl
 				let code = reader.getn(cat as usize);
 				let coeff = make_coeff(cat,code as isize);
 				i += run;
+				if i > end {
+					break;
+				}
 				coeffs[FOLDING[i as usize] as usize] = (coeff << shift) as i32;
 			}
 			else {
@@ -373,11 +364,14 @@ fn unpack_progressive_refine_ac(reader: &mut Reader,coeffs: &mut[i32],acht: &Tab
 			if cat != 0 {
 				let sb = reader.get1();
 				i = update_nonzeros(reader,&mut coeffs[0..64],i,end,shift,run);
+				if i > end {
+					break;
+				}
 				if sb {
 					coeffs[FOLDING[i as usize] as usize] = 1 << shift;
 				}
 				else {
-					coeffs[FOLDING[i as usize] as usize] = 11 << shift;
+					coeffs[FOLDING[i as usize] as usize] = -1 << shift;
 				}
 			}
 			else {
@@ -394,6 +388,7 @@ fn unpack_progressive_refine_ac(reader: &mut Reader,coeffs: &mut[i32],acht: &Tab
 					break;
 				}
 			}
+			i += 1;
 		}
 	}
 }
@@ -466,17 +461,50 @@ fn unpack_macroblock(reader: &mut Reader,coeffs: &mut [i32],dcht: &[Table],acht:
 				unpack_block(reader,&mut coeffs[128..192],&dcht[dt[2]],&acht[at[2]],&mut dc[2],start,end,shift,refine,eobrun);
 			}
 		},
+		Type::CMYK444 | Type::YCCK444 => {
+			for c in 0..4 {
+				if (mask & (1 << c)) != 0 {
+					unpack_block(reader,&mut coeffs[c * 64..c * 64 + 64],&dcht[dt[c]],&acht[at[c]],&mut dc[c],start,end,shift,refine,eobrun);
+				}
+			}
+		},
+		Type::YCCK420 => {
+			if (mask & 1) != 0 {
+				unpack_block(reader,&mut coeffs[0..64],&dcht[dt[0]],&acht[at[0]],&mut dc[0],start,end,shift,refine,eobrun);
+				unpack_block(reader,&mut coeffs[64..128],&dcht[dt[0]],&acht[at[0]],&mut dc[0],start,end,shift,refine,eobrun);
+				unpack_block(reader,&mut coeffs[128..192],&dcht[dt[0]],&acht[at[0]],&mut dc[0],start,end,shift,refine,eobrun);
+				unpack_block(reader,&mut coeffs[192..256],&dcht[dt[0]],&acht[at[0]],&mut dc[0],start,end,shift,refine,eobrun);
+			}
+			if (mask & 2) != 0 {
+				unpack_block(reader,&mut coeffs[256..320],&dcht[dt[1]],&acht[at[1]],&mut dc[1],start,end,shift,refine,eobrun);
+			}
+			if (mask & 4) != 0 {
+				unpack_block(reader,&mut coeffs[320..384],&dcht[dt[2]],&acht[at[2]],&mut dc[2],start,end,shift,refine,eobrun);
+			}
+			if (mask & 8) != 0 {
+				unpack_block(reader,&mut coeffs[384..448],&dcht[dt[3]],&acht[at[3]],&mut dc[3],start,end,shift,refine,eobrun);
+				unpack_block(reader,&mut coeffs[448..512],&dcht[dt[3]],&acht[at[3]],&mut dc[3],start,end,shift,refine,eobrun);
+				unpack_block(reader,&mut coeffs[512..576],&dcht[dt[3]],&acht[at[3]],&mut dc[3],start,end,shift,refine,eobrun);
+				unpack_block(reader,&mut coeffs[576..640],&dcht[dt[3]],&acht[at[3]],&mut dc[3],start,end,shift,refine,eobrun);
+			}
+		},
 	}
+	restart(reader,dc,rescnt,resint);
+}
+
+// count down to the next restart marker and skip it
+fn restart(reader: &mut Reader,dc: &mut [i32],rescnt: &mut usize,resint: usize) {
 	if resint != 0 {
 		*rescnt -= 1;
 		if *rescnt == 0 {
+			// the interval restarts even if the marker is missing, like the encoder did
 			let mut tsp = reader.leave();
-			if (reader.block[tsp] == 0xFF) && ((reader.block[tsp + 1] >= 0xD0) && (reader.block[tsp + 1] < 0xD8)) {
+			if (tsp + 1 < reader.block.len()) && (reader.block[tsp] == 0xFF) && ((reader.block[tsp + 1] >= 0xD0) && (reader.block[tsp + 1] < 0xD8)) {
 				tsp += 2;
-				*rescnt = resint;
-				dc[0] = 0;
-				dc[1] = 0;
-				dc[2] = 0;
+			}
+			*rescnt = resint;
+			for c in dc.iter_mut() {
+				*c = 0;
 			}
 			reader.enter(tsp);
 		}
@@ -689,29 +717,28 @@ fn convert_block(block: &mut [i32],qtable: &[i32]) {
 	unswizzle_transpose(block,&temp3);
 }
 
-fn convert_blocks(coeffs: &mut [i32],count: usize,itype: Type,qtable: &[[i32; 64]],qt: &[usize; 3]) {
-	let pattern = type_order(itype);
-	let mut curp = pattern;
+fn convert_blocks(coeffs: &mut [i32],count: usize,itype: Type,qtable: &[[i32; 64]],qt: &[usize; 4]) {
+	let order = type_order(itype);
 	for i in 0..count {
-		if (curp & 3) == 3 {
-			curp = pattern;
-		}
-		convert_block(&mut coeffs[i * 64..i * 64 + 64],&qtable[qt[(curp & 3) as usize]]);
-		curp >>= 2;
+		convert_block(&mut coeffs[i * 64..i * 64 + 64],&qtable[qt[order[i % order.len()]]]);
 	}
 }
 
+// scale 8.8 fixed point to 16 bits
+fn to16(v: i32) -> u16 {
+	let v = if v < 0 { 0 } else { if v > 0xFF00 { 0xFF00 } else { v } };
+	(v + (v >> 8)) as u16
+}
+
+// r, g and b are 8.8 fixed point
 fn draw_rgb<T: pixel::Pixel>(image: &mut Mat<T>,px: usize,py: usize,r: i32,g: i32,b: i32) {
-	let r = if r < 0 { 0 } else { if r > 255 { 255 } else { r as u8 } };
-	let g = if g < 0 { 0 } else { if g > 255 { 255 } else { g as u8 } };
-	let b = if b < 0 { 0 } else { if b > 255 { 255 } else { b as u8 } };
-	image[(px,py)].set(r,g,b,0xFF);
+	image[(px,py)].set16(to16(r),to16(g),to16(b),0xFFFF);
 }
 
 fn draw_yuv<T: pixel::Pixel>(image: &mut Mat<T>,px: usize,py: usize,y: i32,u: i32,v: i32) {
-	let r = ((y << 8) + 359 * v) >> 8;
-	let g = ((y << 8) - 88 * u - 183 * v) >> 8;
-	let b = ((y << 8) + 454 * u) >> 8;
+	let r = (y << 8) + 359 * v;
+	let g = (y << 8) - 88 * u - 183 * v;
+	let b = (y << 8) + 454 * u;
 	draw_rgb(image,px,py,r,g,b);
 }
 
@@ -784,123 +811,323 @@ fn draw_macroblock_rgb444<T: pixel::Pixel>(image: &mut Mat<T>,x0: usize,y0: usiz
 			let r = coeffs[i * 8 + k] + 128;
 			let g = coeffs[64 + i * 8 + k] + 128;
 			let b = coeffs[128 + i * 8 + k] + 128;
-			draw_rgb(image,x0 + k,y0 + i,r as i32,g as i32,b as i32);
+			draw_rgb(image,x0 + k,y0 + i,(r as i32) << 8,(g as i32) << 8,(b as i32) << 8);
 		}
 	}
 }
 
-pub fn test(src: &[u8]) -> Option<(u32,u32)> {
-	let mut sp = 0;
-	if from_be16(&src[sp..sp + 2]) != 0xFFD8 {
-		return None;
+// c, m, y and k are 8.8 fixed point, inverted means 0 is full ink (as Adobe writes them)
+fn draw_cmyk<T: pixel::Pixel>(image: &mut Mat<T>,px: usize,py: usize,c: i32,m: i32,y: i32,k: i32,inverted: bool) {
+	let clamp = |v: i32| if v < 0 { 0 } else if v > 0xFF00 { 0xFF00 } else { v };
+	let paper = |v: i32| if inverted { clamp(v) } else { 0xFF00 - clamp(v) };
+	let pk = paper(k) as i64;
+	let r = ((paper(c) as i64) * pk / 0xFF00) as i32;
+	let g = ((paper(m) as i64) * pk / 0xFF00) as i32;
+	let b = ((paper(y) as i64) * pk / 0xFF00) as i32;
+	draw_rgb(image,px,py,r,g,b);
+}
+
+fn draw_macroblock_cmyk444<T: pixel::Pixel>(image: &mut Mat<T>,x0: usize,y0: usize,width: usize,height: usize,coeffs: &[i32],inverted: bool) {
+	for i in 0..height {
+		for k in 0..width {
+			let c = coeffs[i * 8 + k] + 128;
+			let m = coeffs[64 + i * 8 + k] + 128;
+			let y = coeffs[128 + i * 8 + k] + 128;
+			let kk = coeffs[192 + i * 8 + k] + 128;
+			draw_cmyk(image,x0 + k,y0 + i,c << 8,m << 8,y << 8,kk << 8,inverted);
+		}
 	}
-	sp += 2;
-	while sp < src.len() {
+}
+
+// YCbCr encodes 255 - C, 255 - M and 255 - Y, K is stored as is
+fn draw_macroblock_ycck444<T: pixel::Pixel>(image: &mut Mat<T>,x0: usize,y0: usize,width: usize,height: usize,coeffs: &[i32],inverted: bool) {
+	for i in 0..height {
+		for k in 0..width {
+			let y = coeffs[i * 8 + k] + 128;
+			let u = coeffs[64 + i * 8 + k];
+			let v = coeffs[128 + i * 8 + k];
+			let kk = coeffs[192 + i * 8 + k] + 128;
+			let r = (y << 8) + 359 * v;
+			let g = (y << 8) - 88 * u - 183 * v;
+			let b = (y << 8) + 454 * u;
+			draw_cmyk(image,x0 + k,y0 + i,0xFF00 - r,0xFF00 - g,0xFF00 - b,kk << 8,inverted);
+		}
+	}
+}
+
+// Y and K are full resolution, CbCr is shared by 2x2 pixels
+fn draw_macroblock_ycck420<T: pixel::Pixel>(image: &mut Mat<T>,x0: usize,y0: usize,width: usize,height: usize,coeffs: &[i32],inverted: bool) {
+	for i in 0..height {
+		for k in 0..width {
+			let by = (i >> 3) * 2 + (k >> 3);
+			let si = i & 7;
+			let sk = k & 7;
+			let y = coeffs[by * 64 + si * 8 + sk] + 128;
+			let hi = i >> 1;
+			let hk = k >> 1;
+			let u = coeffs[256 + hi * 8 + hk];
+			let v = coeffs[320 + hi * 8 + hk];
+			let kk = coeffs[384 + by * 64 + si * 8 + sk] + 128;
+			let r = (y << 8) + 359 * v;
+			let g = (y << 8) - 88 * u - 183 * v;
+			let b = (y << 8) + 454 * u;
+			draw_cmyk(image,x0 + k,y0 + i,0xFF00 - r,0xFF00 - g,0xFF00 - b,kk << 8,inverted);
+		}
+	}
+}
+
+pub fn info(src: &[u8]) -> Result<ImageInfo,ImageError> {
+	if (src.len() < 2) || (from_be16(&src[0..2]) != 0xFFD8) {
+		return Err(ImageError::UnknownFormat);
+	}
+	let mut info: Option<ImageInfo> = None;
+	let mut density: Option<Vec2<f32>> = None;  // from JFIF
+	let mut exif_dpi: Option<Vec2<f32>> = None;
+	let mut icc: Vec<(u8,&[u8])> = Vec::new();  // ICC profile pieces and their sequence numbers
+	let mut sp = 2;
+	while sp + 4 <= src.len() {
 		let marker = from_be16(&src[sp..sp + 2]);
 		let length = from_be16(&src[sp + 2..sp + 4]) as usize;
+		if (marker == 0xFFDA) || (marker == 0xFFD9) {
+			break;
+		}
+		if (length < 2) || (sp + 2 + length > src.len()) {
+			return Err(ImageError::Corrupt(sp,"truncated segment"));
+		}
+		let data = &src[sp + 4..sp + 2 + length];
 		match marker {
-			0xFFC0 | 0xFFC1 | 0xFFC2 => {
-				let width = from_be16(&src[sp + 5..sp + 7]) as u32;
-				let height = from_be16(&src[sp + 7..sp + 9]) as u32;
-				let components = src[sp + 9];
-				if (components == 1) || (components == 3) {  // does not support RGBA or CMYK JPEGs
-					return Some((width,height));
+			0xFFC0 | 0xFFC1 | 0xFFC2 => {  // baseline sequential, extended sequential, progressive
+				if data.len() < 6 {
+					return Err(ImageError::Corrupt(sp,"invalid frame header"));
+				}
+				let height = from_be16(&data[1..3]) as usize;
+				let width = from_be16(&data[3..5]) as usize;
+				if (width == 0) || (height == 0) {
+					return Err(ImageError::Dimensions(width,height));
+				}
+				let channels = match data[5] {
+					1 => ChannelLayout::Gray,
+					3 => ChannelLayout::Rgb,
+					4 => ChannelLayout::Cmyk,
+					_ => { return Err(ImageError::Unsupported("JPEG component count")); },
+				};
+				let mut frame = ImageInfo::new(ImageFormat::Jpeg,width,height,data[0] as u32,channels);
+				frame.progressive = marker == 0xFFC2;
+				info = Some(frame);
+			},
+			0xFFC3 | 0xFFC5 | 0xFFC6 | 0xFFC7 | 0xFFC9 | 0xFFCA | 0xFFCB | 0xFFCD | 0xFFCE | 0xFFCF => {
+				return Err(ImageError::Unsupported("JPEG coding process"));
+			},
+			0xFFE0 => {  // JFIF
+				if (data.len() >= 12) && (&data[0..5] == b"JFIF\0") {
+					let x = from_be16(&data[8..10]) as f32;
+					let y = from_be16(&data[10..12]) as f32;
+					density = match data[7] {
+						1 => Some(vec2!(x,y)),
+						2 => Some(vec2!(x * 2.54,y * 2.54)),
+						_ => None,
+					};
+				}
+			},
+			0xFFE1 => {  // Exif
+				if (data.len() >= 6) && (&data[0..6] == b"Exif\0\0") {
+					exif_dpi = Exif::parse(data).and_then(|exif| exif.dpi());
+				}
+			},
+			0xFFE2 => {  // ICC profile, possibly split over several segments
+				if (data.len() >= 14) && (&data[0..12] == b"ICC_PROFILE\0") {
+					icc.push((data[12],&data[14..]));
 				}
-				return None;
 			},
 			_ => { },
 		}
 		sp += length + 2;
-	}		
-	None
+	}
+	let mut info = info.ok_or(ImageError::Corrupt(sp,"missing frame header"))?;
+	info.dpi = if density.is_some() { density } else { exif_dpi };
+	if icc.len() > 0 {
+		icc.sort_by_key(|piece| piece.0);
+		info.icc_profile = Some(icc.iter().flat_map(|piece| piece.1.iter().cloned()).collect());
+	}
+	Ok(info)
 }
 
-pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Option<Mat<T>> {
-	if from_be16(&src[0..2]) != 0xFFD8 {
+pub fn test(src: &[u8]) -> Result<(u32,u32),ImageError> {
+	let info = info(src)?;
+	Ok((info.size.x as u32,info.size.y as u32))
+}
+
+pub fn exif(src: &[u8]) -> Option<Exif> {
+	if (src.len() < 4) || (from_be16(&src[0..2]) != 0xFFD8) {
 		return None;
 	}
-	let mut qtable = [[0i32; 64]; 4];
-	let mut dcht = [Table::new_empty(); 4];
-	let mut acht = [Table::new_empty(); 4];
-	let mut qt = [0usize; 3];
-	let mut dt = [0usize; 3];
-	let mut at = [0usize; 3];
-	#[allow(unused_assignments)]
-	let mut width = 1;
-	#[allow(unused_assignments)]
-	let mut height = 1;
-	#[allow(unused_assignments)]
-	let mut itype = Type::Y;
-	#[allow(unused_assignments)]
-	let mut mbtotal = 0;  // total number of macroblocks
-	#[allow(unused_assignments)]
-	let mut mbwidth = 0;
-	#[allow(unused_assignments)]
-	let mut mbheight = 0;
-	#[allow(unused_assignments)]
-	let mut cpmb = 0;
-	let mut coeffs: Vec<i32> = Vec::new();  // the coefficients
-	#[allow(unused_assignments)]
-	let mut resint = 0;
-	#[allow(unused_assignments)]
 	let mut sp = 2;
-	while sp < src.len() {
+	while sp + 4 <= src.len() {
 		let marker = from_be16(&src[sp..sp + 2]);
-		let length = if marker != 0xFFD9 { from_be16(&src[sp + 2..sp + 4]) as usize } else { 0 };
+		let length = from_be16(&src[sp + 2..sp + 4]) as usize;
+		if (marker == 0xFFDA) || (marker == 0xFFD9) || (sp + 2 + length > src.len()) {
+			return None;
+		}
+		if (marker == 0xFFE1) && (length >= 8) && (from_be32(&src[sp + 4..sp + 8]) == 0x45786966) {  // Exif
+			return Exif::parse(&src[sp + 10..sp + 2 + length]);
+		}
+		sp += length + 2;
+	}
+	None
+}
+
+// decoder state that carries over from one segment to the next
+struct Decoder {
+	qtable: [[i32; 64]; 4],
+	dcht: Vec<Table>,  // 4 each, on the heap because they are large
+	acht: Vec<Table>,
+	qt: [usize; 4],
+	dt: [usize; 4],
+	at: [usize; 4],
+	width: usize,
+	height: usize,
+	itype: Type,
+	mbtotal: usize,  // total number of macroblocks
+	mbwidth: usize,
+	mbheight: usize,
+	cpmb: usize,
+	coeffs: Vec<i32>,  // the coefficients
+	rgb: bool,
+	adobe: Option<u8>,  // transform from the Adobe segment
+	resint: usize,
+	ids: Vec<u8>,  // component identifiers, in frame header order
+}
+
+impl Decoder {
+	fn new() -> Decoder {
+		Decoder {
+			qtable: [[0i32; 64]; 4],
+			dcht: vec![Table::new_empty(); 4],
+			acht: vec![Table::new_empty(); 4],
+			qt: [0usize; 4],
+			dt: [0usize; 4],
+			at: [0usize; 4],
+			width: 1,
+			height: 1,
+			itype: Type::Y,
+			mbtotal: 0,
+			mbwidth: 0,
+			mbheight: 0,
+			cpmb: 0,
+			coeffs: Vec::new(),
+			rgb: false,
+			adobe: None,
+			resint: 0,
+			ids: Vec::new(),
+		}
+	}
+
+	// process the segment at sp, returns where the next one starts, or None at the end of the image
+	fn segment(&mut self,src: &[u8],sp: usize) -> Result<Option<usize>,ImageError> {
+		let marker = from_be16(&src[sp..sp + 2]);
+		let length = if marker != 0xFFD9 {
+			if sp + 4 > src.len() {
+				return Err(ImageError::Corrupt(sp,"truncated segment"));
+			}
+			from_be16(&src[sp + 2..sp + 4]) as usize
+		} else {
+			0
+		};
+		if sp + 2 + length > src.len() {
+			return Err(ImageError::Corrupt(sp,"truncated segment"));
+		}
 		//println!("marker {:04X}, length {}",marker,length);
 		match marker {
 			0xFFC0 | 0xFFC1 | 0xFFC2 => {  // baseline sequential, extended sequential, progressive
 				//println!("precision {}",src[sp + 4]);
+				if length < 8 {
+					return Err(ImageError::Corrupt(sp,"invalid frame header"));
+				}
 				if src[sp + 4] != 8 {
-					return None;
+					return Err(ImageError::Unsupported("JPEG sample precision"));
 				}
-				height = from_be16(&src[sp + 5..sp + 7]) as usize;
-				width = from_be16(&src[sp + 7..sp + 9]) as usize;
+				self.height = from_be16(&src[sp + 5..sp + 7]) as usize;
+				self.width = from_be16(&src[sp + 7..sp + 9]) as usize;
 				let components = src[sp + 9];
 				//println!("size {}x{}, components {}",width,height,components);
-				if (components != 1) && (components != 3) {
-					return None;
+				check_dimensions(self.width,self.height)?;
+				if (components != 1) && (components != 3) && (components != 4) {
+					return Err(ImageError::Unsupported("JPEG component count"));
+				}
+				if length < 8 + 3 * components as usize {
+					return Err(ImageError::Corrupt(sp,"invalid frame header"));
 				}
-				let mut samp = [0u8; 3];
+				// JFIF numbers the components from 1, libjpeg writes CMYK as 'C', 'M', 'Y', 'K'
+				let mut samp = [0u8; 4];
 				let mut tsp = sp + 10;
+				self.ids.clear();
 				for i in 0..components {
-					if src[tsp] != i + 1 {
-						return None;
+					if self.ids.contains(&src[tsp]) {
+						return Err(ImageError::Corrupt(tsp,"duplicate component identifier"));
+					}
+					self.ids.push(src[tsp]);
+					if src[tsp + 2] > 3 {
+						return Err(ImageError::Corrupt(tsp,"invalid quantization table index"));
 					}
 					samp[i as usize] = src[tsp + 1];
-					qt[i as usize] = src[tsp + 2] as usize;
+					self.qt[i as usize] = src[tsp + 2] as usize;
 					tsp += 3;
 					//println!("{}: samp {:02X}, qt {}",i,samp[i as usize],qt[i as usize]);
 				}
 				if components == 3 {
 					if (samp[1] != 0x11) || (samp[2] != 0x11) {
-						return None;
+						return Err(ImageError::Unsupported("JPEG subsampling"));
 					}
-					let sw = ((samp[0] >> 4) * 8) as usize;
-					let sh = ((samp[0] & 15) * 8) as usize;
-					//println!("one macroblock = {}x{}",sw,sh);
-					mbwidth = (width + sw - 1) / sw;
-					mbheight = (height + sh - 1) / sh;
-					//println!("{}x{} macroblocks ({}x{} pixels)",mbwidth,mbheight,mbwidth * sw,mbheight * sh);
-					cpmb = 128 + 64 * ((samp[0] >> 4) as usize) * ((samp[0] & 15) as usize);
-					itype = match samp[0] {
-						0x11 => Type::YUV444,
+					// only the supported factors, the others (like 0) do not make macroblocks
+					self.itype = match samp[0] {
+						0x11 => if self.rgb || (self.adobe == Some(0)) { Type::RGB444 } else { Type::YUV444 },
 						0x12 => Type::YUV440,
 						0x21 => Type::YUV422,
 						0x22 => Type::YUV420,
 						_ => {
-							return None;
+							return Err(ImageError::Unsupported("JPEG subsampling"));
 						},
 					};
+					let sw = ((samp[0] >> 4) * 8) as usize;
+					let sh = ((samp[0] & 15) * 8) as usize;
+					//println!("one macroblock = {}x{}",sw,sh);
+					self.mbwidth = (self.width + sw - 1) / sw;
+					self.mbheight = (self.height + sh - 1) / sh;
+					//println!("{}x{} macroblocks ({}x{} pixels)",mbwidth,mbheight,mbwidth * sw,mbheight * sh);
+					self.cpmb = 128 + 64 * ((samp[0] >> 4) as usize) * ((samp[0] & 15) as usize);
+				}
+				else if components == 4 {
+					// 4:4:4, or YCCK with the 4:2:0 that libjpeg and Photoshop write by default
+					if (samp[1] != 0x11) || (samp[2] != 0x11) {
+						return Err(ImageError::Unsupported("JPEG subsampling"));
+					}
+					match (samp[0],samp[3],self.adobe == Some(2)) {
+						(0x11,0x11,true) => { self.itype = Type::YCCK444; },
+						(0x11,0x11,false) => { self.itype = Type::CMYK444; },
+						(0x22,0x22,true) => { self.itype = Type::YCCK420; },
+						_ => {
+							return Err(ImageError::Unsupported("JPEG subsampling"));
+						},
+					}
+					if self.itype == Type::YCCK420 {
+						self.mbwidth = (self.width + 15) / 16;
+						self.mbheight = (self.height + 15) / 16;
+						self.cpmb = 640;
+					}
+					else {
+						self.mbwidth = (self.width + 7) / 8;
+						self.mbheight = (self.height + 7) / 8;
+						self.cpmb = 256;
+					}
 				}
 				else {
-					mbwidth = (width + 7) / 8;
-					mbheight = (height + 7) / 8;
-					cpmb = 64;
-					itype = Type::Y;
+					self.mbwidth = (self.width + 7) / 8;
+					self.mbheight = (self.height + 7) / 8;
+					self.cpmb = 64;
+					self.itype = Type::Y;
 				}
-				mbtotal = mbwidth * mbheight;
-				coeffs.resize(mbtotal * cpmb as usize,0);
+				self.mbtotal = self.mbwidth * self.mbheight;
+				self.coeffs.resize(self.mbtotal * self.cpmb as usize,0);
 				//println!("type {:04X}, {} macroblocks in total, {} coefficients per row",itype,mbtotal,mbstride);
 				//println!("size {}x{}, macroblocks {}",width,height,mbtotal);
 			},
@@ -912,15 +1139,20 @@ pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Option<Mat<T>> {
 					let tc = d >> 4;
 					let n = d & 15;
 					//println!("tc = {}, n = {}",tc,n);
+					if (n > 3) || (tsp + 16 > sp + length + 2) {
+						return Err(ImageError::Corrupt(tsp - 1,"invalid Huffman table"));
+					}
 					let mut bits = [0u8; 16];
 					let mut total = 0usize;
+					let mut space = 0usize;  // part of the code space that is used, must fit in 65536
 					for i in 0..16 {
 						bits[i] = src[tsp];
 						tsp += 1;
 						total += bits[i] as usize;
+						space += (bits[i] as usize) << (15 - i);
 					}
-					if total >= 256 {
-						return None;
+					if (total >= 256) || (space > 65536) || (tsp + total > sp + length + 2) {
+						return Err(ImageError::Corrupt(tsp - 16,"invalid Huffman table"));
 					}
 					//println!("total codes: {}",total);
 					let mut huffval = [0u8; 256];
@@ -929,12 +1161,16 @@ pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Option<Mat<T>> {
 						//println!("code {}: run {}, cat {}",i,huffval[i] >> 4,huffval[i] & 15);
 						tsp += 1;
 					}
+					// DC categories above 16 do not fit the bit reader
+					if (tc == 0) && huffval[0..total].iter().any(|&v| v > 16) {
+						return Err(ImageError::Corrupt(tsp - total,"invalid DC category"));
+					}
 					let table = Table::new(bits,huffval);
 					if tc != 0 {
-						acht[n as usize] = table;
+						self.acht[n as usize] = table;
 					}
 					else {
-						dcht[n as usize] = table;
+						self.dcht[n as usize] = table;
 					}
 				}
 			},
@@ -942,72 +1178,40 @@ pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Option<Mat<T>> {
 			},
 			0xFFD9 => {  // image end
 				//println!("end");
-				let mut image = Mat::new(vec2!(width,height));
-				match itype {
-					Type::Y => { convert_blocks(&mut coeffs,mbtotal,Type::Y,&qtable,&qt); },
-					Type::YUV420 => { convert_blocks(&mut coeffs,mbtotal * 6,Type::YUV420,&qtable,&qt); },
-					Type::YUV422 => { convert_blocks(&mut coeffs,mbtotal * 4,Type::YUV422,&qtable,&qt); },
-					Type::YUV440 => { convert_blocks(&mut coeffs,mbtotal * 4,Type::YUV440,&qtable,&qt); },
-					Type::YUV444 => { convert_blocks(&mut coeffs,mbtotal * 3,Type::YUV444,&qtable,&qt); },
-					Type::RGB444 => { convert_blocks(&mut coeffs,mbtotal * 3,Type::RGB444,&qtable,&qt); },
-				}
-				#[allow(unused_assignments)]
-				let mut mb = 0;
-				for i in 0..mbheight - 1 {
-					for k in 0..mbwidth - 1 {
-						match itype {
-							Type::Y => { draw_macroblock_y(&mut image,k * 8,i * 8,8,8,&coeffs[mb..mb + 64]); mb += 64; },
-							Type::YUV420 => { draw_macroblock_yuv420(&mut image,k * 16,i * 16,16,16,&coeffs[mb..mb + 384]); mb += 384; },
-							Type::YUV422 => { draw_macroblock_yuv422(&mut image,k * 16,i * 8,16,8,&coeffs[mb..mb + 256]); mb += 256; },
-							Type::YUV440 => { draw_macroblock_yuv440(&mut image,k * 8,i * 16,8,16,&coeffs[mb..mb + 256]); mb += 256; },
-							Type::YUV444 => { draw_macroblock_yuv444(&mut image,k * 8,i * 8,8,8,&coeffs[mb..mb + 192]); mb += 192; },
-							Type::RGB444 => { draw_macroblock_rgb444(&mut image,k * 8,i * 8,8,8,&coeffs[mb..mb + 192]); mb += 192; },
-						}
-					}
-					match itype {
-						Type::Y => { draw_macroblock_y(&mut image,mbwidth * 8 - 8,i * 8,width - (mbwidth - 1) * 8,8,&coeffs[mb..mb + 64]); mb += 64; },
-						Type::YUV420 => { draw_macroblock_yuv420(&mut image,mbwidth * 16 - 16,i * 16,width - (mbwidth - 1) * 16,16,&coeffs[mb..mb + 384]); mb += 384; },
-						Type::YUV422 => { draw_macroblock_yuv422(&mut image,mbwidth * 16 - 16,i * 8,width - (mbwidth - 1) * 16,8,&coeffs[mb..mb + 256]); mb += 256; },
-						Type::YUV440 => { draw_macroblock_yuv440(&mut image,mbwidth * 8 - 8,i * 16,width - (mbwidth - 1) * 8,16,&coeffs[mb..mb + 256]); mb += 256; },
-						Type::YUV444 => { draw_macroblock_yuv444(&mut image,mbwidth * 8 - 8,i * 8,width - (mbwidth - 1) * 8,8,&coeffs[mb..mb + 192]); mb += 192; },
-						Type::RGB444 => { draw_macroblock_rgb444(&mut image,mbwidth * 8 - 8,i * 8,width - (mbwidth - 1) * 8,8,&coeffs[mb..mb + 192]); mb += 192; },
-					}
+				if self.coeffs.is_empty() {
+					return Err(ImageError::Corrupt(sp,"missing frame header"));
 				}
-				for k in 0..mbwidth - 1 {
-					match itype {
-						Type::Y => { draw_macroblock_y(&mut image,k * 8,mbheight * 8 - 8,8,mbheight * 8 - height,&coeffs[mb..mb + 64]); mb += 64; },
-						Type::YUV420 => { draw_macroblock_yuv420(&mut image,k * 16,mbheight * 16 - 16,16,height - (mbheight - 1) * 16,&coeffs[mb..mb + 384]); mb += 384; },
-						Type::YUV422 => { draw_macroblock_yuv422(&mut image,k * 16,mbheight * 8 - 8,16,height - (mbheight - 1) * 8,&coeffs[mb..mb + 256]); mb += 256; },
-						Type::YUV440 => { draw_macroblock_yuv440(&mut image,k * 8,mbheight * 16 - 16,8,height - (mbheight - 1) * 16,&coeffs[mb..mb + 256]); mb += 256; },
-						Type::YUV444 => { draw_macroblock_yuv444(&mut image,k * 8,mbheight * 8 - 8,8,height - (mbheight - 1) * 8,&coeffs[mb..mb + 192]); mb += 192; },
-						Type::RGB444 => { draw_macroblock_rgb444(&mut image,k * 8,mbheight * 8 - 8,8,height - (mbheight - 1) * 8,&coeffs[mb..mb + 192]); mb += 192; },
-					}
-				}
-				match itype {
-					Type::Y => { draw_macroblock_y(&mut image,mbwidth * 8 - 8,mbheight * 8 - 8,width - (mbwidth - 1) * 8,height - (mbheight - 1) * 8,&coeffs[mb..mb + 64]); },
-					Type::YUV420 => { draw_macroblock_yuv420(&mut image,mbwidth * 16 - 16,mbheight * 16 - 16,width - (mbwidth - 1) * 16,height - (mbheight - 1) * 16,&coeffs[mb..mb + 384]); },
-					Type::YUV422 => { draw_macroblock_yuv422(&mut image,mbwidth * 16 - 16,mbheight * 8 - 8,width - (mbwidth - 1) * 16,height - (mbheight - 1) * 8,&coeffs[mb..mb + 256]); },
-					Type::YUV440 => { draw_macroblock_yuv440(&mut image,mbwidth * 8 - 8,mbheight * 16 - 16,width - (mbwidth - 1) * 8,height - (mbheight - 1) * 16,&coeffs[mb..mb + 256]); },
-					Type::YUV444 => { draw_macroblock_yuv444(&mut image,mbwidth * 8 - 8,mbheight * 8 - 8,width - (mbwidth - 1) * 8,height - (mbheight - 1) * 8,&coeffs[mb..mb + 192]); },
-					Type::RGB444 => { draw_macroblock_rgb444(&mut image,mbwidth * 8 - 8,mbheight * 8 - 8,width - (mbwidth - 1) * 8,height - (mbheight - 1) * 8,&coeffs[mb..mb + 192]); },
-				}
-				return Some(image);
+				return Ok(None);
 			},
 			0xFFDA => {  // scan start
 				//println!("scan start");
 				let mut tsp = sp + 4;
 				let count = src[tsp];
 				tsp += 1;
+				if self.coeffs.is_empty() {
+					return Err(ImageError::Corrupt(sp,"scan before frame header"));
+				}
+				if (count == 0) || (count > 4) || (length < 6 + 2 * count as usize) {
+					return Err(ImageError::Corrupt(sp,"invalid scan header"));
+				}
 				// acht[4], dcht[4]
-				let mut mask = 0;
+				let mut mask = 0u8;
 				for _i in 0..count {
-					let index = src[tsp] - 1;
+					let index = match self.ids.iter().position(|&id| id == src[tsp]) {
+						Some(index) => index,
+						None => {
+							return Err(ImageError::Corrupt(tsp,"invalid scan component"));
+						},
+					};
+					if ((src[tsp + 1] >> 4) > 3) || ((src[tsp + 1] & 15) > 3) {
+						return Err(ImageError::Corrupt(tsp,"invalid scan component"));
+					}
 					tsp += 1;
 					mask |= 1 << index;
 					let n = src[tsp];
 					tsp += 1;
-					dt[index as usize] = (n >> 4) as usize;
-					at[index as usize] = (n & 15) as usize;
+					self.dt[index as usize] = (n >> 4) as usize;
+					self.at[index as usize] = (n & 15) as usize;
 					//println!("index {}, dt {}, at {}",index,n >> 4,n & 15);
 				}
 				let start = src[tsp];
@@ -1018,17 +1222,53 @@ pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Option<Mat<T>> {
 				tsp += 1;
 				let refine = (d & 0xF0) != 0;
 				let shift = d & 15;
+				if (start > end) || (end > 63) {
+					return Err(ImageError::Corrupt(tsp - 3,"invalid spectral selection"));
+				}
 				//println!("start = {}, end = {}, refine = {}, shift = {}",start,end,refine,shift);
 				let mut reader = Reader::new(&src[tsp..]);
-				let mut rescnt = resint;
+				let mut rescnt = self.resint;
 				let mut eobrun = 0;
-				let mut dc = [0i32; 3];
-				for i in 0..mbtotal {
-					//println!("macroblock {}:",i);
-					unpack_macroblock(&mut reader,&mut coeffs[i * cpmb..(i + 1) * cpmb],&dcht,&acht,&dt,&at,&mut dc,start,end,shift,refine,&mut eobrun,itype,&mut rescnt,resint,mask);
+				let mut dc = [0i32; 4];
+				if count == 1 {
+					// non-interleaved scan, the blocks of the component are coded in raster order
+					let c = mask.trailing_zeros() as usize;
+					let (h,v) = match self.itype {
+						Type::YUV420 | Type::YCCK420 => (2,2),
+						Type::YUV422 => (2,1),
+						Type::YUV440 => (1,2),
+						_ => (1,1),
+					};
+					// Y, and K of YCCK, are full resolution, the other components have one block per macroblock
+					let full = (c == 0) || ((c == 3) && (self.itype == Type::YCCK420));
+					let bw = if full { (self.width + 7) / 8 } else { self.mbwidth };
+					let bh = if full { (self.height + 7) / 8 } else { self.mbheight };
+					let base = if c == 0 { 0 } else { 64 * h * v + (c - 1) * 64 };
+					for by in 0..bh {
+						for bx in 0..bw {
+							let bp = if full {
+								((by / v) * self.mbwidth + bx / h) * self.cpmb + base + ((by % v) * h + (bx % h)) * 64
+							}
+							else {
+								(by * self.mbwidth + bx) * self.cpmb + base
+							};
+							unpack_block(&mut reader,&mut self.coeffs[bp..bp + 64],&self.dcht[self.dt[c]],&self.acht[self.at[c]],&mut dc[c],start,end,shift,refine,&mut eobrun);
+							restart(&mut reader,&mut dc,&mut rescnt,self.resint);
+						}
+					}
 				}
-				sp = (tsp + reader.leave()) - length - 2;
-				//println!("sp = {}, ({:02X} {:02X})",sp,src[sp + length + 2],src[sp + length + 2 + 1]);
+				else {
+					for i in 0..self.mbtotal {
+						//println!("macroblock {}:",i);
+						unpack_macroblock(&mut reader,&mut self.coeffs[i * self.cpmb..(i + 1) * self.cpmb],&self.dcht,&self.acht,&self.dt,&self.at,&mut dc,start,end,shift,refine,&mut eobrun,self.itype,&mut rescnt,self.resint,mask);
+					}
+				}
+				// skip anything left over up to the next marker
+				let mut rp = tsp + reader.leave();
+				while (rp + 1 < src.len()) && ((src[rp] != 0xFF) || (src[rp + 1] == 0x00) || ((src[rp + 1] >= 0xD0) && (src[rp + 1] < 0xD8))) {
+					rp += 1;
+				}
+				return Ok(Some(rp));
 			},
 			0xFFDB => {  // quantization tables
 				let mut tsp = sp + 4;
@@ -1036,85 +1276,1162 @@ pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Option<Mat<T>> {
 					let d = src[tsp];
 					tsp += 1;
 					let n = d & 15;
+					if (n > 3) || (tsp + if (d >> 4) != 0 { 128 } else { 64 } > sp + length + 2) {
+						return Err(ImageError::Corrupt(tsp - 1,"invalid quantization table"));
+					}
 					//println!("updating qtable[{}]",n);
 					if (d >> 4) != 0 {
 						for k in 0..64 {
-							qtable[n as usize][FOLDING[k as usize] as usize] = from_be16(&src[tsp..tsp + 2]) as i32;
+							self.qtable[n as usize][FOLDING[k as usize] as usize] = from_be16(&src[tsp..tsp + 2]) as i32;
 							tsp += 2;
 						}
 					}
 					else {
 						for k in 0..64 {
-							qtable[n as usize][FOLDING[k as usize] as usize] = src[tsp] as i32;
+							self.qtable[n as usize][FOLDING[k as usize] as usize] = src[tsp] as i32;
 							tsp += 1;
 						}
 					}
 				}
 			},
 			0xFFDD => {  // restart interval
-				resint = from_be16(&src[sp + 4..sp + 6]) as usize;
+				if length < 4 {
+					return Err(ImageError::Corrupt(sp,"invalid restart interval"));
+				}
+				self.resint = from_be16(&src[sp + 4..sp + 6]) as usize;
 			},
 			0xFFE1 => {  // EXIF
-				let header = from_be32(&src[sp + 4..sp + 8]);
-				if header == 0x45786966 {  // Exif
-					let start = sp + 10;
-					let mut tsp = start;
-					let le = from_be16(&src[tsp..tsp + 2]) == 0x4949;  // figure out endianness
-					tsp += 4;  // skip 0x2A
-					tsp += (if le { from_le32(&src[tsp..tsp + 4]) } else { from_be32(&src[tsp..tsp + 4]) } - 8) as usize;  // go to IFD0
-					let entries = if le { from_le16(&src[tsp..tsp + 2]) } else { from_be16(&src[tsp..tsp + 2]) };  // number of entries
-					tsp += 2;
-					for _i in 0..entries {
-						let tag = if le { from_le16(&src[tsp..tsp + 2]) } else { from_be16(&src[tsp..tsp + 2]) };
-						tsp += 2;
-						let format = if le { from_le16(&src[tsp..tsp + 2]) } else { from_be16(&src[tsp..tsp + 2]) };
-						tsp += 2;
-						if format > 12 {
-							return None;							
-						}
-						let components = if le { from_le32(&src[tsp..tsp + 4]) } else { from_be32(&src[tsp..tsp + 4]) };
-						tsp += 4;
-						let data = if le { from_le32(&src[tsp..tsp + 4]) } else { from_be32(&src[tsp..tsp + 4]) };
-						tsp += 4;
-						let elsize = [0usize,1,1,2,4,8,1,0,2,4,8,4,8];
-						let total = elsize[format as usize] * (components as usize);
-						let mut dsp = start + data as usize;
-						if total <= 4 {
-							dsp = tsp - 4;
-						}
-						//println!("EXIF tag {:04X}, format {}, components {}, data {:08X}",tag,format,components,data);
-						match tag {
-							0x0106 => { // photometric interpretation
-								let pe = if le { from_le16(&src[dsp..dsp + 2]) } else { from_be16(&src[dsp..dsp + 2]) };
-								if let Type::YUV444 = itype {
-									if pe != 2 {
-										return None;
-									}
-									itype = Type::RGB444;
-								}
-								else {
-									return None;
-								}
-							},
-							0xA001 => { // colorspace
-							},
-							_ => {
-							}
+				if (length >= 8) && (from_be32(&src[sp + 4..sp + 8]) == 0x45786966) {  // Exif
+					if let Some(exif) = Exif::parse(&src[sp + 10..sp + 2 + length]) {
+						if exif.photometric == Some(2) {  // RGB instead of YCbCr
+							self.rgb = true;
 						}
 					}
 				}
 			},
-			0xFFC8 | 0xFFDC | 0xFFE0 | 0xFFE2..=0xFFEF | 0xFFF0..=0xFFFF => {  // other accepted markers
+			0xFFEE => {  // Adobe
+				if (length >= 14) && (&src[sp + 4..sp + 9] == b"Adobe") {
+					self.adobe = Some(src[sp + 15]);
+				}
+			},
+			0xFFC8 | 0xFFDC | 0xFFE0 | 0xFFE2..=0xFFED | 0xFFEF | 0xFFF0..=0xFFFF => {  // other accepted markers
+			},
+			0xFFC3 | 0xFFC5..=0xFFC7 | 0xFFC9..=0xFFCF => {  // lossless, hierarchical and arithmetic coding
+				return Err(ImageError::Unsupported("JPEG coding process"));
 			},
-			_ => { 
-				return None;
+			_ => {
+				return Err(ImageError::Corrupt(sp,"invalid marker"));
 			},
 		}
-		sp += length + 2;
+		Ok(Some(sp + length + 2))
+	}
+
+	// convert and draw the coefficients, which are destroyed in the process
+	fn render<T: pixel::Pixel>(&self,coeffs: &mut [i32]) -> Mat<T> {
+		let mut image = Mat::new(vec2!(self.width,self.height));
+		let inverted = self.adobe.is_some();
+		match self.itype {
+			Type::Y => { convert_blocks(coeffs,self.mbtotal,Type::Y,&self.qtable,&self.qt); },
+			Type::YUV420 => { convert_blocks(coeffs,self.mbtotal * 6,Type::YUV420,&self.qtable,&self.qt); },
+			Type::YUV422 => { convert_blocks(coeffs,self.mbtotal * 4,Type::YUV422,&self.qtable,&self.qt); },
+			Type::YUV440 => { convert_blocks(coeffs,self.mbtotal * 4,Type::YUV440,&self.qtable,&self.qt); },
+			Type::YUV444 => { convert_blocks(coeffs,self.mbtotal * 3,Type::YUV444,&self.qtable,&self.qt); },
+			Type::RGB444 => { convert_blocks(coeffs,self.mbtotal * 3,Type::RGB444,&self.qtable,&self.qt); },
+			Type::CMYK444 => { convert_blocks(coeffs,self.mbtotal * 4,Type::CMYK444,&self.qtable,&self.qt); },
+			Type::YCCK444 => { convert_blocks(coeffs,self.mbtotal * 4,Type::YCCK444,&self.qtable,&self.qt); },
+			Type::YCCK420 => { convert_blocks(coeffs,self.mbtotal * 10,Type::YCCK420,&self.qtable,&self.qt); },
+		}
+		#[allow(unused_assignments)]
+		let mut mb = 0;
+		for i in 0..self.mbheight - 1 {
+			for k in 0..self.mbwidth - 1 {
+				match self.itype {
+					Type::Y => { draw_macroblock_y(&mut image,k * 8,i * 8,8,8,&coeffs[mb..mb + 64]); mb += 64; },
+					Type::YUV420 => { draw_macroblock_yuv420(&mut image,k * 16,i * 16,16,16,&coeffs[mb..mb + 384]); mb += 384; },
+					Type::YUV422 => { draw_macroblock_yuv422(&mut image,k * 16,i * 8,16,8,&coeffs[mb..mb + 256]); mb += 256; },
+					Type::YUV440 => { draw_macroblock_yuv440(&mut image,k * 8,i * 16,8,16,&coeffs[mb..mb + 256]); mb += 256; },
+					Type::YUV444 => { draw_macroblock_yuv444(&mut image,k * 8,i * 8,8,8,&coeffs[mb..mb + 192]); mb += 192; },
+					Type::RGB444 => { draw_macroblock_rgb444(&mut image,k * 8,i * 8,8,8,&coeffs[mb..mb + 192]); mb += 192; },
+					Type::CMYK444 => { draw_macroblock_cmyk444(&mut image,k * 8,i * 8,8,8,&coeffs[mb..mb + 256],inverted); mb += 256; },
+					Type::YCCK444 => { draw_macroblock_ycck444(&mut image,k * 8,i * 8,8,8,&coeffs[mb..mb + 256],inverted); mb += 256; },
+					Type::YCCK420 => { draw_macroblock_ycck420(&mut image,k * 16,i * 16,16,16,&coeffs[mb..mb + 640],inverted); mb += 640; },
+				}
+			}
+			match self.itype {
+				Type::Y => { draw_macroblock_y(&mut image,self.mbwidth * 8 - 8,i * 8,self.width - (self.mbwidth - 1) * 8,8,&coeffs[mb..mb + 64]); mb += 64; },
+				Type::YUV420 => { draw_macroblock_yuv420(&mut image,self.mbwidth * 16 - 16,i * 16,self.width - (self.mbwidth - 1) * 16,16,&coeffs[mb..mb + 384]); mb += 384; },
+				Type::YUV422 => { draw_macroblock_yuv422(&mut image,self.mbwidth * 16 - 16,i * 8,self.width - (self.mbwidth - 1) * 16,8,&coeffs[mb..mb + 256]); mb += 256; },
+				Type::YUV440 => { draw_macroblock_yuv440(&mut image,self.mbwidth * 8 - 8,i * 16,self.width - (self.mbwidth - 1) * 8,16,&coeffs[mb..mb + 256]); mb += 256; },
+				Type::YUV444 => { draw_macroblock_yuv444(&mut image,self.mbwidth * 8 - 8,i * 8,self.width - (self.mbwidth - 1) * 8,8,&coeffs[mb..mb + 192]); mb += 192; },
+				Type::RGB444 => { draw_macroblock_rgb444(&mut image,self.mbwidth * 8 - 8,i * 8,self.width - (self.mbwidth - 1) * 8,8,&coeffs[mb..mb + 192]); mb += 192; },
+				Type::CMYK444 => { draw_macroblock_cmyk444(&mut image,self.mbwidth * 8 - 8,i * 8,self.width - (self.mbwidth - 1) * 8,8,&coeffs[mb..mb + 256],inverted); mb += 256; },
+				Type::YCCK444 => { draw_macroblock_ycck444(&mut image,self.mbwidth * 8 - 8,i * 8,self.width - (self.mbwidth - 1) * 8,8,&coeffs[mb..mb + 256],inverted); mb += 256; },
+				Type::YCCK420 => { draw_macroblock_ycck420(&mut image,self.mbwidth * 16 - 16,i * 16,self.width - (self.mbwidth - 1) * 16,16,&coeffs[mb..mb + 640],inverted); mb += 640; },
+			}
+		}
+		for k in 0..self.mbwidth - 1 {
+			match self.itype {
+				Type::Y => { draw_macroblock_y(&mut image,k * 8,self.mbheight * 8 - 8,8,self.height - (self.mbheight - 1) * 8,&coeffs[mb..mb + 64]); mb += 64; },
+				Type::YUV420 => { draw_macroblock_yuv420(&mut image,k * 16,self.mbheight * 16 - 16,16,self.height - (self.mbheight - 1) * 16,&coeffs[mb..mb + 384]); mb += 384; },
+				Type::YUV422 => { draw_macroblock_yuv422(&mut image,k * 16,self.mbheight * 8 - 8,16,self.height - (self.mbheight - 1) * 8,&coeffs[mb..mb + 256]); mb += 256; },
+				Type::YUV440 => { draw_macroblock_yuv440(&mut image,k * 8,self.mbheight * 16 - 16,8,self.height - (self.mbheight - 1) * 16,&coeffs[mb..mb + 256]); mb += 256; },
+				Type::YUV444 => { draw_macroblock_yuv444(&mut image,k * 8,self.mbheight * 8 - 8,8,self.height - (self.mbheight - 1) * 8,&coeffs[mb..mb + 192]); mb += 192; },
+				Type::RGB444 => { draw_macroblock_rgb444(&mut image,k * 8,self.mbheight * 8 - 8,8,self.height - (self.mbheight - 1) * 8,&coeffs[mb..mb + 192]); mb += 192; },
+				Type::CMYK444 => { draw_macroblock_cmyk444(&mut image,k * 8,self.mbheight * 8 - 8,8,self.height - (self.mbheight - 1) * 8,&coeffs[mb..mb + 256],inverted); mb += 256; },
+				Type::YCCK444 => { draw_macroblock_ycck444(&mut image,k * 8,self.mbheight * 8 - 8,8,self.height - (self.mbheight - 1) * 8,&coeffs[mb..mb + 256],inverted); mb += 256; },
+				Type::YCCK420 => { draw_macroblock_ycck420(&mut image,k * 16,self.mbheight * 16 - 16,16,self.height - (self.mbheight - 1) * 16,&coeffs[mb..mb + 640],inverted); mb += 640; },
+			}
+		}
+		match self.itype {
+			Type::Y => { draw_macroblock_y(&mut image,self.mbwidth * 8 - 8,self.mbheight * 8 - 8,self.width - (self.mbwidth - 1) * 8,self.height - (self.mbheight - 1) * 8,&coeffs[mb..mb + 64]); },
+			Type::YUV420 => { draw_macroblock_yuv420(&mut image,self.mbwidth * 16 - 16,self.mbheight * 16 - 16,self.width - (self.mbwidth - 1) * 16,self.height - (self.mbheight - 1) * 16,&coeffs[mb..mb + 384]); },
+			Type::YUV422 => { draw_macroblock_yuv422(&mut image,self.mbwidth * 16 - 16,self.mbheight * 8 - 8,self.width - (self.mbwidth - 1) * 16,self.height - (self.mbheight - 1) * 8,&coeffs[mb..mb + 256]); },
+			Type::YUV440 => { draw_macroblock_yuv440(&mut image,self.mbwidth * 8 - 8,self.mbheight * 16 - 16,self.width - (self.mbwidth - 1) * 8,self.height - (self.mbheight - 1) * 16,&coeffs[mb..mb + 256]); },
+			Type::YUV444 => { draw_macroblock_yuv444(&mut image,self.mbwidth * 8 - 8,self.mbheight * 8 - 8,self.width - (self.mbwidth - 1) * 8,self.height - (self.mbheight - 1) * 8,&coeffs[mb..mb + 192]); },
+			Type::RGB444 => { draw_macroblock_rgb444(&mut image,self.mbwidth * 8 - 8,self.mbheight * 8 - 8,self.width - (self.mbwidth - 1) * 8,self.height - (self.mbheight - 1) * 8,&coeffs[mb..mb + 192]); },
+			Type::CMYK444 => { draw_macroblock_cmyk444(&mut image,self.mbwidth * 8 - 8,self.mbheight * 8 - 8,self.width - (self.mbwidth - 1) * 8,self.height - (self.mbheight - 1) * 8,&coeffs[mb..mb + 256],inverted); },
+			Type::YCCK444 => { draw_macroblock_ycck444(&mut image,self.mbwidth * 8 - 8,self.mbheight * 8 - 8,self.width - (self.mbwidth - 1) * 8,self.height - (self.mbheight - 1) * 8,&coeffs[mb..mb + 256],inverted); },
+			Type::YCCK420 => { draw_macroblock_ycck420(&mut image,self.mbwidth * 16 - 16,self.mbheight * 16 - 16,self.width - (self.mbwidth - 1) * 16,self.height - (self.mbheight - 1) * 16,&coeffs[mb..mb + 640],inverted); },
+		}
+		image
 	}
-	None
 }
 
-pub fn encode<T: pixel::Pixel>(_image: &Mat<T>) -> Option<Vec<u8>> {
-	None
+pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Result<Mat<T>,ImageError> {
+	if (src.len() < 2) || (from_be16(&src[0..2]) != 0xFFD8) {
+		return Err(ImageError::UnknownFormat);
+	}
+	let mut decoder = Decoder::new();
+	let mut sp = 2;
+	while sp + 1 < src.len() {
+		match decoder.segment(src,sp)? {
+			Some(next) => { sp = next; },
+			None => {
+				let mut coeffs = mem::take(&mut decoder.coeffs);
+				return Ok(decoder.render(&mut coeffs));
+			},
+		}
+	}
+	Err(ImageError::Corrupt(src.len(),"missing end of image"))
+}
+
+// incremental reader behind StreamDecoder, decodes a preview after every scan of a progressive image
+pub(crate) struct ScanDecoder<R: Read> {
+	reader: R,
+	buffer: Vec<u8>,  // everything read so far
+	sp: usize,  // next marker
+	decoder: Decoder,  // state after the segments up to sp
+	progressive: bool,
+	scans: usize,
+	done: bool,
+}
+
+impl<R: Read> ScanDecoder<R> {
+	// read the segments up to the first scan
+	pub(crate) fn new(reader: R) -> Result<(ImageInfo,ScanDecoder<R>),ImageError> {
+		let mut decoder = ScanDecoder {
+			reader: reader,
+			buffer: Vec::new(),
+			sp: 2,
+			decoder: Decoder::new(),
+			progressive: false,
+			scans: 0,
+			done: false,
+		};
+		decoder.fill(2)?;
+		if from_be16(&decoder.buffer[0..2]) != 0xFFD8 {
+			return Err(ImageError::UnknownFormat);
+		}
+		loop {
+			let marker = decoder.marker()?;
+			if (marker == 0xFFDA) || (marker == 0xFFD9) {
+				break;
+			}
+			decoder.read_segment()?;
+		}
+		let info = info(&decoder.buffer)?;
+		decoder.progressive = info.progressive;
+		Ok((info,decoder))
+	}
+
+	// make sure the first n bytes are read
+	fn fill(&mut self,n: usize) -> Result<(),ImageError> {
+		let mut chunk = [0u8; 16384];
+		while self.buffer.len() < n {
+			let count = self.reader.read(&mut chunk)?;
+			if count == 0 {
+				return Err(ImageError::Corrupt(self.buffer.len(),"missing end of image"));
+			}
+			self.buffer.extend_from_slice(&chunk[0..count]);
+		}
+		Ok(())
+	}
+
+	fn marker(&mut self) -> Result<u16,ImageError> {
+		self.fill(self.sp + 2)?;
+		Ok(from_be16(&self.buffer[self.sp..self.sp + 2]))
+	}
+
+	fn skip_segment(&mut self) -> Result<(),ImageError> {
+		self.fill(self.sp + 4)?;
+		let length = from_be16(&self.buffer[self.sp + 2..self.sp + 4]) as usize;
+		if length < 2 {
+			return Err(ImageError::Corrupt(self.sp,"truncated segment"));
+		}
+		self.fill(self.sp + 2 + length)?;
+		self.sp += 2 + length;
+		Ok(())
+	}
+
+	// skip the entropy coded data up to the next marker that is not a restart
+	fn skip_scan(&mut self) -> Result<(),ImageError> {
+		loop {
+			self.fill(self.sp + 2)?;
+			let d = self.buffer[self.sp + 1];
+			if (self.buffer[self.sp] == 0xFF) && (d != 0x00) && ((d < 0xD0) || (d >= 0xD8)) {
+				return Ok(());
+			}
+			self.sp += 1;
+		}
+	}
+
+	// read the next segment, with the entropy coded data of a scan, and decode it; returns false at the end of the image
+	fn read_segment(&mut self) -> Result<bool,ImageError> {
+		let start = self.sp;
+
+		// a scan ends at the marker after it, so the decoder needs to see that one too
+		let end = match self.marker()? {
+			0xFFD9 => start + 2,
+			0xFFDA => {
+				self.skip_segment()?;
+				self.skip_scan()?;
+				self.sp + 2
+			},
+			_ => {
+				self.skip_segment()?;
+				self.sp
+			},
+		};
+		match self.decoder.segment(&self.buffer[0..end],start)? {
+			Some(next) => {
+				self.sp = next;
+				Ok(true)
+			},
+			None => Ok(false),
+		}
+	}
+
+	// read up to the end of the next scan
+	pub(crate) fn advance<T: pixel::Pixel>(&mut self,image: &mut Mat<T>) -> Result<Progress,ImageError> {
+		if self.done {
+			return Ok(Progress::Done);
+		}
+		loop {
+			let scan = self.marker()? == 0xFFDA;
+			if !self.read_segment()? {
+				let mut coeffs = mem::take(&mut self.decoder.coeffs);
+				*image = self.decoder.render(&mut coeffs);
+				self.done = true;
+				return Ok(Progress::Done);
+			}
+
+			// show the coefficients so far, unless the image ends here
+			if scan && self.progressive && (self.marker()? != 0xFFD9) {
+				let mut coeffs = self.decoder.coeffs.clone();
+				*image = self.decoder.render(&mut coeffs);
+				self.scans += 1;
+				return Ok(Progress::Pass(self.scans - 1));
+			}
+		}
+	}
+}
+
+// natural order index of each zigzag position
+const ZIGZAG: [usize; 64] = [
+	0,1,8,16,9,2,3,10,
+	17,24,32,25,18,11,4,5,
+	12,19,26,33,40,48,41,34,
+	27,20,13,6,7,14,21,28,
+	35,42,49,56,57,50,43,36,
+	29,22,15,23,30,37,44,51,
+	58,59,52,45,38,31,39,46,
+	53,60,61,54,47,55,62,63,
+];
+
+// quantization tables from Annex K, in natural order
+const LUMINANCE_QUANTIZATION: [u8; 64] = [
+	16,11,10,16,24,40,51,61,
+	12,12,14,19,26,58,60,55,
+	14,13,16,24,40,57,69,56,
+	14,17,22,29,51,87,80,62,
+	18,22,37,56,68,109,103,77,
+	24,35,55,64,81,104,113,92,
+	49,64,78,87,103,121,120,101,
+	72,92,95,98,112,100,103,99,
+];
+
+const CHROMINANCE_QUANTIZATION: [u8; 64] = [
+	17,18,24,47,99,99,99,99,
+	18,21,26,66,99,99,99,99,
+	24,26,56,99,99,99,99,99,
+	47,66,99,99,99,99,99,99,
+	99,99,99,99,99,99,99,99,
+	99,99,99,99,99,99,99,99,
+	99,99,99,99,99,99,99,99,
+	99,99,99,99,99,99,99,99,
+];
+
+// Huffman tables from Annex K
+const DC_LUMINANCE_BITS: [u8; 16] = [0,1,5,1,1,1,1,1,1,0,0,0,0,0,0,0];
+const DC_CHROMINANCE_BITS: [u8; 16] = [0,3,1,1,1,1,1,1,1,1,1,0,0,0,0,0];
+const DC_VALUES: [u8; 12] = [0,1,2,3,4,5,6,7,8,9,10,11];
+
+const AC_LUMINANCE_BITS: [u8; 16] = [0,2,1,3,3,2,4,3,5,5,4,4,0,0,1,0x7D];
+const AC_LUMINANCE_VALUES: [u8; 162] = [
+	0x01,0x02,0x03,0x00,0x04,0x11,0x05,0x12,0x21,0x31,0x41,0x06,0x13,0x51,0x61,0x07,
+	0x22,0x71,0x14,0x32,0x81,0x91,0xA1,0x08,0x23,0x42,0xB1,0xC1,0x15,0x52,0xD1,0xF0,
+	0x24,0x33,0x62,0x72,0x82,0x09,0x0A,0x16,0x17,0x18,0x19,0x1A,0x25,0x26,0x27,0x28,
+	0x29,0x2A,0x34,0x35,0x36,0x37,0x38,0x39,0x3A,0x43,0x44,0x45,0x46,0x47,0x48,0x49,
+	0x4A,0x53,0x54,0x55,0x56,0x57,0x58,0x59,0x5A,0x63,0x64,0x65,0x66,0x67,0x68,0x69,
+	0x6A,0x73,0x74,0x75,0x76,0x77,0x78,0x79,0x7A,0x83,0x84,0x85,0x86,0x87,0x88,0x89,
+	0x8A,0x92,0x93,0x94,0x95,0x96,0x97,0x98,0x99,0x9A,0xA2,0xA3,0xA4,0xA5,0xA6,0xA7,
+	0xA8,0xA9,0xAA,0xB2,0xB3,0xB4,0xB5,0xB6,0xB7,0xB8,0xB9,0xBA,0xC2,0xC3,0xC4,0xC5,
+	0xC6,0xC7,0xC8,0xC9,0xCA,0xD2,0xD3,0xD4,0xD5,0xD6,0xD7,0xD8,0xD9,0xDA,0xE1,0xE2,
+	0xE3,0xE4,0xE5,0xE6,0xE7,0xE8,0xE9,0xEA,0xF1,0xF2,0xF3,0xF4,0xF5,0xF6,0xF7,0xF8,
+	0xF9,0xFA,
+];
+
+const AC_CHROMINANCE_BITS: [u8; 16] = [0,2,1,2,4,4,3,4,7,5,4,4,0,1,2,0x77];
+const AC_CHROMINANCE_VALUES: [u8; 162] = [
+	0x00,0x01,0x02,0x03,0x11,0x04,0x05,0x21,0x31,0x06,0x12,0x41,0x51,0x07,0x61,0x71,
+	0x13,0x22,0x32,0x81,0x08,0x14,0x42,0x91,0xA1,0xB1,0xC1,0x09,0x23,0x33,0x52,0xF0,
+	0x15,0x62,0x72,0xD1,0x0A,0x16,0x24,0x34,0xE1,0x25,0xF1,0x17,0x18,0x19,0x1A,0x26,
+	0x27,0x28,0x29,0x2A,0x35,0x36,0x37,0x38,0x39,0x3A,0x43,0x44,0x45,0x46,0x47,0x48,
+	0x49,0x4A,0x53,0x54,0x55,0x56,0x57,0x58,0x59,0x5A,0x63,0x64,0x65,0x66,0x67,0x68,
+	0x69,0x6A,0x73,0x74,0x75,0x76,0x77,0x78,0x79,0x7A,0x82,0x83,0x84,0x85,0x86,0x87,
+	0x88,0x89,0x8A,0x92,0x93,0x94,0x95,0x96,0x97,0x98,0x99,0x9A,0xA2,0xA3,0xA4,0xA5,
+	0xA6,0xA7,0xA8,0xA9,0xAA,0xB2,0xB3,0xB4,0xB5,0xB6,0xB7,0xB8,0xB9,0xBA,0xC2,0xC3,
+	0xC4,0xC5,0xC6,0xC7,0xC8,0xC9,0xCA,0xD2,0xD3,0xD4,0xD5,0xD6,0xD7,0xD8,0xD9,0xDA,
+	0xE2,0xE3,0xE4,0xE5,0xE6,0xE7,0xE8,0xE9,0xEA,0xF2,0xF3,0xF4,0xF5,0xF6,0xF7,0xF8,
+	0xF9,0xFA,
+];
+
+/// Chroma subsampling of an encoded image.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Subsampling {
+	/// Chroma at full resolution.
+	YUV444,
+	/// Chroma at half horizontal resolution.
+	YUV422,
+	/// Chroma at half horizontal and half vertical resolution.
+	YUV420,
+}
+
+/// JPEG encoder settings.
+#[derive(Copy,Clone,Debug)]
+pub struct EncodeOptions {
+	/// Quality from 1 (smallest) to 100 (best), scales the standard quantization tables.
+	pub quality: u32,
+	/// Chroma subsampling.
+	pub subsampling: Subsampling,
+	/// Build Huffman tables from the image statistics instead of using the standard tables.
+	pub optimize_huffman: bool,
+	/// Write the DC coefficients and the AC bands as separate scans. Progressive images always use optimized Huffman tables.
+	pub progressive: bool,
+	/// Number of MCUs between restart markers, 0 for no restart markers.
+	pub restart_interval: u16,
+}
+
+impl Default for EncodeOptions {
+	fn default() -> EncodeOptions {
+		EncodeOptions {
+			quality: 85,
+			subsampling: Subsampling::YUV420,
+			optimize_huffman: false,
+			progressive: false,
+			restart_interval: 0,
+		}
+	}
+}
+
+// scale a standard quantization table to quality, like the IJG encoder does
+fn scale_quantization(table: &[u8; 64],quality: u32) -> [i32; 64] {
+	let quality = if quality < 1 { 1 } else if quality > 100 { 100 } else { quality as i32 };
+	let scale = if quality < 50 { 5000 / quality } else { 200 - quality * 2 };
+	let mut result = [0i32; 64];
+	for i in 0..64 {
+		let q = ((table[i] as i32) * scale + 50) / 100;
+		result[i] = if q < 1 { 1 } else if q > 255 { 255 } else { q };
+	}
+	result
+}
+
+// Huffman table as stored in DHT
+struct HuffmanSpec {
+	bits: [u8; 16],
+	values: Vec<u8>,
+}
+
+#[derive(Copy,Clone)]
+struct HuffmanCodes {
+	code: [u16; 256],
+	length: [u8; 256],
+}
+
+impl HuffmanCodes {
+	fn new(spec: &HuffmanSpec) -> HuffmanCodes {
+		let mut codes = HuffmanCodes {
+			code: [0u16; 256],
+			length: [0u8; 256],
+		};
+		let mut code = 0u16;
+		let mut k = 0;
+		for i in 0..16 {
+			for _j in 0..spec.bits[i] {
+				codes.code[spec.values[k] as usize] = code;
+				codes.length[spec.values[k] as usize] = (i + 1) as u8;
+				code += 1;
+				k += 1;
+			}
+			code <<= 1;
+		}
+		codes
+	}
+}
+
+// optimal table for the symbol frequencies, limited to 16 bits and without an all-ones code (Annex K.2)
+fn optimal_huffman(freqs: &[u32; 256]) -> HuffmanSpec {
+	let mut freq = [0u64; 257];
+	for i in 0..256 {
+		freq[i] = freqs[i] as u64;
+	}
+	freq[256] = 1;  // reserved, takes the all-ones code
+	let mut codesize = [0usize; 257];
+	let mut others = [usize::MAX; 257];
+	loop {
+		// two least frequent symbols, ties go to the higher symbol
+		let mut c1 = usize::MAX;
+		let mut v = u64::MAX;
+		for i in 0..257 {
+			if (freq[i] != 0) && (freq[i] <= v) {
+				v = freq[i];
+				c1 = i;
+			}
+		}
+		let mut c2 = usize::MAX;
+		v = u64::MAX;
+		for i in 0..257 {
+			if (freq[i] != 0) && (freq[i] <= v) && (i != c1) {
+				v = freq[i];
+				c2 = i;
+			}
+		}
+		if c2 == usize::MAX {
+			break;
+		}
+		freq[c1] += freq[c2];
+		freq[c2] = 0;
+		codesize[c1] += 1;
+		while others[c1] != usize::MAX {
+			c1 = others[c1];
+			codesize[c1] += 1;
+		}
+		others[c1] = c2;
+		codesize[c2] += 1;
+		while others[c2] != usize::MAX {
+			c2 = others[c2];
+			codesize[c2] += 1;
+		}
+	}
+	let mut bits = [0usize; 33];
+	for i in 0..257 {
+		if codesize[i] != 0 {
+			bits[codesize[i]] += 1;
+		}
+	}
+
+	// move codes longer than 16 bits up the tree
+	for i in (17..33).rev() {
+		while bits[i] > 0 {
+			let mut j = i - 2;
+			while bits[j] == 0 {
+				j -= 1;
+			}
+			bits[i] -= 2;
+			bits[i - 1] += 1;
+			bits[j + 1] += 2;
+			bits[j] -= 1;
+		}
+	}
+
+	// drop the reserved code
+	let mut i = 16;
+	while bits[i] == 0 {
+		i -= 1;
+	}
+	bits[i] -= 1;
+
+	let mut spec = HuffmanSpec {
+		bits: [0u8; 16],
+		values: Vec::new(),
+	};
+	for i in 0..16 {
+		spec.bits[i] = bits[i + 1] as u8;
+	}
+	for i in 1..33 {
+		for j in 0..256 {
+			if codesize[j] == i {
+				spec.values.push(j as u8);
+			}
+		}
+	}
+	spec
+}
+
+// receives the entropy coded symbols of a scan, either to gather statistics or to write them
+trait Sink {
+	fn symbol(&mut self,dc: bool,table: usize,symbol: u8);
+	fn bits(&mut self,value: u32,n: u32);
+	fn restart(&mut self,index: usize);
+}
+
+struct Counter {
+	dc: [[u32; 256]; 2],
+	ac: [[u32; 256]; 2],
+}
+
+impl Sink for Counter {
+	fn symbol(&mut self,dc: bool,table: usize,symbol: u8) {
+		if dc {
+			self.dc[table][symbol as usize] += 1;
+		}
+		else {
+			self.ac[table][symbol as usize] += 1;
+		}
+	}
+
+	fn bits(&mut self,_value: u32,_n: u32) {
+	}
+
+	fn restart(&mut self,_index: usize) {
+	}
+}
+
+// MSB-first bit writer with byte stuffing
+struct Writer {
+	data: Vec<u8>,
+	cache: u32,
+	bit: u32,
+	dc: [HuffmanCodes; 2],
+	ac: [HuffmanCodes; 2],
+}
+
+impl Writer {
+	fn flush(&mut self) {
+		if self.bit > 0 {
+			let n = 8 - self.bit;
+			self.bits((1 << n) - 1,n);
+		}
+	}
+}
+
+impl Sink for Writer {
+	fn symbol(&mut self,dc: bool,table: usize,symbol: u8) {
+		let codes = if dc { &self.dc[table] } else { &self.ac[table] };
+		let code = codes.code[symbol as usize] as u32;
+		let length = codes.length[symbol as usize] as u32;
+		self.bits(code,length);
+	}
+
+	fn bits(&mut self,value: u32,n: u32) {
+		self.cache = (self.cache << n) | (value & ((1 << n) - 1));
+		self.bit += n;
+		while self.bit >= 8 {
+			let b = ((self.cache >> (self.bit - 8)) & 255) as u8;
+			self.data.push(b);
+			if b == 0xFF {
+				self.data.push(0x00);
+			}
+			self.bit -= 8;
+		}
+		self.cache &= (1 << self.bit) - 1;
+	}
+
+	fn restart(&mut self,index: usize) {
+		self.flush();
+		self.data.push(0xFF);
+		self.data.push(0xD0 + (index & 7) as u8);
+	}
+}
+
+// quantized blocks of one component
+struct Plane {
+	h: usize,
+	v: usize,
+	table: usize,
+	stride: usize,  // blocks per row, covering all MCUs
+	blocks_x: usize,  // blocks per row covering the component
+	blocks_y: usize,
+	blocks: Vec<[i32; 64]>,
+}
+
+struct Scan {
+	components: Vec<usize>,
+	start: usize,
+	end: usize,
+}
+
+// category and extra bits of a coefficient
+fn magnitude(value: i32) -> (u32,u32) {
+	if value == 0 {
+		return (0,0);
+	}
+	let a = value.abs() as u32;
+	let cat = 32 - a.leading_zeros();
+	let bits = if value < 0 { ((value - 1) as u32) & ((1 << cat) - 1) } else { value as u32 };
+	(cat,bits)
+}
+
+fn flush_eobrun<S: Sink>(sink: &mut S,table: usize,eobrun: &mut u32) {
+	if *eobrun > 0 {
+		let n = 31 - eobrun.leading_zeros();
+		sink.symbol(false,table,(n << 4) as u8);
+		if n > 0 {
+			sink.bits(*eobrun,n);
+		}
+		*eobrun = 0;
+	}
+}
+
+// code coefficients start..=end of a block; baseline scans code every end of block right away
+fn encode_block<S: Sink>(sink: &mut S,block: &[i32; 64],table: usize,start: usize,end: usize,pred: &mut i32,eobrun: &mut u32,progressive: bool) {
+	if start == 0 {
+		let (cat,bits) = magnitude(block[0] - *pred);
+		*pred = block[0];
+		sink.symbol(true,table,cat as u8);
+		if cat > 0 {
+			sink.bits(bits,cat);
+		}
+	}
+	if end == 0 {
+		return;
+	}
+	let mut run = 0;
+	for k in if start == 0 { 1 } else { start }..end + 1 {
+		let value = block[ZIGZAG[k]];
+		if value == 0 {
+			run += 1;
+		}
+		else {
+			flush_eobrun(sink,table,eobrun);
+			while run > 15 {
+				sink.symbol(false,table,0xF0);
+				run -= 16;
+			}
+			let (cat,bits) = magnitude(value);
+			sink.symbol(false,table,((run << 4) | cat) as u8);
+			sink.bits(bits,cat);
+			run = 0;
+		}
+	}
+	if run > 0 {
+		*eobrun += 1;
+		if !progressive || (*eobrun == 0x7FFF) {
+			flush_eobrun(sink,table,eobrun);
+		}
+	}
+}
+
+fn encode_scan<S: Sink>(sink: &mut S,planes: &[Plane],scan: &Scan,mbwidth: usize,mbheight: usize,restart_interval: usize,progressive: bool) {
+	let mut pred = [0i32; 3];
+	let mut eobrun = 0u32;
+	let mut count = 0;
+	let last_table = planes[scan.components[0]].table;
+	if scan.components.len() > 1 {
+		for my in 0..mbheight {
+			for mx in 0..mbwidth {
+				if (restart_interval != 0) && (count != 0) && ((count % restart_interval) == 0) {
+					sink.restart(count / restart_interval - 1);
+					pred = [0i32; 3];
+				}
+				for &c in scan.components.iter() {
+					let plane = &planes[c];
+					for v in 0..plane.v {
+						for h in 0..plane.h {
+							let block = &plane.blocks[(my * plane.v + v) * plane.stride + mx * plane.h + h];
+							encode_block(sink,block,plane.table,scan.start,scan.end,&mut pred[c],&mut eobrun,progressive);
+						}
+					}
+				}
+				count += 1;
+			}
+		}
+	}
+	else {
+		let c = scan.components[0];
+		let plane = &planes[c];
+		for by in 0..plane.blocks_y {
+			for bx in 0..plane.blocks_x {
+				if (restart_interval != 0) && (count != 0) && ((count % restart_interval) == 0) {
+					flush_eobrun(sink,plane.table,&mut eobrun);
+					sink.restart(count / restart_interval - 1);
+					pred = [0i32; 3];
+				}
+				encode_block(sink,&plane.blocks[by * plane.stride + bx],plane.table,scan.start,scan.end,&mut pred[c],&mut eobrun,progressive);
+				count += 1;
+			}
+		}
+	}
+	flush_eobrun(sink,last_table,&mut eobrun);
+}
+
+fn push_marker(dst: &mut Vec<u8>,marker: u8,data: &[u8]) {
+	dst.push(0xFF);
+	dst.push(marker);
+	dst.push(((data.len() + 2) >> 8) as u8);
+	dst.push(((data.len() + 2) & 255) as u8);
+	dst.extend_from_slice(data);
+}
+
+fn push_huffman(data: &mut Vec<u8>,class: u8,table: usize,spec: &HuffmanSpec) {
+	data.push((class << 4) | (table as u8));
+	data.extend_from_slice(&spec.bits);
+	data.extend_from_slice(&spec.values);
+}
+
+pub fn encode<T: pixel::Pixel>(image: &Mat<T>) -> Result<Vec<u8>,ImageError> {
+	encode_with_options(image,&EncodeOptions::default())
+}
+
+pub fn encode_with_options<T: pixel::Pixel>(image: &Mat<T>,options: &EncodeOptions) -> Result<Vec<u8>,ImageError> {
+	let width = image.size.x;
+	let height = image.size.y;
+	if (width == 0) || (height == 0) || (width >= 65536) || (height >= 65536) {
+		return Err(ImageError::Dimensions(width,height));
+	}
+	let (hmax,vmax) = match options.subsampling {
+		Subsampling::YUV444 => (1,1),
+		Subsampling::YUV422 => (2,1),
+		Subsampling::YUV420 => (2,2),
+	};
+	let mbwidth = (width + hmax * 8 - 1) / (hmax * 8);
+	let mbheight = (height + vmax * 8 - 1) / (vmax * 8);
+	let pwidth = mbwidth * hmax * 8;
+	let pheight = mbheight * vmax * 8;
+
+	// convert to YCbCr, replicating the edges into the padding
+	let mut planes_yuv = vec![vec![0f32; pwidth * pheight]; 3];
+	for y in 0..pheight {
+		for x in 0..pwidth {
+			let sx = if x < width { x } else { width - 1 };
+			let sy = if y < height { y } else { height - 1 };
+			let (r,g,b,_) = image[(sx,sy)].getf();
+
+			// float formats can go outside 0..1, which would give coefficients the Huffman tables have no codes for
+			let r = if r > 1.0 { 255.0 } else if r > 0.0 { r * 255.0 } else { 0.0 };
+			let g = if g > 1.0 { 255.0 } else if g > 0.0 { g * 255.0 } else { 0.0 };
+			let b = if b > 1.0 { 255.0 } else if b > 0.0 { b * 255.0 } else { 0.0 };
+			planes_yuv[0][y * pwidth + x] = 0.299 * r + 0.587 * g + 0.114 * b - 128.0;
+			planes_yuv[1][y * pwidth + x] = -0.168736 * r - 0.331264 * g + 0.5 * b;
+			planes_yuv[2][y * pwidth + x] = 0.5 * r - 0.418688 * g - 0.081312 * b;
+		}
+	}
+
+	// DCT basis, including the normalization
+	let mut basis = [[0f32; 8]; 8];
+	for u in 0..8 {
+		for x in 0..8 {
+			let c = if u == 0 { 0.5 / 2.0f32.sqrt() } else { 0.5 };
+			basis[u][x] = c * ((((2 * x + 1) * u) as f32) * std::f32::consts::PI / 16.0).cos();
+		}
+	}
+
+	// transform and quantize
+	let qtables = [scale_quantization(&LUMINANCE_QUANTIZATION,options.quality),scale_quantization(&CHROMINANCE_QUANTIZATION,options.quality)];
+	let mut planes: Vec<Plane> = Vec::new();
+	for c in 0..3 {
+		let (h,v,sx,sy) = if c == 0 { (hmax,vmax,1,1) } else { (1,1,hmax,vmax) };
+		let stride = mbwidth * h;
+		let rows = mbheight * v;
+		let cwidth = (width + sx - 1) / sx;
+		let cheight = (height + sy - 1) / sy;
+		let mut plane = Plane {
+			h: h,
+			v: v,
+			table: if c == 0 { 0 } else { 1 },
+			stride: stride,
+			blocks_x: (cwidth + 7) / 8,
+			blocks_y: (cheight + 7) / 8,
+			blocks: Vec::with_capacity(stride * rows),
+		};
+		let qtable = &qtables[plane.table];
+		for by in 0..rows {
+			for bx in 0..stride {
+
+				// fetch block, averaging subsampled chroma
+				let mut samples = [0f32; 64];
+				for i in 0..8 {
+					for k in 0..8 {
+						let mut sum = 0.0;
+						for j in 0..sy {
+							for l in 0..sx {
+								sum += planes_yuv[c][((by * 8 + i) * sy + j) * pwidth + (bx * 8 + k) * sx + l];
+							}
+						}
+						samples[i * 8 + k] = sum / ((sx * sy) as f32);
+					}
+				}
+
+				// separable DCT
+				let mut temp = [0f32; 64];
+				for i in 0..8 {
+					for u in 0..8 {
+						let mut sum = 0.0;
+						for k in 0..8 {
+							sum += basis[u][k] * samples[i * 8 + k];
+						}
+						temp[i * 8 + u] = sum;
+					}
+				}
+				let mut block = [0i32; 64];
+				for u in 0..8 {
+					for v in 0..8 {
+						let mut sum = 0.0;
+						for i in 0..8 {
+							sum += basis[v][i] * temp[i * 8 + u];
+						}
+						block[v * 8 + u] = (sum / (qtable[v * 8 + u] as f32)).round() as i32;
+					}
+				}
+				plane.blocks.push(block);
+			}
+		}
+		planes.push(plane);
+	}
+
+	// scans
+	let progressive = options.progressive;
+	let optimize = options.optimize_huffman || progressive;
+	let scans = if progressive {
+		vec![
+			Scan { components: vec![0,1,2],start: 0,end: 0, },
+			Scan { components: vec![0],start: 1,end: 5, },
+			Scan { components: vec![1],start: 1,end: 63, },
+			Scan { components: vec![2],start: 1,end: 63, },
+			Scan { components: vec![0],start: 6,end: 63, },
+		]
+	}
+	else {
+		vec![Scan { components: vec![0,1,2],start: 0,end: 63, }]
+	};
+
+	// headers
+	let mut dst: Vec<u8> = vec![0xFF,0xD8];
+	push_marker(&mut dst,0xE0,&[0x4A,0x46,0x49,0x46,0x00,0x01,0x01,0x00,0x00,0x01,0x00,0x01,0x00,0x00]);  // JFIF
+	let mut data: Vec<u8> = Vec::new();
+	for i in 0..2 {
+		data.push(i as u8);
+		for k in 0..64 {
+			data.push(qtables[i][ZIGZAG[k]] as u8);
+		}
+	}
+	push_marker(&mut dst,0xDB,&data);
+	let mut data: Vec<u8> = vec![8,(height >> 8) as u8,(height & 255) as u8,(width >> 8) as u8,(width & 255) as u8,3];
+	for c in 0..3 {
+		data.push((c + 1) as u8);
+		data.push(((planes[c].h << 4) | planes[c].v) as u8);
+		data.push(planes[c].table as u8);
+	}
+	push_marker(&mut dst,if progressive { 0xC2 } else { 0xC0 },&data);
+	let mut writer = Writer {
+		data: dst,
+		cache: 0,
+		bit: 0,
+		dc: [HuffmanCodes { code: [0u16; 256],length: [0u8; 256], }; 2],
+		ac: [HuffmanCodes { code: [0u16; 256],length: [0u8; 256], }; 2],
+	};
+	if !optimize {
+		let dc = [
+			HuffmanSpec { bits: DC_LUMINANCE_BITS,values: DC_VALUES.to_vec(), },
+			HuffmanSpec { bits: DC_CHROMINANCE_BITS,values: DC_VALUES.to_vec(), },
+		];
+		let ac = [
+			HuffmanSpec { bits: AC_LUMINANCE_BITS,values: AC_LUMINANCE_VALUES.to_vec(), },
+			HuffmanSpec { bits: AC_CHROMINANCE_BITS,values: AC_CHROMINANCE_VALUES.to_vec(), },
+		];
+		let mut data: Vec<u8> = Vec::new();
+		for i in 0..2 {
+			push_huffman(&mut data,0,i,&dc[i]);
+			push_huffman(&mut data,1,i,&ac[i]);
+			writer.dc[i] = HuffmanCodes::new(&dc[i]);
+			writer.ac[i] = HuffmanCodes::new(&ac[i]);
+		}
+		push_marker(&mut writer.data,0xC4,&data);
+	}
+	let restart_interval = options.restart_interval as usize;
+	if restart_interval != 0 {
+		push_marker(&mut writer.data,0xDD,&[(restart_interval >> 8) as u8,(restart_interval & 255) as u8]);
+	}
+
+	for scan in scans.iter() {
+		if optimize {
+			// gather statistics and write the tables this scan needs
+			let mut counter = Counter {
+				dc: [[0u32; 256]; 2],
+				ac: [[0u32; 256]; 2],
+			};
+			encode_scan(&mut counter,&planes,scan,mbwidth,mbheight,restart_interval,progressive);
+			let mut data: Vec<u8> = Vec::new();
+			for i in 0..2 {
+				if counter.dc[i].iter().any(|&f| f != 0) {
+					let spec = optimal_huffman(&counter.dc[i]);
+					push_huffman(&mut data,0,i,&spec);
+					writer.dc[i] = HuffmanCodes::new(&spec);
+				}
+				if counter.ac[i].iter().any(|&f| f != 0) {
+					let spec = optimal_huffman(&counter.ac[i]);
+					push_huffman(&mut data,1,i,&spec);
+					writer.ac[i] = HuffmanCodes::new(&spec);
+				}
+			}
+			push_marker(&mut writer.data,0xC4,&data);
+		}
+		let mut data: Vec<u8> = vec![scan.components.len() as u8];
+		for &c in scan.components.iter() {
+			data.push((c + 1) as u8);
+			data.push(((planes[c].table << 4) | planes[c].table) as u8);
+		}
+		data.push(scan.start as u8);
+		data.push(scan.end as u8);
+		data.push(0);
+		push_marker(&mut writer.data,0xDA,&data);
+		encode_scan(&mut writer,&planes,scan,mbwidth,mbheight,restart_interval,progressive);
+		writer.flush();
+	}
+	let mut dst = writer.data;
+	dst.push(0xFF);
+	dst.push(0xD9);
+	Ok(dst)
+}
+
+#[cfg(test)]
+mod tests {
+	use {
+		super::*,
+		pixel::Pixel,
+	};
+
+	// 24x13 Adobe CMYK and YCCK from libjpeg, flat C, M and Y per 16x16 macroblock and flat K per 8x8 block, see INKS
+	const CMYK: [u8; 207] = [
+		0xFF,0xD8,0xFF,0xEE,0x00,0x0E,0x41,0x64,0x6F,0x62,0x65,0x00,0x64,0x00,0x00,0x00,
+		0x00,0x00,0xFF,0xDB,0x00,0x43,0x00,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,
+		0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,
+		0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,
+		0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,
+		0x01,0x01,0x01,0x01,0x01,0x01,0x01,0xFF,0xC0,0x00,0x14,0x08,0x00,0x0D,0x00,0x18,
+		0x04,0x43,0x11,0x00,0x4D,0x11,0x00,0x59,0x11,0x00,0x4B,0x11,0x00,0xFF,0xC4,0x00,
+		0x17,0x00,0x00,0x03,0x01,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
+		0x00,0x00,0x00,0x0A,0x0B,0x09,0xFF,0xC4,0x00,0x14,0x10,0x01,0x00,0x00,0x00,0x00,
+		0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0xFF,0xDA,0x00,0x0E,
+		0x04,0x43,0x00,0x4D,0x00,0x59,0x00,0x4B,0x00,0x00,0x3F,0x00,0x7F,0x80,0x4F,0xF3,
+		0xFC,0x00,0x18,0x0E,0x90,0x39,0x90,0x16,0xF8,0x2F,0xFA,0xBF,0x05,0xBF,0x44,0x1C,
+		0xE0,0x00,0x04,0xFF,0x00,0xD2,0x07,0x32,0x02,0xDF,0x0A,0x7C,0x3F,0xFF,0xD9,
+	];
+
+	const YCCK444: [u8; 323] = [
+		0xFF,0xD8,0xFF,0xEE,0x00,0x0E,0x41,0x64,0x6F,0x62,0x65,0x00,0x64,0x00,0x00,0x00,
+		0x00,0x02,0xFF,0xDB,0x00,0x43,0x00,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,
+		0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,
+		0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,
+		0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,
+		0x01,0x01,0x01,0x01,0x01,0x01,0x01,0xFF,0xDB,0x00,0x43,0x01,0x01,0x01,0x01,0x01,
+		0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,
+		0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,
+		0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,
+		0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0xFF,0xC0,0x00,0x14,
+		0x08,0x00,0x0D,0x00,0x18,0x04,0x01,0x11,0x00,0x02,0x11,0x01,0x03,0x11,0x01,0x04,
+		0x11,0x00,0xFF,0xC4,0x00,0x19,0x00,0x00,0x02,0x03,0x01,0x00,0x00,0x00,0x00,0x00,
+		0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x07,0x0A,0x00,0x08,0x0B,0x09,0xFF,0xC4,0x00,
+		0x14,0x10,0x01,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
+		0x00,0x00,0x00,0xFF,0xC4,0x00,0x16,0x01,0x01,0x01,0x01,0x00,0x00,0x00,0x00,0x00,
+		0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x0A,0x00,0x0B,0xFF,0xC4,0x00,0x14,0x11,
+		0x01,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
+		0x00,0xFF,0xDA,0x00,0x0E,0x04,0x01,0x00,0x02,0x11,0x03,0x11,0x04,0x00,0x00,0x3F,
+		0x00,0xA3,0xE4,0x50,0x38,0xE7,0xF8,0x44,0x9C,0x07,0x09,0xC1,0x1D,0xA3,0xC1,0x7F,
+		0xC5,0x83,0xB8,0x67,0x0E,0x70,0x04,0x49,0x9F,0xF8,0x4E,0x08,0xED,0x1E,0x1A,0x7C,
+		0x3F,0xFF,0xD9,
+	];
+
+	// sampling 0x22,0x11,0x11,0x22
+	const YCCK420: [u8; 314] = [
+		0xFF,0xD8,0xFF,0xEE,0x00,0x0E,0x41,0x64,0x6F,0x62,0x65,0x00,0x64,0x00,0x00,0x00,
+		0x00,0x02,0xFF,0xDB,0x00,0x43,0x00,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,
+		0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,
+		0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,
+		0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,
+		0x01,0x01,0x01,0x01,0x01,0x01,0x01,0xFF,0xDB,0x00,0x43,0x01,0x01,0x01,0x01,0x01,
+		0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,
+		0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,
+		0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,
+		0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0x01,0xFF,0xC0,0x00,0x14,
+		0x08,0x00,0x0D,0x00,0x18,0x04,0x01,0x22,0x00,0x02,0x11,0x01,0x03,0x11,0x01,0x04,
+		0x22,0x00,0xFF,0xC4,0x00,0x19,0x00,0x01,0x00,0x02,0x03,0x00,0x00,0x00,0x00,0x00,
+		0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x09,0x0A,0x07,0x08,0x0B,0xFF,0xC4,0x00,
+		0x14,0x10,0x01,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
+		0x00,0x00,0x00,0xFF,0xC4,0x00,0x15,0x01,0x01,0x01,0x00,0x00,0x00,0x00,0x00,0x00,
+		0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x0A,0x0B,0xFF,0xC4,0x00,0x14,0x11,0x01,
+		0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
+		0xFF,0xDA,0x00,0x0E,0x04,0x01,0x00,0x02,0x11,0x03,0x11,0x04,0x00,0x00,0x3F,0x00,
+		0xD1,0xF0,0x08,0xA0,0x71,0xD7,0xF8,0x40,0x3A,0x40,0x1C,0xFF,0x00,0xD8,0x9C,0x00,
+		0x47,0x51,0xE1,0x20,0x02,0xFF,0x00,0x03,0xFF,0xD9,
+	];
+
+	// CMYK of each 8x8 block of the fixtures, as stored with the Adobe segment (255 is no ink)
+	const INKS: [[(i32,i32,i32,i32); 3]; 2] = [
+		[(255,128,32,255),(255,128,32,192),(64,200,255,128)],
+		[(255,128,32,224),(255,128,32,96),(64,200,255,255)],
+	];
+
+	fn check_blocks(image: &Mat<pixel::RGB8UN>,adobe: bool) {
+		assert_eq!(image.size,vec2!(24,13));
+		for y in 0..13 {
+			for x in 0..24 {
+				let (r,g,b,_) = image[(x,y)].get();
+				let (c,m,ye,k) = INKS[y / 8][x / 8];
+				let (c,m,ye,k) = if adobe { (c,m,ye,k) } else { (255 - c,255 - m,255 - ye,255 - k) };
+				let (er,eg,eb) = (c * k / 255,m * k / 255,ye * k / 255);
+				assert!(((r as i32 - er).abs() <= 2) && ((g as i32 - eg).abs() <= 2) && ((b as i32 - eb).abs() <= 2),"({},{}): ({},{},{}) instead of ({},{},{})",x,y,r,g,b,er,eg,eb);
+			}
+		}
+	}
+
+	#[test]
+	fn decode_cmyk() {
+		check_blocks(&decode::<pixel::RGB8UN>(&CMYK).unwrap(),true);
+		check_blocks(&decode::<pixel::RGB8UN>(&YCCK444).unwrap(),true);
+		check_blocks(&decode::<pixel::RGB8UN>(&YCCK420).unwrap(),true);
+		assert_eq!(info(&YCCK420).unwrap().channels,ChannelLayout::Cmyk);
+
+		// without the Adobe segment, 0 is no ink
+		let mut data = CMYK.to_vec();
+		data.drain(2..18);
+		check_blocks(&decode::<pixel::RGB8UN>(&data).unwrap(),false);
+
+		// 4:2:0 needs the YCCK transform
+		let mut data = YCCK420.to_vec();
+		data[17] = 0;
+		assert!(matches!(decode::<pixel::RGB8UN>(&data),Err(ImageError::Unsupported(_))));
+	}
+
+	fn gradient() -> Mat<pixel::RGB8UN> {
+		let mut image = Mat::<pixel::RGB8UN>::new(vec2!(37,21));
+		for y in 0..21 {
+			for x in 0..37 {
+				image[(x,y)].set((x * 6) as u8,(y * 12) as u8,128,255);
+			}
+		}
+		image
+	}
+
+	#[test]
+	fn roundtrip() {
+		let image = gradient();
+		for progressive in [false,true] {
+			let options = EncodeOptions { quality: 95,progressive: progressive,..EncodeOptions::default() };
+			let decoded = decode::<pixel::RGB8UN>(&encode_with_options(&image,&options).unwrap()).unwrap();
+			assert_eq!(decoded.size,image.size);
+			for (a,b) in image.data().iter().zip(decoded.data().iter()) {
+				let (a,b) = (a.get(),b.get());
+				assert!(((a.0 as i32) - (b.0 as i32)).abs() <= 12);
+				assert!(((a.1 as i32) - (b.1 as i32)).abs() <= 12);
+				assert!(((a.2 as i32) - (b.2 as i32)).abs() <= 12);
+			}
+		}
+	}
+
+	#[test]
+	fn stream_progressive() {
+		let options = EncodeOptions { progressive: true,..EncodeOptions::default() };
+		let data = encode_with_options(&gradient(),&options).unwrap();
+		let mut decoder = StreamDecoder::<pixel::RGB8UN,_>::new(std::io::Cursor::new(data.clone())).unwrap();
+		let mut passes = 0;
+		loop {
+			match decoder.advance().unwrap() {
+				Progress::Pass(pass) => {
+					assert_eq!(pass,passes);
+					passes += 1;
+				},
+				Progress::Done => { break; },
+				progress => { panic!("unexpected {:?}",progress); },
+			}
+		}
+		assert_eq!(passes,4);  // every scan but the last gives a preview
+		let image = decoder.into_image();
+		let decoded = decode::<pixel::RGB8UN>(&data).unwrap();
+		for (a,b) in image.data().iter().zip(decoded.data().iter()) {
+			assert_eq!(a.get(),b.get());
+		}
+	}
+
+	#[test]
+	fn encode_clamps_float() {
+		// out of range values, which would have no Huffman codes in the standard tables
+		let mut image = Mat::<pixel::RGBA32F>::new(vec2!(16,16));
+		for y in 0..16 {
+			for x in 0..16 {
+				if x < 8 {
+					image[(x,y)].setf(100.0,50.0,1000.0,1.0);
+				}
+				else {
+					image[(x,y)].setf(-10.0,0.0,-1.0,1.0);
+				}
+			}
+		}
+		let decoded = decode::<pixel::RGB8UN>(&encode(&image).unwrap()).unwrap();
+		for y in 0..16 {
+			for x in 0..16 {
+				let (r,g,b,_) = decoded[(x,y)].get();
+				if x < 8 {
+					assert!((r >= 240) && (g >= 240) && (b >= 240));
+				}
+				else {
+					assert!((r <= 15) && (g <= 15) && (b <= 15));
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn decode_truncated_restart() {
+		// missing restart markers must not break the decoder
+		for progressive in [false,true] {
+			let options = EncodeOptions { progressive: progressive,restart_interval: 2,..EncodeOptions::default() };
+			let data = encode_with_options(&gradient(),&options).unwrap();
+			assert!(decode::<pixel::RGB8UN>(&data).is_ok());
+			for length in 0..data.len() {
+				let _ = decode::<pixel::RGB8UN>(&data[0..length]);
+			}
+		}
+	}
+
+	#[test]
+	fn decode_invalid_sampling() {
+		let data = encode_with_options(&gradient(),&EncodeOptions::default()).unwrap();
+		let sof = data.windows(2).position(|w| w == [0xFF,0xC0]).unwrap();
+		for samp in [0x01,0x10,0x00,0x31] {
+			let mut data = data.clone();
+			data[sof + 11] = samp;
+			assert!(matches!(decode::<pixel::RGB8UN>(&data),Err(ImageError::Unsupported(_))));
+		}
+	}
+
+	#[test]
+	fn decode_invalid_category() {
+		let mut data = encode_with_options(&gradient(),&EncodeOptions::default()).unwrap();
+		let dht = data.windows(2).position(|w| w == [0xFF,0xC4]).unwrap();
+		assert_eq!(data[dht + 4],0x00);  // luma DC table first
+		let total: usize = data[dht + 5..dht + 21].iter().map(|&b| b as usize).sum();
+		data[dht + 21 + total - 1] = 0x20;
+		assert!(matches!(decode::<pixel::RGB8UN>(&data),Err(ImageError::Corrupt(_,_))));
+	}
 }
@@ -0,0 +1,206 @@
+// E - image - Mipmap
+// Desmond Germans, 2020
+
+use crate::*;
+
+/// Options for building mipmaps.
+#[derive(Copy,Clone,Debug)]
+pub struct MipmapOptions {
+    /// Filter to reduce each level with.
+    pub filter: Filter,
+    /// The pixels are sRGB encoded, filter them in linear light. Ignored for normal maps.
+    pub linear: bool,
+    /// Alpha test threshold of a cutout texture. Alpha of each level is scaled so the same fraction of pixels passes the test as in the full size image.
+    pub alpha_cutoff: Option<f32>,
+    /// The pixels are tangent space normals, stored as `n * 0.5 + 0.5` in red, green and blue. They are renormalized after filtering.
+    pub normal_map: bool,
+}
+
+impl Default for MipmapOptions {
+    fn default() -> MipmapOptions {
+        MipmapOptions {
+            filter: Filter::Box,
+            linear: true,
+            alpha_cutoff: None,
+            normal_map: false,
+        }
+    }
+}
+
+/// One level of a `MipChain`.
+#[derive(Copy,Clone,Debug)]
+pub struct MipLevel {
+    /// Size of the level.
+    pub size: Vec2<usize>,
+    /// Offset of the level in `MipChain::data`, in pixels. With Vulkan, `MipChain::vk_buffer_image_copies` turns these into copy regions.
+    pub offset: usize,
+}
+
+/// Full mipmap pyramid of an image, from the image itself down to 1x1, stored back to back for uploading.
+#[derive(Clone)]
+pub struct MipChain<T: pixel::Pixel> {
+    /// The levels, largest first.
+    pub levels: Vec<MipLevel>,
+    /// Pixels of all levels, each level in rows from top to bottom.
+    pub data: Vec<T>,
+}
+
+impl<T: pixel::Pixel> MipChain<T> {
+    /// Pixels of one level.
+    pub fn level(&self,index: usize) -> &[T] {
+        let level = &self.levels[index];
+        &self.data[level.offset..level.offset + level.size.x * level.size.y]
+    }
+}
+
+// fraction of pixels that pass the alpha test
+fn coverage<T: pixel::Pixel>(image: &Mat<T>,cutoff: f32) -> f32 {
+    let count = image.data().iter().filter(|p| p.getf().3 >= cutoff).count();
+    (count as f32) / (image.data().len() as f32)
+}
+
+// scale alpha so the coverage matches the full size image
+fn preserve_coverage<T: pixel::Pixel>(image: &mut Mat<T>,cutoff: f32,target: f32) {
+    let mut alphas: Vec<f32> = image.data().iter().map(|p| p.getf().3).collect();
+
+    // the alpha that ends up exactly at the cutoff is the one with (target * count) pixels above it
+    let above = ((target * (alphas.len() as f32)).round() as usize).min(alphas.len());
+    let threshold = if above == 0 {
+        1.0
+    }
+    else {
+        let index = alphas.len() - above;
+        *alphas.select_nth_unstable_by(index,|a,b| a.total_cmp(b)).1
+    };
+    if threshold <= 0.0 {
+        return;
+    }
+    let scale = cutoff / threshold;
+    for p in image.data_mut().iter_mut() {
+        let (r,g,b,a) = p.getf();
+        let a = a * scale;
+        p.setf(r,g,b,if a > 1.0 { 1.0 } else { a });
+    }
+}
+
+fn renormalize<T: pixel::Pixel>(image: &mut Mat<T>) {
+    for p in image.data_mut().iter_mut() {
+        let (r,g,b,a) = p.getf();
+        let x = r * 2.0 - 1.0;
+        let y = g * 2.0 - 1.0;
+        let z = b * 2.0 - 1.0;
+        let length = (x * x + y * y + z * z).sqrt();
+        if length > 0.0 {
+            p.setf(x / length * 0.5 + 0.5,y / length * 0.5 + 0.5,z / length * 0.5 + 0.5,a);
+        }
+        else {
+            p.setf(0.5,0.5,1.0,a);
+        }
+    }
+}
+
+/// Build the mipmaps of an image.
+/// # Generic
+/// * `T` - The pixel format of the image.
+/// # Arguments
+/// * `image` - Full size image.
+/// # Returns
+/// The mipmap pyramid, box filtered in linear light.
+pub fn mipmaps<T: pixel::Pixel>(image: &Mat<T>) -> MipChain<T> {
+    mipmaps_with_options(image,&MipmapOptions::default())
+}
+
+/// Build the mipmaps of an image with options.
+/// # Generic
+/// * `T` - The pixel format of the image.
+/// # Arguments
+/// * `image` - Full size image.
+/// * `options` - Filter, color space, alpha coverage and normal map handling.
+/// # Returns
+/// The mipmap pyramid. Each level is half the size of the one above, rounded down, until both sides are 1.
+pub fn mipmaps_with_options<T: pixel::Pixel>(image: &Mat<T>,options: &MipmapOptions) -> MipChain<T> {
+    let resize_options = ResizeOptions {
+        filter: options.filter,
+        linear: options.linear && !options.normal_map,
+        premultiply: !options.normal_map,
+    };
+    let target = options.alpha_cutoff.map(|cutoff| coverage(image,cutoff));
+    let mut chain = MipChain {
+        levels: vec![MipLevel { size: image.size,offset: 0, }],
+        data: image.data().to_vec(),
+    };
+    let mut current = image.clone();
+    while (current.size.x > 1) || (current.size.y > 1) {
+        let size = vec2!(if current.size.x > 1 { current.size.x / 2 } else { 1 },if current.size.y > 1 { current.size.y / 2 } else { 1 });
+
+        // reduce from the level above, so the work halves with every level
+        let mut level = resize_with_options(&current,size,&resize_options);
+        if options.normal_map {
+            renormalize(&mut level);
+        }
+        if let (Some(cutoff),Some(target)) = (options.alpha_cutoff,target) {
+            preserve_coverage(&mut level,cutoff,target);
+        }
+        chain.levels.push(MipLevel { size: size,offset: chain.data.len(), });
+        chain.data.extend_from_slice(level.data());
+        current = level;
+    }
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        pixel::Pixel,
+    };
+
+    #[test]
+    fn chain_levels() {
+        let image = Mat::<pixel::RGBA8UN>::new(vec2!(13usize,5));
+        let chain = mipmaps(&image);
+        let sizes: Vec<(usize,usize)> = chain.levels.iter().map(|level| (level.size.x,level.size.y)).collect();
+        assert_eq!(sizes,vec![(13,5),(6,2),(3,1),(1,1)]);
+        let mut offset = 0;
+        for (i,level) in chain.levels.iter().enumerate() {
+            assert_eq!(level.offset,offset);
+            assert_eq!(chain.level(i).len(),level.size.x * level.size.y);
+            offset += level.size.x * level.size.y;
+        }
+        assert_eq!(chain.data.len(),offset);
+    }
+
+    #[test]
+    fn coverage_nan_alpha() {
+        // NaN alpha must not panic the threshold search
+        let mut image = Mat::<pixel::RGBA32F>::new(vec2!(8usize,8));
+        for (i,p) in image.data_mut().iter_mut().enumerate() {
+            p.setf(1.0,1.0,1.0,if (i % 5) == 0 { f32::NAN } else if (i & 1) != 0 { 1.0 } else { 0.0 });
+        }
+        let options = MipmapOptions { alpha_cutoff: Some(0.5),..MipmapOptions::default() };
+        let chain = mipmaps_with_options(&image,&options);
+        assert_eq!(chain.levels.len(),4);
+    }
+
+    #[test]
+    fn coverage_preserved() {
+        // a soft disk, the plain box filter would shrink the part that passes the alpha test
+        let mut image = Mat::<pixel::RGBA8UN>::new(vec2!(32usize,32));
+        for y in 0..32 {
+            for x in 0..32 {
+                let dx = (x as f32) - 15.5;
+                let dy = (y as f32) - 15.5;
+                let a = 1.0 - (dx * dx + dy * dy).sqrt() / 16.0;
+                image[(x,y)].setf(1.0,1.0,1.0,if a < 0.0 { 0.0 } else { a * a });
+            }
+        }
+        let target = coverage(&image,0.5);
+        let options = MipmapOptions { alpha_cutoff: Some(0.5),..MipmapOptions::default() };
+        let chain = mipmaps_with_options(&image,&options);
+        for i in 1..3 {
+            let mut level = Mat::<pixel::RGBA8UN>::new(chain.levels[i].size);
+            level.data_mut().copy_from_slice(chain.level(i));
+            assert!((coverage(&level,0.5) - target).abs() < 0.05,"level {}",i);
+        }
+    }
+}
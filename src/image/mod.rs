@@ -8,6 +8,7 @@ use {
     std::{
         fs::File,
         io::prelude::*,
+        path::Path,
     }
 };
 
@@ -20,45 +21,110 @@ pub mod pbm;
 pub mod tiff;
 pub mod xbm;
 pub mod webp;
+pub mod hdr;
+
+mod animation;
+pub use animation::*;
+
+mod exif;
+pub use exif::*;
+
+mod error;
+pub use error::*;
+
+mod info;
+pub use info::*;
+
+mod codec;
+pub use codec::*;
+
+mod stream;
+pub use stream::*;
+
+mod resample;
+pub use resample::*;
+
+mod mipmap;
+pub use mipmap::*;
+
+mod color;
+pub use color::*;
+
+#[cfg(test)]
+mod fixture;
+
+/// Options for decoding.
+#[derive(Copy,Clone,Debug)]
+pub struct DecodeOptions {
+    /// Transform the image according to the EXIF orientation, so it shows upright.
+    pub apply_orientation: bool,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> DecodeOptions {
+        DecodeOptions {
+            apply_orientation: false,
+        }
+    }
+}
+
+/// Encoder settings for each format, for `encode_as` and `save_with_options`.
+#[derive(Clone,Debug)]
+pub struct EncodeOptions {
+    /// BMP settings.
+    pub bmp: bmp::EncodeOptions,
+    /// PNG settings.
+    pub png: png::EncodeOptions,
+    /// JPEG settings.
+    pub jpeg: jpeg::EncodeOptions,
+    /// TGA settings.
+    pub tga: tga::EncodeOptions,
+    /// TIFF settings.
+    pub tiff: tiff::EncodeOptions,
+    /// Netpbm settings.
+    pub pbm: pbm::EncodeOptions,
+    /// XBM settings.
+    pub xbm: xbm::EncodeOptions,
+    /// Radiance HDR settings.
+    pub hdr: hdr::EncodeOptions,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> EncodeOptions {
+        EncodeOptions {
+            bmp: bmp::EncodeOptions::default(),
+            png: png::EncodeOptions::default(),
+            jpeg: jpeg::EncodeOptions::default(),
+            tga: tga::EncodeOptions::default(),
+            tiff: tiff::EncodeOptions::default(),
+            pbm: pbm::EncodeOptions::default(),
+            xbm: xbm::EncodeOptions::default(),
+            hdr: hdr::EncodeOptions::default(),
+        }
+    }
+}
 
 /// Test if a slice can be decoded.
 /// # Arguments
 /// * `src` - Slice to test.
 /// # Returns
-/// * `None` - Slice cannot be decoded.
-/// * `Some((width,height))` - Slice can be decoded and `width`,`height` are the dimensions of the image.
+/// * `Ok((width,height))` - Slice can be decoded and `width`,`height` are the dimensions of the image.
+/// * `Err(ImageError)` - Slice is not in a known format, or its header is invalid.
 #[allow(dead_code)]
-pub fn test(src: &[u8]) -> Option<(u32,u32)> {
-    if let Some(size) = bmp::test(src) {
-        Some(size)
-    }
-    else if let Some(size) = png::test(src) {
-        Some(size)
-    }
-    else if let Some(size) = jpeg::test(src) {
-        Some(size)
-    }
-    else if let Some(size) = gif::test(src) {
-        Some(size)
-    }
-    else if let Some(size) = tga::test(src) {
-        Some(size)
-    }
-    else if let Some(size) = tiff::test(src) {
-        Some(size)
-    }
-    else if let Some(size) = pbm::test(src) {
-        Some(size)
-    }
-    else if let Some(size) = xbm::test(src) {
-        Some(size)
-    }
-    else if let Some(size) = webp::test(src) {
-        Some(size)
-    }
-    else {
-        None
-    }
+pub fn test(src: &[u8]) -> Result<(u32,u32),ImageError> {
+    let info = registry().info(src)?;
+    Ok((info.size.x as u32,info.size.y as u32))
+}
+
+/// Probe the header of a slice, without decoding the pixels.
+/// # Arguments
+/// * `src` - Slice to examine.
+/// # Returns
+/// * `Ok(info)` - Format, size, layout and color metadata of the image.
+/// * `Err(ImageError)` - Slice is not in a known format, or its header is invalid.
+#[allow(dead_code)]
+pub fn info(src: &[u8]) -> Result<ImageInfo,ImageError> {
+    registry().info(src)
 }
 
 /// Decode a slice.
@@ -67,59 +133,115 @@ pub fn test(src: &[u8]) -> Option<(u32,u32)> {
 /// # Arguments
 /// * `src` - Slice to decode.
 /// # Returns
-/// * `None` - Slice could not be decoded.
-/// * `Some(mat)` - Slice is decoded into `mat`.
+/// * `Ok(mat)` - Slice is decoded into `mat`.
+/// * `Err(ImageError)` - Slice could not be decoded.
 #[allow(dead_code)]
-pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Option<Mat<T>> {
-    if let Some(image) = bmp::decode::<T>(src) {
-        Some(image)
-    }
-    else if let Some(image) = png::decode::<T>(src) {
-        Some(image)
-    }
-    else if let Some(image) = jpeg::decode::<T>(src) {
-        Some(image)
-    }
-    else if let Some(image) = gif::decode::<T>(src) {
-        Some(image)
-    }
-    else if let Some(image) = tga::decode::<T>(src) {
-        Some(image)
-    }
-    else if let Some(image) = tiff::decode::<T>(src) {
-        Some(image)
-    }
-    else if let Some(image) = pbm::decode::<T>(src) {
-        Some(image)
-    }
-    else if let Some(image) = xbm::decode::<T>(src) {
-        Some(image)
-    }
-    else if let Some(image) = webp::decode::<T>(src) {
-        Some(image)
-    }
-    else {
-        None
-    }
+pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Result<Mat<T>,ImageError> {
+    registry().decode::<T>(src)
 }
 
-/// Load and decode
-pub fn load<T: pixel::Pixel>(filename: &str) -> Result<Mat<T>,SystemError> {
-    //println!("opening {}",filename);
-    let mut file = match File::open(filename) {
-        Ok(file) => file,
-        Err(_) => { return Err(SystemError::Generic); },
-    };
-    //println!("reading {}",filename);
-    let mut buffer: Vec<u8> = Vec::new();
-    if let Ok(_) = file.read_to_end(&mut buffer) {
-        //println!("decoding {}",filename);
-        match decode::<T>(&buffer) {
-            Some(mat) => Ok(mat),
-            None => { println!("decoding failed"); Err(SystemError::Generic) },
+/// Decode a slice with options.
+/// # Generic
+/// * `T` - The resulting pixel format.
+/// # Arguments
+/// * `src` - Slice to decode.
+/// * `options` - Decoding options.
+/// # Returns
+/// * `Ok(mat)` - Slice is decoded into `mat`.
+/// * `Err(ImageError)` - Slice could not be decoded.
+#[allow(dead_code)]
+pub fn decode_with_options<T: pixel::Pixel>(src: &[u8],options: &DecodeOptions) -> Result<Mat<T>,ImageError> {
+    let image = decode::<T>(src)?;
+    if options.apply_orientation {
+        if let Some(exif) = exif(src) {
+            return Ok(exif.orientation.apply(&image));
         }
     }
-    else {
-        Err(SystemError::Generic)
-    }
-}
\ No newline at end of file
+    Ok(image)
+}
+
+/// Read the EXIF metadata of a slice.
+/// # Arguments
+/// * `src` - Slice to examine, JPEG (APP1), PNG (eXIf) or the format of a registered codec that reads EXIF.
+/// # Returns
+/// * `None` - Slice has no (valid) EXIF metadata.
+/// * `Some(exif)` - The metadata.
+#[allow(dead_code)]
+pub fn exif(src: &[u8]) -> Option<Exif> {
+    registry().exif(src)
+}
+
+/// Decode a slice as animation.
+/// # Generic
+/// * `T` - The resulting pixel format.
+/// # Arguments
+/// * `src` - Slice to decode.
+/// # Returns
+/// * `Ok(animation)` - Slice is decoded into `animation`. Still images become a single frame animation.
+/// * `Err(ImageError)` - Slice could not be decoded.
+#[allow(dead_code)]
+pub fn decode_animation<T: pixel::Pixel>(src: &[u8]) -> Result<Animation<T>,ImageError> {
+    registry().decode_animation::<T>(src)
+}
+
+/// Load and decode an image file.
+/// # Generic
+/// * `T` - The resulting pixel format.
+/// # Arguments
+/// * `filename` - Name of the file.
+/// # Returns
+/// * `Ok(mat)` - The decoded image.
+/// * `Err(ImageError)` - The file could not be read or decoded.
+pub fn load<T: pixel::Pixel>(filename: &str) -> Result<Mat<T>,ImageError> {
+    let mut file = File::open(filename)?;
+    let mut buffer: Vec<u8> = Vec::new();
+    file.read_to_end(&mut buffer)?;
+    decode::<T>(&buffer)
+}
+
+/// Encode an image in a specific format.
+/// # Generic
+/// * `T` - The pixel format of the image.
+/// # Arguments
+/// * `image` - Image to encode.
+/// * `format` - Format to encode to, with the registered codec of that name.
+/// * `options` - Encoder settings, only those for `format` are used.
+/// # Returns
+/// * `Ok(data)` - The encoded image.
+/// * `Err(ImageError)` - The image could not be encoded in this format.
+pub fn encode_as<T: pixel::Pixel>(image: &Mat<T>,format: ImageFormat,options: &EncodeOptions) -> Result<Vec<u8>,ImageError> {
+    registry().encode_with_options(format.name(),image,options)
+}
+
+/// Encode and save an image file, in the format that matches the extension.
+/// # Generic
+/// * `T` - The pixel format of the image.
+/// # Arguments
+/// * `image` - Image to save.
+/// * `filename` - Name of the file.
+/// # Returns
+/// * `Ok(())` - The image is saved.
+/// * `Err(ImageError)` - The extension is not known, or the image could not be encoded or written.
+pub fn save<T: pixel::Pixel>(image: &Mat<T>,filename: &str) -> Result<(),ImageError> {
+    save_with_options(image,filename,&EncodeOptions::default())
+}
+
+/// Encode and save an image file with encoder settings, in the format that matches the extension.
+/// # Generic
+/// * `T` - The pixel format of the image.
+/// # Arguments
+/// * `image` - Image to save.
+/// * `filename` - Name of the file.
+/// * `options` - Encoder settings.
+/// # Returns
+/// * `Ok(())` - The image is saved.
+/// * `Err(ImageError)` - The extension is not known, or the image could not be encoded or written.
+pub fn save_with_options<T: pixel::Pixel>(image: &Mat<T>,filename: &str,options: &EncodeOptions) -> Result<(),ImageError> {
+    let extension = Path::new(filename).extension().and_then(|extension| extension.to_str()).ok_or(ImageError::UnknownFormat)?;
+    let registry = registry();
+    let codec = registry.by_extension(extension).ok_or(ImageError::UnknownFormat)?;
+    let data = registry.encode_with_options(codec.name(),image,options)?;
+    let mut file = File::create(filename)?;
+    file.write_all(&data)?;
+    Ok(())
+}
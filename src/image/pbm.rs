@@ -5,14 +5,460 @@
 
 use crate::*;
 
-pub fn test(_src: &[u8]) -> Option<(u32,u32)> {
-    None
+struct Header {
+    magic: u8,  // 1 to 7
+    width: usize,
+    height: usize,
+    depth: usize,  // samples per pixel
+    maxval: u32,
+    color: bool,
+    alpha: bool,
+    sp: usize,  // start of the pixel data
 }
 
-pub fn decode<T: pixel::Pixel>(_src: &[u8]) -> Option<Mat<T>> {
-    None
+fn is_space(b: u8) -> bool {
+    (b == b' ') || (b == b'\t') || (b == b'\n') || (b == b'\r') || (b == 0x0B) || (b == 0x0C)
 }
 
-pub fn encode<T: pixel::Pixel>(_src: &Mat<T>) -> Option<Vec<u8>> {
-    None
+// skip whitespace and comments
+fn skip(src: &[u8],mut sp: usize) -> usize {
+    while sp < src.len() {
+        if src[sp] == b'#' {
+            while (sp < src.len()) && (src[sp] != b'\n') && (src[sp] != b'\r') {
+                sp += 1;
+            }
+        }
+        else if is_space(src[sp]) {
+            sp += 1;
+        }
+        else {
+            break;
+        }
+    }
+    sp
+}
+
+fn number(src: &[u8],sp: &mut usize) -> Option<u32> {
+    *sp = skip(src,*sp);
+    let start = *sp;
+    let mut value: u32 = 0;
+    while (*sp < src.len()) && src[*sp].is_ascii_digit() {
+        value = value.checked_mul(10)?.checked_add((src[*sp] - b'0') as u32)?;
+        *sp += 1;
+    }
+    if *sp == start {
+        return None;
+    }
+    Some(value)
+}
+
+// PAM header lines, up to ENDHDR
+fn parse_pam(src: &[u8],header: &mut Header) -> Result<(),ImageError> {
+    let mut sp = header.sp;
+    let mut tupltype = String::new();
+    loop {
+        let start = sp;
+        while (sp < src.len()) && (src[sp] != b'\n') {
+            sp += 1;
+        }
+        if sp >= src.len() {
+            return Err(ImageError::Corrupt(start,"missing ENDHDR"));
+        }
+        let invalid = || ImageError::Corrupt(start,"invalid header line");
+        let line = std::str::from_utf8(&src[start..sp]).map_err(|_| invalid())?.trim();
+        sp += 1;
+        if (line.len() == 0) || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let key = words.next().ok_or_else(invalid)?;
+        let mut value = || -> Result<u32,ImageError> { words.next().ok_or_else(invalid)?.parse::<u32>().map_err(|_| invalid()) };
+        match key {
+            "WIDTH" => { header.width = value()? as usize; },
+            "HEIGHT" => { header.height = value()? as usize; },
+            "DEPTH" => { header.depth = value()? as usize; },
+            "MAXVAL" => { header.maxval = value()?; },
+            "TUPLTYPE" => { tupltype = line[8..].trim().to_string(); },
+            "ENDHDR" => { break; },
+            _ => { return Err(invalid()); },
+        }
+    }
+    header.sp = sp;
+
+    // unknown tuple types are interpreted by depth
+    let (color,alpha,needed) = match tupltype.as_str() {
+        "BLACKANDWHITE" | "GRAYSCALE" => (false,false,1),
+        "BLACKANDWHITE_ALPHA" | "GRAYSCALE_ALPHA" => (false,true,2),
+        "RGB" => (true,false,3),
+        "RGB_ALPHA" => (true,true,4),
+        _ => match header.depth {
+            1 => (false,false,1),
+            2 => (false,true,2),
+            3 => (true,false,3),
+            4 => (true,true,4),
+            _ => { return Err(ImageError::Unsupported("PAM depth")); },
+        },
+    };
+    if header.depth < needed {
+        return Err(ImageError::Corrupt(3,"depth too small for tuple type"));
+    }
+    header.color = color;
+    header.alpha = alpha;
+    Ok(())
+}
+
+fn parse_header(src: &[u8]) -> Result<Header,ImageError> {
+    if (src.len() < 3) || (src[0] != b'P') || (src[1] < b'1') || (src[1] > b'7') || !is_space(src[2]) {
+        return Err(ImageError::UnknownFormat);
+    }
+    let magic = src[1] - b'0';
+    let mut header = Header {
+        magic: magic,
+        width: 0,
+        height: 0,
+        depth: if (magic == 3) || (magic == 6) { 3 } else { 1 },
+        maxval: 1,
+        color: (magic == 3) || (magic == 6),
+        alpha: false,
+        sp: 3,
+    };
+    if magic == 7 {
+        parse_pam(src,&mut header)?;
+    }
+    else {
+        let mut sp = 2;
+        header.width = number(src,&mut sp).ok_or(ImageError::Corrupt(sp,"invalid width"))? as usize;
+        header.height = number(src,&mut sp).ok_or(ImageError::Corrupt(sp,"invalid height"))? as usize;
+        if (magic != 1) && (magic != 4) {
+            header.maxval = number(src,&mut sp).ok_or(ImageError::Corrupt(sp,"invalid maxval"))?;
+        }
+
+        // exactly one whitespace character before binary data
+        if (sp >= src.len()) || !is_space(src[sp]) {
+            return Err(ImageError::Corrupt(sp,"missing whitespace before pixel data"));
+        }
+        header.sp = sp + 1;
+    }
+    if (header.width == 0) || (header.height == 0) {
+        return Err(ImageError::Dimensions(header.width,header.height));
+    }
+    if (header.maxval == 0) || (header.maxval > 65535) {
+        return Err(ImageError::Corrupt(3,"invalid maxval"));
+    }
+    Ok(header)
+}
+
+pub fn test(src: &[u8]) -> Result<(u32,u32),ImageError> {
+    let header = parse_header(src)?;
+    Ok((header.width as u32,header.height as u32))
+}
+
+pub fn info(src: &[u8]) -> Result<ImageInfo,ImageError> {
+    let header = parse_header(src)?;
+    let channels = match (header.color,header.alpha) {
+        (false,false) => ChannelLayout::Gray,
+        (false,true) => ChannelLayout::GrayAlpha,
+        (true,false) => ChannelLayout::Rgb,
+        (true,true) => ChannelLayout::Rgba,
+    };
+
+    // enough bits to hold maxval
+    Ok(ImageInfo::new(ImageFormat::Pbm,header.width,header.height,32 - header.maxval.leading_zeros(),channels))
+}
+
+pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Result<Mat<T>,ImageError> {
+    let header = parse_header(src)?;
+    let width = header.width;
+    let height = header.height;
+    let depth = header.depth;
+    let total = check_dimensions(width,height)?.checked_mul(depth).ok_or(ImageError::Dimensions(width,height))?;
+
+    // every sample takes at least one byte, except in P4
+    let available = src.len() - header.sp;
+    if if header.magic == 4 { ((width + 7) / 8) * height > available } else { total > available } {
+        return Err(ImageError::Corrupt(src.len(),"truncated pixel data"));
+    }
+
+    // read samples
+    let mut samples: Vec<u32> = Vec::with_capacity(total);
+    let mut sp = header.sp;
+    match header.magic {
+        1 => {
+            for _i in 0..total {
+                sp = skip(src,sp);
+                if sp >= src.len() {
+                    return Err(ImageError::Corrupt(sp,"truncated pixel data"));
+                }
+                samples.push(match src[sp] {
+                    b'0' => 1,
+                    b'1' => 0,  // 1 is black
+                    _ => { return Err(ImageError::Corrupt(sp,"invalid bit")); },
+                });
+                sp += 1;
+            }
+        },
+        2 | 3 => {
+            for _i in 0..total {
+                samples.push(number(src,&mut sp).ok_or(ImageError::Corrupt(sp,"invalid sample"))?);
+            }
+        },
+        4 => {
+            let stride = (width + 7) / 8;
+            for y in 0..height {
+                for x in 0..width {
+                    samples.push(if src[sp + y * stride + (x >> 3)] & (0x80 >> (x & 7)) != 0 { 0 } else { 1 });
+                }
+            }
+        },
+        _ => {
+            if header.maxval < 256 {
+                samples.extend(src[sp..sp + total].iter().map(|&b| b as u32));
+            }
+            else {
+                if total * 2 > available {
+                    return Err(ImageError::Corrupt(src.len(),"truncated pixel data"));
+                }
+                for i in 0..total {
+                    samples.push((src[sp + i * 2] as u32) << 8 | src[sp + i * 2 + 1] as u32);
+                }
+            }
+        },
+    }
+
+    // convert to pixels
+    let maxval = header.maxval;
+    let scale = |v: u32| -> u16 {
+        let v = if v > maxval { maxval } else { v };
+        ((v * 65535 + maxval / 2) / maxval) as u16
+    };
+    let mut image = Mat::<T>::new(vec2!(width,height));
+    for y in 0..height {
+        for x in 0..width {
+            let s = &samples[(y * width + x) * depth..(y * width + x + 1) * depth];
+            if header.color {
+                let a = if header.alpha { scale(s[3]) } else { 0xFFFF };
+                image[(x,y)].set16(scale(s[0]),scale(s[1]),scale(s[2]),a);
+            }
+            else {
+                let l = scale(s[0]);
+                let a = if header.alpha { scale(s[1]) } else { 0xFFFF };
+                image[(x,y)].set16(l,l,l,a);
+            }
+        }
+    }
+    Ok(image)
+}
+
+/// What kind of Netpbm file to write.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum ImageType {
+    /// PBM, black where the luminance is below half.
+    Bitmap,
+    /// PGM, luminance.
+    Graymap,
+    /// PPM, RGB.
+    Pixmap,
+    /// PAM, RGB, or RGB with alpha if the pixel format has alpha.
+    Arbitrary,
+}
+
+/// Netpbm encoder settings.
+#[derive(Copy,Clone,Debug)]
+pub struct EncodeOptions {
+    /// Kind of Netpbm file.
+    pub image_type: ImageType,
+    /// Write decimal numbers instead of binary data. PAM is always binary.
+    pub ascii: bool,
+    /// Largest sample value, 1 to 65535. Ignored for `Bitmap`. `None` picks 255 for 8-bit pixel formats and 65535 for wider ones, so the image reloads without loss.
+    pub maxval: Option<u16>,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> EncodeOptions {
+        EncodeOptions {
+            image_type: ImageType::Arbitrary,
+            ascii: false,
+            maxval: None,
+        }
+    }
+}
+
+pub fn encode<T: pixel::Pixel>(image: &Mat<T>) -> Result<Vec<u8>,ImageError> {
+    encode_with_options(image,&EncodeOptions::default())
+}
+
+pub fn encode_with_options<T: pixel::Pixel>(image: &Mat<T>,options: &EncodeOptions) -> Result<Vec<u8>,ImageError> {
+    let width = image.size.x;
+    let height = image.size.y;
+    if (width == 0) || (height == 0) {
+        return Err(ImageError::Dimensions(width,height));
+    }
+    let maxval = options.maxval.unwrap_or(if T::BITS > 8 { 65535 } else { 255 });
+    if maxval == 0 {
+        return Err(ImageError::Unsupported("Netpbm maxval of 0"));
+    }
+    let maxval = if options.image_type == ImageType::Bitmap { 1 } else { maxval as u32 };
+    let ascii = options.ascii && (options.image_type != ImageType::Arbitrary);
+    let alpha = T::ALPHA && (options.image_type == ImageType::Arbitrary);
+    let depth = match options.image_type {
+        ImageType::Bitmap | ImageType::Graymap => 1,
+        ImageType::Pixmap => 3,
+        ImageType::Arbitrary => if alpha { 4 } else { 3 },
+    };
+
+    // header
+    let mut dst: Vec<u8> = Vec::new();
+    let magic = match options.image_type {
+        ImageType::Bitmap => 1,
+        ImageType::Graymap => 2,
+        ImageType::Pixmap => 3,
+        ImageType::Arbitrary => 7,
+    } + if ascii || (options.image_type == ImageType::Arbitrary) { 0 } else { 3 };
+    if magic == 7 {
+        dst.extend_from_slice(format!("P7\nWIDTH {}\nHEIGHT {}\nDEPTH {}\nMAXVAL {}\nTUPLTYPE {}\nENDHDR\n",width,height,depth,maxval,if alpha { "RGB_ALPHA" } else { "RGB" }).as_bytes());
+    }
+    else if options.image_type == ImageType::Bitmap {
+        dst.extend_from_slice(format!("P{}\n{} {}\n",magic,width,height).as_bytes());
+    }
+    else {
+        dst.extend_from_slice(format!("P{}\n{} {}\n{}\n",magic,width,height,maxval).as_bytes());
+    }
+
+    // samples
+    let scale = |v: u16| -> u32 { ((v as u32) * maxval + 32767) / 65535 };
+    let mut line = 0;
+    for y in 0..height {
+        let mut samples: Vec<u32> = Vec::with_capacity(width * depth);
+        for x in 0..width {
+            let (r,g,b,a) = image[(x,y)].get16();
+            match options.image_type {
+                ImageType::Bitmap | ImageType::Graymap => {
+                    let l = (((r as u32) * 19595 + (g as u32) * 38470 + (b as u32) * 7471 + 32768) >> 16) as u16;
+                    if options.image_type == ImageType::Bitmap {
+                        samples.push(if l < 0x8000 { 1 } else { 0 });  // 1 is black
+                    }
+                    else {
+                        samples.push(scale(l));
+                    }
+                },
+                _ => {
+                    samples.push(scale(r));
+                    samples.push(scale(g));
+                    samples.push(scale(b));
+                    if alpha {
+                        samples.push(scale(a));
+                    }
+                },
+            }
+        }
+        if ascii {
+            // lines of at most 70 characters
+            for v in samples.iter() {
+                let text = v.to_string();
+                if line + text.len() + 1 > 70 {
+                    dst.push(b'\n');
+                    line = 0;
+                }
+                else if line > 0 {
+                    dst.push(b' ');
+                    line += 1;
+                }
+                dst.extend_from_slice(text.as_bytes());
+                line += text.len();
+            }
+        }
+        else if options.image_type == ImageType::Bitmap {
+            let mut bits = vec![0u8; (width + 7) / 8];
+            for (x,v) in samples.iter().enumerate() {
+                if *v != 0 {
+                    bits[x >> 3] |= 0x80 >> (x & 7);
+                }
+            }
+            dst.extend_from_slice(&bits);
+        }
+        else if maxval < 256 {
+            dst.extend(samples.iter().map(|&v| v as u8));
+        }
+        else {
+            for v in samples.iter() {
+                dst.push((v >> 8) as u8);
+                dst.push((v & 255) as u8);
+            }
+        }
+    }
+    if ascii {
+        dst.push(b'\n');
+    }
+    Ok(dst)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        pixel::Pixel,
+        crate::image::fixture,
+    };
+
+    #[test]
+    fn roundtrip_16bit() {
+        let image = fixture::pattern::<pixel::RGBA16UN>(vec2!(13,5),65536);
+        let opaque = fixture::opaque(&image);
+        let gray = fixture::grayscale(&opaque);
+        for (image_type,ascii,expected) in [(ImageType::Arbitrary,false,&image),(ImageType::Pixmap,false,&opaque),(ImageType::Pixmap,true,&opaque),(ImageType::Graymap,false,&gray),(ImageType::Graymap,true,&gray)] {
+            let options = EncodeOptions { image_type: image_type,ascii: ascii,..EncodeOptions::default() };
+            fixture::assert_same(expected,&decode::<pixel::RGBA16UN>(&encode_with_options(expected,&options).unwrap()).unwrap());
+        }
+    }
+
+    #[test]
+    fn roundtrip_8bit() {
+        let image = fixture::pattern::<pixel::RGBA8UN>(vec2!(9,4),256);
+        let data = encode(&image).unwrap();
+        assert!(String::from_utf8_lossy(&data[0..50]).contains("MAXVAL 255\n"));
+        fixture::assert_same(&image,&decode::<pixel::RGBA8UN>(&data).unwrap());
+    }
+
+    #[test]
+    fn encode_bytes() {
+        let mut image = Mat::<pixel::RGB8UN>::new(vec2!(2,1));
+        image[(0,0)].set(255,128,0,255);
+        image[(1,0)].set(1,2,3,255);
+        let encoded = |image_type: ImageType,ascii: bool,maxval: Option<u16>| encode_with_options(&image,&EncodeOptions { image_type: image_type,ascii: ascii,maxval: maxval, }).unwrap();
+        let with = |header: &[u8],data: &[u8]| [header,data].concat();
+        assert_eq!(encoded(ImageType::Pixmap,false,None),with(b"P6\n2 1\n255\n",&[255,128,0,1,2,3]));
+        assert_eq!(encoded(ImageType::Pixmap,true,None),b"P3\n2 1\n255\n255 128 0 1 2 3\n");
+        assert_eq!(encoded(ImageType::Pixmap,false,Some(1000)),with(b"P6\n2 1\n1000\n",&[0x03,0xE8,0x01,0xF6,0x00,0x00,0x00,0x04,0x00,0x08,0x00,0x0C]));
+
+        // luminance with Rec. 601 weights
+        assert_eq!(encoded(ImageType::Graymap,false,None),with(b"P5\n2 1\n255\n",&[151,2]));
+        assert_eq!(encoded(ImageType::Graymap,true,None),b"P2\n2 1\n255\n151 2\n");
+
+        // 1 is black, rows are padded to whole bytes
+        assert_eq!(encoded(ImageType::Bitmap,false,None),with(b"P4\n2 1\n",&[0x40]));
+        assert_eq!(encoded(ImageType::Bitmap,true,None),b"P1\n2 1\n0 1\n");
+        assert_eq!(encoded(ImageType::Arbitrary,false,None),with(b"P7\nWIDTH 2\nHEIGHT 1\nDEPTH 3\nMAXVAL 255\nTUPLTYPE RGB\nENDHDR\n",&[255,128,0,1,2,3]));
+
+        let mut image = Mat::<pixel::RGBA8UN>::new(vec2!(1,1));
+        image[(0,0)].set(255,128,0,64);
+        assert_eq!(encode(&image).unwrap(),with(b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n",&[255,128,0,64]));
+    }
+
+    #[test]
+    fn roundtrip_bitmap() {
+        let mut image = Mat::<pixel::RGB8UN>::new(vec2!(11,3));
+        for y in 0..3 {
+            for x in 0..11 {
+                let l = if ((x * 3 + y) % 4) == 0 { 255 } else { 0 };
+                image[(x,y)].set(l,l,l,255);
+            }
+        }
+        for ascii in [false,true] {
+            let options = EncodeOptions { image_type: ImageType::Bitmap,ascii: ascii,..EncodeOptions::default() };
+            let decoded = decode::<pixel::RGB8UN>(&encode_with_options(&image,&options).unwrap()).unwrap();
+            for (a,b) in image.data().iter().zip(decoded.data().iter()) {
+                assert_eq!(a.get(),b.get());
+            }
+        }
+    }
 }
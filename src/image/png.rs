@@ -3,14 +3,13 @@
 
 //! PNG
 
-use crate::*;
-
-// Inflate algorithm
-const LITLEN_LENGTH: [u16; 29] = [3,4,5,6,7,8,9,10,11,13,15,17,19,23,27,31,35,43,51,59,67,83,99,115,131,163,195,227,258];
-const LITLEN_EXTRA: [u8; 29] = [0,0,0,0,0,0,0,0,1,1,1,1,2,2,2,2,3,3,3,3,4,4,4,4,5,5,5,5,0];
-const DIST_DIST: [u16; 30] = [1,2,3,4,5,7,9,13,17,25,33,49,65,97,129,193,257,385,513,769,1025,1537,2049,3073,4097,6145,8193,12289,16385,24577];
-const DIST_EXTRA: [u8; 30] = [0,0,0,0,1,1,2,2,3,3,4,4,5,5,6,6,7,7,8,8,9,9,10,10,11,11,12,12,13,13];
-const HCORD: [usize; 19] = [16,17,18,0,8,7,9,6,10,5,11,4,12,3,13,2,14,1,15];
+use {
+    crate::*,
+    std::io::{
+        self,
+        Read,
+    },
+};
 
 #[derive(Copy,Clone)]
 enum Type {
@@ -31,512 +30,73 @@ enum Type {
     RGBA16,
 }
 
-// grayscale distributions
-const GRAY2: [f32; 4] = [0.0,0.33333333,0.66666667,1.0];
-
-const GRAY4: [f32; 16] = [
-    0.0,0.06666667,0.13333333,0.2,
-    0.26666667,0.33333333,0.4,0.46666667,
-    0.53333333,0.6,0.66666667,0.73333333,
-    0.8,0.86666667,0.93333333,1.0
-];
-
-const TABLE: usize = 8;  // 8 seems to be a good balance
-const TABLE_SIZE: usize = 1 << TABLE;
-
-fn bit_reverse(value: u32,width: u32) -> u32 {
-    let mut result: u32 = 0;
-    for i in 0..width {
-        let bit: u32 = (value >> i) & 1;
-        result |= bit << (width - i - 1);
-    }
-    result
-}
-
-fn insert_code(tables: &mut Vec<[i16; TABLE_SIZE]>,ofs: u32,code: u16,length: u8) -> u32 {
-    let shift = 32 - TABLE;
-    if (length as usize) > TABLE {
-        let pos: usize = ((ofs >> shift) & ((TABLE_SIZE - 1) as u32)) as usize;
-        let p = bit_reverse(pos as u32,TABLE as u32) as usize;
-        let mut n: i16 = tables.len() as i16;
-        if tables[0][p] == 0 {
-            tables.push([0i16; TABLE_SIZE]);
-            tables[0][p] = -n;
-        }
-        else {
-            n = -tables[0][p];
-        }
-        let shift = 32 - TABLE - TABLE;
-        let pos = ((ofs >> shift) & ((TABLE_SIZE - 1) as u32)) as usize;
-        let count = TABLE_SIZE >> (length - TABLE as u8) as usize;
-        for i in pos..pos + count {
-            let p = bit_reverse(i as u32,TABLE as u32) as usize;
-            tables[n as usize][p] = ((code << 5) | (length as u16)) as i16;
-        }
-        (count << shift) as u32				
-    }
-    else {
-        let pos = ((ofs >> shift) & ((TABLE_SIZE - 1) as u32)) as usize;
-        let count = TABLE_SIZE >> length as usize;
-        for i in pos..pos + count {
-            let p = bit_reverse(i as u32,TABLE as u32) as usize;
-            tables[0][p] = ((code << 5) | (length as u16)) as i16;
-        }
-        (count << shift) as u32
-    }
-}
-
-fn create_huffman_tables(lengths: &[u8]) -> Vec<[i16; TABLE_SIZE]> {
-    let mut tables: Vec<[i16; TABLE_SIZE]> = Vec::new();
-    tables.push([0i16; TABLE_SIZE]);
-    let mut ofs: u64 = 0;
-    for i in 1..25 {
-        for k in 0..lengths.len() {
-            if lengths[k] == i {
-                let size = insert_code(&mut tables,ofs as u32,k as u16,lengths[k]);
-                ofs += size as u64;
-            }
-        }
-    }
-    tables
-}
-
-struct ZipReader<'a> {
-    block: &'a [u8],
-    rp: usize,
-    bit: u32,
-    cache: u32,
-}
-
-impl<'a> ZipReader<'a> {
-    // This currently entirely ignores the ZLIB wrapper that goes around the INFLATE blocks; this might not always be a good thing
-    fn new(block: &'a [u8]) -> ZipReader<'a> {
-        ZipReader {
-            block: block,
-            rp: 6,
-            bit: 32,
-            cache: ((block[2] as u32) | ((block[3] as u32) << 8) | ((block[4] as u32) << 16) | ((block[5] as u32) << 24)) as u32,
-        }        
-    }
-
-    fn align(&mut self) -> usize {
-        if self.bit < 32 {
-            self.bit = 32;
-            self.rp - 3
-        }
-        else {
-            self.rp - 4
-        }
-    }
-
-    fn set(&mut self,rp: usize) {
-        self.rp = rp;
-        self.cache = (self.block[self.rp] as u32) |
-            ((self.block[self.rp + 1] as u32) << 8) |
-            ((self.block[self.rp + 2] as u32) << 16) |
-            ((self.block[self.rp + 3] as u32) << 24);
-        self.rp += 4;
-        self.bit = 32;
-    }
-
-    fn read_bits(&mut self,n: u32) -> Option<u32> {
-        let result: u32 = self.cache & ((1 << n) - 1);
-        self.cache >>= n;
-        self.bit -= n;
-        while self.bit <= 24 {
-            if self.rp >= self.block.len() {
-                return None;
-            }
-            self.cache |= (self.block[self.rp] as u32) << self.bit;
-            self.rp += 1;
-            self.bit += 8;
-        }
-        Some(result)
-    }
-
-    fn read_symbol(&mut self,prefix: &Vec<[i16; TABLE_SIZE]>) -> Option<u32> {
-
-        let mut n: usize = 0;
-        let mut index = (self.cache & (TABLE_SIZE - 1) as u32) as usize;
-        let mut stuff = prefix[n][index];
-        let mut already_shifted = 0;
-        while stuff < 0 {
-            self.cache >>= TABLE;
-            self.bit -= TABLE as u32;
-            while self.bit <= 24 {
-                if self.rp >= self.block.len() {
-                    return None;
-                }
-                self.cache |= (self.block[self.rp] as u32) << self.bit;
-                self.rp += 1;
-                self.bit += 8;
-            }
-            already_shifted += TABLE;
-            n = (-stuff) as usize;
-            index = (self.cache & (TABLE_SIZE - 1) as u32) as usize;
-            stuff = prefix[n][index];
-        }
-        let symbol = stuff >> 5;
-        let length = (stuff & 31) - already_shifted as i16;
-        self.cache >>= length;
-        self.bit -= length as u32;
-        while self.bit <= 24 {
-            if self.rp >= self.block.len() {
-                return None;
-            }
-            self.cache |= (self.block[self.rp] as u32) << self.bit;
-            self.rp += 1;
-            self.bit += 8;
-        }
-        Some(symbol as u32)
-    }
-}
-
-fn inflate(src: &[u8],inflated_size: u32) -> Option<Vec<u8>> {
-
-    //println!("PNG: ZIP INFLATE: zlib header: {:02X} {:02X}; inflated size supposed to be {}, source size {}",src[0],src[1],inflated_size,src.len());
+// 2-bit grayscale levels
+const GRAY2: [u16; 4] = [0x0000,0x5555,0xAAAA,0xFFFF];
 
-    let mut dst: Vec<u8> = vec![0; inflated_size as usize];
-    let mut reader = ZipReader::new(&src);
-    let mut dp: usize = 0;
-
-    // create default litlen table
-    let mut lengths: [u8; 288] = [0; 288];
-    for i in 0..144 {
-        lengths[i] = 8;
-    }
-    for i in 144..256 {
-        lengths[i] = 9;
-    }
-    for i in 256..280 {
-        lengths[i] = 7;
-    }
-    for i in 280..288 {
-        lengths[i] = 8;
-    }
-
-    let default_hlitlen_tables = create_huffman_tables(&lengths);
-    #[allow(unused_assignments)]
-    let mut current_hlitlen_tables: Vec<[i16; TABLE_SIZE]> = Vec::new();
-
-    // create default dist table
-    let lengths: [u8; 32] = [5; 32];
-    let default_hdist_tables = create_huffman_tables(&lengths);
-    #[allow(unused_assignments)]
-    let mut current_hdist_tables: Vec<[i16; TABLE_SIZE]> = Vec::new();
-
-    let mut hlitlen_tables: &Vec<[i16; TABLE_SIZE]> = &default_hlitlen_tables;
-    let mut hdist_tables: &Vec<[i16; TABLE_SIZE]> = &default_hdist_tables;
-
-    // main loop
-    let mut is_final = false;
-    while !is_final {
-
-        // get final block and type bits
-        match reader.read_bits(1) {
-            Some(value) => { if value == 1 { is_final = true; } else { is_final = false; } },
-            None => {
-                //println!("PNG: ZIP INFLATE: unable to read final block bit");
-                return None;
-            },
-        }
-        let block_type = match reader.read_bits(2) {
-            Some(value) => { value },
-            None => {
-                //println!("PNG: ZIP INFLATE: unable to read type bits");
-                return None;
+// undo the filter of one row in place, prior is the unfiltered row above (zeros for the first row)
+fn unfilter_row(row: &mut [u8],prior: &[u8],ftype: u8,bpp: usize) {
+    for x in 0..row.len() {
+        let mut s = row[x] as i32;
+        let a: i32 = if x >= bpp { row[x - bpp] as i32 } else { 0 };
+        let b: i32 = prior[x] as i32;
+        let c: i32 = if x >= bpp { prior[x - bpp] as i32 } else { 0 };
+        s += match ftype {
+            0 => { 0 },
+            1 => { a },
+            2 => { b },
+            3 => { (a + b) >> 1 },
+            4 => {
+                let d: i32 = a + b - c;
+                let da: i32 = d - a;
+                let pa: i32 = if da < 0 { -da } else { da };
+                let db: i32 = d - b;
+                let pb: i32 = if db < 0 { -db } else { db };
+                let dc: i32 = d - c;
+                let pc: i32 = if dc < 0 { -dc } else { dc };
+                if (pa <= pb) && (pa <= pc) { a } else if pb <= pc { b } else { c }
             },
+            _ => { 0 },
         };
-
-        //println!("PNG: ZIP INFLATE: block final {}, type {}",is_final,block_type);
-
-        // process uncompressed data
-        match block_type {
-            0 => {
-                let mut sp = reader.align();
-                let length = (((src[sp + 1] as u16) << 8) | (src[sp] as u16)) as usize;
-                sp += 4;
-                if (sp + length > src.len()) || (dp + length > dst.len()) {
-                    //println!("PNG: ZIP INFLATE: uncompressed data out of range");
-                    return None;
-                }
-                dst[dp..dp + length].copy_from_slice(&src[sp..sp + length]);
-                sp += length;
-                dp += length;
-                reader.set(sp);
-            },
-            1 => {
-                hlitlen_tables = &default_hlitlen_tables;
-                hdist_tables = &default_hdist_tables;
-            },
-            2 => {
-                // get table metrics
-                let hlit = match reader.read_bits(5) {
-                    Some(value) => { value + 257 },
-                    None => {
-                        //println!("PNG: ZIP INFLATE: unable to read hlit bits");
-                        return None;
-                    },
-                } as usize;
-                let hdist = match reader.read_bits(5) {
-                    Some(value) => { value + 1 },
-                    None => {
-                        //println!("PNG: ZIP INFLATE: unable to read hdist bits");
-                        return None;
-                    },
-                } as usize;
-                let hclen = match reader.read_bits(4) {
-                    Some(value) => { value + 4 },
-                    None => {
-                        //println!("PNG: ZIP INFLATE: unable to read hclen bits");
-                        return None;
-                    },
-                };
-
-                // get length codes
-                let mut lengths: [u8; 20] = [0; 20];
-                for i in 0..hclen {
-                    lengths[HCORD[i as usize]] = match reader.read_bits(3) {
-                        Some(value) => { value },
-                        None => {
-                            //println!("PNG: ZIP INFLATE: unable to read length code bits");
-                            return None;
-                        },
-                    } as u8;
-                }
-                let hctree_tables = create_huffman_tables(&lengths);
-
-                // no really, get length codes
-                let mut lengths: [u8; 320] = [0; 320];
-                let mut ll: usize = 0;
-                while ll < hlit + hdist {
-                    let code = match reader.read_symbol(&hctree_tables) {
-                        Some(value) => { value },
-                        None => {
-                            //println!("PNG: ZIP INFLATE: unable to read code symbol");
-                            return None;
-                        },
-                    };
-                    if code == 16 {
-                        let length = match reader.read_bits(2) {
-                            Some(value) => { value + 3 },
-                            None => {
-                                //println!("PNG: ZIP INFLATE: unable to read 2 length bits");
-                                return None;
-                            },
-                        };
-                        for _i in 0..length { // TODO: for loop might be expressed differently in rust
-                            lengths[ll] = lengths[ll - 1];
-                            ll += 1;
-                        }
-                    }
-                    else if code == 17 {
-                        let length = match reader.read_bits(3) {
-                            Some(value) => { value + 3 },
-                            None => {
-                                //println!("PNG: ZIP INFLATE: unable to read 3 length bits");
-                                return None;
-                            },
-                        };
-                        for _i in 0..length { // TODO: for loop might be expressed differently in rust
-                            lengths[ll] = 0;
-                            ll += 1;
-                        }
-                    }
-                    else if code == 18 {
-                        let length = match reader.read_bits(7) {
-                            Some(value) => { value + 11 },
-                            None => {
-                                //println!("PNG: ZIP INFLATE: unable to read 7 length bits");
-                                return None;
-                            },
-                        };
-                        for _i in 0..length { // TODO: for loop might be expressed differently in rust
-                            lengths[ll] = 0;
-                            ll += 1;
-                        }
-                    }
-                    else {
-                        lengths[ll] = code as u8;
-                        ll += 1;
-                    }
-                }
-
-                current_hlitlen_tables = create_huffman_tables(&lengths[0..hlit]);
-                current_hdist_tables = create_huffman_tables(&lengths[hlit..hlit + hdist]);
-
-                hlitlen_tables = &current_hlitlen_tables;
-                hdist_tables = &current_hdist_tables;
-            },
-            3 => {
-                if is_final {
-                    break;
-                }
-                else {
-                    //println!("PNG: ZIP INFLATE: block type 3 not supported");
-                    return None;
-                }
-            },
-            _ => { },
-        }
-        if (block_type == 1) || (block_type == 2) {
-            // read them codes
-            while dp < dst.len() {
-                let mut code = match reader.read_symbol(&hlitlen_tables) {
-                    Some(value) => { value },
-                    None => {
-                        //println!("PNG: ZIP INFLATE: unable to read code symbol");
-                        return None;
-                    },
-                };
-                if code < 256 {
-                    dst[dp] = code as u8;
-                    dp += 1;
-                }
-                else if code == 256 {
-                    break;
-                }
-                else {
-                    // get lit/len length and extra bit entries
-                    code -= 257;
-                    let mut length = LITLEN_LENGTH[code as usize] as usize;
-                    let extra = LITLEN_EXTRA[code as usize] as u32;
-
-                    // read extra bits
-                    if extra > 0 {
-                        length += match reader.read_bits(extra) {
-                            Some(value) => { value },
-                            None => {
-                                //println!("PNG: ZIP INFLATE: unable to read extra length bits");
-                                return None;
-                            },
-                        } as usize;
-                    }
-
-                    // get dist length and extra bit entries
-                    code = match reader.read_symbol(&hdist_tables) {
-                        Some(value) => { value },
-                        None => {
-                            //println!("PNG: ZIP INFLATE: unable to read extra dist length");
-                            return None;
-                        },
-                    };
-                    let mut dist = DIST_DIST[code as usize] as usize;
-                    let extra = DIST_EXTRA[code as usize] as u32;
-
-                    // read extra bits
-                    if extra > 0 {
-                        dist += match reader.read_bits(extra) {
-                            Some(value) => { value },
-                            None => {
-                                //println!("PNG: ZIP INFLATE: unable to read extra bits");
-                                return None;
-                            },
-                        } as usize;
-                    }
-
-                    // copy block
-                    if dp + length > dst.len() {
-                        length = dst.len() - dp;
-                        //return None;
-                    }
-                    if dist > dp {
-                        //println!("PNG: ZIP INFLATE: distance too large");
-                        return None;
-                    }
-                    if dp + length - dist > dst.len() {
-                        //println!("PNG: ZIP INFLATE: source block too big");
-                        return None;
-                    }
-                    for i in 0..length {
-                        dst[dp + i] = dst[dp - dist + i];
-                    }
-                    dp += length;
-                }
-            }
-        }
+        if s >= 256 { s -= 256 };
+        if s < 0 { s += 256 };
+        row[x] = s as u8;
     }
-    Some(dst)
 }
 
 fn unfilter(src: &[u8],height: usize,stride: usize,bpp: usize) -> Vec<u8> {
-    let mut dst: Vec<u8> = vec![0; stride * height * bpp];
-    let mut sp: usize = 0;
-    let mut dp: usize = 0;
+    let mut dst: Vec<u8> = vec![0; stride * height];
+    let zeros: Vec<u8> = vec![0; stride];
     for y in 0..height {
+        let sp = y * (stride + 1);
         let ftype = src[sp];
-        sp += 1;
-        for x in 0..stride {
-            let mut s = src[sp] as i32;
-            sp += 1;
-            let a: i32 = if x >= bpp { dst[dp - bpp] as i32 } else { 0 };
-            let b: i32 = if y >= 1 { dst[dp - stride] as i32 } else { 0 };
-            let c: i32 = if (y >= 1) && (x >= bpp) { dst[dp - stride - bpp] as i32 } else { 0 };
-            s += match ftype {
-                0 => { 0 },
-                1 => { a },
-                2 => { b },
-                3 => { (a + b) >> 1 },
-                4 => {
-                    let d: i32 = a + b - c;
-                    let da: i32 = d - a;
-                    let pa: i32 = if da < 0 { -da } else { da };
-                    let db: i32 = d - b;
-                    let pb: i32 = if db < 0 { -db } else { db };
-                    let dc: i32 = d - c;
-                    let pc: i32 = if dc < 0 { -dc } else { dc };
-                    if (pa <= pb) && (pa <= pc) { a } else if pb <= pc { b } else { c }
-                },
-                _ => { 0 },
-            };
-            if s >= 256 { s -= 256 };
-            if s < 0 { s += 256 };
-            dst[dp] = s as u8;
-            dp += 1;
-        }
+        let (above,rest) = dst.split_at_mut(y * stride);
+        let row = &mut rest[0..stride];
+        row.copy_from_slice(&src[sp + 1..sp + 1 + stride]);
+        let prior = if y >= 1 { &above[(y - 1) * stride..] } else { &zeros[..] };
+        unfilter_row(row,prior,ftype,bpp);
     }
     dst
 }
 
-fn clampf(v: f32,min: f32,max: f32) -> f32 {
-    if v < min {
-        min
-    }
-    else if v > max {
-        max
-    }
-    else {
-        v
-    }
+// set pixel from 16-bit samples
+fn set_rgba16<T: pixel::Pixel>(p: &mut T,r: u16,g: u16,b: u16,a: u16) {
+    p.set16(r,g,b,a);
 }
 
-fn set_lf<T: pixel::Pixel>(p: &mut T,l: f32,gamma: f32) {
-    let ul = (clampf(l.powf(gamma),0.0,1.0) * 255.0) as u8;
-    p.set(ul,ul,ul,255);
+// set opaque pixel, or transparent if it matches the tRNS color key
+fn set_rgb16<T: pixel::Pixel>(p: &mut T,r: u16,g: u16,b: u16,key: Option<(u16,u16,u16)>) {
+    set_rgba16(p,r,g,b,if key == Some((r,g,b)) { 0 } else { 0xFFFF });
 }
 
-fn set_rgbaf<T: pixel::Pixel>(p: &mut T,r: f32,g: f32,b: f32,a: f32,gamma: f32) {
-    let ur = (clampf(r.powf(gamma),0.0,1.0) * 255.0) as u8;
-    let ug = (clampf(g.powf(gamma),0.0,1.0) * 255.0) as u8;
-    let ub = (clampf(b.powf(gamma),0.0,1.0) * 255.0) as u8;
-    let ua = (clampf(a.powf(gamma),0.0,1.0) * 255.0) as u8;
-    p.set(ur,ug,ub,ua);
+fn set_l16<T: pixel::Pixel>(p: &mut T,l: u16,key: Option<(u16,u16,u16)>) {
+    set_rgb16(p,l,l,l,key);
 }
 
-fn set_c<T: pixel::Pixel>(p: &mut T,c: T,gamma: f32) {
-    let (r,g,b,a) = c.get();
-    let r = (r as f32) / 255.0;
-    let g = (g as f32) / 255.0;
-    let b = (b as f32) / 255.0;
-    let a = (a as f32) / 255.0;
-    let ur = (clampf(r.powf(gamma),0.0,1.0) * 255.0) as u8;
-    let ug = (clampf(g.powf(gamma),0.0,1.0) * 255.0) as u8;
-    let ub = (clampf(b.powf(gamma),0.0,1.0) * 255.0) as u8;
-    let ua = (clampf(a.powf(gamma),0.0,1.0) * 255.0) as u8;
-    p.set(ur,ug,ub,ua);
+fn set_c<T: pixel::Pixel>(p: &mut T,c: T) {
+    *p = c;
 }
 
-fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,height: usize,stride: usize,x0: usize,y0: usize,dx: usize,dy: usize,itype: Type,palette: &[T; 256],gamma: f32) {
+fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,height: usize,stride: usize,x0: usize,y0: usize,dx: usize,dy: usize,itype: Type,palette: &[T; 256],key: Option<(u16,u16,u16)>) {
     let mut sp = 0;
     match itype {
         Type::L1 => {
@@ -545,16 +105,16 @@ fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,heigh
                     let d = src[sp];
                     sp += 1;
                     for i in 0..8 {
-                        let l = if(d & (0x80 >> i)) != 0 { 1.0 } else { 0.0 };
-                        set_lf(&mut dst[(y0 + y * dy) * stride + x0 + (x * 8 + i) * dx],l,gamma);
+                        let l = if(d & (0x80 >> i)) != 0 { 0xFFFF } else { 0x0000 };
+                        set_l16(&mut dst[(y0 + y * dy) * stride + x0 + (x * 8 + i) * dx],l,key);
                     }
                 }
                 if (width & 7) != 0 {
                     let d = src[sp];
                     sp += 1;
-                    for i in 0..8 {
-                        let l = if(d & (0x80 >> i)) != 0 { 1.0 } else { 0.0 };
-                        set_lf(&mut dst[(y0 + y * dy) * stride + x0 + ((width & 0xFFFFFFF8) + i) * dx],l,gamma);
+                    for i in 0..(width & 7) {
+                        let l = if(d & (0x80 >> i)) != 0 { 0xFFFF } else { 0x0000 };
+                        set_l16(&mut dst[(y0 + y * dy) * stride + x0 + ((width & 0xFFFFFFF8) + i) * dx],l,key);
                     }
                 }
             }
@@ -566,7 +126,7 @@ fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,heigh
                     sp += 1;
                     for i in 0..8 {
                         let c = if (d & (0x80 >> i)) != 0 { palette[1] } else { palette[0] };
-                        set_c(&mut dst[(y0 + y * dy) * stride + x0 + (x * 8 + i) * dx],c,gamma);
+                        set_c(&mut dst[(y0 + y * dy) * stride + x0 + (x * 8 + i) * dx],c);
                     }
                 }
                 if (width & 7) != 0 {
@@ -574,7 +134,7 @@ fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,heigh
                     sp += 1;
                     for i in 0..(width & 7) {
                         let c = if (d & (0x80 >> i)) != 0 { palette[1] } else { palette[0] };
-                        set_c(&mut dst[(y0 + y * dy) * stride + x0 + ((width & 0xFFFFFFF8) + i) * dx],c,gamma);
+                        set_c(&mut dst[(y0 + y * dy) * stride + x0 + ((width & 0xFFFFFFF8) + i) * dx],c);
                     }
                 }
             }
@@ -585,14 +145,14 @@ fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,heigh
                     let d = src[sp];
                     sp += 1;
                     for i in 0..4 {
-                        set_lf(&mut dst[(y0 + y * dy) * stride + x0 + (x * 4 + i) * dx],GRAY2[((d >> ((3 - i) * 2)) & 3) as usize],gamma);
+                        set_l16(&mut dst[(y0 + y * dy) * stride + x0 + (x * 4 + i) * dx],GRAY2[((d >> ((3 - i) * 2)) & 3) as usize],key);
                     }
                 }
                 if(width & 3) != 0 {
                     let d = src[sp];
                     sp += 1;
                     for i in 0..(width & 3) {
-                        set_lf(&mut dst[(y0 + y * dy) * stride + x0 + ((width & 0xFFFFFFFC) + i) * dx],GRAY2[((d >> ((3 - i) * 2)) & 3) as usize],gamma);
+                        set_l16(&mut dst[(y0 + y * dy) * stride + x0 + ((width & 0xFFFFFFFC) + i) * dx],GRAY2[((d >> ((3 - i) * 2)) & 3) as usize],key);
                     }
                 }
             }
@@ -603,14 +163,14 @@ fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,heigh
                     let d = src[sp];
                     sp += 1;
                     for i in 0..4 {
-                        set_c(&mut dst[(y0 + y * dy) * stride + x0 + (x * 4 + i) * dx],palette[((d >> ((3 - i) * 2)) & 3) as usize],gamma);
+                        set_c(&mut dst[(y0 + y * dy) * stride + x0 + (x * 4 + i) * dx],palette[((d >> ((3 - i) * 2)) & 3) as usize]);
                     }
                 }
                 if(width & 3) != 0 {
                     let d = src[sp];
                     sp += 1;
                     for i in 0..(width & 3) {
-                        set_c(&mut dst[(y0 + y * dy) * stride + x0 + ((width & 0xFFFFFFFC) + i) * dx],palette[((d >> ((3 - i) * 2)) & 3) as usize],gamma);
+                        set_c(&mut dst[(y0 + y * dy) * stride + x0 + ((width & 0xFFFFFFFC) + i) * dx],palette[((d >> ((3 - i) * 2)) & 3) as usize]);
                     }
                 }
             }
@@ -621,11 +181,11 @@ fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,heigh
                     let d = src[sp];
                     sp += 1;
                     for i in 0..2 {
-                        set_lf(&mut dst[(y0 + y * dy) * stride + x0 + (x * 2 + i) * dx],GRAY4[((d >> ((1 >> i) * 4)) & 15) as usize],gamma);
+                        set_l16(&mut dst[(y0 + y * dy) * stride + x0 + (x * 2 + i) * dx],(((d >> ((1 >> i) * 4)) & 15) as u16) * 0x1111,key);
                     }
                 }
                 if (width & 1) != 0 {
-                    set_lf(&mut dst[(y0 + y * dy) * stride + x0 + (width & 0xFFFFFFFE) * dx],GRAY4[(src[sp] >> 4) as usize],gamma);
+                    set_l16(&mut dst[(y0 + y * dy) * stride + x0 + (width & 0xFFFFFFFE) * dx],((src[sp] >> 4) as u16) * 0x1111,key);
                     sp += 1;
                 }
             }
@@ -636,11 +196,11 @@ fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,heigh
                     let d = src[sp];
                     sp += 1;
                     for i in 0..2 {
-                        set_c(&mut dst[(y0 + y * dy) * stride + x0 + (x * 2 + i) * dx],palette[((d >> ((1 >> i) * 4)) & 15) as usize],gamma);
+                        set_c(&mut dst[(y0 + y * dy) * stride + x0 + (x * 2 + i) * dx],palette[((d >> ((1 >> i) * 4)) & 15) as usize]);
                     }
                 }
                 if (width & 1) != 0 {
-                    set_c(&mut dst[(y0 + y * dy) * stride + x0 + (width & 0xFFFFFFFE) * dx],palette[(src[sp] >> 4) as usize],gamma);
+                    set_c(&mut dst[(y0 + y * dy) * stride + x0 + (width & 0xFFFFFFFE) * dx],palette[(src[sp] >> 4) as usize]);
                     sp += 1;
                 }
             }
@@ -648,20 +208,20 @@ fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,heigh
         Type::L8 => {
             for y in 0..height {
                 for x in 0..width {
-                    let l = (src[sp] as f32) / 255.0;
+                    let l = (src[sp] as u16) * 257;
                     sp += 1;
-                    set_lf(&mut dst[(y0 + y * dy) * stride + x0 + x * dx],l,gamma);
+                    set_l16(&mut dst[(y0 + y * dy) * stride + x0 + x * dx],l,key);
                 }
             }
         },
         Type::RGB8 => {
             for y in 0..height {
                 for x in 0..width {
-                    let r = (src[sp] as f32) / 255.0;
-                    let g = (src[sp + 1] as f32) / 255.0;
-                    let b = (src[sp + 2] as f32) / 255.0;
+                    let r = (src[sp] as u16) * 257;
+                    let g = (src[sp + 1] as u16) * 257;
+                    let b = (src[sp + 2] as u16) * 257;
                     sp += 3;
-                    set_rgbaf(&mut dst[(y0 + y * dy) * stride + x0 + x * dx],r,g,b,1.0,gamma);
+                    set_rgb16(&mut dst[(y0 + y * dy) * stride + x0 + x * dx],r,g,b,key);
                 }
             }
         },
@@ -670,71 +230,71 @@ fn decode_pixels<T: pixel::Pixel>(dst: &mut Mat<T>,src: &[u8],width: usize,heigh
                 for x in 0..width {
                     let c = src[sp];
                     sp += 1;
-                    set_c(&mut dst[(y0 + y * dy) * stride + x0 + x * dx],palette[c as usize],gamma);
+                    set_c(&mut dst[(y0 + y * dy) * stride + x0 + x * dx],palette[c as usize]);
                 }
             }
         },
         Type::LA8 => {
             for y in 0..height {
                 for x in 0..width {
-                    let l = (src[sp] as f32) / 255.0;
-                    let a = (src[sp + 1] as f32) / 255.0;
+                    let l = (src[sp] as u16) * 257;
+                    let a = (src[sp + 1] as u16) * 257;
                     sp += 2;
-                    set_rgbaf(&mut dst[(y0 + y * dy) * stride + x0 + x * dx],l,l,l,a,gamma);
+                    set_rgba16(&mut dst[(y0 + y * dy) * stride + x0 + x * dx],l,l,l,a);
                 }
             }
         },
         Type::RGBA8 => {
             for y in 0..height {
                 for x in 0..width {
-                    let r = (src[sp] as f32) / 255.0;
-                    let g = (src[sp + 1] as f32) / 255.0;
-                    let b = (src[sp + 2] as f32) / 255.0;
-                    let a = (src[sp + 3] as f32) / 255.0;
+                    let r = (src[sp] as u16) * 257;
+                    let g = (src[sp + 1] as u16) * 257;
+                    let b = (src[sp + 2] as u16) * 257;
+                    let a = (src[sp + 3] as u16) * 257;
                     sp += 4;
-                    set_rgbaf(&mut dst[(y0 + y * dy) * stride + x0 + x * dx],r,g,b,a,gamma);
+                    set_rgba16(&mut dst[(y0 + y * dy) * stride + x0 + x * dx],r,g,b,a);
                 }
             }
         },
         Type::L16 => {
             for y in 0..height {
                 for x in 0..width {
-                    let l = (src[sp] as f32) / 255.0;
+                    let l = from_be16(&src[sp..]);
                     sp += 2;
-                    set_lf(&mut dst[(y0 + y * dy) * stride + x0 + x * dx],l,gamma);
+                    set_l16(&mut dst[(y0 + y * dy) * stride + x0 + x * dx],l,key);
                 }
             }
         },
         Type::RGB16 => {
             for y in 0..height {
                 for x in 0..width {
-                    let r = (src[sp] as f32) / 255.0;
-                    let g = (src[sp + 2] as f32) / 255.0;
-                    let b = (src[sp + 4] as f32) / 255.0;
+                    let r = from_be16(&src[sp..]);
+                    let g = from_be16(&src[sp + 2..]);
+                    let b = from_be16(&src[sp + 4..]);
                     sp += 6;
-                    set_rgbaf(&mut dst[(y0 + y * dy) * stride + x0 + x * dx],r,g,b,1.0,gamma);
+                    set_rgb16(&mut dst[(y0 + y * dy) * stride + x0 + x * dx],r,g,b,key);
                 }
             }
         },
         Type::LA16 => {
             for y in 0..height {
                 for x in 0..width {
-                    let l = (src[sp] as f32) / 255.0;
-                    let a = (src[sp + 2] as f32) / 255.0;
+                    let l = from_be16(&src[sp..]);
+                    let a = from_be16(&src[sp + 2..]);
                     sp += 4;
-                    set_rgbaf(&mut dst[(y0 + y * dy) * stride + x0 + x * dx],l,l,l,a,gamma);
+                    set_rgba16(&mut dst[(y0 + y * dy) * stride + x0 + x * dx],l,l,l,a);
                 }
             }
         },
         Type::RGBA16 => {
             for y in 0..height {
                 for x in 0..width {
-                    let r = (src[sp] as f32) / 255.0;
-                    let g = (src[sp + 2] as f32) / 255.0;
-                    let b = (src[sp + 4] as f32) / 255.0;
-                    let a = (src[sp + 6] as f32) / 255.0;
+                    let r = from_be16(&src[sp..]);
+                    let g = from_be16(&src[sp + 2..]);
+                    let b = from_be16(&src[sp + 4..]);
+                    let a = from_be16(&src[sp + 6..]);
                     sp += 8;
-                    set_rgbaf(&mut dst[(y0 + y * dy) * stride + x0 + x * dx],r,g,b,a,gamma);
+                    set_rgba16(&mut dst[(y0 + y * dy) * stride + x0 + x * dx],r,g,b,a);
                 }
             }
         },
@@ -749,91 +309,271 @@ fn from_be32(src: &[u8]) -> u32 {
     ((src[0] as u32) << 24) | ((src[1] as u32) << 16) | ((src[2] as u32) << 8) | (src[3] as u32)
 }
 
-pub fn test(src: &[u8]) -> Option<(u32,u32)> {
-    if (src[0] == 0x89) && (src[1] == 0x50) && (src[2] == 0x4E) && (src[3] == 0x47) && (src[4] == 0x0D) && (src[5] == 0x0A) && (src[6] == 0x1A) && (src[7] == 0x0A) {
-        let mut sp: usize = 8;
-        while sp < src.len() {
-            let chunk_length = from_be32(&src[sp..sp + 4]) as usize;
-            sp += 4;
-            let chunk_type = from_be32(&src[sp..sp + 4]);
-            sp += 4;
-            if chunk_type == 0x49484452 { // IHDR
-                let width = from_be32(&src[sp..sp + 4]);
-                sp += 4;
-                let height = from_be32(&src[sp..sp + 4]);
-                sp += 4;
-                let t = from_be16(&src[sp..sp + 2]);
-                match t {
-                    0x0100 | 0x0103 | 0x0200 | 0x0203 | 0x0400 | 0x0403 | 0x0800 | 0x0802 | 0x0803 | 0x0804 | 0x0806 | 0x1000 | 0x1002 | 0x1004 | 0x1006 => { return Some((width,height)); },
-                    _ => { return None; },
-                }
+pub fn info(src: &[u8]) -> Result<ImageInfo,ImageError> {
+    if (src.len() < 8) || (src[0..8] != [0x89,0x50,0x4E,0x47,0x0D,0x0A,0x1A,0x0A]) {
+        return Err(ImageError::UnknownFormat);
+    }
+    let mut info: Option<ImageInfo> = None;
+    let mut palette: &[u8] = &[];
+    let mut sp: usize = 8;
+    while sp + 8 <= src.len() {
+        let chunk_length = from_be32(&src[sp..sp + 4]) as usize;
+        let chunk_type = from_be32(&src[sp + 4..sp + 8]);
+        sp += 8;
+        if sp + chunk_length > src.len() {
+            return Err(ImageError::Corrupt(sp - 8,"truncated chunk"));
+        }
+        let data = &src[sp..sp + chunk_length];
+        if chunk_type == 0x49484452 { // IHDR
+            if chunk_length < 13 {
+                return Err(ImageError::Corrupt(sp,"invalid IHDR"));
             }
-            else if chunk_type == 0x49454E44 { // IEND
-                break;
+            let width = from_be32(&data[0..4]) as usize;
+            let height = from_be32(&data[4..8]) as usize;
+            if (width == 0) || (height == 0) {
+                return Err(ImageError::Dimensions(width,height));
             }
-            else {
-                sp += chunk_length;
+            let channels = match from_be16(&data[8..10]) {
+                0x0100 | 0x0200 | 0x0400 | 0x0800 | 0x1000 => ChannelLayout::Gray,
+                0x0802 | 0x1002 | 0x0103 | 0x0203 | 0x0403 | 0x0803 => ChannelLayout::Rgb,
+                0x0804 | 0x1004 => ChannelLayout::GrayAlpha,
+                0x0806 | 0x1006 => ChannelLayout::Rgba,
+                _ => { return Err(ImageError::Unsupported("PNG color type")); },
+            };
+            let mut ihdr = ImageInfo::new(ImageFormat::Png,width,height,data[8] as u32,channels);
+            ihdr.palette = data[9] == 3;
+            ihdr.interlaced = data[12] == 1;
+            info = Some(ihdr);
+        }
+        else {
+            // IHDR has to be the first chunk
+            let info = info.as_mut().ok_or(ImageError::Corrupt(8,"missing IHDR"))?;
+            match chunk_type {
+                0x49444154 | 0x49454E44 => { // IDAT, IEND
+                    break;
+                },
+                0x6163544C => { // acTL
+                    if chunk_length >= 4 {
+                        info.frames = from_be32(&data[0..4]) as usize;
+                    }
+                },
+                0x504C5445 => { // PLTE
+                    palette = data;
+                },
+                0x624B4744 => { // bKGD
+                    // palette index, or gray or RGB samples in the bit depth of the image
+                    let scale = |v: u16| if info.bit_depth >= 16 { v } else { ((v as u32) * 65535 / ((1 << info.bit_depth) - 1)) as u16 };
+                    info.background = if info.palette {
+                        let index = if chunk_length >= 1 { data[0] as usize * 3 } else { palette.len() };
+                        if index + 3 <= palette.len() {
+                            Some(((palette[index] as u16) * 257,(palette[index + 1] as u16) * 257,(palette[index + 2] as u16) * 257))
+                        }
+                        else {
+                            None
+                        }
+                    }
+                    else if (info.channels == ChannelLayout::Gray) || (info.channels == ChannelLayout::GrayAlpha) {
+                        if chunk_length >= 2 {
+                            let level = scale(from_be16(&data[0..2]));
+                            Some((level,level,level))
+                        }
+                        else {
+                            None
+                        }
+                    }
+                    else if chunk_length >= 6 {
+                        Some((scale(from_be16(&data[0..2])),scale(from_be16(&data[2..4])),scale(from_be16(&data[4..6]))))
+                    }
+                    else {
+                        None
+                    };
+                },
+                0x6348524D => { // cHRM
+                    if chunk_length >= 32 {
+                        let v = |i: usize| (from_be32(&data[i * 4..i * 4 + 4]) as f32) / 100000.0;
+                        info.chromaticities = Some(Chromaticities {
+                            white: vec2!(v(0),v(1)),
+                            red: vec2!(v(2),v(3)),
+                            green: vec2!(v(4),v(5)),
+                            blue: vec2!(v(6),v(7)),
+                        });
+                    }
+                },
+                0x67414D41 => { // gAMA
+                    if chunk_length >= 4 {
+                        info.gamma = Some((from_be32(&data[0..4]) as f32) / 100000.0);
+                    }
+                },
+                0x69434350 => { // iCCP
+                    // profile name, compression method, zlib compressed profile
+                    let name_length = data.iter().position(|&b| b == 0).ok_or(ImageError::Corrupt(sp,"invalid iCCP"))?;
+                    if (name_length + 2 > chunk_length) || (data[name_length + 1] != 0) {
+                        return Err(ImageError::Corrupt(sp,"invalid iCCP"));
+                    }
+                    info.icc_profile = Some(compress::inflate(&data[name_length + 2..],compress::Format::Zlib).ok_or(ImageError::Corrupt(sp,"invalid iCCP"))?);
+                },
+                0x73524742 => { // sRGB
+                    info.srgb = true;
+                },
+                0x70485973 => { // pHYs
+                    // only pixels per meter has an absolute unit
+                    if (chunk_length >= 9) && (data[8] == 1) {
+                        info.dpi = Some(vec2!((from_be32(&data[0..4]) as f32) * 0.0254,(from_be32(&data[4..8]) as f32) * 0.0254));
+                    }
+                },
+                0x74524E53 => { // tRNS
+                    info.channels = match info.channels {
+                        ChannelLayout::Gray => ChannelLayout::GrayAlpha,
+                        ChannelLayout::Rgb => ChannelLayout::Rgba,
+                        channels => channels,
+                    };
+                },
+                _ => { },
             }
-            sp += 4;
         }
+        sp += chunk_length + 4;
     }
-    None
+    info.ok_or(ImageError::Corrupt(8,"missing IHDR"))
+}
+
+pub fn test(src: &[u8]) -> Result<(u32,u32),ImageError> {
+    let info = info(src)?;
+    Ok((info.size.x as u32,info.size.y as u32))
 }
 
-pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Option<Mat<T>> {
-    if (src[0] != 0x89) ||
-        (src[1] != 0x50) ||
-        (src[2] != 0x4E) ||
-        (src[3] != 0x47) ||
-        (src[4] != 0x0D) ||
-        (src[5] != 0x0A) ||
-        (src[6] != 0x1A) ||
-        (src[7] != 0x0A) {
-        println!("PNG: decoding tag failed");
+pub fn exif(src: &[u8]) -> Option<Exif> {
+    if (src.len() < 8) || (src[0..8] != [0x89,0x50,0x4E,0x47,0x0D,0x0A,0x1A,0x0A]) {
         return None;
     }
     let mut sp: usize = 8;
-    let mut width: u32 = 0;
-    let mut height: u32 = 0;
-    let mut itype = Type::L1;
-    #[allow(unused_assignments)]
-    let mut compression: u8 = 0;
-    #[allow(unused_assignments)]
-    let mut filter: u8 = 0;
-    let mut interlace: u8 = 0;
-    let mut stride: u32 = 0;
-    let mut bpp: usize = 0;
+    while sp + 8 <= src.len() {
+        let chunk_length = from_be32(&src[sp..sp + 4]) as usize;
+        let chunk_type = from_be32(&src[sp + 4..sp + 8]);
+        sp += 8;
+        if sp + chunk_length > src.len() {
+            return None;
+        }
+        match chunk_type {
+            0x65584966 => { // eXIf
+                return Exif::parse(&src[sp..sp + chunk_length]);
+            },
+            0x49454E44 => { // IEND
+                return None;
+            },
+            _ => { },
+        }
+        sp += chunk_length + 4;
+    }
+    None
+}
+
+// frame as described by fcTL
+#[derive(Copy,Clone)]
+struct FrameControl {
+    rect: Rect<usize>,
+    delay: u32,
+    disposal: Disposal,
+    blend: FrameBlend,
+}
+
+// everything collected from the chunks that is needed to decode the images
+struct Stream<T: pixel::Pixel> {
+    width: u32,
+    height: u32,
+    itype: Type,
+    interlace: u8,
+    palette: [T; 256],
+    key: Option<(u16,u16,u16)>,  // color key from tRNS, as 16-bit samples
+    zipped_data: Vec<u8>,
+    data_offset: usize,  // where the first IDAT starts, for error reporting
+    loops: Option<u32>,  // from acTL, None if not animated
+    default_frame: Option<FrameControl>,  // fcTL before IDAT means the default image is also the first frame
+    frames: Vec<(FrameControl,usize,Vec<u8>)>,  // control, offset of the first fdAT and zipped data
+}
+
+fn row_stride(itype: Type,width: usize) -> usize {
+    match itype {
+        Type::L1 | Type::C1 => { (width + 7) / 8 },
+        Type::L2 | Type::C2 => { (width + 3) / 4 },
+        Type::L4 | Type::C4 => { (width + 1) / 2 },
+        Type::L8 | Type::C8 => { width },
+        Type::RGB8 => { width * 3 },
+        Type::LA8 => { width * 2 },
+        Type::RGBA8 => { width * 4 },
+        Type::L16 => { width * 2 },
+        Type::RGB16 => { width * 6 },
+        Type::LA16 => { width * 4 },
+        Type::RGBA16 => { width * 8 },
+    }
+}
+
+fn bytes_per_pixel(itype: Type) -> usize {
+    match itype {
+        Type::L1 | Type::C1 | Type::L2 | Type::C2 | Type::L4 | Type::C4 | Type::L8 | Type::C8 => { 1 },
+        Type::RGB8 => { 3 },
+        Type::LA8 => { 2 },
+        Type::RGBA8 => { 4 },
+        Type::L16 => { 2 },
+        Type::RGB16 => { 6 },
+        Type::LA16 => { 4 },
+        Type::RGBA16 => { 8 },
+    }
+}
+
+// header_only accepts the chunks in front of the first IDAT, for streaming
+fn parse<T: pixel::Pixel>(src: &[u8],header_only: bool) -> Result<Stream<T>,ImageError> {
+    if (src.len() < 8) || (src[0..8] != [0x89,0x50,0x4E,0x47,0x0D,0x0A,0x1A,0x0A]) {
+        return Err(ImageError::UnknownFormat);
+    }
+    let mut sp: usize = 8;
+    let mut stream = Stream {
+        width: 0,
+        height: 0,
+        itype: Type::L1,
+        interlace: 0,
+        palette: [T::zero(); 256],
+        key: None,
+        zipped_data: Vec::new(),
+        data_offset: 0,
+        loops: None,
+        default_frame: None,
+        frames: Vec::new(),
+    };
+    let mut ihdr_found = false;
     let mut need_plte = false;
     let mut plte_present = false;
-    let mut zipped_data: Vec<u8> = vec![0; src.len()];
-    let mut dp: usize = 0;
     let mut idat_found = false;
     let mut iend_found = false;
-    let mut palette = [T::zero(); 256];
-    let mut _background = T::zero();
-    let mut gamma: f32 = 1.0;
-    while sp < src.len() {
+    while sp + 12 <= src.len() {
         let chunk_length = from_be32(&src[sp..sp + 4]) as usize;
         sp += 4;
         let chunk_type = from_be32(&src[sp..sp + 4]);
         sp += 4;
+        if sp + chunk_length + 4 > src.len() {
+            return Err(ImageError::Corrupt(sp - 8,"truncated chunk"));
+        }
+        if compress::crc32(&src[sp - 4..sp + chunk_length]) != from_be32(&src[sp + chunk_length..]) {
+            return Err(ImageError::Corrupt(sp + chunk_length,"chunk CRC mismatch"));
+        }
         match chunk_type {
             0x49484452 => { // IHDR
-                width = from_be32(&src[sp..]);
-                height = from_be32(&src[sp + 4..]);
+                if chunk_length < 13 {
+                    return Err(ImageError::Corrupt(sp,"invalid IHDR"));
+                }
+                let width = from_be32(&src[sp..]);
+                let height = from_be32(&src[sp + 4..]);
                 let itype_code = from_be16(&src[sp + 8..]);
-                compression = src[sp + 10];
-                filter = src[sp + 11];
-                interlace = src[sp + 12];
-                if (width >= 65536) ||
-                    (height >= 65536) ||
-                    (compression != 0) ||
-                    (filter != 0) ||
-                    (interlace > 1) {
-                    //println!("PNG: header sanity check failed");
-                    return None;
+                let compression = src[sp + 10];
+                let filter = src[sp + 11];
+                let interlace = src[sp + 12];
+                if (width >= 65536) || (height >= 65536) {
+                    return Err(ImageError::Dimensions(width as usize,height as usize));
+                }
+                check_dimensions(width as usize,height as usize)?;
+                if (compression != 0) || (filter != 0) || (interlace > 1) {
+                    return Err(ImageError::Unsupported("PNG compression, filter or interlace method"));
                 }
-                itype = match itype_code {
+                stream.itype = match itype_code {
                     0x0100 => Type::L1,
                     0x0103 => Type::C1,
                     0x0200 => Type::L2,
@@ -850,33 +590,25 @@ pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Option<Mat<T>> {
                     0x1004 => Type::LA16,
                     0x1006 => Type::RGBA16,
                     _ => {
-                        //println!("PNG: unknown itype {:04X}",itype_code);
-                        return None;
+                        return Err(ImageError::Unsupported("PNG color type"));
                     },
                 };
-                match itype {
-                    Type::L1 => { stride = (width + 7) / 8; bpp = 1; },
-                    Type::C1 => { stride = (width + 7) / 8; bpp = 1; need_plte = true; },
-                    Type::L2 => { stride = (width + 3) / 4; bpp = 1; },
-                    Type::C2 => { stride = (width + 3) / 4; bpp = 1; need_plte = true; },
-                    Type::L4 => { stride = (width + 1) / 2; bpp = 1; },
-                    Type::C4 => { stride = (width + 1) / 2; bpp = 1; need_plte = true; },
-                    Type::L8 => { stride = width; bpp = 1; },
-                    Type::RGB8 => { stride = width * 3; bpp = 3; },
-                    Type::C8 => { stride = width; bpp = 1; need_plte = true; },
-                    Type::LA8 => { stride = width * 2; bpp = 2; },
-                    Type::RGBA8 => { stride = width * 4; bpp = 4; },
-                    Type::L16 => { stride = width * 2; bpp = 2; },
-                    Type::RGB16 => { stride = width * 6; bpp = 6; },
-                    Type::LA16 => { stride = width * 2; bpp = 4; },
-                    Type::RGBA16 => { stride = width * 4; bpp = 8; },
-                }
+                need_plte = match stream.itype {
+                    Type::C1 | Type::C2 | Type::C4 | Type::C8 => true,
+                    _ => false,
+                };
+                stream.width = width;
+                stream.height = height;
+                stream.interlace = interlace;
+                ihdr_found = true;
                 sp += chunk_length;
             },
             0x49444154 => { // IDAT
-                &zipped_data[dp..dp + chunk_length].copy_from_slice(&src[sp..sp + chunk_length]);
+                if !idat_found {
+                    stream.data_offset = sp;
+                }
+                stream.zipped_data.extend_from_slice(&src[sp..sp + chunk_length]);
                 sp += chunk_length;
-                dp += chunk_length;
                 idat_found = true;
             },
             0x49454E44 => { // IEND
@@ -886,49 +618,78 @@ pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Option<Mat<T>> {
             0x504C5445 => { // PLTE
                 plte_present = true;
                 if chunk_length > 768 {
-                    //println!("PNG: PLTE chunk too big ({})",chunk_length);
-                    return None;
+                    return Err(ImageError::Corrupt(sp,"invalid PLTE"));
                 }
                 for i in 0..(chunk_length / 3) {
                     let r = src[sp];
                     let g = src[sp + 1];
                     let b = src[sp + 2];
                     sp += 3;
-                    palette[i].set(r,g,b,255);
+                    stream.palette[i].set(r,g,b,255);
                 }
+                sp += chunk_length % 3;
             },
-            0x624B4744 => { // bKGD
-                match itype {
-                    Type::C1 | Type::C2 | Type::C4 | Type::C8 => {
-                        _background = palette[src[sp] as usize];
-                    },
-                    Type::L1 | Type::L2 | Type::L4 | Type::L8 | Type::LA8 | Type::L16 | Type::LA16 => {
-                        let level = src[sp];
-                        _background.set(level,level,level,255);
-                    },
-                    _ => {
-                        let r = src[sp];
-                        let g = src[sp + 2];
-                        let b = src[sp + 4];
-                        _background.set(r,g,b,255);
+            0x6163544C => { // acTL
+                if chunk_length < 8 {
+                    return Err(ImageError::Corrupt(sp,"invalid acTL"));
+                }
+                stream.loops = Some(from_be32(&src[sp + 4..]));
+                sp += chunk_length;
+            },
+            0x6663544C => { // fcTL
+                if chunk_length < 26 {
+                    return Err(ImageError::Corrupt(sp,"invalid fcTL"));
+                }
+                let width = from_be32(&src[sp + 4..]) as usize;
+                let height = from_be32(&src[sp + 8..]) as usize;
+                let x0 = from_be32(&src[sp + 12..]) as usize;
+                let y0 = from_be32(&src[sp + 16..]) as usize;
+                let delay_num = from_be16(&src[sp + 20..]) as u32;
+                let delay_den = from_be16(&src[sp + 22..]) as u32;
+                if (width == 0) || (height == 0) || (x0 + width > stream.width as usize) || (y0 + height > stream.height as usize) {
+                    return Err(ImageError::Corrupt(sp,"frame outside of canvas"));
+                }
+                let control = FrameControl {
+                    rect: rect!(x0,y0,width,height),
+                    delay: (delay_num * 1000) / if delay_den == 0 { 100 } else { delay_den },
+                    disposal: match src[sp + 24] {
+                        1 => Disposal::Background,
+                        2 => Disposal::Previous,
+                        _ => Disposal::None,
                     },
+                    blend: if src[sp + 25] == 1 { FrameBlend::Over } else { FrameBlend::Source },
+                };
+                if idat_found {
+                    stream.frames.push((control,sp + chunk_length + 4,Vec::new()));
+                }
+                else {
+                    stream.default_frame = Some(control);
                 }
                 sp += chunk_length;
             },
+            0x66644154 => { // fdAT
+                if chunk_length >= 4 {
+                    if let Some(frame) = stream.frames.last_mut() {
+                        frame.2.extend_from_slice(&src[sp + 4..sp + chunk_length]);
+                    }
+                }
+                sp += chunk_length;
+            },
+            0x624B4744 => { // bKGD
+                // read by info()
+                sp += chunk_length;
+            },
             0x6348524D => { // cHRM
-                //println!("cHRM {}",chunk_length);
-                // chromaticity coordinates of display
+                // read by info(), applied by color management
                 sp += chunk_length;
             },
             // dSIG (digital signature)
             0x65584966 => { // eXIf
-                //println!("eXIf {}",chunk_length);
-                // EXIF metadata
+                // EXIF metadata, read separately by exif()
                 sp += chunk_length;
             },
             0x67414D41 => { // gAMA
-                let level = ((src[sp] as u32) << 24) | ((src[sp + 1] as u32) << 16) | ((src[sp + 2] as u32) << 8) | (src[sp + 3] as u32);
-                gamma = (level as f32) / 100000.0;
+                // read by info(), applied by color management
                 sp += chunk_length;
             },
             0x68495354 => { // hIST
@@ -970,8 +731,33 @@ pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Option<Mat<T>> {
                 sp += chunk_length;
             },
             0x74524E53 => { // tRNS
-                //println!("tRNS {}",chunk_length);
-                // transparency information
+                // palette alpha, or a color key scaled like the samples
+                let (max,scale) = match stream.itype {
+                    Type::L1 => (1,0xFFFF),
+                    Type::L2 => (3,0x5555),
+                    Type::L4 => (15,0x1111),
+                    Type::L8 | Type::RGB8 => (255,257),
+                    _ => (0xFFFF,1),
+                };
+                match stream.itype {
+                    Type::C1 | Type::C2 | Type::C4 | Type::C8 => {
+                        for i in 0..if chunk_length > 256 { 256 } else { chunk_length } {
+                            let (r,g,b,_) = stream.palette[i].get();
+                            stream.palette[i].set(r,g,b,src[sp + i]);
+                        }
+                    },
+                    Type::L1 | Type::L2 | Type::L4 | Type::L8 | Type::L16 if chunk_length >= 2 => {
+                        let l = (from_be16(&src[sp..]) & max) * scale;
+                        stream.key = Some((l,l,l));
+                    },
+                    Type::RGB8 | Type::RGB16 if chunk_length >= 6 => {
+                        let r = (from_be16(&src[sp..]) & max) * scale;
+                        let g = (from_be16(&src[sp + 2..]) & max) * scale;
+                        let b = (from_be16(&src[sp + 4..]) & max) * scale;
+                        stream.key = Some((r,g,b));
+                    },
+                    _ => { },
+                }
                 sp += chunk_length;
             }
             0x7A545874 => { // zTXt
@@ -987,115 +773,486 @@ pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Option<Mat<T>> {
         sp += 4; // also skip the CRC
     }
 
+    // sanity check the header
+    if !ihdr_found {
+        return Err(ImageError::Corrupt(8,"missing IHDR"));
+    }
+
     // sanity check the palette
     if need_plte && !plte_present {
-        //println!("PNG: palette needed but not present");
-        return None;
+        return Err(ImageError::Corrupt(sp,"missing PLTE"));
     }
 
     // sanity check the data
-    if !idat_found || !iend_found {
-        //println!("PNG: missing IDAT or IEND chunk");
-        return None;
+    if !header_only && (!idat_found || !iend_found) {
+        return Err(ImageError::Corrupt(sp,"missing IDAT or IEND"));
     }
 
-    if interlace == 1 {
-        let ax0: [u32; 7] = [0,4,0,2,0,1,0];
-        let ay0: [u32; 7] = [0,0,4,0,2,0,1];
-        let adx: [u32; 7] = [8,8,4,4,2,2,1];
-        let ady: [u32; 7] = [8,8,8,4,4,2,2];
-        let mut awidth: [u32; 7] = [0; 7];
-        let mut aheight: [u32; 7] = [0; 7];
-        let mut astride: [u32; 7] = [0; 7];
+    Ok(stream)
+}
+
+// inflate, unfilter and convert one image of the stream
+fn decode_image<T: pixel::Pixel>(stream: &Stream<T>,zipped_data: &[u8],offset: usize,width: usize,height: usize) -> Result<Mat<T>,ImageError> {
+    check_dimensions(width,height)?;
+    let itype = stream.itype;
+    let bpp = bytes_per_pixel(itype);
+    let too_large = || ImageError::Dimensions(width,height);
+    if stream.interlace == 1 {
+        let ax0: [usize; 7] = [0,4,0,2,0,1,0];
+        let ay0: [usize; 7] = [0,0,4,0,2,0,1];
+        let adx: [usize; 7] = [8,8,4,4,2,2,1];
+        let ady: [usize; 7] = [8,8,8,4,4,2,2];
+        let mut awidth: [usize; 7] = [0; 7];
+        let mut aheight: [usize; 7] = [0; 7];
+        let mut astride: [usize; 7] = [0; 7];
         let mut apresent: [bool; 7] = [false; 7];
-        let mut adsize: [u32; 7] = [0; 7];
-        let mut total_dsize = 0;
+        let mut adsize: [usize; 7] = [0; 7];
+        let mut total_dsize = 0usize;
         //println!("size: {}x{}",width,height);
         for i in 0..7 {
             awidth[i] = (width + adx[i] - ax0[i] - 1) / adx[i];
             aheight[i] = (height + ady[i] - ay0[i] - 1) / ady[i];
-            astride[i] = match itype {
-                Type::L1 => { (awidth[i] + 7) / 8 },
-                Type::C1 => { (awidth[i] + 7) / 8 },
-                Type::L2 => { (awidth[i] + 3) / 4 },
-                Type::C2 => { (awidth[i] + 3) / 4 },
-                Type::L4 => { (awidth[i] + 1) / 2 },
-                Type::C4 => { (awidth[i] + 1) / 2 },
-                Type::L8 => { awidth[i] },
-                Type::RGB8 => { awidth[i] * 3 },
-                Type::C8 => { awidth[i] },
-                Type::LA8 => { awidth[i] * 2 },
-                Type::RGBA8 => { awidth[i] * 4 },
-                Type::L16 => { awidth[i] * 2 },
-                Type::RGB16 => { awidth[i] * 6 },
-                Type::LA16 => { awidth[i] * 4 },
-                Type::RGBA16 => { awidth[i] * 8 },
-            };
+            astride[i] = row_stride(itype,awidth[i]);
             apresent[i] = (awidth[i] != 0) && (aheight[i] != 0);
-            adsize[i] = if apresent[i] { (astride[i] + 1) * aheight[i] } else { 0 };
-            total_dsize += adsize[i];
+            adsize[i] = if apresent[i] { (astride[i] + 1).checked_mul(aheight[i]).ok_or_else(too_large)? } else { 0 };
+            total_dsize = total_dsize.checked_add(adsize[i]).ok_or_else(too_large)?;
             //println!("{}: size {}x{}, offset {},{}, step {},{}",i,awidth[i],aheight[i],ax0[i],ay0[i],adx[i],ady[i]);
         }
-        let filtered_data = match inflate(&zipped_data,total_dsize) {
-            Some(data) => { data },
+        let filtered_data = match compress::inflate(zipped_data,compress::Format::Zlib) {
+            Some(data) if data.len() >= total_dsize => { data },
+            Some(_) => {
+                return Err(ImageError::Corrupt(offset,"not enough image data"));
+            },
             None => {
-                //println!("PNG: ZIP INFLATE failed");
-                return None;
+                return Err(ImageError::Corrupt(offset,"invalid compressed data"));
             },
         };
         let mut sp = 0usize;
-        let mut result = Mat::<T>::new(vec2!(width as usize,height as usize));
+        let mut result = Mat::<T>::new(vec2!(width,height));
         for i in 0..7 {
             if apresent[i] {
-                let raw_data = unfilter(&filtered_data[sp..sp + adsize[i] as usize],aheight[i] as usize,astride[i] as usize,bpp);
-                decode_pixels(&mut result,&raw_data,awidth[i] as usize,aheight[i] as usize,width as usize,ax0[i] as usize,ay0[i] as usize,adx[i] as usize,ady[i] as usize,itype,&palette,gamma);
-                sp += adsize[i] as usize;
+                let raw_data = unfilter(&filtered_data[sp..sp + adsize[i]],aheight[i],astride[i],bpp);
+                decode_pixels(&mut result,&raw_data,awidth[i],aheight[i],width,ax0[i],ay0[i],adx[i],ady[i],itype,&stream.palette,stream.key);
+                sp += adsize[i];
             }
         }
-        Some(result)
+        Ok(result)
     } else
     {
-        //let after0 = Instant::now();
-        
-        let filtered_data = match inflate(&zipped_data,(stride + 1) * height) {
-            Some(data) => { data },
+        let stride = row_stride(itype,width);
+        let dsize = (stride + 1).checked_mul(height).ok_or_else(too_large)?;
+        let filtered_data = match compress::inflate(zipped_data,compress::Format::Zlib) {
+            Some(data) if data.len() >= dsize => { data },
+            Some(_) => {
+                return Err(ImageError::Corrupt(offset,"not enough image data"));
+            },
             None => {
-                //println!("PNG: ZIP INFLATE failed");
-                return None;
+                return Err(ImageError::Corrupt(offset,"invalid compressed data"));
             },
         };
-        
-        //let after_inflate = Instant::now();
-        
-        let raw_data = unfilter(&filtered_data,height as usize,stride as usize,bpp);
-        
-        //let after_unfilter = Instant::now();
-        
-        let mut result = Mat::new(vec2!(width as usize,height as usize));
-        decode_pixels(&mut result,&raw_data,width as usize,height as usize,width as usize,0,0,1,1,itype,&palette,gamma);
-        
-        //let after_decode = Instant::now();
-
-        //let total_duration = after_decode.duration_since(after0);
-        //let inflate_duration = after_inflate.duration_since(after0);
-        //let inflate_percentage = (100.0 * inflate_duration.as_secs_f32()) / (total_duration.as_secs_f32());
-        //let unfilter_duration = after_unfilter.duration_since(after_inflate);
-        //let unfilter_percentage = (100.0 * unfilter_duration.as_secs_f32()) / (total_duration.as_secs_f32());
-        //let decode_duration = after_decode.duration_since(after_unfilter);
-        //let decode_percentage = (100.0 * decode_duration.as_secs_f32()) / (total_duration.as_secs_f32());
-
-        //println!("inflate: {} us ({}%)",inflate_duration.as_micros(),inflate_percentage);
-        //println!("unfilter: {} us ({}%)",unfilter_duration.as_micros(),unfilter_percentage);
-        //println!("decode: {} us ({}%)",decode_duration.as_micros(),decode_percentage);
-        //println!("------------------");
-        //println!("total: {} us (100.0%)",total_duration.as_micros());
-
-        Some(result)
+        let raw_data = unfilter(&filtered_data,height,stride,bpp);
+        let mut result = Mat::new(vec2!(width,height));
+        decode_pixels(&mut result,&raw_data,width,height,width,0,0,1,1,itype,&stream.palette,stream.key);
+        Ok(result)
     }
 }
 
-pub fn encode<T: pixel::Pixel>(_src: &Mat<T>) -> Option<Vec<u8>> {
-    None
+pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Result<Mat<T>,ImageError> {
+    let stream = parse::<T>(src,false)?;
+    decode_image(&stream,&stream.zipped_data,stream.data_offset,stream.width as usize,stream.height as usize)
+}
+
+// IDAT data of a stream, as one piece of compressed data that ends at the first chunk that is not IDAT
+struct IdatReader<R: Read> {
+    inner: R,
+    offset: usize,  // position in the file, for error reporting
+    remaining: usize,  // bytes left in the current IDAT
+    crc: compress::Crc32,
+    done: bool,
+}
+
+impl<R: Read> IdatReader<R> {
+    fn read_be32(&mut self) -> io::Result<u32> {
+        let mut buffer = [0u8; 4];
+        self.inner.read_exact(&mut buffer)?;
+        self.offset += 4;
+        Ok(from_be32(&buffer))
+    }
+}
+
+impl<R: Read> Read for IdatReader<R> {
+    fn read(&mut self,buf: &mut [u8]) -> io::Result<usize> {
+        while !self.done && (self.remaining == 0) {
+            if self.read_be32()? != self.crc.value() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,"chunk CRC mismatch"));
+            }
+            let chunk_length = self.read_be32()? as usize;
+            let chunk_type = self.read_be32()?;
+            if chunk_type == 0x49444154 { // IDAT
+                self.remaining = chunk_length;
+                self.crc = compress::Crc32::new();
+                self.crc.update(&[0x49,0x44,0x41,0x54]);
+            }
+            else {
+                self.done = true;
+            }
+        }
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        let n = if buf.len() < self.remaining { buf.len() } else { self.remaining };
+        let n = self.inner.read(&mut buf[0..n])?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,"truncated chunk"));
+        }
+        self.crc.update(&buf[0..n]);
+        self.offset += n;
+        self.remaining -= n;
+        Ok(n)
+    }
+}
+
+// Adam7 passes: x0, y0, dx, dy and the size of the block each pixel covers when the pass is done
+const ADAM7: [(usize,usize,usize,usize,usize,usize); 7] = [
+    (0,0,8,8,8,8),
+    (4,0,8,8,4,8),
+    (0,4,4,8,4,4),
+    (2,0,4,4,2,4),
+    (0,2,2,4,2,2),
+    (1,0,2,2,1,2),
+    (0,1,1,2,1,1),
+];
+
+// copy each pixel decoded so far over the block it covers, so a partially decoded interlaced image shows as a preview
+fn fill_blocks<T: pixel::Pixel>(image: &mut Mat<T>,bw: usize,bh: usize) {
+    for y in (0..image.size.y).step_by(bh) {
+        for x in (0..image.size.x).step_by(bw) {
+            let c = image[(x,y)];
+            for by in y..if y + bh < image.size.y { y + bh } else { image.size.y } {
+                for bx in x..if x + bw < image.size.x { x + bw } else { image.size.x } {
+                    image[(bx,by)] = c;
+                }
+            }
+        }
+    }
+}
+
+// incremental decoder behind StreamDecoder, reads one row at a time
+pub(crate) struct RowDecoder<T: pixel::Pixel,R: Read> {
+    inflater: compress::Inflater<IdatReader<R>>,
+    itype: Type,
+    palette: [T; 256],
+    key: Option<(u16,u16,u16)>,
+    passes: Vec<(usize,usize,usize,usize,usize,usize)>,  // the non-empty Adam7 passes, or one pass for the whole image
+    pass: usize,
+    y: usize,  // next row in the pass
+    row: Vec<u8>,  // filter type and data of the current row
+    prior: Vec<u8>,  // filter type and data of the row above
+}
+
+impl<T: pixel::Pixel,R: Read> RowDecoder<T,R> {
+    // read the chunks up to the first IDAT
+    pub(crate) fn new(mut reader: R) -> Result<(ImageInfo,RowDecoder<T,R>),ImageError> {
+        let mut header: Vec<u8> = Vec::new();
+        (&mut reader).take(8).read_to_end(&mut header)?;
+        if header[..] != [0x89,0x50,0x4E,0x47,0x0D,0x0A,0x1A,0x0A] {
+            return Err(ImageError::UnknownFormat);
+        }
+        let chunk_length = loop {
+            let sp = header.len();
+            (&mut reader).take(8).read_to_end(&mut header)?;
+            if header.len() < sp + 8 {
+                return Err(ImageError::Corrupt(sp,"missing IDAT or IEND"));
+            }
+            let chunk_length = from_be32(&header[sp..sp + 4]) as usize;
+            let chunk_type = from_be32(&header[sp + 4..sp + 8]);
+            if chunk_type == 0x49444154 { // IDAT
+                header.truncate(sp);
+                break chunk_length;
+            }
+            if chunk_type == 0x49454E44 { // IEND
+                return Err(ImageError::Corrupt(sp,"missing IDAT or IEND"));
+            }
+            (&mut reader).take((chunk_length + 4) as u64).read_to_end(&mut header)?;
+            if header.len() < sp + 8 + chunk_length + 4 {
+                return Err(ImageError::Corrupt(sp,"truncated chunk"));
+            }
+        };
+        let info = info(&header)?;
+        let stream = parse::<T>(&header,true)?;
+        let mut crc = compress::Crc32::new();
+        crc.update(&[0x49,0x44,0x41,0x54]);
+        let idat = IdatReader {
+            inner: reader,
+            offset: header.len() + 8,
+            remaining: chunk_length,
+            crc: crc,
+            done: false,
+        };
+        let width = stream.width as usize;
+        let height = stream.height as usize;
+        let passes = if stream.interlace == 1 {
+            ADAM7.iter().filter(|(x0,y0,_,_,_,_)| (*x0 < width) && (*y0 < height)).cloned().collect()
+        }
+        else {
+            vec![(0,0,1,1,1,1)]
+        };
+        let stride = row_stride(stream.itype,stream.width as usize);
+        Ok((info,RowDecoder {
+            inflater: compress::Inflater::new(idat,compress::Format::Zlib),
+            itype: stream.itype,
+            palette: stream.palette,
+            key: stream.key,
+            passes: passes,
+            pass: 0,
+            y: 0,
+            row: vec![0; stride + 1],
+            prior: vec![0; stride + 1],
+        }))
+    }
+
+    // decode the next row, or the rest of the current pass when interlaced
+    pub(crate) fn advance(&mut self,image: &mut Mat<T>) -> Result<Progress,ImageError> {
+        let bpp = bytes_per_pixel(self.itype);
+        let stride = image.size.x;
+        loop {
+            if self.pass >= self.passes.len() {
+                return Ok(Progress::Done);
+            }
+            let (x0,y0,dx,dy,bw,bh) = self.passes[self.pass];
+            let width = (image.size.x + dx - x0 - 1) / dx;
+            let height = (image.size.y + dy - y0 - 1) / dy;
+            let row_length = row_stride(self.itype,width);
+            if let Err(error) = self.inflater.read_exact(&mut self.row[0..row_length + 1]) {
+                let offset = self.inflater.get_ref().offset;
+                return Err(match error.kind() {
+                    io::ErrorKind::InvalidData => ImageError::Corrupt(offset,"invalid compressed data"),
+                    io::ErrorKind::UnexpectedEof => ImageError::Corrupt(offset,"not enough image data"),
+                    _ => ImageError::Io(error),
+                });
+            }
+            let ftype = self.row[0];
+            unfilter_row(&mut self.row[1..row_length + 1],&self.prior[1..row_length + 1],ftype,bpp);
+            decode_pixels(image,&self.row[1..row_length + 1],width,1,stride,x0,y0 + self.y * dy,dx,dy,self.itype,&self.palette,self.key);
+            std::mem::swap(&mut self.row,&mut self.prior);
+            self.y += 1;
+            if self.passes.len() == 1 {
+                let y = self.y;
+                if y == height {
+                    self.pass += 1;
+                }
+                return Ok(Progress::Rows(y - 1,y));
+            }
+            if self.y == height {
+                let pass = self.pass;
+                self.pass += 1;
+                self.y = 0;
+                for d in self.prior.iter_mut() {
+                    *d = 0;
+                }
+                if self.pass < self.passes.len() {
+                    fill_blocks(image,bw,bh);
+                }
+                return Ok(Progress::Pass(pass));
+            }
+        }
+    }
+}
+
+// render one frame onto the compositor canvas
+fn render<T: pixel::Pixel>(compositor: &mut Compositor<T>,control: &FrameControl,image: &Mat<T>) {
+    // a first frame that disposes to previous restores the empty canvas, which is the same as disposing to background
+    compositor.begin(control.rect,control.disposal);
+    compositor.draw(image,control.blend);
+    compositor.end(control.delay);
+}
+
+pub fn decode_animation<T: pixel::Pixel>(src: &[u8]) -> Result<Animation<T>,ImageError> {
+    let stream = parse::<T>(src,false)?;
+    let loops = match stream.loops {
+        Some(loops) => loops,
+        None => {
+            // not animated, the default image is the only frame
+            let image = decode_image(&stream,&stream.zipped_data,stream.data_offset,stream.width as usize,stream.height as usize)?;
+            return Ok(Animation {
+                size: image.size,
+                frames: vec![Frame { image: image,delay: 0, }],
+                loops: 1,
+            });
+        },
+    };
+    let mut compositor = Compositor::<T>::new(vec2!(stream.width as usize,stream.height as usize));
+    if let Some(control) = stream.default_frame {
+        let image = decode_image(&stream,&stream.zipped_data,stream.data_offset,stream.width as usize,stream.height as usize)?;
+        render(&mut compositor,&control,&image);
+    }
+    for (control,offset,zipped_data) in stream.frames.iter() {
+        let image = decode_image(&stream,zipped_data,*offset,control.rect.s.x,control.rect.s.y)?;
+        render(&mut compositor,control,&image);
+    }
+    if compositor.frames.is_empty() {
+        return Err(ImageError::Corrupt(stream.data_offset,"animation has no frames"));
+    }
+    Ok(compositor.finish(loops))
+}
+
+trait WriteTypes {
+    fn push32b(&mut self,d: u32);
+}
+
+impl WriteTypes for Vec<u8> {
+    fn push32b(&mut self,d: u32) {
+        self.push((d >> 24) as u8);
+        self.push(((d >> 16) & 255) as u8);
+        self.push(((d >> 8) & 255) as u8);
+        self.push((d & 255) as u8);
+    }
+}
+
+fn push_chunk(dst: &mut Vec<u8>,chunk_type: u32,data: &[u8]) {
+    dst.push32b(data.len() as u32);
+    let start = dst.len();
+    dst.push32b(chunk_type);
+    dst.extend_from_slice(data);
+    let crc = compress::crc32(&dst[start..]);
+    dst.push32b(crc);
+}
+
+// filter each row with whichever filter gives the smallest sum of absolute differences
+fn filter(src: &[u8],height: usize,stride: usize,bpp: usize) -> Vec<u8> {
+    let mut dst: Vec<u8> = Vec::with_capacity((stride + 1) * height);
+    let mut candidates = vec![vec![0u8; stride]; 5];
+    for y in 0..height {
+        let line = &src[y * stride..(y + 1) * stride];
+        for x in 0..stride {
+            let s = line[x] as i32;
+            let a: i32 = if x >= bpp { line[x - bpp] as i32 } else { 0 };
+            let b: i32 = if y >= 1 { src[(y - 1) * stride + x] as i32 } else { 0 };
+            let c: i32 = if (y >= 1) && (x >= bpp) { src[(y - 1) * stride + x - bpp] as i32 } else { 0 };
+            let d: i32 = a + b - c;
+            let pa = (d - a).abs();
+            let pb = (d - b).abs();
+            let pc = (d - c).abs();
+            let paeth = if (pa <= pb) && (pa <= pc) { a } else if pb <= pc { b } else { c };
+            candidates[0][x] = s as u8;
+            candidates[1][x] = (s - a) as u8;
+            candidates[2][x] = (s - b) as u8;
+            candidates[3][x] = (s - ((a + b) >> 1)) as u8;
+            candidates[4][x] = (s - paeth) as u8;
+        }
+        let mut best = 0;
+        let mut best_sum = u64::MAX;
+        for f in 0..5 {
+            let sum: u64 = candidates[f].iter().map(|&v| (v as i8).unsigned_abs() as u64).sum();
+            if sum < best_sum {
+                best = f;
+                best_sum = sum;
+            }
+        }
+        dst.push(best as u8);
+        dst.extend_from_slice(&candidates[best]);
+    }
+    dst
+}
+
+/// PNG encoder settings.
+#[derive(Copy,Clone,Debug)]
+pub struct EncodeOptions {
+    /// Deflate compression level, from 0 (store) to 9 (smallest).
+    pub level: u32,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> EncodeOptions {
+        EncodeOptions {
+            level: 6,
+        }
+    }
+}
+
+pub fn encode_with_level<T: pixel::Pixel>(image: &Mat<T>,level: u32) -> Result<Vec<u8>,ImageError> {
+    let width = image.size.x;
+    let height = image.size.y;
+    if (width == 0) || (height == 0) || (width >= 65536) || (height >= 65536) {
+        return Err(ImageError::Dimensions(width,height));
+    }
+
+    // gray if the image has no color, 16 bits if the pixel format has more than 8
+    let gray = image.data().iter().all(|p| {
+        let (r,g,b,_) = p.get16();
+        (r == g) && (g == b)
+    });
+    let sixteen = T::BITS > 8;
+    let channels = if gray { 1 } else { 3 } + if T::ALPHA { 1 } else { 0 };
+    let bpp = if sixteen { channels * 2 } else { channels };
+    let stride = width * bpp;
+    let mut raw_data: Vec<u8> = Vec::with_capacity(stride * height);
+    let mut samples: Vec<u16> = Vec::with_capacity(4);
+    for y in 0..height {
+        for x in 0..width {
+            samples.clear();
+            if sixteen {
+                let (r,g,b,a) = image[(x,y)].get16();
+                if gray {
+                    samples.push(r);
+                }
+                else {
+                    samples.push(r);
+                    samples.push(g);
+                    samples.push(b);
+                }
+                if T::ALPHA {
+                    samples.push(a);
+                }
+                for s in samples.iter() {
+                    raw_data.push((s >> 8) as u8);
+                    raw_data.push((s & 255) as u8);
+                }
+            }
+            else {
+                let (r,g,b,a) = image[(x,y)].get();
+                if gray {
+                    raw_data.push(r);
+                }
+                else {
+                    raw_data.push(r);
+                    raw_data.push(g);
+                    raw_data.push(b);
+                }
+                if T::ALPHA {
+                    raw_data.push(a);
+                }
+            }
+        }
+    }
+    let filtered_data = filter(&raw_data,height,stride,bpp);
+    let zipped_data = compress::deflate(&filtered_data,compress::Format::Zlib,level);
+    let mut dst: Vec<u8> = vec![0x89,0x50,0x4E,0x47,0x0D,0x0A,0x1A,0x0A];
+    let mut header: Vec<u8> = Vec::new();
+    header.push32b(width as u32);
+    header.push32b(height as u32);
+    header.push(if sixteen { 16 } else { 8 });  // bit depth
+    header.push(match (gray,T::ALPHA) {  // color type
+        (true,false) => 0,
+        (true,true) => 4,
+        (false,false) => 2,
+        (false,true) => 6,
+    });
+    header.push(0);  // compression
+    header.push(0);  // filter
+    header.push(0);  // interlace
+    push_chunk(&mut dst,0x49484452,&header);  // IHDR
+    push_chunk(&mut dst,0x49444154,&zipped_data);  // IDAT
+    push_chunk(&mut dst,0x49454E44,&[]);  // IEND
+    Ok(dst)
+}
+
+pub fn encode<T: pixel::Pixel>(image: &Mat<T>) -> Result<Vec<u8>,ImageError> {
+    encode_with_options(image,&EncodeOptions::default())
+}
+
+pub fn encode_with_options<T: pixel::Pixel>(image: &Mat<T>,options: &EncodeOptions) -> Result<Vec<u8>,ImageError> {
+    encode_with_level(image,options.level)
 }
 
 
@@ -1103,4 +1260,171 @@ pub fn encode<T: pixel::Pixel>(_src: &Mat<T>) -> Option<Vec<u8>> {
 
 78 5E = 01111000 01011110: CM=8 (deflate), CINFO=7 (32k window size), FLEVEL=fast, FDICT=no, FCHECK=1E
 78 DA = 01111000 11011010: CM=8 (deflate), CINFO=7 (32k window size), FLEVEL=maximum, FDICT=no, FCHECK=1A
-*/
\ No newline at end of file
+*/
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        pixel::Pixel,
+    };
+
+    // 5x3 1-bit gray, pixels are white where x + y is odd
+    const L1_5X3: [u8; 71] = [
+        137,80,78,71,13,10,26,10,0,0,0,13,73,72,68,82,0,0,0,5,0,0,0,3,1,0,0,0,0,115,77,248,85,
+        0,0,0,14,73,68,65,84,120,156,99,8,96,88,193,16,0,0,3,222,1,73,119,192,136,225,
+        0,0,0,0,73,69,78,68,174,66,96,130,
+    ];
+
+    #[test]
+    fn decode_l1_partial_byte() {
+        let image = decode::<pixel::RGBA8UN>(&L1_5X3).unwrap();
+        assert_eq!(image.size,vec2!(5usize,3));
+        for y in 0..3 {
+            for x in 0..5 {
+                let l = if ((x + y) & 1) != 0 { 255 } else { 0 };
+                assert_eq!(image[(x,y)].get(),(l,l,l,255));
+            }
+        }
+    }
+
+    #[test]
+    fn decode_oversized() {
+        // 65535x65535 RGBA16 with an empty IDAT
+        let src: [u8; 65] = [
+            137,80,78,71,13,10,26,10,0,0,0,13,73,72,68,82,0,0,255,255,0,0,255,255,16,6,0,0,0,230,149,5,
+            19,0,0,0,8,73,68,65,84,120,156,3,0,0,0,0,1,72,6,137,210,0,0,0,0,73,69,78,68,174,66,96,
+            130,
+        ];
+        assert!(matches!(decode::<pixel::RGBA16UN>(&src),Err(ImageError::Dimensions(65535,65535))));
+    }
+
+    // 3x1 RGB8 red, green, red with red as color key
+    const RGB8_KEY_3X1: [u8; 91] = [
+        137,80,78,71,13,10,26,10,0,0,0,13,73,72,68,82,0,0,0,3,0,0,0,1,8,2,0,0,0,148,130,131,
+        227,0,0,0,6,116,82,78,83,0,255,0,0,0,0,164,194,192,29,0,0,0,16,73,68,65,84,120,218,99,
+        248,207,192,192,240,31,8,25,0,16,249,2,254,236,54,117,103,0,0,0,0,73,69,78,68,174,66,96,
+        130,
+    ];
+
+    // 4x1 L4 0, 5, 10, 15 with 5 as color key
+    const L4_KEY_4X1: [u8; 82] = [
+        137,80,78,71,13,10,26,10,0,0,0,13,73,72,68,82,0,0,0,4,0,0,0,1,4,0,0,0,0,25,167,189,
+        16,0,0,0,2,116,82,78,83,0,5,6,249,57,183,0,0,0,11,73,68,65,84,120,218,99,96,93,15,0,
+        0,188,0,181,17,229,245,123,0,0,0,0,73,69,78,68,174,66,96,130,
+    ];
+
+    #[test]
+    fn decode_color_key() {
+        assert_eq!(info(&RGB8_KEY_3X1).unwrap().channels,ChannelLayout::Rgba);
+        let image = decode::<pixel::RGBA8UN>(&RGB8_KEY_3X1).unwrap();
+        assert_eq!(image[(0,0)].get(),(255,0,0,0));
+        assert_eq!(image[(1,0)].get(),(0,255,0,255));
+        assert_eq!(image[(2,0)].get(),(255,0,0,0));
+
+        assert_eq!(info(&L4_KEY_4X1).unwrap().channels,ChannelLayout::GrayAlpha);
+        let image = decode::<pixel::RGBA8UN>(&L4_KEY_4X1).unwrap();
+        assert_eq!(image[(0,0)].get(),(0,0,0,255));
+        assert_eq!(image[(1,0)].get(),(85,85,85,0));
+        assert_eq!(image[(2,0)].get(),(170,170,170,255));
+        assert_eq!(image[(3,0)].get(),(255,255,255,255));
+    }
+
+    fn chunk(chunk_type: &[u8; 4],data: &[u8]) -> Vec<u8> {
+        let mut result = (data.len() as u32).to_be_bytes().to_vec();
+        result.extend_from_slice(chunk_type);
+        result.extend_from_slice(data);
+        let crc = compress::crc32(&result[4..]);
+        result.extend_from_slice(&crc.to_be_bytes());
+        result
+    }
+
+    // insert a chunk right after IHDR
+    fn with_chunk(src: &[u8],chunk_type: &[u8; 4],data: &[u8]) -> Vec<u8> {
+        [&src[0..33],&chunk(chunk_type,data),&src[33..]].concat()
+    }
+
+    #[test]
+    fn info_background() {
+        assert_eq!(info(&L4_KEY_4X1).unwrap().background,None);
+
+        // samples in the bit depth of the image
+        let data = with_chunk(&L4_KEY_4X1,b"bKGD",&[0,10]);
+        assert_eq!(info(&data).unwrap().background,Some((43690,43690,43690)));
+        let data = with_chunk(&RGB8_KEY_3X1,b"bKGD",&[0,255,0,128,0,0]);
+        assert_eq!(info(&data).unwrap().background,Some((65535,32896,0)));
+        let mut image = Mat::<pixel::RGB16UN>::new(vec2!(2,2));
+        image[(0,0)].set16(1,2,3,0xFFFF);
+        let data = with_chunk(&encode(&image).unwrap(),b"bKGD",&[0x12,0x34,0x56,0x78,0x9A,0xBC]);
+        assert_eq!(info(&data).unwrap().background,Some((0x1234,0x5678,0x9ABC)));
+
+        // too short, the image still decodes
+        let data = with_chunk(&RGB8_KEY_3X1,b"bKGD",&[0,255]);
+        assert_eq!(info(&data).unwrap().background,None);
+        assert_eq!(decode::<pixel::RGBA8UN>(&data).unwrap()[(1,0)].get(),(0,255,0,255));
+
+        // 1x1 palette image, the index has to be in the palette
+        let mut src = vec![137,80,78,71,13,10,26,10];
+        src.extend_from_slice(&chunk(b"IHDR",&[0,0,0,1,0,0,0,1,8,3,0,0,0]));
+        src.extend_from_slice(&chunk(b"PLTE",&[255,0,0,0,0,255]));
+        let end = src.len();
+        src.extend_from_slice(&chunk(b"IDAT",&compress::deflate(&[0,1],compress::Format::Zlib,6)));
+        src.extend_from_slice(&chunk(b"IEND",&[]));
+        for (index,background) in [(1,Some((0,0,65535))),(0,Some((65535,0,0))),(2,None)] {
+            let data = [&src[0..end],&chunk(b"bKGD",&[index]),&src[end..]].concat();
+            assert_eq!(info(&data).unwrap().background,background);
+            assert_eq!(decode::<pixel::RGBA8UN>(&data).unwrap()[(0,0)].get(),(0,0,255,255));
+        }
+    }
+
+    fn roundtrip<T: pixel::Pixel>(image: &Mat<T>,bit_depth: u8,color_type: u8) {
+        for level in [0,6,9] {
+            let data = encode_with_options(image,&EncodeOptions { level: level, }).unwrap();
+            assert_eq!((data[24],data[25]),(bit_depth,color_type));
+            let decoded = decode::<T>(&data).unwrap();
+            assert_eq!(decoded.size,image.size);
+            for (a,b) in image.data().iter().zip(decoded.data().iter()) {
+                assert_eq!(a.get16(),b.get16());
+            }
+        }
+    }
+
+    #[test]
+    fn roundtrip_rgba16() {
+        let mut image = Mat::<pixel::RGBA16UN>::new(vec2!(31,17));
+        for y in 0..17 {
+            for x in 0..31 {
+                image[(x,y)].set16((x * 2111) as u16,(y * 3851 + x) as u16,((x * y * 977) ^ 0x1234) as u16,(65535 - x * y * 13) as u16);
+            }
+        }
+        roundtrip(&image,16,6);
+    }
+
+    #[test]
+    fn roundtrip_gray16() {
+        let mut image = Mat::<pixel::RGB16UN>::new(vec2!(20,9));
+        for y in 0..9 {
+            for x in 0..20 {
+                let l = (x * 3001 + y * 257) as u16;
+                image[(x,y)].set16(l,l,l,0xFFFF);
+            }
+        }
+        roundtrip(&image,16,0);
+    }
+
+    #[test]
+    fn roundtrip_8bit() {
+        let mut color = Mat::<pixel::RGBA8UN>::new(vec2!(40,12));
+        let mut gray = Mat::<pixel::RGBA8UN>::new(vec2!(40,12));
+        for y in 0..12 {
+            for x in 0..40 {
+                // flat areas and noise, so all filter types get picked
+                let n = if x < 20 { 0 } else { ((x * 7919 + y * 104729) % 251) as u8 };
+                color[(x,y)].set(n,(x * 6) as u8,(y * 20) as u8,(255 - x) as u8);
+                gray[(x,y)].set(n,n,n,(y * 20) as u8);
+            }
+        }
+        roundtrip(&color,8,6);
+        roundtrip(&gray,8,4);
+    }
+}
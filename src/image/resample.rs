@@ -0,0 +1,358 @@
+// E - image - Resample
+// Desmond Germans, 2020
+
+use {
+    crate::*,
+    std::{
+        f32::consts::PI,
+        sync::OnceLock,
+    },
+};
+
+/// Reconstruction filter for resizing.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Filter {
+    /// Average of the covered pixels, nearest neighbor when enlarging.
+    Box,
+    /// Linear interpolation (triangle filter).
+    Bilinear,
+    /// Mitchell-Netravali cubic (B = C = 1/3).
+    Bicubic,
+    /// Lanczos windowed sinc with 3 lobes.
+    Lanczos3,
+}
+
+impl Filter {
+    // radius of the kernel at scale 1
+    fn support(&self) -> f32 {
+        match self {
+            Filter::Box => 0.5,
+            Filter::Bilinear => 1.0,
+            Filter::Bicubic => 2.0,
+            Filter::Lanczos3 => 3.0,
+        }
+    }
+
+    fn weight(&self,x: f32) -> f32 {
+        let x = x.abs();
+        match self {
+            Filter::Box => if x <= 0.5 { 1.0 } else { 0.0 },
+            Filter::Bilinear => if x < 1.0 { 1.0 - x } else { 0.0 },
+            Filter::Bicubic => {
+                let b = 1.0 / 3.0;
+                let c = 1.0 / 3.0;
+                if x < 1.0 {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x * x * x + (-18.0 + 12.0 * b + 6.0 * c) * x * x + (6.0 - 2.0 * b)) / 6.0
+                }
+                else if x < 2.0 {
+                    ((-b - 6.0 * c) * x * x * x + (6.0 * b + 30.0 * c) * x * x + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)) / 6.0
+                }
+                else {
+                    0.0
+                }
+            },
+            Filter::Lanczos3 => {
+                if x < 1e-6 {
+                    1.0
+                }
+                else if x < 3.0 {
+                    let px = PI * x;
+                    3.0 * px.sin() * (px / 3.0).sin() / (px * px)
+                }
+                else {
+                    0.0
+                }
+            },
+        }
+    }
+}
+
+/// Options for resizing.
+#[derive(Copy,Clone,Debug)]
+pub struct ResizeOptions {
+    /// Reconstruction filter.
+    pub filter: Filter,
+    /// The pixels are sRGB encoded, filter them in linear light. Float pixel formats are taken to be linear already.
+    pub linear: bool,
+    /// Premultiply the colors by alpha while filtering, so transparent pixels do not bleed into their neighbors.
+    pub premultiply: bool,
+}
+
+impl Default for ResizeOptions {
+    fn default() -> ResizeOptions {
+        ResizeOptions {
+            filter: Filter::Bicubic,
+            linear: true,
+            premultiply: true,
+        }
+    }
+}
+
+const TABLE_SIZE: usize = 65536;
+
+fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) }
+}
+
+fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.0031308 { v * 12.92 } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 }
+}
+
+// sRGB to linear and back, sampled at 16 bits
+fn tables() -> &'static (Vec<f32>,Vec<f32>) {
+    static TABLES: OnceLock<(Vec<f32>,Vec<f32>)> = OnceLock::new();
+    TABLES.get_or_init(|| {
+        let to_linear = (0..TABLE_SIZE).map(|i| srgb_to_linear((i as f32) / 65535.0)).collect();
+        let to_srgb = (0..TABLE_SIZE).map(|i| linear_to_srgb((i as f32) / 65535.0)).collect();
+        (to_linear,to_srgb)
+    })
+}
+
+fn lookup(table: &[f32],v: f32) -> f32 {
+    let v = if v < 0.0 { 0.0 } else if v > 1.0 { 1.0 } else { v };
+    table[(v * 65535.0 + 0.5) as usize]
+}
+
+// contributions of the source pixels to each destination pixel along one axis
+struct Weights {
+    spans: Vec<(usize,usize)>,  // first source pixel and offset into weights, per destination pixel
+    counts: Vec<usize>,
+    weights: Vec<f32>,
+}
+
+impl Weights {
+    fn new(filter: Filter,src_size: usize,dst_size: usize) -> Weights {
+        let scale = (src_size as f32) / (dst_size as f32);
+        let fscale = if scale > 1.0 { scale } else { 1.0 };  // widen the kernel when shrinking
+        let support = filter.support() * fscale;
+        let mut spans: Vec<(usize,usize)> = Vec::with_capacity(dst_size);
+        let mut counts: Vec<usize> = Vec::with_capacity(dst_size);
+        let mut weights: Vec<f32> = Vec::new();
+        for i in 0..dst_size {
+            let center = ((i as f32) + 0.5) * scale;
+            let mut start = (center - support).floor() as isize;
+            let mut end = (center + support).ceil() as isize;
+            if start < 0 {
+                start = 0;
+            }
+            if end > src_size as isize {
+                end = src_size as isize;
+            }
+            let offset = weights.len();
+            let mut total = 0.0;
+            for k in start..end {
+                let w = filter.weight(((k as f32) + 0.5 - center) / fscale);
+                weights.push(w);
+                total += w;
+            }
+
+            // normalize, or fall back to the nearest pixel if the kernel misses everything
+            if total != 0.0 {
+                for w in weights[offset..].iter_mut() {
+                    *w /= total;
+                }
+                spans.push((start as usize,offset));
+                counts.push((end - start) as usize);
+            }
+            else {
+                weights.truncate(offset);
+                weights.push(1.0);
+                let nearest = if (center as usize) < src_size { center as usize } else { src_size - 1 };
+                spans.push((nearest,offset));
+                counts.push(1);
+            }
+        }
+        Weights {
+            spans: spans,
+            counts: counts,
+            weights: weights,
+        }
+    }
+
+    fn get(&self,i: usize) -> (usize,&[f32]) {
+        let (start,offset) = self.spans[i];
+        (start,&self.weights[offset..offset + self.counts[i]])
+    }
+}
+
+/// Resize an image.
+/// # Generic
+/// * `T` - The pixel format of the image.
+/// # Arguments
+/// * `image` - Image to resize.
+/// * `size` - New size.
+/// * `filter` - Reconstruction filter.
+/// # Returns
+/// The resized image, filtered in linear light with premultiplied alpha.
+pub fn resize<T: pixel::Pixel>(image: &Mat<T>,size: Vec2<usize>,filter: Filter) -> Mat<T> {
+    resize_with_options(image,size,&ResizeOptions { filter: filter,..ResizeOptions::default() })
+}
+
+/// Resize an image with options.
+/// # Generic
+/// * `T` - The pixel format of the image.
+/// # Arguments
+/// * `image` - Image to resize.
+/// * `size` - New size.
+/// * `options` - Filter, color space and alpha handling.
+/// # Returns
+/// The resized image.
+pub fn resize_with_options<T: pixel::Pixel>(image: &Mat<T>,size: Vec2<usize>,options: &ResizeOptions) -> Mat<T> {
+    if size == image.size {
+        return image.clone();
+    }
+    let mut result = Mat::<T>::new(size);
+    if (image.size.x == 0) || (image.size.y == 0) || (size.x == 0) || (size.y == 0) {
+        return result;
+    }
+    // float formats are linear already and may go beyond 1, so they skip the sRGB tables, which clamp
+    let linear = options.linear && (T::BITS <= 16);
+    let (to_linear,to_srgb): (&[f32],&[f32]) = if linear { (&tables().0,&tables().1) } else { (&[],&[]) };
+
+    // the vertical kernel needs at most this many rows of the horizontal pass at a time, kept in a ring
+    let horizontal = Weights::new(options.filter,image.size.x,size.x);
+    let vertical = Weights::new(options.filter,image.size.y,size.y);
+    let window = (0..size.y).map(|y| vertical.get(y).1.len()).max().unwrap_or(1);
+    let mut ring: Vec<[f32; 4]> = vec![[0.0; 4]; window * size.x];
+    let mut loaded = 0;  // next source row to filter, the ones before it are in the ring
+    let mut src_row: Vec<[f32; 4]> = vec![[0.0; 4]; image.size.x];
+    let mut sums: Vec<[f32; 4]> = vec![[0.0; 4]; size.x];
+    for y in 0..size.y {
+        let (start,weights) = vertical.get(y);
+
+        // the kernel moves down, so usually only the new rows are filtered
+        if (loaded < start) || (loaded > start + window) {
+            loaded = start;
+        }
+        while loaded < start + weights.len() {
+
+            // convert to linear premultiplied floats
+            for (d,p) in src_row.iter_mut().zip(image.data()[loaded * image.size.x..(loaded + 1) * image.size.x].iter()) {
+                let (mut r,mut g,mut b,a) = p.getf();
+                if linear {
+                    r = lookup(to_linear,r);
+                    g = lookup(to_linear,g);
+                    b = lookup(to_linear,b);
+                }
+                if options.premultiply {
+                    r *= a;
+                    g *= a;
+                    b *= a;
+                }
+                *d = [r,g,b,a];
+            }
+
+            // horizontal pass
+            let slot = loaded % window;
+            let dst_row = &mut ring[slot * size.x..(slot + 1) * size.x];
+            for x in 0..size.x {
+                let (start,weights) = horizontal.get(x);
+                let mut sum = [0.0f32; 4];
+                for (s,w) in src_row[start..start + weights.len()].iter().zip(weights.iter()) {
+                    sum[0] += s[0] * w;
+                    sum[1] += s[1] * w;
+                    sum[2] += s[2] * w;
+                    sum[3] += s[3] * w;
+                }
+                dst_row[x] = sum;
+            }
+            loaded += 1;
+        }
+
+        // vertical pass
+        for sum in sums.iter_mut() {
+            *sum = [0.0; 4];
+        }
+        for (k,w) in weights.iter().enumerate() {
+            let slot = (start + k) % window;
+            let ring_row = &ring[slot * size.x..(slot + 1) * size.x];
+            for (sum,t) in sums.iter_mut().zip(ring_row.iter()) {
+                sum[0] += t[0] * w;
+                sum[1] += t[1] * w;
+                sum[2] += t[2] * w;
+                sum[3] += t[3] * w;
+            }
+        }
+
+        // back to the pixel format
+        for (x,sum) in sums.iter().enumerate() {
+            let [mut r,mut g,mut b,a] = *sum;
+            if options.premultiply && (a > 0.0) {
+                r /= a;
+                g /= a;
+                b /= a;
+            }
+            if linear {
+                r = lookup(to_srgb,r);
+                g = lookup(to_srgb,g);
+                b = lookup(to_srgb,b);
+            }
+            result[(x,y)].setf(r,g,b,a);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        pixel::Pixel,
+    };
+
+    const FILTERS: [Filter; 4] = [Filter::Box,Filter::Bilinear,Filter::Bicubic,Filter::Lanczos3];
+
+    #[test]
+    fn resize_constant() {
+        let mut image = Mat::<pixel::RGBA8UN>::new(vec2!(13usize,7));
+        for p in image.data_mut().iter_mut() {
+            p.set(200,100,30,255);
+        }
+        for filter in FILTERS {
+            for size in [vec2!(1usize,1),vec2!(5usize,3),vec2!(13usize,40),vec2!(40usize,2),vec2!(29usize,17)] {
+                let result = resize(&image,size,filter);
+                for p in result.data().iter() {
+                    let (r,g,b,a) = p.get();
+                    assert!(((r as i32) - 200).abs() <= 1,"{:?} {:?}",filter,size);
+                    assert!(((g as i32) - 100).abs() <= 1,"{:?} {:?}",filter,size);
+                    assert!(((b as i32) - 30).abs() <= 1,"{:?} {:?}",filter,size);
+                    assert_eq!(a,255);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn resize_float_keeps_range() {
+        let mut image = Mat::<pixel::RGBA32F>::new(vec2!(8usize,8));
+        for (i,p) in image.data_mut().iter_mut().enumerate() {
+            let v = if (i & 1) != 0 { 16.0 } else { 4.0 };
+            p.setf(v,v,v,1.0);
+        }
+
+        // the 2x2 box average of 4 and 16 is 10, both with and without the linear option
+        for linear in [false,true] {
+            let result = resize_with_options(&image,vec2!(4usize,4),&ResizeOptions { filter: Filter::Box,linear: linear,premultiply: true });
+            for p in result.data().iter() {
+                let (r,g,b,a) = p.getf();
+                assert!((r - 10.0).abs() < 1e-4);
+                assert!((g - 10.0).abs() < 1e-4);
+                assert!((b - 10.0).abs() < 1e-4);
+                assert!((a - 1.0).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn resize_premultiplied() {
+        // a transparent red pixel must not tint its opaque green neighbor
+        let mut image = Mat::<pixel::RGBA8UN>::new(vec2!(2usize,1));
+        image[(0,0)].set(255,0,0,0);
+        image[(1,0)].set(0,255,0,255);
+        let result = resize(&image,vec2!(1usize,1),Filter::Box);
+        let (r,g,_,a) = result[(0,0)].get();
+        assert_eq!(r,0);
+        assert_eq!(g,255);
+        assert!((a as i32 - 128).abs() <= 1);
+    }
+}
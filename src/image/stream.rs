@@ -0,0 +1,121 @@
+// E - image - Stream
+// Desmond Germans, 2020
+
+use {
+    crate::*,
+    std::io::{
+        self,
+        Cursor,
+        Read,
+    },
+};
+
+/// What a call to `StreamDecoder::advance` completed.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Progress {
+    /// Rows from the first up to (not including) the second are decoded.
+    Rows(usize,usize),
+    /// Interlace pass or progressive scan (counting from 0) is decoded, the image holds a preview that the next passes refine.
+    Pass(usize),
+    /// The image is complete.
+    Done,
+}
+
+// the bytes read to detect the format, followed by the rest of the source
+type Source<R> = io::Chain<Cursor<Vec<u8>>,R>;
+
+enum State<T: pixel::Pixel,R: Read> {
+    Png(png::RowDecoder<T,Source<R>>),
+    Jpeg(jpeg::ScanDecoder<Source<R>>),
+    Buffered(Vec<u8>),  // read completely, decoded in one go
+    Done,
+}
+
+/// Pull-based decoder that reads an image from a `Read` as the data comes in.
+///
+/// PNG is decoded row by row, or pass by pass when interlaced. Progressive JPEG gives a preview after every scan. Other formats are read completely and decoded at once. To cancel, stop calling `advance` and drop the decoder.
+pub struct StreamDecoder<T: pixel::Pixel,R: Read> {
+    info: ImageInfo,
+    image: Mat<T>,
+    state: State<T,R>,
+}
+
+impl<T: pixel::Pixel,R: Read> StreamDecoder<T,R> {
+    /// Create a decoder and read the header.
+    /// # Arguments
+    /// * `reader` - Source of the encoded image.
+    /// # Returns
+    /// * `Ok(decoder)` - The header is read and the image can be decoded.
+    /// * `Err(ImageError)` - The source could not be read, is not in a known format, or its header is invalid.
+    pub fn new(mut reader: R) -> Result<StreamDecoder<T,R>,ImageError> {
+        let mut signature: Vec<u8> = Vec::new();
+        (&mut reader).take(8).read_to_end(&mut signature)?;
+        let name = registry().detect(&signature).map(|codec| codec.name());
+        let mut source = Cursor::new(signature).chain(reader);
+        let (info,state) = match name {
+            Some("png") => {
+                let (info,decoder) = png::RowDecoder::new(source)?;
+                (info,State::Png(decoder))
+            },
+            Some("jpeg") => {
+                let (info,decoder) = jpeg::ScanDecoder::new(source)?;
+                (info,State::Jpeg(decoder))
+            },
+            _ => {
+                let mut src: Vec<u8> = Vec::new();
+                source.read_to_end(&mut src)?;
+                (registry().info(&src)?,State::Buffered(src))
+            },
+        };
+        check_dimensions(info.size.x,info.size.y)?;
+        Ok(StreamDecoder {
+            image: Mat::new(info.size),
+            info: info,
+            state: state,
+        })
+    }
+
+    /// Header of the image.
+    pub fn info(&self) -> &ImageInfo {
+        &self.info
+    }
+
+    /// The image as far as it is decoded. Parts that are not decoded yet are zero.
+    pub fn image(&self) -> &Mat<T> {
+        &self.image
+    }
+
+    /// Take the image as far as it is decoded.
+    pub fn into_image(self) -> Mat<T> {
+        self.image
+    }
+
+    /// Read and decode the next rows, pass or scan.
+    /// # Returns
+    /// * `Ok(progress)` - What part of `image` is updated. Once the image is complete, this keeps returning `Progress::Done`.
+    /// * `Err(ImageError)` - The source could not be read, or the data is invalid.
+    pub fn advance(&mut self) -> Result<Progress,ImageError> {
+        let progress = match &mut self.state {
+            State::Png(decoder) => decoder.advance(&mut self.image)?,
+            State::Jpeg(decoder) => decoder.advance(&mut self.image)?,
+            State::Buffered(src) => {
+                self.image = registry().decode::<T>(src)?;
+                Progress::Done
+            },
+            State::Done => Progress::Done,
+        };
+        if progress == Progress::Done {
+            self.state = State::Done;
+        }
+        Ok(progress)
+    }
+
+    /// Decode the rest of the image.
+    /// # Returns
+    /// * `Ok(mat)` - The complete image.
+    /// * `Err(ImageError)` - The source could not be read, or the data is invalid.
+    pub fn finish(mut self) -> Result<Mat<T>,ImageError> {
+        while self.advance()? != Progress::Done { }
+        Ok(self.image)
+    }
+}
@@ -5,14 +5,574 @@
 
 use crate::*;
 
-pub fn test(_src: &[u8]) -> Option<(u32,u32)> {
-    None
+const SIGNATURE: &[u8; 18] = b"TRUEVISION-XFILE.\0";
+
+fn from_le16(src: &[u8]) -> u16 {
+    ((src[1] as u16) << 8) | (src[0] as u16)
+}
+
+fn from_le32(src: &[u8]) -> u32 {
+    ((src[3] as u32) << 24) | ((src[2] as u32) << 16) | ((src[1] as u32) << 8) | (src[0] as u32)
+}
+
+struct Header {
+    id_length: usize,
+    colormap_type: u8,
+    image_type: u8,
+    colormap_first: usize,
+    colormap_length: usize,
+    colormap_depth: usize,
+    width: usize,
+    height: usize,
+    depth: usize,
+    descriptor: u8,
+}
+
+// TGA has no magic number, so check everything that can be checked
+fn parse_header(src: &[u8]) -> Result<Header,ImageError> {
+    if src.len() < 18 {
+        return Err(ImageError::UnknownFormat);
+    }
+    let header = Header {
+        id_length: src[0] as usize,
+        colormap_type: src[1],
+        image_type: src[2],
+        colormap_first: from_le16(&src[3..5]) as usize,
+        colormap_length: from_le16(&src[5..7]) as usize,
+        colormap_depth: src[7] as usize,
+        width: from_le16(&src[12..14]) as usize,
+        height: from_le16(&src[14..16]) as usize,
+        depth: src[16] as usize,
+        descriptor: src[17],
+    };
+    if (header.width == 0) || (header.height == 0) || ((header.descriptor & 0xC0) != 0) || ((header.descriptor & 15) > 8) {
+        return Err(ImageError::UnknownFormat);
+    }
+    match header.colormap_type {
+        0 => {
+            if (header.colormap_length != 0) && (header.colormap_depth != 0) {
+                return Err(ImageError::UnknownFormat);
+            }
+        },
+        1 => {
+            if (header.colormap_length == 0) || ((header.colormap_depth != 15) && (header.colormap_depth != 16) && (header.colormap_depth != 24) && (header.colormap_depth != 32)) {
+                return Err(ImageError::UnknownFormat);
+            }
+        },
+        _ => { return Err(ImageError::UnknownFormat); },
+    }
+    match header.image_type & 7 {
+        1 => {
+            if (header.colormap_type != 1) || ((header.depth != 8) && (header.depth != 16)) {
+                return Err(ImageError::UnknownFormat);
+            }
+        },
+        2 => {
+            if (header.depth != 15) && (header.depth != 16) && (header.depth != 24) && (header.depth != 32) {
+                return Err(ImageError::UnknownFormat);
+            }
+        },
+        3 => {
+            if (header.depth != 8) && (header.depth != 16) {
+                return Err(ImageError::UnknownFormat);
+            }
+        },
+        _ => { return Err(ImageError::UnknownFormat); },
+    }
+    if (header.image_type & !0x0B) != 0 {
+        return Err(ImageError::UnknownFormat);
+    }
+    let data_start = 18 + header.id_length + header.colormap_length * ((header.colormap_depth + 7) / 8);
+    if data_start > src.len() {
+        return Err(ImageError::Corrupt(src.len(),"truncated color map"));
+    }
+    if ((header.image_type & 8) == 0) && (data_start + header.width * header.height * ((header.depth + 7) / 8) > src.len()) {
+        return Err(ImageError::Corrupt(src.len(),"truncated pixel data"));
+    }
+    Ok(header)
+}
+
+// returns the extension area attributes type, if there is a TGA 2.0 footer with an extension area
+fn attributes_type(src: &[u8]) -> Option<u8> {
+    if (src.len() < 18 + 26) || (&src[src.len() - 18..] != SIGNATURE) {
+        return None;
+    }
+    let offset = from_le32(&src[src.len() - 26..src.len() - 22]) as usize;
+    if (offset == 0) || (offset + 495 > src.len() - 26) || (from_le16(&src[offset..offset + 2]) < 495) {
+        return None;
+    }
+    Some(src[offset + 494])
+}
+
+// whether the image has alpha and whether it is premultiplied, the footer overrides the header
+fn alpha_mode(src: &[u8],header: &Header) -> (bool,bool) {
+    match attributes_type(src) {
+        Some(0) | Some(1) | Some(2) => (false,false),
+        Some(3) => (true,false),
+        Some(4) => (true,true),
+        _ => ((header.descriptor & 15) != 0,false),
+    }
+}
+
+pub fn test(src: &[u8]) -> Result<(u32,u32),ImageError> {
+    let header = parse_header(src)?;
+    Ok((header.width as u32,header.height as u32))
+}
+
+pub fn info(src: &[u8]) -> Result<ImageInfo,ImageError> {
+    let header = parse_header(src)?;
+    let (alpha,_) = alpha_mode(src,&header);
+    let mut info = ImageInfo::new(ImageFormat::Tga,header.width,header.height,8,if alpha { ChannelLayout::Rgba } else { ChannelLayout::Rgb });
+    match header.image_type & 7 {
+        1 => {
+            info.bit_depth = header.depth as u32;
+            info.palette = true;
+            if (header.colormap_depth == 15) || (header.colormap_depth == 24) {
+                info.channels = ChannelLayout::Rgb;
+            }
+        },
+        2 => {
+            if header.depth < 24 {
+                info.bit_depth = 5;
+            }
+            if (header.depth == 15) || (header.depth == 24) {
+                info.channels = ChannelLayout::Rgb;
+            }
+        },
+        _ => {
+            info.channels = if alpha && (header.depth == 16) { ChannelLayout::GrayAlpha } else { ChannelLayout::Gray };
+        },
+    }
+    Ok(info)
+}
+
+// 8-bit RGBA of a little-endian pixel
+fn unpack(src: &[u8],depth: usize,alpha: bool) -> (u8,u8,u8,u8) {
+    match depth {
+        15 | 16 => {
+            let d = from_le16(src);
+            let r = ((d >> 10) & 31) as u8;
+            let g = ((d >> 5) & 31) as u8;
+            let b = (d & 31) as u8;
+            let a = if alpha && (depth == 16) && ((d & 0x8000) == 0) { 0 } else { 255 };
+            ((r << 3) | (r >> 2),(g << 3) | (g >> 2),(b << 3) | (b >> 2),a)
+        },
+        24 => (src[2],src[1],src[0],255),
+        _ => (src[2],src[1],src[0],if alpha { src[3] } else { 255 }),
+    }
+}
+
+pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Result<Mat<T>,ImageError> {
+    let header = parse_header(src)?;
+    let (alpha,premultiplied) = alpha_mode(src,&header);
+
+    // color map
+    let mut sp = 18 + header.id_length;
+    let mut colormap: Vec<(u8,u8,u8,u8)> = Vec::new();
+    if header.colormap_type == 1 {
+        let size = (header.colormap_depth + 7) / 8;
+        for _i in 0..header.colormap_length {
+            colormap.push(unpack(&src[sp..sp + size],header.colormap_depth,alpha));
+            sp += size;
+        }
+    }
+
+    // uncompress
+    let bpp = (header.depth + 7) / 8;
+    let total = check_dimensions(header.width,header.height)?.checked_mul(bpp).ok_or(ImageError::Dimensions(header.width,header.height))?;
+    let data = if (header.image_type & 8) != 0 {
+        let mut data: Vec<u8> = Vec::with_capacity(total);
+        while data.len() < total {
+            if sp >= src.len() {
+                return Err(ImageError::Corrupt(sp,"truncated RLE data"));
+            }
+            let packet = src[sp];
+            sp += 1;
+            let count = ((packet & 0x7F) as usize) + 1;
+            if (packet & 0x80) != 0 {
+                if sp + bpp > src.len() {
+                    return Err(ImageError::Corrupt(sp,"truncated RLE data"));
+                }
+                for _i in 0..count {
+                    data.extend_from_slice(&src[sp..sp + bpp]);
+                }
+                sp += bpp;
+            }
+            else {
+                if sp + count * bpp > src.len() {
+                    return Err(ImageError::Corrupt(sp,"truncated RLE data"));
+                }
+                data.extend_from_slice(&src[sp..sp + count * bpp]);
+                sp += count * bpp;
+            }
+        }
+        data.truncate(total);
+        data
+    }
+    else {
+        src[sp..sp + total].to_vec()
+    };
+
+    // convert
+    let right_to_left = (header.descriptor & 0x10) != 0;
+    let top_down = (header.descriptor & 0x20) != 0;
+    let mut image = Mat::<T>::new(vec2!(header.width,header.height));
+    let mut dp = 0;
+    for y in 0..header.height {
+        let dy = if top_down { y } else { header.height - 1 - y };
+        for x in 0..header.width {
+            let dx = if right_to_left { header.width - 1 - x } else { x };
+            let p = &data[dp..dp + bpp];
+            dp += bpp;
+            let (r,g,b,a) = match header.image_type & 7 {
+                1 => {
+                    let index = if bpp == 2 { from_le16(p) as usize } else { p[0] as usize };
+                    if (index < header.colormap_first) || (index - header.colormap_first >= colormap.len()) {
+                        (0,0,0,255)
+                    }
+                    else {
+                        colormap[index - header.colormap_first]
+                    }
+                },
+                3 => (p[0],p[0],p[0],if alpha && (bpp == 2) { p[1] } else { 255 }),
+                _ => unpack(p,header.depth,alpha),
+            };
+            if premultiplied && (a != 0) && (a != 255) {
+                let un = |c: u8| { let v = ((c as u32) * 255 + (a as u32) / 2) / (a as u32); if v > 255 { 255 } else { v as u8 } };
+                image[(dx,dy)].set(un(r),un(g),un(b),a);
+            }
+            else {
+                image[(dx,dy)].set(r,g,b,a);
+            }
+        }
+    }
+    Ok(image)
+}
+
+/// What kind of TGA to write.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum ImageType {
+    /// 24-bit BGR, or 32-bit BGRA if the pixel format has alpha.
+    TrueColor,
+    /// 8-bit luminance, or 8-bit luminance with 8-bit alpha if the pixel format has alpha.
+    Grayscale,
+    /// 8-bit indices into a color map. Encoding fails if the image has more than 256 different colors.
+    ColorMapped,
+}
+
+/// TGA encoder settings.
+#[derive(Copy,Clone,Debug)]
+pub struct EncodeOptions {
+    /// Kind of TGA.
+    pub image_type: ImageType,
+    /// Compress the pixels with run-length encoding.
+    pub rle: bool,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> EncodeOptions {
+        EncodeOptions {
+            image_type: ImageType::TrueColor,
+            rle: true,
+        }
+    }
+}
+
+// run-length encode one row of pixels, packets do not cross rows
+fn push_rle(dst: &mut Vec<u8>,row: &[u8],bpp: usize) {
+    let count = row.len() / bpp;
+    let pixel = |i: usize| &row[i * bpp..(i + 1) * bpp];
+    let mut i = 0;
+    while i < count {
+        let mut run = 1;
+        while (i + run < count) && (run < 128) && (pixel(i + run) == pixel(i)) {
+            run += 1;
+        }
+        if run > 1 {
+            dst.push(0x80 | ((run - 1) as u8));
+            dst.extend_from_slice(pixel(i));
+            i += run;
+        }
+        else {
+            // raw packet until the next run of at least two starts
+            let mut raw = 1;
+            while (i + raw < count) && (raw < 128) && !((i + raw + 1 < count) && (pixel(i + raw) == pixel(i + raw + 1))) {
+                raw += 1;
+            }
+            dst.push((raw - 1) as u8);
+            dst.extend_from_slice(&row[i * bpp..(i + raw) * bpp]);
+            i += raw;
+        }
+    }
 }
 
-pub fn decode<T: pixel::Pixel>(_src: &[u8]) -> Option<Mat<T>> {
-    None
+pub fn encode<T: pixel::Pixel>(image: &Mat<T>) -> Result<Vec<u8>,ImageError> {
+    encode_with_options(image,&EncodeOptions::default())
+}
+
+pub fn encode_with_options<T: pixel::Pixel>(image: &Mat<T>,options: &EncodeOptions) -> Result<Vec<u8>,ImageError> {
+    let width = image.size.x;
+    let height = image.size.y;
+    if (width == 0) || (height == 0) || (width > 65535) || (height > 65535) {
+        return Err(ImageError::Dimensions(width,height));
+    }
+
+    // convert pixels
+    let mut colormap: Vec<(u8,u8,u8,u8)> = Vec::new();
+    let mut pixels: Vec<u8> = Vec::new();
+    let (image_type,depth,alpha_bits) = match options.image_type {
+        ImageType::TrueColor => {
+            for y in 0..height {
+                for x in 0..width {
+                    let (r,g,b,a) = image[(x,y)].get();
+                    pixels.push(b);
+                    pixels.push(g);
+                    pixels.push(r);
+                    if T::ALPHA {
+                        pixels.push(a);
+                    }
+                }
+            }
+            if T::ALPHA { (2,32,8) } else { (2,24,0) }
+        },
+        ImageType::Grayscale => {
+            for y in 0..height {
+                for x in 0..width {
+                    let (r,g,b,a) = image[(x,y)].get();
+                    pixels.push(((r as u32 * 77 + g as u32 * 150 + b as u32 * 29 + 128) >> 8) as u8);
+                    if T::ALPHA {
+                        pixels.push(a);
+                    }
+                }
+            }
+            if T::ALPHA { (3,16,8) } else { (3,8,0) }
+        },
+        ImageType::ColorMapped => {
+            for y in 0..height {
+                for x in 0..width {
+                    let (r,g,b,a) = image[(x,y)].get();
+                    let color = (r,g,b,if T::ALPHA { a } else { 255 });
+                    let index = match colormap.iter().position(|&c| c == color) {
+                        Some(index) => index,
+                        None => {
+                            if colormap.len() == 256 {
+                                return Err(ImageError::Unsupported("more than 256 colors in a color-mapped TGA"));
+                            }
+                            colormap.push(color);
+                            colormap.len() - 1
+                        },
+                    };
+                    pixels.push(index as u8);
+                }
+            }
+            if T::ALPHA { (1,8,8) } else { (1,8,0) }
+        },
+    };
+    let colormap_depth = if colormap.len() == 0 { 0 } else if T::ALPHA { 32 } else { 24 };
+
+    let mut dst: Vec<u8> = Vec::new();
+    dst.push(0);  // no image ID
+    dst.push(if colormap.len() == 0 { 0 } else { 1 });
+    dst.push(if options.rle { image_type | 8 } else { image_type });
+    dst.push(0);  // color map starts at 0
+    dst.push(0);
+    dst.push((colormap.len() & 255) as u8);
+    dst.push((colormap.len() >> 8) as u8);
+    dst.push(colormap_depth);
+    dst.extend_from_slice(&[0,0,0,0]);  // origin
+    dst.push((width & 255) as u8);
+    dst.push((width >> 8) as u8);
+    dst.push((height & 255) as u8);
+    dst.push((height >> 8) as u8);
+    dst.push(depth);
+    dst.push(0x20 | alpha_bits);  // top-left origin
+    for &(r,g,b,a) in colormap.iter() {
+        dst.push(b);
+        dst.push(g);
+        dst.push(r);
+        if T::ALPHA {
+            dst.push(a);
+        }
+    }
+    if options.rle {
+        let stride = width * (depth as usize / 8);
+        for y in 0..height {
+            push_rle(&mut dst,&pixels[y * stride..(y + 1) * stride],depth as usize / 8);
+        }
+    }
+    else {
+        dst.extend_from_slice(&pixels);
+    }
+
+    // TGA 2.0 footer without extension or developer area
+    dst.extend_from_slice(&[0,0,0,0,0,0,0,0]);
+    dst.extend_from_slice(SIGNATURE);
+    Ok(dst)
 }
 
-pub fn encode<T: pixel::Pixel>(_src: &Mat<T>) -> Option<Vec<u8>> {
-    None
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        pixel::Pixel,
+        crate::image::fixture,
+    };
+
+    // 18-byte header, color map and image ID are left to the caller
+    fn header(image_type: u8,colormap: (u16,u16,u8),width: u16,height: u16,depth: u8,descriptor: u8) -> Vec<u8> {
+        let mut data = vec![0,if colormap.1 != 0 { 1 } else { 0 },image_type];
+        data.extend_from_slice(&colormap.0.to_le_bytes());
+        data.extend_from_slice(&colormap.1.to_le_bytes());
+        data.push(colormap.2);
+        data.extend_from_slice(&[0,0,0,0]);
+        data.extend_from_slice(&width.to_le_bytes());
+        data.extend_from_slice(&height.to_le_bytes());
+        data.push(depth);
+        data.push(descriptor);
+        data
+    }
+
+    fn pixels(image: &Mat<pixel::RGBA8UN>) -> Vec<(u8,u8,u8,u8)> {
+        image.data().iter().map(|p| p.get()).collect()
+    }
+
+    #[test]
+    fn decode_origin() {
+        // 24-bit 2x2, stored bottom row first
+        let mut data = header(2,(0,0,0),2,2,24,0x00);
+        data.extend_from_slice(&[0,0,255,0,255,0,255,0,0,255,255,255]);
+        let image = decode::<pixel::RGBA8UN>(&data).unwrap();
+        assert_eq!(pixels(&image),[(0,0,255,255),(255,255,255,255),(255,0,0,255),(0,255,0,255)]);
+
+        // bottom-right
+        data[17] = 0x10;
+        let image = decode::<pixel::RGBA8UN>(&data).unwrap();
+        assert_eq!(pixels(&image),[(255,255,255,255),(0,0,255,255),(0,255,0,255),(255,0,0,255)]);
+
+        // top-left
+        data[17] = 0x20;
+        let image = decode::<pixel::RGBA8UN>(&data).unwrap();
+        assert_eq!(pixels(&image),[(255,0,0,255),(0,255,0,255),(0,0,255,255),(255,255,255,255)]);
+    }
+
+    #[test]
+    fn decode_16bit() {
+        // A1R5G5B5, red with the attribute bit, half green without it, blue with it, and white without it
+        let data16 = [0x00,0xFC,0x00,0x02,0x1F,0x80,0xFF,0x7F];
+        let mut data = header(2,(0,0,0),4,1,16,0x21);
+        data.extend_from_slice(&data16);
+        let image = decode::<pixel::RGBA8UN>(&data).unwrap();
+        assert_eq!(pixels(&image),[(255,0,0,255),(0,132,0,0),(0,0,255,255),(255,255,255,0)]);
+
+        // without alpha bits in the descriptor, the attribute bit is ignored
+        data[17] = 0x20;
+        let image = decode::<pixel::RGBA8UN>(&data).unwrap();
+        assert_eq!(pixels(&image),[(255,0,0,255),(0,132,0,255),(0,0,255,255),(255,255,255,255)]);
+
+        // 15-bit has no attribute bit
+        let mut data = header(2,(0,0,0),4,1,15,0x21);
+        data.extend_from_slice(&data16);
+        let image = decode::<pixel::RGBA8UN>(&data).unwrap();
+        assert_eq!(pixels(&image),[(255,0,0,255),(0,132,0,255),(0,0,255,255),(255,255,255,255)]);
+        assert_eq!(info(&data).unwrap().bit_depth,5);
+    }
+
+    #[test]
+    fn decode_16bit_indices() {
+        // color map of 16-bit A1R5G5B5 entries that starts at 300, one index falls outside
+        let mut data = header(1,(300,2,16),3,1,16,0x21);
+        data.extend_from_slice(&[0xE0,0x83,0x1F,0x00]);
+        data.extend_from_slice(&[0x2C,0x01,0x2D,0x01,0x00,0x00]);
+        let image = decode::<pixel::RGBA8UN>(&data).unwrap();
+        assert_eq!(pixels(&image),[(0,255,0,255),(0,0,255,0),(0,0,0,255)]);
+        let info = info(&data).unwrap();
+        assert!(info.palette);
+        assert_eq!(info.bit_depth,16);
+    }
+
+    #[test]
+    fn decode_extension_alpha() {
+        // TGA 2.0 file with an extension area, the attributes type overrides the descriptor
+        let with_attributes = |attributes: u8,descriptor: u8| {
+            let mut data = header(2,(0,0,0),2,1,32,descriptor);
+            data.extend_from_slice(&[0,32,64,128,255,255,255,0]);
+            let offset = data.len() as u32;
+            let mut extension = vec![0u8; 495];
+            extension[0..2].copy_from_slice(&495u16.to_le_bytes());
+            extension[494] = attributes;
+            data.extend_from_slice(&extension);
+            data.extend_from_slice(&offset.to_le_bytes());
+            data.extend_from_slice(&[0,0,0,0]);
+            data.extend_from_slice(SIGNATURE);
+            data
+        };
+
+        // premultiplied
+        let data = with_attributes(4,0x28);
+        assert_eq!(pixels(&decode::<pixel::RGBA8UN>(&data).unwrap()),[(128,64,0,128),(255,255,255,0)]);
+        assert_eq!(info(&data).unwrap().channels,ChannelLayout::Rgba);
+
+        // straight alpha, even without alpha bits in the descriptor
+        let data = with_attributes(3,0x20);
+        assert_eq!(pixels(&decode::<pixel::RGBA8UN>(&data).unwrap()),[(64,32,0,128),(255,255,255,0)]);
+
+        // no alpha, even with alpha bits in the descriptor
+        let data = with_attributes(0,0x28);
+        assert_eq!(pixels(&decode::<pixel::RGBA8UN>(&data).unwrap()),[(64,32,0,255),(255,255,255,255)]);
+        assert_eq!(info(&data).unwrap().channels,ChannelLayout::Rgb);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let image = fixture::pattern::<pixel::RGBA8UN>(vec2!(23,7),256);
+        let gray = fixture::grayscale(&image);
+        let mapped = fixture::pattern::<pixel::RGBA8UN>(vec2!(23,7),40);
+        for rle in [false,true] {
+            for (image_type,image) in [(ImageType::TrueColor,&image),(ImageType::Grayscale,&gray),(ImageType::ColorMapped,&mapped)] {
+                let data = encode_with_options(image,&EncodeOptions { image_type: image_type,rle: rle, }).unwrap();
+                fixture::assert_same(image,&decode::<pixel::RGBA8UN>(&data).unwrap());
+            }
+        }
+        let options = EncodeOptions { image_type: ImageType::ColorMapped,..EncodeOptions::default() };
+        let mut image = Mat::<pixel::RGBA8UN>::new(vec2!(300,1));
+        for (i,p) in image.data_mut().iter_mut().enumerate() {
+            p.set((i & 255) as u8,(i >> 8) as u8,0,255);
+        }
+        assert!(encode_with_options(&image,&options).is_err());
+    }
+
+    #[test]
+    fn encode_bytes() {
+        // red, red, blue
+        let mut image = Mat::<pixel::RGB8UN>::new(vec2!(3,1));
+        image[(0,0)].set(255,0,0,255);
+        image[(1,0)].set(255,0,0,255);
+        image[(2,0)].set(0,0,255,255);
+        let footer = |mut data: Vec<u8>| {
+            data.extend_from_slice(&[0,0,0,0,0,0,0,0]);
+            data.extend_from_slice(SIGNATURE);
+            data
+        };
+        let encoded = |image_type: ImageType,rle: bool| encode_with_options(&image,&EncodeOptions { image_type: image_type,rle: rle, }).unwrap();
+
+        // BGR, top-left origin
+        let mut expected = header(2,(0,0,0),3,1,24,0x20);
+        expected.extend_from_slice(&[0,0,255,0,0,255,255,0,0]);
+        assert_eq!(encoded(ImageType::TrueColor,false),footer(expected));
+
+        // a run of two and a raw packet of one
+        let mut expected = header(10,(0,0,0),3,1,24,0x20);
+        expected.extend_from_slice(&[0x81,0,0,255,0x00,255,0,0]);
+        assert_eq!(encoded(ImageType::TrueColor,true),footer(expected));
+
+        // color map in order of appearance
+        let mut expected = header(1,(0,2,24),3,1,8,0x20);
+        expected.extend_from_slice(&[0,0,255,255,0,0,0,0,1]);
+        assert_eq!(encoded(ImageType::ColorMapped,false),footer(expected));
+
+        // luminance
+        let mut expected = header(3,(0,0,0),3,1,8,0x20);
+        expected.extend_from_slice(&[77,77,29]);
+        assert_eq!(encoded(ImageType::Grayscale,false),footer(expected));
+    }
 }
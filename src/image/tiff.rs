@@ -5,14 +5,967 @@
 
 use crate::*;
 
-pub fn test(_src: &[u8]) -> Option<(u32,u32)> {
-    None
+// everything from one IFD that is needed to decode the image
+struct Page {
+    width: usize,
+    height: usize,
+    bits: usize,
+    samples: usize,
+    photometric: u32,
+    compression: u32,
+    predictor: u32,
+    planar: bool,
+    alpha: Option<u32>,  // extra sample type of the alpha channel: 1 associated, 2 unassociated
+    colormap: Vec<u32>,
+    chunk_width: usize,  // size of a strip or tile
+    chunk_height: usize,
+    offsets: Vec<u32>,
+    counts: Vec<u32>,
 }
 
-pub fn decode<T: pixel::Pixel>(_src: &[u8]) -> Option<Mat<T>> {
-    None
+fn find<'a>(entries: &'a [Entry],tag: u16) -> Option<&'a Entry> {
+    entries.iter().find(|e| e.tag == tag)
 }
 
-pub fn encode<T: pixel::Pixel>(_src: &Mat<T>) -> Option<Vec<u8>> {
-    None
+// ifd is the offset of the IFD, for error reporting
+fn parse_page(reader: &TiffReader,entries: &[Entry],ifd: usize) -> Result<Page,ImageError> {
+    let invalid = || ImageError::Corrupt(ifd,"invalid IFD entry");
+    let uint = |tag: u16,def: u32| -> Result<u32,ImageError> {
+        match find(entries,tag) {
+            Some(entry) => reader.uint(entry,0).ok_or_else(invalid),
+            None => Ok(def),
+        }
+    };
+    let width = uint(256,0)? as usize;
+    let height = uint(257,0)? as usize;
+    let bits = uint(258,1)? as usize;  // all samples have the same size in baseline TIFF
+    let samples = uint(277,1)? as usize;
+    let photometric = match find(entries,262) {
+        Some(entry) => reader.uint(entry,0).ok_or_else(invalid)?,
+        None => if samples >= 3 { 2 } else { 1 },
+    };
+    let compression = uint(259,1)?;
+    let predictor = uint(317,1)?;
+    let planar = uint(284,1)? == 2;
+    check_dimensions(width,height)?;
+    if (samples == 0) || (samples > 8) {
+        return Err(ImageError::Unsupported("TIFF samples per pixel"));
+    }
+    match bits {
+        1 | 2 | 4 | 8 | 16 => { },
+        _ => { return Err(ImageError::Unsupported("TIFF bits per sample")); },
+    }
+    let color_samples = match photometric {
+        0 | 1 | 3 => 1,
+        2 => 3,
+        _ => { return Err(ImageError::Unsupported("TIFF photometric interpretation")); },
+    };
+    if samples < color_samples {
+        return Err(ImageError::Corrupt(ifd,"not enough samples per pixel"));
+    }
+    if (photometric == 2) && (bits < 8) {
+        return Err(ImageError::Unsupported("TIFF RGB with less than 8 bits per sample"));
+    }
+    let mut alpha = None;
+    if samples > color_samples {
+        if let Some(entry) = find(entries,338) {
+            let extra = reader.uint(entry,0).ok_or_else(invalid)?;
+            if (extra == 1) || (extra == 2) {
+                alpha = Some(extra);
+            }
+        }
+    }
+    let colormap = if photometric == 3 {
+        let values = reader.uints(find(entries,320).ok_or(ImageError::Corrupt(ifd,"missing color map"))?).ok_or_else(invalid)?;
+        if values.len() < 3 << bits {
+            return Err(ImageError::Corrupt(ifd,"color map too small"));
+        }
+        values
+    }
+    else {
+        Vec::new()
+    };
+    let (chunk_width,chunk_height,offsets,counts) = if let Some(entry) = find(entries,324) {
+        let tile_width = uint(322,0)? as usize;
+        let tile_height = uint(323,0)? as usize;
+        let counts = reader.uints(find(entries,325).ok_or(ImageError::Corrupt(ifd,"missing tile byte counts"))?).ok_or_else(invalid)?;
+        (tile_width,tile_height,reader.uints(entry).ok_or_else(invalid)?,counts)
+    }
+    else {
+        let rows = uint(278,0xFFFFFFFF)? as usize;
+        let counts = reader.uints(find(entries,279).ok_or(ImageError::Corrupt(ifd,"missing strip byte counts"))?).ok_or_else(invalid)?;
+        let offsets = reader.uints(find(entries,273).ok_or(ImageError::Corrupt(ifd,"missing strip offsets"))?).ok_or_else(invalid)?;
+        (width,if rows > height { height } else { rows },offsets,counts)
+    };
+    if (chunk_width == 0) || (chunk_height == 0) {
+        return Err(ImageError::Corrupt(ifd,"invalid strip or tile size"));
+    }
+    check_dimensions(chunk_width,chunk_height)?;
+    let across = (width + chunk_width - 1) / chunk_width;
+    let down = (height + chunk_height - 1) / chunk_height;
+    let chunks = across * down * if planar { samples } else { 1 };
+    if (offsets.len() < chunks) || (counts.len() < chunks) {
+        return Err(ImageError::Corrupt(ifd,"missing strips or tiles"));
+    }
+    Ok(Page {
+        width: width,
+        height: height,
+        bits: bits,
+        samples: samples,
+        photometric: photometric,
+        compression: compression,
+        predictor: predictor,
+        planar: planar,
+        alpha: alpha,
+        colormap: colormap,
+        chunk_width: chunk_width,
+        chunk_height: chunk_height,
+        offsets: offsets,
+        counts: counts,
+    })
+}
+
+fn unpack_packbits(src: &[u8],size: usize) -> Vec<u8> {
+    let mut dst: Vec<u8> = Vec::with_capacity(size);
+    let mut sp = 0;
+    while (sp < src.len()) && (dst.len() < size) {
+        let n = src[sp] as i8;
+        sp += 1;
+        if n >= 0 {
+            let count = (n as usize) + 1;
+            let end = if sp + count > src.len() { src.len() } else { sp + count };
+            dst.extend_from_slice(&src[sp..end]);
+            sp = end;
+        }
+        else if n != -128 {
+            if sp < src.len() {
+                for _i in 0..(1 - (n as isize)) {
+                    dst.push(src[sp]);
+                }
+                sp += 1;
+            }
+        }
+    }
+    dst
+}
+
+// MSB-first LZW with early code size change
+fn unpack_lzw(src: &[u8],size: usize) -> Vec<u8> {
+    let mut dst: Vec<u8> = Vec::with_capacity(size);
+    let mut prefix = [0u16; 4096];
+    let mut suffix = [0u8; 4096];
+    let mut first = [0u8; 4096];
+    let mut length = [0u16; 4096];
+    for i in 0..256 {
+        suffix[i] = i as u8;
+        first[i] = i as u8;
+        length[i] = 1;
+    }
+    let mut next = 258;
+    let mut width = 9;
+    let mut old: Option<usize> = None;
+    let mut bit = 0usize;
+    while (bit + width <= src.len() * 8) && (dst.len() < size) {
+        let mut code = 0usize;
+        for _i in 0..width {
+            code = (code << 1) | (((src[bit >> 3] >> (7 - (bit & 7))) & 1) as usize);
+            bit += 1;
+        }
+        if code == 256 {
+            next = 258;
+            width = 9;
+            old = None;
+            continue;
+        }
+        if code == 257 {
+            break;
+        }
+        let (entry,extra) = if code < next {
+            (code,None)
+        }
+        else if (code == next) && old.is_some() {
+            (old.unwrap(),Some(first[old.unwrap()]))
+        }
+        else {
+            break;
+        };
+
+        // write the string of entry backwards, then the extra byte
+        let start = dst.len();
+        let len = length[entry] as usize;
+        dst.resize(start + len,0);
+        let mut c = entry;
+        for i in (0..len).rev() {
+            dst[start + i] = suffix[c];
+            c = prefix[c] as usize;
+        }
+        if let Some(b) = extra {
+            dst.push(b);
+        }
+
+        if let Some(o) = old {
+            if next < 4096 {
+                prefix[next] = o as u16;
+                suffix[next] = if let Some(b) = extra { b } else { first[entry] };
+                first[next] = first[o];
+                length[next] = length[o] + 1;
+                next += 1;
+                if (next + 1 >= (1 << width)) && (width < 12) {
+                    width += 1;
+                }
+            }
+        }
+        old = Some(code);
+    }
+    dst.truncate(size);
+    dst
+}
+
+// most bytes one compressed byte can turn into
+fn expansion(compression: u32) -> Result<usize,ImageError> {
+    match compression {
+        1 => Ok(1),
+        5 => Ok(4096),  // one 9 bit code can be a string of 4096 bytes
+        8 | 32946 => Ok(1032),
+        32773 => Ok(64),
+        _ => Err(ImageError::Unsupported("TIFF compression")),
+    }
+}
+
+// decompressed data of one strip or tile, that starts at offset, up to size bytes
+fn unpack_chunk(page: &Page,src: &[u8],offset: usize,size: usize) -> Result<Vec<u8>,ImageError> {
+    let mut data = match page.compression {
+        1 => src.to_vec(),
+        5 => unpack_lzw(src,size),
+        8 | 32946 => compress::inflate(src,compress::Format::Zlib).ok_or(ImageError::Corrupt(offset,"invalid compressed data"))?,
+        32773 => unpack_packbits(src,size),
+        _ => { return Err(ImageError::Unsupported("TIFF compression")); },
+    };
+    data.truncate(size);
+    Ok(data)
+}
+
+fn decode_page<T: pixel::Pixel>(reader: &TiffReader,page: &Page) -> Result<Mat<T>,ImageError> {
+    let width = page.width;
+    let height = page.height;
+    let spp = page.samples;
+    let chunk_samples = if page.planar { 1 } else { spp };
+    let row_bytes = (page.chunk_width * chunk_samples * page.bits + 7) / 8;
+    let across = (width + page.chunk_width - 1) / page.chunk_width;
+    let down = (height + page.chunk_height - 1) / page.chunk_height;
+    let planes = if page.planar { spp } else { 1 };
+    let max = ((1u32 << page.bits) - 1) as u32;
+
+    // the strips or tiles in the file have to be able to fill the image, before it is allocated
+    let total = check_dimensions(width,height)?.checked_mul(spp).ok_or(ImageError::Dimensions(width,height))?;
+    let ratio = expansion(page.compression)?;
+    let needed = ((width * spp * page.bits + 7) / 8).saturating_mul(height);
+    let chunks = planes * down * across;
+    let available = page.counts[0..chunks].iter().map(|&count| if count as usize > reader.src.len() { reader.src.len() } else { count as usize }).fold(0usize,|sum,count| sum.saturating_add(count.saturating_mul(ratio)));
+    if available < needed {
+        return Err(ImageError::Corrupt(page.offsets[0] as usize,"strips or tiles too small for the image"));
+    }
+
+    // all samples of the image, scaled to 16 bits
+    let mut samples = vec![0u16; total];
+    for plane in 0..planes {
+        for cy in 0..down {
+            for cx in 0..across {
+                let index = (plane * down + cy) * across + cx;
+                let offset = page.offsets[index] as usize;
+                let count = page.counts[index] as usize;
+                if offset + count > reader.src.len() {
+                    return Err(ImageError::Corrupt(offset,"strip or tile exceeds data"));
+                }
+                // only the rows inside the image, missing data stays 0
+                let rows = if (cy + 1) * page.chunk_height > height { height - cy * page.chunk_height } else { page.chunk_height };
+                let size = row_bytes * rows;
+                let mut data = unpack_chunk(page,&reader.src[offset..offset + count],offset,if size > count.saturating_mul(ratio) { count.saturating_mul(ratio) } else { size })?;
+
+                // undo horizontal differencing
+                if page.predictor == 2 {
+                    for row in data.chunks_mut(row_bytes) {
+                        if page.bits == 8 {
+                            for i in chunk_samples..row.len() {
+                                row[i] = row[i].wrapping_add(row[i - chunk_samples]);
+                            }
+                        }
+                        else if page.bits == 16 {
+                            for i in chunk_samples..row.len() / 2 {
+                                let get = |row: &[u8],i: usize| if reader.le { (row[i * 2 + 1] as u16) << 8 | row[i * 2] as u16 } else { (row[i * 2] as u16) << 8 | row[i * 2 + 1] as u16 };
+                                let v = get(row,i).wrapping_add(get(row,i - chunk_samples));
+                                if reader.le {
+                                    row[i * 2] = (v & 255) as u8;
+                                    row[i * 2 + 1] = (v >> 8) as u8;
+                                }
+                                else {
+                                    row[i * 2] = (v >> 8) as u8;
+                                    row[i * 2 + 1] = (v & 255) as u8;
+                                }
+                            }
+                        }
+                    }
+                }
+                else if page.predictor != 1 {
+                    return Err(ImageError::Unsupported("TIFF predictor"));
+                }
+
+                // place the samples, the last row can be short
+                for y in 0..rows {
+                    let py = cy * page.chunk_height + y;
+                    if y * row_bytes >= data.len() {
+                        break;
+                    }
+                    let end = if (y + 1) * row_bytes > data.len() { data.len() } else { (y + 1) * row_bytes };
+                    let row = &data[y * row_bytes..end];
+                    let byte = |i: usize| if i < row.len() { row[i] } else { 0 };
+                    for x in 0..page.chunk_width {
+                        let px = cx * page.chunk_width + x;
+                        if px >= width {
+                            break;
+                        }
+                        for s in 0..chunk_samples {
+                            let i = x * chunk_samples + s;
+                            let v = match page.bits {
+                                8 => byte(i) as u32,
+                                16 => if reader.le { (byte(i * 2 + 1) as u32) << 8 | byte(i * 2) as u32 } else { (byte(i * 2) as u32) << 8 | byte(i * 2 + 1) as u32 },
+                                _ => ((byte((i * page.bits) >> 3) >> (8 - page.bits - ((i * page.bits) & 7))) as u32) & max,
+                            };
+                            samples[(py * width + px) * spp + plane + s] = if page.photometric == 3 { v as u16 } else { (v * 65535 / max) as u16 };
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // convert to pixels
+    let mut image = Mat::<T>::new(vec2!(width,height));
+    for y in 0..height {
+        for x in 0..width {
+            let s = &samples[(y * width + x) * spp..(y * width + x + 1) * spp];
+            match page.photometric {
+                0 | 1 => {
+                    let l = if page.photometric == 0 { 65535 - s[0] } else { s[0] };
+                    let a = if page.alpha.is_some() { s[1] } else { 0xFFFF };
+                    image[(x,y)].set16(l,l,l,a);
+                },
+                3 => {
+                    let colors = 1 << page.bits;
+                    let index = s[0] as usize;
+                    let r = page.colormap[index] as u16;
+                    let g = page.colormap[colors + index] as u16;
+                    let b = page.colormap[2 * colors + index] as u16;
+                    let a = if page.alpha.is_some() { s[1] } else { 0xFFFF };
+                    image[(x,y)].set16(r,g,b,a);
+                },
+                _ => {
+                    let (mut r,mut g,mut b) = (s[0],s[1],s[2]);
+                    let a = if page.alpha.is_some() { s[3] } else { 0xFFFF };
+                    if (page.alpha == Some(1)) && (a != 0) && (a != 0xFFFF) {
+                        let un = |c: u16| { let v = (c as u32) * 65535 / (a as u32); if v > 65535 { 65535 } else { v as u16 } };
+                        r = un(r);
+                        g = un(g);
+                        b = un(b);
+                    }
+                    image[(x,y)].set16(r,g,b,a);
+                },
+            }
+        }
+    }
+    Ok(image)
+}
+
+// the TIFF structure and the offset of its first IFD
+fn open<'a>(src: &'a [u8]) -> Result<(TiffReader<'a>,usize),ImageError> {
+    let reader = TiffReader::new(src).ok_or(ImageError::UnknownFormat)?;
+    let offset = reader.first_ifd().ok_or(ImageError::Corrupt(4,"invalid IFD offset"))?;
+    Ok((reader,offset))
+}
+
+pub fn test(src: &[u8]) -> Result<(u32,u32),ImageError> {
+    let (reader,offset) = open(src)?;
+    let (entries,_) = reader.ifd(offset).ok_or(ImageError::Corrupt(offset,"invalid IFD"))?;
+    let page = parse_page(&reader,&entries,offset)?;
+    Ok((page.width as u32,page.height as u32))
+}
+
+pub fn info(src: &[u8]) -> Result<ImageInfo,ImageError> {
+    let (reader,offset) = open(src)?;
+    let (entries,mut next) = reader.ifd(offset).ok_or(ImageError::Corrupt(offset,"invalid IFD"))?;
+    let page = parse_page(&reader,&entries,offset)?;
+    let channels = match (page.photometric,page.alpha.is_some()) {
+        (0,false) | (1,false) => ChannelLayout::Gray,
+        (0,true) | (1,true) => ChannelLayout::GrayAlpha,
+        (_,false) => ChannelLayout::Rgb,
+        (_,true) => ChannelLayout::Rgba,
+    };
+    let mut info = ImageInfo::new(ImageFormat::Tiff,page.width,page.height,page.bits as u32,channels);
+    info.palette = page.photometric == 3;
+    if let Some(entry) = find(&entries,34675) {  // ICC profile
+        info.icc_profile = Some(reader.src[entry.offset..entry.offset + entry.count as usize].to_vec());
+    }
+    info.dpi = Exif::parse(src).and_then(|exif| exif.dpi());
+
+    // count the pages without parsing them
+    let mut visited: Vec<usize> = vec![offset];
+    while (next != 0) && !visited.contains(&next) {
+        visited.push(next);
+        next = match reader.ifd(next) {
+            Some((_,next)) => next,
+            None => 0,
+        };
+    }
+    info.frames = visited.len();
+    Ok(info)
+}
+
+pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Result<Mat<T>,ImageError> {
+    let (reader,offset) = open(src)?;
+    let (entries,_) = reader.ifd(offset).ok_or(ImageError::Corrupt(offset,"invalid IFD"))?;
+    let page = parse_page(&reader,&entries,offset)?;
+    decode_page(&reader,&page)
+}
+
+pub fn decode_pages<T: pixel::Pixel>(src: &[u8]) -> Result<Vec<Mat<T>>,ImageError> {
+    let (reader,mut offset) = open(src)?;
+    let mut pages: Vec<Mat<T>> = Vec::new();
+    let mut visited: Vec<usize> = Vec::new();
+    while (offset != 0) && !visited.contains(&offset) {
+        visited.push(offset);
+        let (entries,next) = reader.ifd(offset).ok_or(ImageError::Corrupt(offset,"invalid IFD"))?;
+        let page = parse_page(&reader,&entries,offset)?;
+        pages.push(decode_page(&reader,&page)?);
+        offset = next;
+    }
+    if pages.len() == 0 {
+        Err(ImageError::Corrupt(4,"no pages"))
+    }
+    else {
+        Ok(pages)
+    }
+}
+
+/// TIFF compression methods.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Compression {
+    /// Store the samples as they are.
+    None,
+    /// Lempel-Ziv-Welch.
+    LZW,
+    /// Deflate, as in ZIP and PNG.
+    Deflate,
+}
+
+/// TIFF encoder settings.
+#[derive(Copy,Clone,Debug)]
+pub struct EncodeOptions {
+    /// Compression of the strips.
+    pub compression: Compression,
+    /// Write 16-bit instead of 8-bit channels.
+    pub sixteen_bit: bool,
+    /// Store differences between neighbouring samples, which usually compresses better. Ignored without compression.
+    pub predictor: bool,
+    /// Horizontal and vertical resolution, if known.
+    pub resolution: Option<Vec2<f32>>,
+    /// Unit of `resolution`.
+    pub resolution_unit: ResolutionUnit,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> EncodeOptions {
+        EncodeOptions {
+            compression: Compression::LZW,
+            sixteen_bit: false,
+            predictor: true,
+            resolution: None,
+            resolution_unit: ResolutionUnit::Inch,
+        }
+    }
+}
+
+trait WriteTypes {
+    fn push16(&mut self,d: u16);
+    fn push32(&mut self,d: u32);
+}
+
+impl WriteTypes for Vec<u8> {
+    fn push16(&mut self,d: u16) {
+        self.push((d & 255) as u8);
+        self.push((d >> 8) as u8);
+    }
+    fn push32(&mut self,d: u32) {
+        self.push((d & 255) as u8);
+        self.push(((d >> 8) & 255) as u8);
+        self.push(((d >> 16) & 255) as u8);
+        self.push((d >> 24) as u8);
+    }
+}
+
+// MSB-first code writer for LZW
+struct BitWriter {
+    dst: Vec<u8>,
+    acc: u32,
+    bits: usize,
+}
+
+impl BitWriter {
+    fn put(&mut self,code: u16,width: usize) {
+        self.acc = (self.acc << width) | (code as u32);
+        self.bits += width;
+        while self.bits >= 8 {
+            self.dst.push((self.acc >> (self.bits - 8)) as u8);
+            self.bits -= 8;
+        }
+    }
+
+    fn flush(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.dst.push((self.acc << (8 - self.bits)) as u8);
+        }
+        self.dst
+    }
+}
+
+// LZW with early code size change, the counterpart of unpack_lzw
+fn pack_lzw(src: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter {
+        dst: Vec::new(),
+        acc: 0,
+        bits: 0,
+    };
+    let mut table: std::collections::HashMap<(u16,u8),u16> = std::collections::HashMap::new();
+    let mut next = 258;
+    let mut width = 9;
+    writer.put(256,width);
+    let mut current: Option<u16> = None;
+    for &b in src.iter() {
+        let c = match current {
+            Some(c) => c,
+            None => {
+                current = Some(b as u16);
+                continue;
+            },
+        };
+        if let Some(&code) = table.get(&(c,b)) {
+            current = Some(code);
+            continue;
+        }
+        writer.put(c,width);
+        table.insert((c,b),next);
+        next += 1;
+        if (next >= (1 << width)) && (width < 12) {
+            width += 1;
+        }
+        if next == 4094 {
+            writer.put(256,width);
+            table.clear();
+            next = 258;
+            width = 9;
+        }
+        current = Some(b as u16);
+    }
+    if let Some(c) = current {
+        writer.put(c,width);
+
+        // the decoder adds one more entry before it reads the end code
+        next += 1;
+        if (next >= (1 << width)) && (width < 12) {
+            width += 1;
+        }
+    }
+    writer.put(257,width);
+    writer.flush()
+}
+
+// rational approximation of a resolution
+fn rational(v: f32) -> (u32,u32) {
+    if v.fract() == 0.0 {
+        (v as u32,1)
+    }
+    else {
+        ((v * 1000.0).round() as u32,1000)
+    }
+}
+
+fn encode_images<T: pixel::Pixel>(images: &[&Mat<T>],options: &EncodeOptions) -> Result<Vec<u8>,ImageError> {
+    if images.len() == 0 {
+        return Err(ImageError::Dimensions(0,0));
+    }
+    let spp = if T::ALPHA { 4 } else { 3 };
+    let bits = if options.sixteen_bit { 16 } else { 8 };
+    let predictor = options.predictor && (options.compression != Compression::None);
+
+    let mut dst: Vec<u8> = Vec::new();
+    dst.extend_from_slice(b"II");
+    dst.push16(42);
+    dst.push32(0);
+    let mut ifd_link = 4;  // where the offset of the next IFD goes
+    for image in images.iter() {
+        let width = image.size.x;
+        let height = image.size.y;
+        if (width == 0) || (height == 0) {
+            return Err(ImageError::Dimensions(width,height));
+        }
+
+        // strips of about 8KB
+        let row_bytes = width * spp * bits / 8;
+        let rows_per_strip = if row_bytes >= 8192 { 1 } else { 8192 / row_bytes };
+        let mut offsets: Vec<u32> = Vec::new();
+        let mut counts: Vec<u32> = Vec::new();
+        let mut y0 = 0;
+        while y0 < height {
+            let y1 = if y0 + rows_per_strip > height { height } else { y0 + rows_per_strip };
+            let mut strip: Vec<u8> = Vec::with_capacity((y1 - y0) * row_bytes);
+            for y in y0..y1 {
+                let mut row: Vec<u16> = Vec::with_capacity(width * spp);
+                for x in 0..width {
+                    let (r,g,b,a) = if options.sixteen_bit {
+                        image[(x,y)].get16()
+                    }
+                    else {
+                        let (r,g,b,a) = image[(x,y)].get();
+                        (r as u16,g as u16,b as u16,a as u16)
+                    };
+                    row.push(r);
+                    row.push(g);
+                    row.push(b);
+                    if T::ALPHA {
+                        row.push(a);
+                    }
+                }
+                if predictor {
+                    for i in (spp..row.len()).rev() {
+                        row[i] = row[i].wrapping_sub(row[i - spp]);
+                    }
+                }
+                for v in row.iter() {
+                    if options.sixteen_bit {
+                        strip.push16(*v);
+                    }
+                    else {
+                        strip.push(*v as u8);
+                    }
+                }
+            }
+            let strip = match options.compression {
+                Compression::None => strip,
+                Compression::LZW => pack_lzw(&strip),
+                Compression::Deflate => compress::deflate(&strip,compress::Format::Zlib,6),
+            };
+            offsets.push(dst.len() as u32);
+            counts.push(strip.len() as u32);
+            dst.extend_from_slice(&strip);
+            if dst.len() & 1 != 0 {
+                dst.push(0);
+            }
+            y0 = y1;
+        }
+
+        // tag, type, count, value
+        let mut entries: Vec<(u16,u16,u32,Vec<u8>)> = Vec::new();
+        let short = |v: &[u16]| { let mut d: Vec<u8> = Vec::new(); for x in v.iter() { d.push16(*x); } d };
+        let long = |v: &[u32]| { let mut d: Vec<u8> = Vec::new(); for x in v.iter() { d.push32(*x); } d };
+        entries.push((256,4,1,long(&[width as u32])));
+        entries.push((257,4,1,long(&[height as u32])));
+        entries.push((258,3,spp as u32,short(&vec![bits as u16; spp])));
+        entries.push((259,3,1,short(&[match options.compression { Compression::None => 1,Compression::LZW => 5,Compression::Deflate => 8, }])));
+        entries.push((262,3,1,short(&[2])));  // RGB
+        entries.push((273,4,offsets.len() as u32,long(&offsets)));
+        entries.push((277,3,1,short(&[spp as u16])));
+        entries.push((278,4,1,long(&[rows_per_strip as u32])));
+        entries.push((279,4,counts.len() as u32,long(&counts)));
+        if let Some(resolution) = options.resolution {
+            let (xn,xd) = rational(resolution.x);
+            let (yn,yd) = rational(resolution.y);
+            entries.push((282,5,1,long(&[xn,xd])));
+            entries.push((283,5,1,long(&[yn,yd])));
+        }
+        entries.push((284,3,1,short(&[1])));  // chunky
+        if options.resolution.is_some() {
+            entries.push((296,3,1,short(&[match options.resolution_unit { ResolutionUnit::None => 1,ResolutionUnit::Inch => 2,ResolutionUnit::Centimeter => 3, }])));
+        }
+        if predictor {
+            entries.push((317,3,1,short(&[2])));
+        }
+        if T::ALPHA {
+            entries.push((338,3,1,short(&[2])));  // unassociated alpha
+        }
+
+        // values that don't fit in the entry go before the IFD
+        let mut values: Vec<[u8; 4]> = Vec::new();
+        for (_,_,_,data) in entries.iter() {
+            let mut value = [0u8; 4];
+            if data.len() <= 4 {
+                value[0..data.len()].copy_from_slice(data);
+            }
+            else {
+                let offset = dst.len() as u32;
+                value = [(offset & 255) as u8,((offset >> 8) & 255) as u8,((offset >> 16) & 255) as u8,(offset >> 24) as u8];
+                dst.extend_from_slice(data);
+                if dst.len() & 1 != 0 {
+                    dst.push(0);
+                }
+            }
+            values.push(value);
+        }
+
+        // IFD, linked from the previous one
+        let ifd = dst.len() as u32;
+        dst[ifd_link..ifd_link + 4].copy_from_slice(&[(ifd & 255) as u8,((ifd >> 8) & 255) as u8,((ifd >> 16) & 255) as u8,(ifd >> 24) as u8]);
+        dst.push16(entries.len() as u16);
+        for (i,(tag,format,count,_)) in entries.iter().enumerate() {
+            dst.push16(*tag);
+            dst.push16(*format);
+            dst.push32(*count);
+            dst.extend_from_slice(&values[i]);
+        }
+        ifd_link = dst.len();
+        dst.push32(0);
+    }
+    Ok(dst)
+}
+
+pub fn encode<T: pixel::Pixel>(image: &Mat<T>) -> Result<Vec<u8>,ImageError> {
+    encode_with_options(image,&EncodeOptions::default())
+}
+
+pub fn encode_with_options<T: pixel::Pixel>(image: &Mat<T>,options: &EncodeOptions) -> Result<Vec<u8>,ImageError> {
+    encode_images(&[image],options)
+}
+
+pub fn encode_pages<T: pixel::Pixel>(images: &[Mat<T>],options: &EncodeOptions) -> Result<Vec<u8>,ImageError> {
+    let images: Vec<&Mat<T>> = images.iter().collect();
+    encode_images(&images,options)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        pixel::Pixel,
+        crate::image::fixture,
+    };
+
+    #[test]
+    fn roundtrip_8bit() {
+        let image = fixture::pattern::<pixel::RGBA8UN>(vec2!(29,11),256);
+        for compression in [Compression::None,Compression::LZW,Compression::Deflate] {
+            for predictor in [false,true] {
+                let options = EncodeOptions { compression: compression,predictor: predictor,..EncodeOptions::default() };
+                fixture::assert_same(&image,&decode::<pixel::RGBA8UN>(&encode_with_options(&image,&options).unwrap()).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn roundtrip_16bit() {
+        let image = fixture::pattern::<pixel::RGBA16UN>(vec2!(29,11),65536);
+        for compression in [Compression::None,Compression::LZW,Compression::Deflate] {
+            let options = EncodeOptions { compression: compression,sixteen_bit: true,..EncodeOptions::default() };
+            fixture::assert_same(&image,&decode::<pixel::RGBA16UN>(&encode_with_options(&image,&options).unwrap()).unwrap());
+        }
+    }
+
+    #[test]
+    fn roundtrip_pages() {
+        let image = fixture::pattern::<pixel::RGBA16UN>(vec2!(29,11),65536);
+        let pages = [image.clone(),fixture::pattern(vec2!(13,5),256),fixture::grayscale(&image)];
+        let options = EncodeOptions { sixteen_bit: true,..EncodeOptions::default() };
+        let data = encode_pages(&pages,&options).unwrap();
+        assert_eq!(info(&data).unwrap().frames,3);
+        let decoded = decode_pages::<pixel::RGBA16UN>(&data).unwrap();
+        assert_eq!(decoded.len(),3);
+        for (page,decoded) in pages.iter().zip(decoded.iter()) {
+            fixture::assert_same(page,decoded);
+        }
+    }
+
+    #[test]
+    fn encode_bytes() {
+        let mut image = Mat::<pixel::RGB8UN>::new(vec2!(2,1));
+        image[(0,0)].set(255,128,0,255);
+        image[(1,0)].set(1,2,3,255);
+        let entry = |data: &mut Vec<u8>,tag: u16,format: u16,count: u32,value: u32| {
+            data.extend_from_slice(&tag.to_le_bytes());
+            data.extend_from_slice(&format.to_le_bytes());
+            data.extend_from_slice(&count.to_le_bytes());
+            data.extend_from_slice(&value.to_le_bytes());
+        };
+
+        // header, the strip, the bits per sample, then the IFD
+        let mut expected = b"II\x2A\0\x14\0\0\0".to_vec();
+        expected.extend_from_slice(&[255,128,0,1,2,3]);
+        expected.extend_from_slice(&[8,0,8,0,8,0]);
+        expected.extend_from_slice(&10u16.to_le_bytes());
+        entry(&mut expected,256,4,1,2);  // width
+        entry(&mut expected,257,4,1,1);  // height
+        entry(&mut expected,258,3,3,14);  // bits per sample
+        entry(&mut expected,259,3,1,1);  // no compression
+        entry(&mut expected,262,3,1,2);  // RGB
+        entry(&mut expected,273,4,1,8);  // strip offset
+        entry(&mut expected,277,3,1,3);  // samples per pixel
+        entry(&mut expected,278,4,1,8192 / 6);  // rows per strip
+        entry(&mut expected,279,4,1,6);  // strip size
+        entry(&mut expected,284,3,1,1);  // chunky
+        expected.extend_from_slice(&[0,0,0,0]);
+
+        // the predictor is only used with compression
+        for predictor in [false,true] {
+            let options = EncodeOptions { compression: Compression::None,predictor: predictor,..EncodeOptions::default() };
+            assert_eq!(encode_with_options(&image,&options).unwrap(),expected);
+        }
+
+        // 16-bit samples are little endian
+        let options = EncodeOptions { compression: Compression::None,sixteen_bit: true,..EncodeOptions::default() };
+        assert_eq!(&encode_with_options(&image,&options).unwrap()[8..20],&[255,255,128,128,0,0,1,1,2,2,3,3]);
+    }
+
+    // little or big endian file with the strip or tile data at offset 8, followed by one IFD with the entries in tag order
+    fn build(le: bool,entries: &[(u16,Vec<u32>)],data: &[u8]) -> Vec<u8> {
+        let u16b = |v: u16| if le { v.to_le_bytes() } else { v.to_be_bytes() };
+        let u32b = |v: u32| if le { v.to_le_bytes() } else { v.to_be_bytes() };
+        let mut src: Vec<u8> = if le { b"II\x2A\0".to_vec() } else { b"MM\0\x2A".to_vec() };
+        let ifd = 8 + data.len() + (data.len() & 1);
+        src.extend_from_slice(&u32b(ifd as u32));
+        src.extend_from_slice(data);
+        src.resize(ifd,0);
+        let extra = ifd + 2 + entries.len() * 12 + 4;
+        let mut values: Vec<u8> = Vec::new();
+        src.extend_from_slice(&u16b(entries.len() as u16));
+        for (tag,v) in entries.iter() {
+            let long = [256,257,273,279,324,325].contains(tag);
+            let mut bytes: Vec<u8> = Vec::new();
+            for &x in v.iter() {
+                if long {
+                    bytes.extend_from_slice(&u32b(x));
+                }
+                else {
+                    bytes.extend_from_slice(&u16b(x as u16));
+                }
+            }
+            src.extend_from_slice(&u16b(*tag));
+            src.extend_from_slice(&u16b(if long { 4 } else { 3 }));
+            src.extend_from_slice(&u32b(v.len() as u32));
+            if bytes.len() <= 4 {
+                bytes.resize(4,0);
+                src.extend_from_slice(&bytes);
+            }
+            else {
+                src.extend_from_slice(&u32b((extra + values.len()) as u32));
+                values.extend_from_slice(&bytes);
+            }
+        }
+        src.extend_from_slice(&u32b(0));
+        src.extend_from_slice(&values);
+        src
+    }
+
+    #[test]
+    fn decode_oversized() {
+        // a 2 KB strip can not fill 11665445x23 pixels
+        let src = build(true,&[(256,vec![11665445]),(257,vec![23]),(258,vec![8]),(262,vec![1]),(273,vec![8]),(277,vec![1]),(278,vec![23]),(279,vec![2000])],&[0x55; 2000]);
+        assert!(matches!(decode::<pixel::RGBA8UN>(&src),Err(ImageError::Corrupt(_,_))));
+
+        // the same strip is fine for an image it can fill
+        let src = build(true,&[(256,vec![100]),(257,vec![20]),(258,vec![8]),(262,vec![1]),(273,vec![8]),(277,vec![1]),(278,vec![20]),(279,vec![2000])],&[0x55; 2000]);
+        assert!(decode::<pixel::RGBA8UN>(&src).unwrap().data().iter().all(|p| p.get() == (0x55,0x55,0x55,255)));
+    }
+
+    fn gray(image: &Mat<pixel::RGBA16UN>) -> Vec<u16> {
+        image.data().iter().map(|p| { let (r,g,b,a) = p.get16(); assert!((r == g) && (g == b) && (a == 0xFFFF)); r }).collect()
+    }
+
+    #[test]
+    fn decode_tiles() {
+        // 20x18 in four 16x16 tiles, the parts outside the image are filled with 0xEE
+        let value = |x: usize,y: usize| ((x * 7 + y * 3) & 255) as u8;
+        let mut data: Vec<u8> = Vec::new();
+        for ty in 0..2 {
+            for tx in 0..2 {
+                for y in 0..16 {
+                    for x in 0..16 {
+                        let (px,py) = (tx * 16 + x,ty * 16 + y);
+                        data.push(if (px < 20) && (py < 18) { value(px,py) } else { 0xEE });
+                    }
+                }
+            }
+        }
+        for le in [true,false] {
+            let src = build(le,&[(256,vec![20]),(257,vec![18]),(258,vec![8]),(262,vec![1]),(277,vec![1]),(322,vec![16]),(323,vec![16]),(324,vec![8,264,520,776]),(325,vec![256,256,256,256])],&data);
+            let image = decode::<pixel::RGBA8UN>(&src).unwrap();
+            assert_eq!(image.size,vec2!(20usize,18));
+            for y in 0..18 {
+                for x in 0..20 {
+                    let v = value(x,y);
+                    assert_eq!(image[(x,y)].get(),(v,v,v,255));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decode_packbits() {
+        // a run of 4, 2 literals, then a row of 6 literals
+        let data = [0xFD,0x10,0x01,0x20,0x30,0x05,1,2,3,4,5,6];
+        for le in [true,false] {
+            let src = build(le,&[(256,vec![6]),(257,vec![2]),(258,vec![8]),(259,vec![32773]),(262,vec![1]),(273,vec![8]),(277,vec![1]),(278,vec![2]),(279,vec![12])],&data);
+            let image = decode::<pixel::RGBA16UN>(&src).unwrap();
+            assert_eq!(gray(&image),[0x10,0x10,0x10,0x10,0x20,0x30,1,2,3,4,5,6].iter().map(|&v| v * 257).collect::<Vec<u16>>());
+        }
+    }
+
+    #[test]
+    fn decode_palette() {
+        // 4-bit indices 0, 5 and 15
+        let mut colormap: Vec<u32> = Vec::new();
+        colormap.extend((0..16).map(|i| i * 4096));
+        colormap.extend((0..16).map(|i| 65535 - i * 4096));
+        colormap.extend((0..16).map(|i| i * 100));
+        for le in [true,false] {
+            let src = build(le,&[(256,vec![3]),(257,vec![1]),(258,vec![4]),(262,vec![3]),(273,vec![8]),(277,vec![1]),(278,vec![1]),(279,vec![2]),(320,colormap.clone())],&[0x05,0xF0]);
+            let image = decode::<pixel::RGBA16UN>(&src).unwrap();
+            let pixels: Vec<(u16,u16,u16,u16)> = image.data().iter().map(|p| p.get16()).collect();
+            assert_eq!(pixels,[(0,65535,0,65535),(20480,45055,500,65535),(61440,4095,1500,65535)]);
+            assert!(info(&src).unwrap().palette);
+        }
+    }
+
+    #[test]
+    fn decode_bilevel() {
+        // 10x2, 1 bit per pixel, rows padded to whole bytes
+        let data = [0b10110000,0b01000000,0b00000000,0b11000000];
+        let bits = [1,0,1,1,0,0,0,0,0,1,0,0,0,0,0,0,0,0,1,1];
+        for le in [true,false] {
+            for photometric in [0,1] {
+                let src = build(le,&[(256,vec![10]),(257,vec![2]),(258,vec![1]),(262,vec![photometric]),(273,vec![8]),(277,vec![1]),(278,vec![2]),(279,vec![4])],&data);
+                let image = decode::<pixel::RGBA16UN>(&src).unwrap();
+
+                // WhiteIsZero has black for set bits
+                let expected: Vec<u16> = bits.iter().map(|&b| if (b == 1) == (photometric == 1) { 65535 } else { 0 }).collect();
+                assert_eq!(gray(&image),expected);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_planar() {
+        // 3x2 RGB, 16 bits per sample, one strip per plane
+        let r = [0x0102u16,0x0304,0x0506,0x0708,0x090A,0x0B0C];
+        let g = [0x1000u16,0x2000,0x3000,0x4000,0x5000,0x6000];
+        let b = [0xFFFFu16,0xEEEE,0xDDDD,0xCCCC,0xBBBB,0xAAAA];
+        for le in [true,false] {
+            let mut data: Vec<u8> = Vec::new();
+            for plane in [&r,&g,&b] {
+                for &v in plane.iter() {
+                    data.extend_from_slice(&if le { v.to_le_bytes() } else { v.to_be_bytes() });
+                }
+            }
+            let src = build(le,&[(256,vec![3]),(257,vec![2]),(258,vec![16,16,16]),(262,vec![2]),(273,vec![8,20,32]),(277,vec![3]),(278,vec![2]),(279,vec![12,12,12]),(284,vec![2])],&data);
+            let image = decode::<pixel::RGBA16UN>(&src).unwrap();
+            for i in 0..6 {
+                assert_eq!(image.data()[i].get16(),(r[i],g[i],b[i],65535));
+            }
+        }
+    }
 }
@@ -5,14 +5,2427 @@
 
 use crate::*;
 
-pub fn test(_src: &[u8]) -> Option<(u32,u32)> {
-    None
+fn from_le16(src: &[u8]) -> u32 {
+    (src[0] as u32) | ((src[1] as u32) << 8)
 }
 
-pub fn decode<T: pixel::Pixel>(_src: &[u8]) -> Option<Mat<T>> {
-    None
+fn from_le24(src: &[u8]) -> u32 {
+    (src[2] as u32) << 16 | (src[1] as u32) << 8 | src[0] as u32
 }
 
-pub fn encode<T: pixel::Pixel>(_src: &Mat<T>) -> Option<Vec<u8>> {
-    None
+fn from_le32(src: &[u8]) -> u32 {
+    (src[3] as u32) << 24 | (src[2] as u32) << 16 | (src[1] as u32) << 8 | src[0] as u32
+}
+
+// least significant bit first reader for VP8L
+struct BitReader<'a> {
+    src: &'a [u8],
+    sp: usize,
+    value: u64,
+    bits: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(src: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            src: src,
+            sp: 0,
+            value: 0,
+            bits: 0,
+        }
+    }
+
+    fn read(&mut self,n: usize) -> Option<u32> {
+        if n == 0 {
+            return Some(0);
+        }
+        while (self.bits < n) && (self.sp < self.src.len()) {
+            self.value |= (self.src[self.sp] as u64) << self.bits;
+            self.sp += 1;
+            self.bits += 8;
+        }
+        if self.bits < n {
+            return None;
+        }
+        let result = (self.value & ((1 << n) - 1)) as u32;
+        self.value >>= n;
+        self.bits -= n;
+        Some(result)
+    }
+}
+
+// canonical prefix code, read bit by bit
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+    single: Option<u16>,  // only one symbol, which takes no bits
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Option<Huffman> {
+        let mut counts = [0u16; 16];
+        for &length in lengths.iter() {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+        let used: usize = counts.iter().map(|&c| c as usize).sum();
+        if used == 0 {
+            return None;
+        }
+        if used == 1 {
+            let symbol = lengths.iter().position(|&l| l != 0)? as u16;
+            return Some(Huffman {
+                counts: counts,
+                symbols: Vec::new(),
+                single: Some(symbol),
+            });
+        }
+
+        // reject oversubscribed codes
+        let mut left = 1i32;
+        for length in 1..16 {
+            left = (left << 1) - counts[length] as i32;
+            if left < 0 {
+                return None;
+            }
+        }
+
+        let mut offsets = [0u16; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0u16; used];
+        for (symbol,&length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Some(Huffman {
+            counts: counts,
+            symbols: symbols,
+            single: None,
+        })
+    }
+
+    fn decode(&self,reader: &mut BitReader) -> Option<usize> {
+        if let Some(symbol) = self.single {
+            return Some(symbol as usize);
+        }
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+        for length in 1..16 {
+            code |= reader.read(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return Some(self.symbols[(index + code - first) as usize] as usize);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        None
+    }
+}
+
+const CODE_LENGTH_ORDER: [usize; 19] = [17,18,0,1,2,3,4,5,16,6,7,8,9,10,11,12,13,14,15];
+
+// (x,y) offsets of the 120 short distance codes
+const DISTANCE_MAP: [(i8,i8); 120] = [
+    (0,1),(1,0),(1,1),(-1,1),(0,2),(2,0),(1,2),(-1,2),(2,1),(-2,1),
+    (2,2),(-2,2),(0,3),(3,0),(1,3),(-1,3),(3,1),(-3,1),(2,3),(-2,3),
+    (3,2),(-3,2),(0,4),(4,0),(1,4),(-1,4),(4,1),(-4,1),(3,3),(-3,3),
+    (2,4),(-2,4),(4,2),(-4,2),(0,5),(3,4),(-3,4),(4,3),(-4,3),(5,0),
+    (1,5),(-1,5),(5,1),(-5,1),(2,5),(-2,5),(5,2),(-5,2),(4,4),(-4,4),
+    (3,5),(-3,5),(5,3),(-5,3),(0,6),(6,0),(1,6),(-1,6),(6,1),(-6,1),
+    (2,6),(-2,6),(6,2),(-6,2),(4,5),(-4,5),(5,4),(-5,4),(3,6),(-3,6),
+    (6,3),(-6,3),(0,7),(7,0),(1,7),(-1,7),(5,5),(-5,5),(7,1),(-7,1),
+    (4,6),(-4,6),(6,4),(-6,4),(2,7),(-2,7),(7,2),(-7,2),(3,7),(-3,7),
+    (7,3),(-7,3),(5,6),(-5,6),(6,5),(-6,5),(8,0),(4,7),(-4,7),(7,4),
+    (-7,4),(8,1),(8,2),(6,6),(-6,6),(8,3),(5,7),(-5,7),(7,5),(-7,5),
+    (8,4),(6,7),(-6,7),(7,6),(-7,6),(8,5),(7,7),(-7,7),(8,6),(8,7),
+];
+
+fn read_huffman(reader: &mut BitReader,size: usize) -> Option<Huffman> {
+    let mut lengths = vec![0u8; size];
+    if reader.read(1)? == 1 {
+        // simple code, one or two symbols
+        let count = reader.read(1)? + 1;
+        let first_bits = if reader.read(1)? == 1 { 8 } else { 1 };
+        let symbol = reader.read(first_bits)? as usize;
+        if symbol >= size {
+            return None;
+        }
+        lengths[symbol] = 1;
+        if count == 2 {
+            let symbol = reader.read(8)? as usize;
+            if symbol >= size {
+                return None;
+            }
+            lengths[symbol] = 1;
+        }
+        return Huffman::new(&lengths);
+    }
+
+    // code length code
+    let count = reader.read(4)? as usize + 4;
+    let mut code_lengths = [0u8; 19];
+    for i in 0..count {
+        code_lengths[CODE_LENGTH_ORDER[i]] = reader.read(3)? as u8;
+    }
+    let code_huffman = Huffman::new(&code_lengths)?;
+
+    // number of codes to read, which can be less than the alphabet size
+    let mut max_codes = if reader.read(1)? == 1 {
+        let bits = 2 + 2 * reader.read(3)? as usize;
+        let max = 2 + reader.read(bits)? as usize;
+        if max > size {
+            return None;
+        }
+        max
+    }
+    else {
+        size
+    };
+
+    // code lengths
+    let mut symbol = 0;
+    let mut previous = 8u8;
+    while symbol < size {
+        if max_codes == 0 {
+            break;
+        }
+        max_codes -= 1;
+        let code = code_huffman.decode(reader)?;
+        if code < 16 {
+            lengths[symbol] = code as u8;
+            symbol += 1;
+            if code != 0 {
+                previous = code as u8;
+            }
+        }
+        else {
+            let (repeat,value) = match code {
+                16 => (3 + reader.read(2)? as usize,previous),
+                17 => (3 + reader.read(3)? as usize,0),
+                _ => (11 + reader.read(7)? as usize,0),
+            };
+            if symbol + repeat > size {
+                return None;
+            }
+            for _i in 0..repeat {
+                lengths[symbol] = value;
+                symbol += 1;
+            }
+        }
+    }
+    Huffman::new(&lengths)
+}
+
+// green + length + color cache, red, blue, alpha, distance
+struct HuffmanGroup {
+    green: Huffman,
+    red: Huffman,
+    blue: Huffman,
+    alpha: Huffman,
+    distance: Huffman,
+}
+
+// length or distance from its prefix symbol
+fn prefix_value(reader: &mut BitReader,prefix: usize) -> Option<usize> {
+    if prefix < 4 {
+        return Some(prefix + 1);
+    }
+    let extra = (prefix - 2) >> 1;
+    let offset = (2 + (prefix & 1)) << extra;
+    Some(offset + reader.read(extra)? as usize + 1)
+}
+
+fn subsample_size(size: usize,bits: usize) -> usize {
+    (size + (1 << bits) - 1) >> bits
+}
+
+// entropy coded image, main is false for the transform, entropy and color table images
+fn decode_image(reader: &mut BitReader,width: usize,height: usize,main: bool) -> Option<Vec<u32>> {
+    let cache_bits = if reader.read(1)? == 1 {
+        let bits = reader.read(4)? as usize;
+        if (bits < 1) || (bits > 11) {
+            return None;
+        }
+        bits
+    }
+    else {
+        0
+    };
+    let cache_size = if cache_bits > 0 { 1 << cache_bits } else { 0 };
+
+    // entropy image selects the prefix code group per block
+    let mut entropy: Option<(usize,Vec<u32>)> = None;
+    let mut groups_count = 1;
+    if main && (reader.read(1)? == 1) {
+        let bits = reader.read(3)? as usize + 2;
+        let image = decode_image(reader,subsample_size(width,bits),subsample_size(height,bits),false)?;
+        for pixel in image.iter() {
+            let group = ((pixel >> 8) & 0xFFFF) as usize;
+            if group >= groups_count {
+                groups_count = group + 1;
+            }
+        }
+        entropy = Some((bits,image));
+    }
+
+    let mut groups: Vec<HuffmanGroup> = Vec::with_capacity(groups_count);
+    for _i in 0..groups_count {
+        groups.push(HuffmanGroup {
+            green: read_huffman(reader,256 + 24 + cache_size)?,
+            red: read_huffman(reader,256)?,
+            blue: read_huffman(reader,256)?,
+            alpha: read_huffman(reader,256)?,
+            distance: read_huffman(reader,40)?,
+        });
+    }
+
+    let total = width * height;
+    let mut cache = vec![0u32; cache_size];
+    let mut data = vec![0u32; total];
+    let mut pos = 0;
+    while pos < total {
+        let group = match &entropy {
+            Some((bits,image)) => {
+                let x = pos % width;
+                let y = pos / width;
+                &groups[((image[(y >> bits) * subsample_size(width,*bits) + (x >> bits)] >> 8) & 0xFFFF) as usize]
+            },
+            None => &groups[0],
+        };
+        let symbol = group.green.decode(reader)?;
+        if symbol < 256 {
+            let red = group.red.decode(reader)? as u32;
+            let blue = group.blue.decode(reader)? as u32;
+            let alpha = group.alpha.decode(reader)? as u32;
+            let pixel = (alpha << 24) | (red << 16) | ((symbol as u32) << 8) | blue;
+            data[pos] = pixel;
+            if cache_bits > 0 {
+                cache[(0x1E35A7BDu32.wrapping_mul(pixel) >> (32 - cache_bits)) as usize] = pixel;
+            }
+            pos += 1;
+        }
+        else if symbol < 256 + 24 {
+            let length = prefix_value(reader,symbol - 256)?;
+            let prefix = group.distance.decode(reader)?;
+            let code = prefix_value(reader,prefix)?;
+            let distance = if code > 120 {
+                code - 120
+            }
+            else {
+                let (dx,dy) = DISTANCE_MAP[code - 1];
+                let d = (dx as isize) + (dy as isize) * (width as isize);
+                if d < 1 { 1 } else { d as usize }
+            };
+            if (distance > pos) || (pos + length > total) {
+                return None;
+            }
+            for _i in 0..length {
+                let pixel = data[pos - distance];
+                data[pos] = pixel;
+                if cache_bits > 0 {
+                    cache[(0x1E35A7BDu32.wrapping_mul(pixel) >> (32 - cache_bits)) as usize] = pixel;
+                }
+                pos += 1;
+            }
+        }
+        else {
+            let index = symbol - 256 - 24;
+            if index >= cache_size {
+                return None;
+            }
+            data[pos] = cache[index];
+            pos += 1;
+        }
+    }
+    Some(data)
+}
+
+enum Transform {
+    Predictor(usize,usize,Vec<u32>),  // width, block bits, modes
+    Color(usize,usize,Vec<u32>),  // width, block bits, multipliers
+    SubtractGreen,
+    ColorIndexing(usize,usize,Vec<u32>),  // packed width, bits per packed index, colors
+}
+
+// per component operations on ARGB pixels
+fn add_pixels(a: u32,b: u32) -> u32 {
+    (((a & 0xFF00FF00).wrapping_add(b & 0xFF00FF00)) & 0xFF00FF00) | (((a & 0x00FF00FF).wrapping_add(b & 0x00FF00FF)) & 0x00FF00FF)
+}
+
+fn average2(a: u32,b: u32) -> u32 {
+    (((a ^ b) & 0xFEFEFEFE) >> 1) + (a & b)
+}
+
+fn component(p: u32,shift: usize) -> i32 {
+    ((p >> shift) & 255) as i32
+}
+
+fn select(l: u32,t: u32,tl: u32) -> u32 {
+    let mut pl = 0;
+    let mut pt = 0;
+    for shift in [0,8,16,24].iter() {
+        pl += (component(t,*shift) - component(tl,*shift)).abs();
+        pt += (component(l,*shift) - component(tl,*shift)).abs();
+    }
+    if pl < pt { l } else { t }
+}
+
+fn clamp_add_subtract_full(a: u32,b: u32,c: u32) -> u32 {
+    let mut result = 0;
+    for shift in [0,8,16,24].iter() {
+        let v = component(a,*shift) + component(b,*shift) - component(c,*shift);
+        result |= (if v < 0 { 0 } else if v > 255 { 255 } else { v } as u32) << shift;
+    }
+    result
+}
+
+fn clamp_add_subtract_half(a: u32,b: u32) -> u32 {
+    let mut result = 0;
+    for shift in [0,8,16,24].iter() {
+        let ca = component(a,*shift);
+        let v = ca + (ca - component(b,*shift)) / 2;
+        result |= (if v < 0 { 0 } else if v > 255 { 255 } else { v } as u32) << shift;
+    }
+    result
+}
+
+fn color_delta(t: u32,c: u32) -> u32 {
+    (((t as u8 as i8 as i32) * (c as u8 as i8 as i32)) >> 5) as u32
+}
+
+fn inverse_transform(transform: &Transform,data: Vec<u32>,height: usize) -> Vec<u32> {
+    match transform {
+        Transform::Predictor(width,bits,modes) => {
+            let width = *width;
+            let mut data = data;
+            let blocks_width = subsample_size(width,*bits);
+            for y in 0..height {
+                for x in 0..width {
+                    let i = y * width + x;
+                    let prediction = if y == 0 {
+                        if x == 0 { 0xFF000000 } else { data[i - 1] }
+                    }
+                    else if x == 0 {
+                        data[i - width]
+                    }
+                    else {
+                        let l = data[i - 1];
+                        let t = data[i - width];
+                        let tl = data[i - width - 1];
+                        let tr = data[i - width + 1];  // leftmost pixel of the current row for the rightmost column
+                        match (modes[(y >> bits) * blocks_width + (x >> bits)] >> 8) & 15 {
+                            1 => l,
+                            2 => t,
+                            3 => tr,
+                            4 => tl,
+                            5 => average2(average2(l,tr),t),
+                            6 => average2(l,tl),
+                            7 => average2(l,t),
+                            8 => average2(tl,t),
+                            9 => average2(t,tr),
+                            10 => average2(average2(l,tl),average2(t,tr)),
+                            11 => select(l,t,tl),
+                            12 => clamp_add_subtract_full(l,t,tl),
+                            13 => clamp_add_subtract_half(average2(l,t),tl),
+                            _ => 0xFF000000,
+                        }
+                    };
+                    data[i] = add_pixels(data[i],prediction);
+                }
+            }
+            data
+        },
+        Transform::Color(width,bits,multipliers) => {
+            let width = *width;
+            let mut data = data;
+            let blocks_width = subsample_size(width,*bits);
+            for y in 0..height {
+                for x in 0..width {
+                    let m = multipliers[(y >> bits) * blocks_width + (x >> bits)];
+                    let green_to_red = m & 255;
+                    let green_to_blue = (m >> 8) & 255;
+                    let red_to_blue = (m >> 16) & 255;
+                    let p = data[y * width + x];
+                    let green = (p >> 8) & 255;
+                    let red = ((p >> 16).wrapping_add(color_delta(green_to_red,green))) & 255;
+                    let mut blue = (p.wrapping_add(color_delta(green_to_blue,green))) & 255;
+                    blue = (blue.wrapping_add(color_delta(red_to_blue,red))) & 255;
+                    data[y * width + x] = (p & 0xFF00FF00) | (red << 16) | blue;
+                }
+            }
+            data
+        },
+        Transform::SubtractGreen => {
+            data.iter().map(|&p| {
+                let green = (p >> 8) & 255;
+                add_pixels(p,(green << 16) | green)
+            }).collect()
+        },
+        Transform::ColorIndexing(width,bits,colors) => {
+            let packed_width = subsample_size(*width,*bits);
+            let per_index = 8 >> bits;
+            let mask = (1 << per_index) - 1;
+            let mut result = vec![0u32; width * height];
+            for y in 0..height {
+                for x in 0..*width {
+                    let p = data[y * packed_width + (x >> bits)];
+                    let index = (((p >> 8) & 255) >> ((x & ((1 << bits) - 1)) * per_index)) & mask;
+                    result[y * width + x] = if (index as usize) < colors.len() { colors[index as usize] } else { 0 };
+                }
+            }
+            result
+        },
+    }
+}
+
+// transforms and image data, everything after the VP8L header
+fn decode_stream(reader: &mut BitReader,width: usize,height: usize) -> Option<Vec<u32>> {
+    let mut transforms: Vec<Transform> = Vec::new();
+    let mut seen = [false; 4];
+    let mut xsize = width;
+    while reader.read(1)? == 1 {
+        let kind = reader.read(2)? as usize;
+        if seen[kind] {
+            return None;
+        }
+        seen[kind] = true;
+        transforms.push(match kind {
+            0 | 1 => {
+                let bits = reader.read(3)? as usize + 2;
+                let image = decode_image(reader,subsample_size(xsize,bits),subsample_size(height,bits),false)?;
+                if kind == 0 { Transform::Predictor(xsize,bits,image) } else { Transform::Color(xsize,bits,image) }
+            },
+            2 => Transform::SubtractGreen,
+            _ => {
+                let size = reader.read(8)? as usize + 1;
+                let mut colors = decode_image(reader,size,1,false)?;
+                for i in 1..size {
+                    colors[i] = add_pixels(colors[i],colors[i - 1]);
+                }
+                let bits = if size <= 2 { 3 } else if size <= 4 { 2 } else if size <= 16 { 1 } else { 0 };
+                let transform = Transform::ColorIndexing(xsize,bits,colors);
+                xsize = subsample_size(xsize,bits);
+                transform
+            },
+        });
+    }
+    let mut data = decode_image(reader,xsize,height,true)?;
+    for transform in transforms.iter().rev() {
+        data = inverse_transform(transform,data,height);
+    }
+    Some(data)
+}
+
+// VP8L bitstream, ARGB pixels
+fn decode_vp8l(src: &[u8]) -> Option<(usize,usize,Vec<u32>)> {
+    if (src.len() < 5) || (src[0] != 0x2F) {
+        return None;
+    }
+    let mut reader = BitReader::new(&src[1..]);
+    let width = reader.read(14)? as usize + 1;
+    let height = reader.read(14)? as usize + 1;
+    let _alpha_is_used = reader.read(1)?;
+    if reader.read(3)? != 0 {
+        return None;
+    }
+    let data = decode_stream(&mut reader,width,height)?;
+    Some((width,height,data))
+}
+
+// boolean entropy decoder for VP8
+struct BoolReader<'a> {
+    src: &'a [u8],
+    sp: usize,
+    value: u32,
+    range: u32,
+    bits: usize,
+    overrun: usize,  // bytes read past the end
+}
+
+impl<'a> BoolReader<'a> {
+    fn new(src: &'a [u8]) -> BoolReader<'a> {
+        let mut reader = BoolReader {
+            src: src,
+            sp: 0,
+            value: 0,
+            range: 255,
+            bits: 0,
+            overrun: 0,
+        };
+        reader.value = (reader.byte() << 8) | reader.byte();
+        reader
+    }
+
+    fn byte(&mut self) -> u32 {
+        if self.sp < self.src.len() {
+            self.sp += 1;
+            self.src[self.sp - 1] as u32
+        }
+        else {
+            self.overrun += 1;
+            0
+        }
+    }
+
+    fn bit(&mut self,prob: u8) -> bool {
+        let split = 1 + (((self.range - 1) * prob as u32) >> 8);
+        let result = if self.value >= split << 8 {
+            self.range -= split;
+            self.value -= split << 8;
+            true
+        }
+        else {
+            self.range = split;
+            false
+        };
+        while self.range < 128 {
+            self.value <<= 1;
+            self.range <<= 1;
+            self.bits += 1;
+            if self.bits == 8 {
+                self.bits = 0;
+                self.value |= self.byte();
+            }
+        }
+        result
+    }
+
+    fn flag(&mut self) -> bool {
+        self.bit(128)
+    }
+
+    fn literal(&mut self,n: usize) -> u32 {
+        let mut value = 0;
+        for _i in 0..n {
+            value = (value << 1) | self.flag() as u32;
+        }
+        value
+    }
+
+    fn signed(&mut self,n: usize) -> i32 {
+        let value = self.literal(n) as i32;
+        if self.flag() { -value } else { value }
+    }
+
+    // the data ran out well before the decoder was done
+    fn eof(&self) -> bool {
+        self.overrun > 2
+    }
+}
+
+// default coefficient probabilities, by type, band, context
+const COEFF_PROBS: [[[[u8; 11]; 3]; 8]; 4] = [
+    [
+        [
+            [128,128,128,128,128,128,128,128,128,128,128],
+            [128,128,128,128,128,128,128,128,128,128,128],
+            [128,128,128,128,128,128,128,128,128,128,128],
+        ],
+        [
+            [253,136,254,255,228,219,128,128,128,128,128],
+            [189,129,242,255,227,213,255,219,128,128,128],
+            [106,126,227,252,214,209,255,255,128,128,128],
+        ],
+        [
+            [1,98,248,255,236,226,255,255,128,128,128],
+            [181,133,238,254,221,234,255,154,128,128,128],
+            [78,134,202,247,198,180,255,219,128,128,128],
+        ],
+        [
+            [1,185,249,255,243,255,128,128,128,128,128],
+            [184,150,247,255,236,224,128,128,128,128,128],
+            [77,110,216,255,236,230,128,128,128,128,128],
+        ],
+        [
+            [1,101,251,255,241,255,128,128,128,128,128],
+            [170,139,241,252,236,209,255,255,128,128,128],
+            [37,116,196,243,228,255,255,255,128,128,128],
+        ],
+        [
+            [1,204,254,255,245,255,128,128,128,128,128],
+            [207,160,250,255,238,128,128,128,128,128,128],
+            [102,103,231,255,211,171,128,128,128,128,128],
+        ],
+        [
+            [1,152,252,255,240,255,128,128,128,128,128],
+            [177,135,243,255,234,225,128,128,128,128,128],
+            [80,129,211,255,194,224,128,128,128,128,128],
+        ],
+        [
+            [1,1,255,128,128,128,128,128,128,128,128],
+            [246,1,255,128,128,128,128,128,128,128,128],
+            [255,128,128,128,128,128,128,128,128,128,128],
+        ],
+    ],
+    [
+        [
+            [198,35,237,223,193,187,162,160,145,155,62],
+            [131,45,198,221,172,176,220,157,252,221,1],
+            [68,47,146,208,149,167,221,162,255,223,128],
+        ],
+        [
+            [1,149,241,255,221,224,255,255,128,128,128],
+            [184,141,234,253,222,220,255,199,128,128,128],
+            [81,99,181,242,176,190,249,202,255,255,128],
+        ],
+        [
+            [1,129,232,253,214,197,242,196,255,255,128],
+            [99,121,210,250,201,198,255,202,128,128,128],
+            [23,91,163,242,170,187,247,210,255,255,128],
+        ],
+        [
+            [1,200,246,255,234,255,128,128,128,128,128],
+            [109,178,241,255,231,245,255,255,128,128,128],
+            [44,130,201,253,205,192,255,255,128,128,128],
+        ],
+        [
+            [1,132,239,251,219,209,255,165,128,128,128],
+            [94,136,225,251,218,190,255,255,128,128,128],
+            [22,100,174,245,186,161,255,199,128,128,128],
+        ],
+        [
+            [1,182,249,255,232,235,128,128,128,128,128],
+            [124,143,241,255,227,234,128,128,128,128,128],
+            [35,77,181,251,193,211,255,205,128,128,128],
+        ],
+        [
+            [1,157,247,255,236,231,255,255,128,128,128],
+            [121,141,235,255,225,227,255,255,128,128,128],
+            [45,99,188,251,195,217,255,224,128,128,128],
+        ],
+        [
+            [1,1,251,255,213,255,128,128,128,128,128],
+            [203,1,248,255,255,128,128,128,128,128,128],
+            [137,1,177,255,224,255,128,128,128,128,128],
+        ],
+    ],
+    [
+        [
+            [253,9,248,251,207,208,255,192,128,128,128],
+            [175,13,224,243,193,185,249,198,255,255,128],
+            [73,17,171,221,161,179,236,167,255,234,128],
+        ],
+        [
+            [1,95,247,253,212,183,255,255,128,128,128],
+            [239,90,244,250,211,209,255,255,128,128,128],
+            [155,77,195,248,188,195,255,255,128,128,128],
+        ],
+        [
+            [1,24,239,251,218,219,255,205,128,128,128],
+            [201,51,219,255,196,186,128,128,128,128,128],
+            [69,46,190,239,201,218,255,228,128,128,128],
+        ],
+        [
+            [1,191,251,255,255,128,128,128,128,128,128],
+            [223,165,249,255,213,255,128,128,128,128,128],
+            [141,124,248,255,255,128,128,128,128,128,128],
+        ],
+        [
+            [1,16,248,255,255,128,128,128,128,128,128],
+            [190,36,230,255,236,255,128,128,128,128,128],
+            [149,1,255,128,128,128,128,128,128,128,128],
+        ],
+        [
+            [1,226,255,128,128,128,128,128,128,128,128],
+            [247,192,255,128,128,128,128,128,128,128,128],
+            [240,128,255,128,128,128,128,128,128,128,128],
+        ],
+        [
+            [1,134,252,255,255,128,128,128,128,128,128],
+            [213,62,250,255,255,128,128,128,128,128,128],
+            [55,93,255,128,128,128,128,128,128,128,128],
+        ],
+        [
+            [128,128,128,128,128,128,128,128,128,128,128],
+            [128,128,128,128,128,128,128,128,128,128,128],
+            [128,128,128,128,128,128,128,128,128,128,128],
+        ],
+    ],
+    [
+        [
+            [202,24,213,235,186,191,220,160,240,175,255],
+            [126,38,182,232,169,184,228,174,255,187,128],
+            [61,46,138,219,151,178,240,170,255,216,128],
+        ],
+        [
+            [1,112,230,250,199,191,247,159,255,255,128],
+            [166,109,228,252,211,215,255,174,128,128,128],
+            [39,77,162,232,172,180,245,178,255,255,128],
+        ],
+        [
+            [1,52,220,246,198,199,249,220,255,255,128],
+            [124,74,191,243,183,193,250,221,255,255,128],
+            [24,71,130,219,154,170,243,182,255,255,128],
+        ],
+        [
+            [1,182,225,249,219,240,255,224,128,128,128],
+            [149,150,226,252,216,205,255,171,128,128,128],
+            [28,108,170,242,183,194,254,223,255,255,128],
+        ],
+        [
+            [1,81,230,252,204,203,255,192,128,128,128],
+            [123,102,209,247,188,196,255,233,128,128,128],
+            [20,95,153,243,164,173,255,203,128,128,128],
+        ],
+        [
+            [1,222,248,255,216,213,128,128,128,128,128],
+            [168,175,246,252,235,205,255,255,128,128,128],
+            [47,116,215,255,211,212,255,255,128,128,128],
+        ],
+        [
+            [1,121,236,253,212,214,255,255,128,128,128],
+            [141,84,213,252,201,202,255,219,128,128,128],
+            [42,80,160,240,162,185,255,205,128,128,128],
+        ],
+        [
+            [1,1,255,128,128,128,128,128,128,128,128],
+            [244,1,255,128,128,128,128,128,128,128,128],
+            [238,1,255,128,128,128,128,128,128,128,128],
+        ],
+    ],
+];
+
+// probabilities that the frame header updates a coefficient probability
+const COEFF_UPDATE_PROBS: [[[[u8; 11]; 3]; 8]; 4] = [
+    [
+        [
+            [255,255,255,255,255,255,255,255,255,255,255],
+            [255,255,255,255,255,255,255,255,255,255,255],
+            [255,255,255,255,255,255,255,255,255,255,255],
+        ],
+        [
+            [176,246,255,255,255,255,255,255,255,255,255],
+            [223,241,252,255,255,255,255,255,255,255,255],
+            [249,253,253,255,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,244,252,255,255,255,255,255,255,255,255],
+            [234,254,254,255,255,255,255,255,255,255,255],
+            [253,255,255,255,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,246,254,255,255,255,255,255,255,255,255],
+            [239,253,254,255,255,255,255,255,255,255,255],
+            [254,255,254,255,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,248,254,255,255,255,255,255,255,255,255],
+            [251,255,254,255,255,255,255,255,255,255,255],
+            [255,255,255,255,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,253,254,255,255,255,255,255,255,255,255],
+            [251,254,254,255,255,255,255,255,255,255,255],
+            [254,255,254,255,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,254,253,255,254,255,255,255,255,255,255],
+            [250,255,254,255,254,255,255,255,255,255,255],
+            [254,255,255,255,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,255,255,255,255,255,255,255,255,255,255],
+            [255,255,255,255,255,255,255,255,255,255,255],
+            [255,255,255,255,255,255,255,255,255,255,255],
+        ],
+    ],
+    [
+        [
+            [217,255,255,255,255,255,255,255,255,255,255],
+            [225,252,241,253,255,255,254,255,255,255,255],
+            [234,250,241,250,253,255,253,254,255,255,255],
+        ],
+        [
+            [255,254,255,255,255,255,255,255,255,255,255],
+            [223,254,254,255,255,255,255,255,255,255,255],
+            [238,253,254,254,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,248,254,255,255,255,255,255,255,255,255],
+            [249,254,255,255,255,255,255,255,255,255,255],
+            [255,255,255,255,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,253,255,255,255,255,255,255,255,255,255],
+            [247,254,255,255,255,255,255,255,255,255,255],
+            [255,255,255,255,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,253,254,255,255,255,255,255,255,255,255],
+            [252,255,255,255,255,255,255,255,255,255,255],
+            [255,255,255,255,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,254,254,255,255,255,255,255,255,255,255],
+            [253,255,255,255,255,255,255,255,255,255,255],
+            [255,255,255,255,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,254,253,255,255,255,255,255,255,255,255],
+            [250,255,255,255,255,255,255,255,255,255,255],
+            [254,255,255,255,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,255,255,255,255,255,255,255,255,255,255],
+            [255,255,255,255,255,255,255,255,255,255,255],
+            [255,255,255,255,255,255,255,255,255,255,255],
+        ],
+    ],
+    [
+        [
+            [186,251,250,255,255,255,255,255,255,255,255],
+            [234,251,244,254,255,255,255,255,255,255,255],
+            [251,251,243,253,254,255,254,255,255,255,255],
+        ],
+        [
+            [255,253,254,255,255,255,255,255,255,255,255],
+            [236,253,254,255,255,255,255,255,255,255,255],
+            [251,253,253,254,254,255,255,255,255,255,255],
+        ],
+        [
+            [255,254,254,255,255,255,255,255,255,255,255],
+            [254,254,254,255,255,255,255,255,255,255,255],
+            [255,255,255,255,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,254,255,255,255,255,255,255,255,255,255],
+            [254,254,255,255,255,255,255,255,255,255,255],
+            [254,255,255,255,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,255,255,255,255,255,255,255,255,255,255],
+            [254,255,255,255,255,255,255,255,255,255,255],
+            [255,255,255,255,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,255,255,255,255,255,255,255,255,255,255],
+            [255,255,255,255,255,255,255,255,255,255,255],
+            [255,255,255,255,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,255,255,255,255,255,255,255,255,255,255],
+            [255,255,255,255,255,255,255,255,255,255,255],
+            [255,255,255,255,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,255,255,255,255,255,255,255,255,255,255],
+            [255,255,255,255,255,255,255,255,255,255,255],
+            [255,255,255,255,255,255,255,255,255,255,255],
+        ],
+    ],
+    [
+        [
+            [248,255,255,255,255,255,255,255,255,255,255],
+            [250,254,252,254,255,255,255,255,255,255,255],
+            [248,254,249,253,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,253,253,255,255,255,255,255,255,255,255],
+            [246,253,253,255,255,255,255,255,255,255,255],
+            [252,254,251,254,254,255,255,255,255,255,255],
+        ],
+        [
+            [255,254,252,255,255,255,255,255,255,255,255],
+            [248,254,253,255,255,255,255,255,255,255,255],
+            [253,255,254,254,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,251,254,255,255,255,255,255,255,255,255],
+            [245,251,254,255,255,255,255,255,255,255,255],
+            [253,253,254,255,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,251,253,255,255,255,255,255,255,255,255],
+            [252,253,254,255,255,255,255,255,255,255,255],
+            [255,254,255,255,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,252,255,255,255,255,255,255,255,255,255],
+            [249,255,254,255,255,255,255,255,255,255,255],
+            [255,255,254,255,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,255,253,255,255,255,255,255,255,255,255],
+            [250,255,255,255,255,255,255,255,255,255,255],
+            [255,255,255,255,255,255,255,255,255,255,255],
+        ],
+        [
+            [255,255,255,255,255,255,255,255,255,255,255],
+            [254,255,255,255,255,255,255,255,255,255,255],
+            [255,255,255,255,255,255,255,255,255,255,255],
+        ],
+    ],
+];
+
+// intra 4x4 mode probabilities, by the modes above and to the left
+const BMODE_PROBS: [[[u8; 9]; 10]; 10] = [
+    [
+        [231,120,48,89,115,113,120,152,112],
+        [152,179,64,126,170,118,46,70,95],
+        [175,69,143,80,85,82,72,155,103],
+        [56,58,10,171,218,189,17,13,152],
+        [114,26,17,163,44,195,21,10,173],
+        [121,24,80,195,26,62,44,64,85],
+        [144,71,10,38,171,213,144,34,26],
+        [170,46,55,19,136,160,33,206,71],
+        [63,20,8,114,114,208,12,9,226],
+        [81,40,11,96,182,84,29,16,36],
+    ],
+    [
+        [134,183,89,137,98,101,106,165,148],
+        [72,187,100,130,157,111,32,75,80],
+        [66,102,167,99,74,62,40,234,128],
+        [41,53,9,178,241,141,26,8,107],
+        [74,43,26,146,73,166,49,23,157],
+        [65,38,105,160,51,52,31,115,128],
+        [104,79,12,27,217,255,87,17,7],
+        [87,68,71,44,114,51,15,186,23],
+        [47,41,14,110,182,183,21,17,194],
+        [66,45,25,102,197,189,23,18,22],
+    ],
+    [
+        [88,88,147,150,42,46,45,196,205],
+        [43,97,183,117,85,38,35,179,61],
+        [39,53,200,87,26,21,43,232,171],
+        [56,34,51,104,114,102,29,93,77],
+        [39,28,85,171,58,165,90,98,64],
+        [34,22,116,206,23,34,43,166,73],
+        [107,54,32,26,51,1,81,43,31],
+        [68,25,106,22,64,171,36,225,114],
+        [34,19,21,102,132,188,16,76,124],
+        [62,18,78,95,85,57,50,48,51],
+    ],
+    [
+        [193,101,35,159,215,111,89,46,111],
+        [60,148,31,172,219,228,21,18,111],
+        [112,113,77,85,179,255,38,120,114],
+        [40,42,1,196,245,209,10,25,109],
+        [88,43,29,140,166,213,37,43,154],
+        [61,63,30,155,67,45,68,1,209],
+        [100,80,8,43,154,1,51,26,71],
+        [142,78,78,16,255,128,34,197,171],
+        [41,40,5,102,211,183,4,1,221],
+        [51,50,17,168,209,192,23,25,82],
+    ],
+    [
+        [138,31,36,171,27,166,38,44,229],
+        [67,87,58,169,82,115,26,59,179],
+        [63,59,90,180,59,166,93,73,154],
+        [40,40,21,116,143,209,34,39,175],
+        [47,15,16,183,34,223,49,45,183],
+        [46,17,33,183,6,98,15,32,183],
+        [57,46,22,24,128,1,54,17,37],
+        [65,32,73,115,28,128,23,128,205],
+        [40,3,9,115,51,192,18,6,223],
+        [87,37,9,115,59,77,64,21,47],
+    ],
+    [
+        [104,55,44,218,9,54,53,130,226],
+        [64,90,70,205,40,41,23,26,57],
+        [54,57,112,184,5,41,38,166,213],
+        [30,34,26,133,152,116,10,32,134],
+        [39,19,53,221,26,114,32,73,255],
+        [31,9,65,234,2,15,1,118,73],
+        [75,32,12,51,192,255,160,43,51],
+        [88,31,35,67,102,85,55,186,85],
+        [56,21,23,111,59,205,45,37,192],
+        [55,38,70,124,73,102,1,34,98],
+    ],
+    [
+        [125,98,42,88,104,85,117,175,82],
+        [95,84,53,89,128,100,113,101,45],
+        [75,79,123,47,51,128,81,171,1],
+        [57,17,5,71,102,57,53,41,49],
+        [38,33,13,121,57,73,26,1,85],
+        [41,10,67,138,77,110,90,47,114],
+        [115,21,2,10,102,255,166,23,6],
+        [101,29,16,10,85,128,101,196,26],
+        [57,18,10,102,102,213,34,20,43],
+        [117,20,15,36,163,128,68,1,26],
+    ],
+    [
+        [102,61,71,37,34,53,31,243,192],
+        [69,60,71,38,73,119,28,222,37],
+        [68,45,128,34,1,47,11,245,171],
+        [62,17,19,70,146,85,55,62,70],
+        [37,43,37,154,100,163,85,160,1],
+        [63,9,92,136,28,64,32,201,85],
+        [75,15,9,9,64,255,184,119,16],
+        [86,6,28,5,64,255,25,248,1],
+        [56,8,17,132,137,255,55,116,128],
+        [58,15,20,82,135,57,26,121,40],
+    ],
+    [
+        [164,50,31,137,154,133,25,35,218],
+        [51,103,44,131,131,123,31,6,158],
+        [86,40,64,135,148,224,45,183,128],
+        [22,26,17,131,240,154,14,1,209],
+        [45,16,21,91,64,222,7,1,197],
+        [56,21,39,155,60,138,23,102,213],
+        [83,12,13,54,192,255,68,47,28],
+        [85,26,85,85,128,128,32,146,171],
+        [18,11,7,63,144,171,4,4,246],
+        [35,27,10,146,174,171,12,26,128],
+    ],
+    [
+        [190,80,35,99,180,80,126,54,45],
+        [85,126,47,87,176,51,41,20,32],
+        [101,75,128,139,118,146,116,128,85],
+        [56,41,15,176,236,85,37,9,62],
+        [71,30,17,119,118,255,17,18,138],
+        [101,38,60,138,55,70,43,26,142],
+        [146,36,19,30,171,255,97,27,20],
+        [138,45,61,62,219,1,81,188,64],
+        [32,41,20,117,151,142,20,21,163],
+        [112,19,12,61,195,128,48,4,24],
+    ],
+];
+
+// quantizer index to DC and AC factors
+const DC_TABLE: [i32; 128] = [
+    4,5,6,7,8,9,10,10,11,12,13,14,15,16,17,17,
+    18,19,20,20,21,21,22,22,23,23,24,25,25,26,27,28,
+    29,30,31,32,33,34,35,36,37,37,38,39,40,41,42,43,
+    44,45,46,46,47,48,49,50,51,52,53,54,55,56,57,58,
+    59,60,61,62,63,64,65,66,67,68,69,70,71,72,73,74,
+    75,76,76,77,78,79,80,81,82,83,84,85,86,87,88,89,
+    91,93,95,96,98,100,101,102,104,106,108,110,112,114,116,118,
+    122,124,126,128,130,132,134,136,138,140,143,145,148,151,154,157,
+];
+
+const AC_TABLE: [i32; 128] = [
+    4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,
+    20,21,22,23,24,25,26,27,28,29,30,31,32,33,34,35,
+    36,37,38,39,40,41,42,43,44,45,46,47,48,49,50,51,
+    52,53,54,55,56,57,58,60,62,64,66,68,70,72,74,76,
+    78,80,82,84,86,88,90,92,94,96,98,100,102,104,106,108,
+    110,112,114,116,119,122,125,128,131,134,137,140,143,146,149,152,
+    155,158,161,164,167,170,173,177,181,185,189,193,197,201,205,209,
+    213,217,221,225,229,234,239,245,249,254,259,264,269,274,279,284,
+];
+
+// intra 4x4 mode tree, leaves are negated modes
+const BMODE_TREE: [i8; 18] = [0,1,-1,2,-2,3,4,6,-3,5,-4,-5,-6,7,-7,8,-8,-9];
+
+// coefficient index to band
+const BANDS: [usize; 17] = [0,1,2,3,6,4,5,6,6,6,6,6,6,6,6,7,0];
+
+const ZIGZAG: [usize; 16] = [0,1,4,8,5,2,3,6,9,12,13,10,7,11,14,15];
+
+// extra bits of the large coefficient categories
+const CATEGORY_PROBS: [&[u8]; 4] = [
+    &[173,148,140],
+    &[176,155,140,135],
+    &[180,157,141,134,130],
+    &[254,254,243,230,196,177,153,140,133,130,129],
+];
+
+// dequantization factors for DC and AC
+#[derive(Copy,Clone)]
+struct Quant {
+    y1: [i32; 2],
+    y2: [i32; 2],
+    uv: [i32; 2],
+}
+
+#[derive(Copy,Clone)]
+struct FilterInfo {
+    limit: i32,  // 0 means no filtering
+    interior: i32,
+    hev_threshold: i32,
+    inner: bool,  // also filter the edges inside the macroblock
+}
+
+// coefficient size from 2 upwards
+fn large_value(reader: &mut BoolReader,p: &[u8; 11]) -> i32 {
+    if !reader.bit(p[3]) {
+        if !reader.bit(p[4]) {
+            2
+        }
+        else {
+            3 + reader.bit(p[5]) as i32
+        }
+    }
+    else if !reader.bit(p[6]) {
+        if !reader.bit(p[7]) {
+            5 + reader.bit(159) as i32
+        }
+        else {
+            7 + 2 * reader.bit(165) as i32 + reader.bit(145) as i32
+        }
+    }
+    else {
+        let bit1 = reader.bit(p[8]) as usize;
+        let bit0 = reader.bit(p[9 + bit1]) as usize;
+        let category = 2 * bit1 + bit0;
+        let mut value = 0;
+        for prob in CATEGORY_PROBS[category].iter() {
+            value = value + value + reader.bit(*prob) as i32;
+        }
+        value + 3 + (8 << category)
+    }
+}
+
+// coefficients of a 4x4 block, returns the position after the last one
+fn read_coefficients(reader: &mut BoolReader,probs: &[[[u8; 11]; 3]; 8],ctx: usize,dq: &[i32; 2],first: usize,out: &mut [i16]) -> usize {
+    let mut n = first;
+    let mut p = &probs[BANDS[n]][ctx];
+    while n < 16 {
+        if !reader.bit(p[0]) {
+            return n;
+        }
+        while !reader.bit(p[1]) {
+            n += 1;
+            if n == 16 {
+                return 16;
+            }
+            p = &probs[BANDS[n]][0];
+        }
+        let value = if !reader.bit(p[2]) {
+            p = &probs[BANDS[n + 1]][1];
+            1
+        }
+        else {
+            let value = large_value(reader,p);
+            p = &probs[BANDS[n + 1]][2];
+            value
+        };
+        let value = if reader.flag() { -value } else { value };
+        out[ZIGZAG[n]] = (value * dq[if n > 0 { 1 } else { 0 }]) as i16;
+        n += 1;
+    }
+    16
+}
+
+// all coefficients of a macroblock, returns false if they are all zero
+fn read_residuals(reader: &mut BoolReader,probs: &[[[[u8; 11]; 3]; 8]; 4],quant: &Quant,i4x4: bool,top: &mut [u8; 9],left: &mut [u8; 9],coeffs: &mut [i16; 384]) -> bool {
+    // contexts are 4 Y, 2 U, 2 V and Y2
+    let mut non_zero = false;
+    let first = if i4x4 {
+        0
+    }
+    else {
+        let mut dc = [0i16; 16];
+        let ctx = (top[8] + left[8]) as usize;
+        let n = read_coefficients(reader,&probs[1],ctx,&quant.y2,0,&mut dc);
+        top[8] = (n > 0) as u8;
+        left[8] = top[8];
+        inverse_wht(&dc,coeffs);
+        1
+    };
+    let ac_probs = if i4x4 { &probs[3] } else { &probs[0] };
+    for y in 0..4 {
+        for x in 0..4 {
+            let block = &mut coeffs[(y * 4 + x) * 16..(y * 4 + x + 1) * 16];
+            let ctx = (top[x] + left[y]) as usize;
+            let n = read_coefficients(reader,ac_probs,ctx,&quant.y1,first,block);
+            top[x] = (n > first) as u8;
+            left[y] = top[x];
+            non_zero |= (n > 1) || (block[0] != 0);
+        }
+    }
+    for c in 0..2 {
+        for y in 0..2 {
+            for x in 0..2 {
+                let block = &mut coeffs[256 + c * 64 + (y * 2 + x) * 16..256 + c * 64 + (y * 2 + x + 1) * 16];
+                let ctx = (top[4 + c * 2 + x] + left[4 + c * 2 + y]) as usize;
+                let n = read_coefficients(reader,&probs[2],ctx,&quant.uv,0,block);
+                top[4 + c * 2 + x] = (n > 0) as u8;
+                left[4 + c * 2 + y] = top[4 + c * 2 + x];
+                non_zero |= (n > 1) || (block[0] != 0);
+            }
+        }
+    }
+    non_zero
+}
+
+// inverse Walsh-Hadamard transform of the Y2 block into the Y DC coefficients
+fn inverse_wht(src: &[i16; 16],coeffs: &mut [i16; 384]) {
+    let mut t = [0i32; 16];
+    for i in 0..4 {
+        let a0 = src[i] as i32 + src[12 + i] as i32;
+        let a1 = src[4 + i] as i32 + src[8 + i] as i32;
+        let a2 = src[4 + i] as i32 - src[8 + i] as i32;
+        let a3 = src[i] as i32 - src[12 + i] as i32;
+        t[i] = a0 + a1;
+        t[8 + i] = a0 - a1;
+        t[4 + i] = a3 + a2;
+        t[12 + i] = a3 - a2;
+    }
+    for i in 0..4 {
+        let dc = t[i * 4] + 3;
+        let a0 = dc + t[i * 4 + 3];
+        let a1 = t[i * 4 + 1] + t[i * 4 + 2];
+        let a2 = t[i * 4 + 1] - t[i * 4 + 2];
+        let a3 = dc - t[i * 4 + 3];
+        coeffs[(i * 4) * 16] = ((a0 + a1) >> 3) as i16;
+        coeffs[(i * 4 + 1) * 16] = ((a3 + a2) >> 3) as i16;
+        coeffs[(i * 4 + 2) * 16] = ((a0 - a1) >> 3) as i16;
+        coeffs[(i * 4 + 3) * 16] = ((a3 - a2) >> 3) as i16;
+    }
+}
+
+// stride of the prediction work areas
+const BPS: usize = 32;
+
+fn clip(v: i32) -> u8 {
+    if v < 0 { 0 } else if v > 255 { 255 } else { v as u8 }
+}
+
+fn mul1(a: i32) -> i32 {
+    (((a as i64 * 20091) >> 16) + a as i64) as i32
+}
+
+fn mul2(a: i32) -> i32 {
+    ((a as i64 * 35468) >> 16) as i32
+}
+
+// inverse DCT of a 4x4 block, added to the prediction
+fn inverse_dct(src: &[i16],dst: &mut [u8],o: usize) {
+    let mut t = [0i32; 16];
+    for i in 0..4 {
+        let a = src[i] as i32 + src[8 + i] as i32;
+        let b = src[i] as i32 - src[8 + i] as i32;
+        let c = mul2(src[4 + i] as i32) - mul1(src[12 + i] as i32);
+        let d = mul1(src[4 + i] as i32) + mul2(src[12 + i] as i32);
+        t[i * 4] = a + d;
+        t[i * 4 + 1] = b + c;
+        t[i * 4 + 2] = b - c;
+        t[i * 4 + 3] = a - d;
+    }
+    for i in 0..4 {
+        let dc = t[i] + 4;
+        let a = dc + t[8 + i];
+        let b = dc - t[8 + i];
+        let c = mul2(t[4 + i]) - mul1(t[12 + i]);
+        let d = mul1(t[4 + i]) + mul2(t[12 + i]);
+        let row = o + i * BPS;
+        dst[row] = clip(dst[row] as i32 + ((a + d) >> 3));
+        dst[row + 1] = clip(dst[row + 1] as i32 + ((b + c) >> 3));
+        dst[row + 2] = clip(dst[row + 2] as i32 + ((b - c) >> 3));
+        dst[row + 3] = clip(dst[row + 3] as i32 + ((a - d) >> 3));
+    }
+}
+
+// 16x16 and 8x8 prediction: 0 = DC, 1 = TrueMotion, 2 = vertical, 3 = horizontal
+fn predict_block(dst: &mut [u8],o: usize,size: usize,mode: u8,top: bool,left: bool) {
+    match mode {
+        0 => {
+            let shift = if size == 16 { 4 } else { 3 };
+            let mut sum = 0u32;
+            for i in 0..size {
+                if top {
+                    sum += dst[o - BPS + i] as u32;
+                }
+                if left {
+                    sum += dst[o + i * BPS - 1] as u32;
+                }
+            }
+            let dc = if top && left {
+                ((sum + size as u32) >> (shift + 1)) as u8
+            }
+            else if top || left {
+                ((sum + (size as u32 >> 1)) >> shift) as u8
+            }
+            else {
+                128
+            };
+            for y in 0..size {
+                for x in 0..size {
+                    dst[o + y * BPS + x] = dc;
+                }
+            }
+        },
+        1 => {
+            predict_true_motion(dst,o,size);
+        },
+        2 => {
+            for y in 0..size {
+                for x in 0..size {
+                    dst[o + y * BPS + x] = dst[o - BPS + x];
+                }
+            }
+        },
+        _ => {
+            for y in 0..size {
+                let l = dst[o + y * BPS - 1];
+                for x in 0..size {
+                    dst[o + y * BPS + x] = l;
+                }
+            }
+        },
+    }
+}
+
+fn predict_true_motion(dst: &mut [u8],o: usize,size: usize) {
+    let tl = dst[o - BPS - 1] as i32;
+    for y in 0..size {
+        let l = dst[o + y * BPS - 1] as i32;
+        for x in 0..size {
+            dst[o + y * BPS + x] = clip(l + dst[o - BPS + x] as i32 - tl);
+        }
+    }
+}
+
+fn avg2(a: u8,b: u8) -> u8 {
+    ((a as u32 + b as u32 + 1) >> 1) as u8
+}
+
+fn avg3(a: u8,b: u8,c: u8) -> u8 {
+    ((a as u32 + 2 * b as u32 + c as u32 + 2) >> 2) as u8
+}
+
+// set several pixels of a 4x4 block to the same value
+fn put(dst: &mut [u8],o: usize,cells: &[(usize,usize)],v: u8) {
+    for (x,y) in cells.iter() {
+        dst[o + y * BPS + x] = v;
+    }
+}
+
+// 4x4 prediction, modes in bitstream order
+fn predict_subblock(dst: &mut [u8],o: usize,mode: u8) {
+    let x = dst[o - BPS - 1];
+    let (a,b,c,d) = (dst[o - BPS],dst[o - BPS + 1],dst[o - BPS + 2],dst[o - BPS + 3]);
+    let (e,f,g,h) = (dst[o - BPS + 4],dst[o - BPS + 5],dst[o - BPS + 6],dst[o - BPS + 7]);
+    let (i,j,k,l) = (dst[o - 1],dst[o + BPS - 1],dst[o + 2 * BPS - 1],dst[o + 3 * BPS - 1]);
+    match mode {
+        0 => {  // DC
+            let sum = [a,b,c,d,i,j,k,l].iter().fold(4u32,|s,&v| s + v as u32);
+            put(dst,o,&[(0,0),(1,0),(2,0),(3,0),(0,1),(1,1),(2,1),(3,1),(0,2),(1,2),(2,2),(3,2),(0,3),(1,3),(2,3),(3,3)],(sum >> 3) as u8);
+        },
+        1 => {  // TrueMotion
+            predict_true_motion(dst,o,4);
+        },
+        2 => {  // vertical
+            let row = [avg3(x,a,b),avg3(a,b,c),avg3(b,c,d),avg3(c,d,e)];
+            for y in 0..4 {
+                for x in 0..4 {
+                    dst[o + y * BPS + x] = row[x];
+                }
+            }
+        },
+        3 => {  // horizontal
+            put(dst,o,&[(0,0),(1,0),(2,0),(3,0)],avg3(x,i,j));
+            put(dst,o,&[(0,1),(1,1),(2,1),(3,1)],avg3(i,j,k));
+            put(dst,o,&[(0,2),(1,2),(2,2),(3,2)],avg3(j,k,l));
+            put(dst,o,&[(0,3),(1,3),(2,3),(3,3)],avg3(k,l,l));
+        },
+        4 => {  // down-right
+            put(dst,o,&[(0,3)],avg3(j,k,l));
+            put(dst,o,&[(1,3),(0,2)],avg3(i,j,k));
+            put(dst,o,&[(2,3),(1,2),(0,1)],avg3(x,i,j));
+            put(dst,o,&[(3,3),(2,2),(1,1),(0,0)],avg3(a,x,i));
+            put(dst,o,&[(3,2),(2,1),(1,0)],avg3(b,a,x));
+            put(dst,o,&[(3,1),(2,0)],avg3(c,b,a));
+            put(dst,o,&[(3,0)],avg3(d,c,b));
+        },
+        5 => {  // vertical-right
+            put(dst,o,&[(0,0),(1,2)],avg2(x,a));
+            put(dst,o,&[(1,0),(2,2)],avg2(a,b));
+            put(dst,o,&[(2,0),(3,2)],avg2(b,c));
+            put(dst,o,&[(3,0)],avg2(c,d));
+            put(dst,o,&[(0,3)],avg3(k,j,i));
+            put(dst,o,&[(0,2)],avg3(j,i,x));
+            put(dst,o,&[(0,1),(1,3)],avg3(i,x,a));
+            put(dst,o,&[(1,1),(2,3)],avg3(x,a,b));
+            put(dst,o,&[(2,1),(3,3)],avg3(a,b,c));
+            put(dst,o,&[(3,1)],avg3(b,c,d));
+        },
+        6 => {  // down-left
+            put(dst,o,&[(0,0)],avg3(a,b,c));
+            put(dst,o,&[(1,0),(0,1)],avg3(b,c,d));
+            put(dst,o,&[(2,0),(1,1),(0,2)],avg3(c,d,e));
+            put(dst,o,&[(3,0),(2,1),(1,2),(0,3)],avg3(d,e,f));
+            put(dst,o,&[(3,1),(2,2),(1,3)],avg3(e,f,g));
+            put(dst,o,&[(3,2),(2,3)],avg3(f,g,h));
+            put(dst,o,&[(3,3)],avg3(g,h,h));
+        },
+        7 => {  // vertical-left
+            put(dst,o,&[(0,0)],avg2(a,b));
+            put(dst,o,&[(1,0),(0,2)],avg2(b,c));
+            put(dst,o,&[(2,0),(1,2)],avg2(c,d));
+            put(dst,o,&[(3,0),(2,2)],avg2(d,e));
+            put(dst,o,&[(0,1)],avg3(a,b,c));
+            put(dst,o,&[(1,1),(0,3)],avg3(b,c,d));
+            put(dst,o,&[(2,1),(1,3)],avg3(c,d,e));
+            put(dst,o,&[(3,1),(2,3)],avg3(d,e,f));
+            put(dst,o,&[(3,2)],avg3(e,f,g));
+            put(dst,o,&[(3,3)],avg3(f,g,h));
+        },
+        8 => {  // horizontal-down
+            put(dst,o,&[(0,0),(2,1)],avg2(i,x));
+            put(dst,o,&[(0,1),(2,2)],avg2(j,i));
+            put(dst,o,&[(0,2),(2,3)],avg2(k,j));
+            put(dst,o,&[(0,3)],avg2(l,k));
+            put(dst,o,&[(3,0)],avg3(a,b,c));
+            put(dst,o,&[(2,0)],avg3(x,a,b));
+            put(dst,o,&[(1,0),(3,1)],avg3(i,x,a));
+            put(dst,o,&[(1,1),(3,2)],avg3(j,i,x));
+            put(dst,o,&[(1,2),(3,3)],avg3(k,j,i));
+            put(dst,o,&[(1,3)],avg3(l,k,j));
+        },
+        _ => {  // horizontal-up
+            put(dst,o,&[(0,0)],avg2(i,j));
+            put(dst,o,&[(2,0),(0,1)],avg2(j,k));
+            put(dst,o,&[(2,1),(0,2)],avg2(k,l));
+            put(dst,o,&[(1,0)],avg3(i,j,k));
+            put(dst,o,&[(3,0),(1,1)],avg3(j,k,l));
+            put(dst,o,&[(3,1),(1,2)],avg3(k,l,l));
+            put(dst,o,&[(3,2),(2,2),(0,3),(1,3),(2,3),(3,3)],l);
+        },
+    }
+}
+
+fn sclip1(v: i32) -> i32 {
+    if v < -128 { -128 } else if v > 127 { 127 } else { v }
+}
+
+fn sclip2(v: i32) -> i32 {
+    if v < -16 { -16 } else if v > 15 { 15 } else { v }
+}
+
+// 4 pixels in, 2 pixels out
+fn filter2(p: &mut [u8],o: usize,step: usize) {
+    let (p1,p0,q0,q1) = (p[o - 2 * step] as i32,p[o - step] as i32,p[o] as i32,p[o + step] as i32);
+    let a = 3 * (q0 - p0) + sclip1(p1 - q1);
+    let a1 = sclip2((a + 4) >> 3);
+    let a2 = sclip2((a + 3) >> 3);
+    p[o - step] = clip(p0 + a2);
+    p[o] = clip(q0 - a1);
+}
+
+// 4 pixels in, 4 pixels out
+fn filter4(p: &mut [u8],o: usize,step: usize) {
+    let (p1,p0,q0,q1) = (p[o - 2 * step] as i32,p[o - step] as i32,p[o] as i32,p[o + step] as i32);
+    let a = 3 * (q0 - p0);
+    let a1 = sclip2((a + 4) >> 3);
+    let a2 = sclip2((a + 3) >> 3);
+    let a3 = (a1 + 1) >> 1;
+    p[o - 2 * step] = clip(p1 + a3);
+    p[o - step] = clip(p0 + a2);
+    p[o] = clip(q0 - a1);
+    p[o + step] = clip(q1 - a3);
+}
+
+// 6 pixels in, 6 pixels out
+fn filter6(p: &mut [u8],o: usize,step: usize) {
+    let (p2,p1,p0) = (p[o - 3 * step] as i32,p[o - 2 * step] as i32,p[o - step] as i32);
+    let (q0,q1,q2) = (p[o] as i32,p[o + step] as i32,p[o + 2 * step] as i32);
+    let a = sclip1(3 * (q0 - p0) + sclip1(p1 - q1));
+    let a1 = (27 * a + 63) >> 7;
+    let a2 = (18 * a + 63) >> 7;
+    let a3 = (9 * a + 63) >> 7;
+    p[o - 3 * step] = clip(p2 + a3);
+    p[o - 2 * step] = clip(p1 + a2);
+    p[o - step] = clip(p0 + a1);
+    p[o] = clip(q0 - a1);
+    p[o + step] = clip(q1 - a2);
+    p[o + 2 * step] = clip(q2 - a3);
+}
+
+fn diff(p: &[u8],a: usize,b: usize) -> i32 {
+    (p[a] as i32 - p[b] as i32).abs()
+}
+
+// simple filter along an edge of 16 pixels, step crosses the edge
+fn simple_filter(p: &mut [u8],o: usize,step: usize,along: usize,limit: i32) {
+    let threshold = 2 * limit + 1;
+    for n in 0..16 {
+        let o = o + n * along;
+        if 4 * diff(p,o - step,o) + diff(p,o - 2 * step,o + step) <= threshold {
+            filter2(p,o,step);
+        }
+    }
+}
+
+// normal filter along an edge, step crosses the edge
+fn normal_filter(p: &mut [u8],o: usize,step: usize,along: usize,count: usize,limit: i32,info: &FilterInfo,macroblock_edge: bool) {
+    let threshold = 2 * limit + 1;
+    let interior = info.interior;
+    for n in 0..count {
+        let o = o + n * along;
+        if (4 * diff(p,o - step,o) + diff(p,o - 2 * step,o + step) > threshold) ||
+            (diff(p,o - 4 * step,o - 3 * step) > interior) || (diff(p,o - 3 * step,o - 2 * step) > interior) ||
+            (diff(p,o - 2 * step,o - step) > interior) || (diff(p,o + 3 * step,o + 2 * step) > interior) ||
+            (diff(p,o + 2 * step,o + step) > interior) || (diff(p,o + step,o) > interior) {
+            continue;
+        }
+        if (diff(p,o - 2 * step,o - step) > info.hev_threshold) || (diff(p,o + step,o) > info.hev_threshold) {
+            filter2(p,o,step);
+        }
+        else if macroblock_edge {
+            filter6(p,o,step);
+        }
+        else {
+            filter4(p,o,step);
+        }
+    }
+}
+
+// YUV to RGB with 14-bit precision, like libwebp
+fn mult_hi(v: i32,coeff: i32) -> i32 {
+    (v * coeff) >> 8
+}
+
+fn clip8(v: i32) -> u32 {
+    if v & !16383 == 0 { (v >> 6) as u32 } else if v < 0 { 0 } else { 255 }
+}
+
+fn yuv_to_argb(y: u8,u: u8,v: u8) -> u32 {
+    let (y,u,v) = (y as i32,u as i32,v as i32);
+    let r = clip8(mult_hi(y,19077) + mult_hi(v,26149) - 14234);
+    let g = clip8(mult_hi(y,19077) - mult_hi(u,6419) - mult_hi(v,13320) + 8708);
+    let b = clip8(mult_hi(y,19077) + mult_hi(u,33050) - 17685);
+    0xFF000000 | (r << 16) | (g << 8) | b
+}
+
+// chroma for a pixel between the nearest chroma row and the other one, like libwebp
+fn upsample(near: &[u8],far: &[u8],x: usize,width: usize) -> u8 {
+    let k = (x + 1) >> 1;
+    if (x == 0) || (k > (width - 1) >> 1) {
+        let k = if x == 0 { 0 } else { k - 1 };
+        return ((3 * near[k] as u32 + far[k] as u32 + 2) >> 2) as u8;
+    }
+    let (a,b,c,d) = (near[k - 1] as u32,near[k] as u32,far[k - 1] as u32,far[k] as u32);
+    let avg = a + b + c + d + 8;
+    if x & 1 == 1 {
+        ((((avg + 2 * (b + c)) >> 3) + a) >> 1) as u8
+    }
+    else {
+        ((((avg + 2 * (a + d)) >> 3) + b) >> 1) as u8
+    }
+}
+
+// VP8 key frame, ARGB pixels
+fn decode_vp8(src: &[u8]) -> Option<(usize,usize,Vec<u32>)> {
+    if (src.len() < 10) || (src[3..6] != [0x9D,0x01,0x2A]) {
+        return None;
+    }
+
+    // key frame, known profile, shown
+    let tag = from_le24(&src[0..3]);
+    if (tag & 1 != 0) || (((tag >> 1) & 7) > 3) || (tag & 0x10 == 0) {
+        return None;
+    }
+    let first_size = (tag >> 5) as usize;
+    let width = (from_le16(&src[6..8]) & 0x3FFF) as usize;
+    let height = (from_le16(&src[8..10]) & 0x3FFF) as usize;
+    if (width == 0) || (height == 0) || (10 + first_size > src.len()) {
+        return None;
+    }
+    let mb_width = (width + 15) / 16;
+    let mb_height = (height + 15) / 16;
+    let mut reader = BoolReader::new(&src[10..10 + first_size]);
+    let _color_space = reader.flag();
+    let _clamping = reader.flag();
+
+    // segments
+    let mut update_map = false;
+    let mut absolute = true;
+    let mut segment_quant = [0i32; 4];
+    let mut segment_level = [0i32; 4];
+    let mut segment_probs = [255u8; 3];
+    let segments = reader.flag();
+    if segments {
+        update_map = reader.flag();
+        if reader.flag() {
+            absolute = reader.flag();
+            for s in 0..4 {
+                segment_quant[s] = if reader.flag() { reader.signed(7) } else { 0 };
+            }
+            for s in 0..4 {
+                segment_level[s] = if reader.flag() { reader.signed(6) } else { 0 };
+            }
+        }
+        if update_map {
+            for s in 0..3 {
+                segment_probs[s] = if reader.flag() { reader.literal(8) as u8 } else { 255 };
+            }
+        }
+    }
+
+    // loop filter
+    let simple = reader.flag();
+    let level = reader.literal(6) as i32;
+    let sharpness = reader.literal(3) as i32;
+    let mut ref_deltas = [0i32; 4];
+    let mut mode_deltas = [0i32; 4];
+    let deltas = reader.flag();
+    if deltas && reader.flag() {
+        for i in 0..4 {
+            if reader.flag() {
+                ref_deltas[i] = reader.signed(6);
+            }
+        }
+        for i in 0..4 {
+            if reader.flag() {
+                mode_deltas[i] = reader.signed(6);
+            }
+        }
+    }
+
+    // token partitions, sizes of all but the last one come first
+    let count = 1usize << reader.literal(2);
+    let mut sp = 10 + first_size + 3 * (count - 1);
+    if sp > src.len() {
+        return None;
+    }
+    let mut partitions: Vec<BoolReader> = Vec::new();
+    for p in 0..count {
+        let size = if p + 1 < count { from_le24(&src[10 + first_size + 3 * p..]) as usize } else { src.len() - sp };
+        let size = if size > src.len() - sp { src.len() - sp } else { size };
+        if (p + 1 == count) && (size == 0) {
+            return None;
+        }
+        partitions.push(BoolReader::new(&src[sp..sp + size]));
+        sp += size;
+    }
+
+    // quantizers
+    let base = reader.literal(7) as i32;
+    let mut quant_deltas = [0i32; 5];  // Y1 DC, Y2 DC, Y2 AC, UV DC, UV AC
+    for i in 0..5 {
+        quant_deltas[i] = if reader.flag() { reader.signed(4) } else { 0 };
+    }
+    let index = |q: i32,max: i32| -> usize { if q < 0 { 0 } else if q > max { max as usize } else { q as usize } };
+    let mut quants = [Quant { y1: [0,0],y2: [0,0],uv: [0,0], }; 4];
+    for s in 0..4 {
+        let q = if segments { segment_quant[s] + if absolute { 0 } else { base } } else { base };
+        let y2_ac = (AC_TABLE[index(q + quant_deltas[2],127)] * 101581) >> 16;
+        quants[s] = Quant {
+            y1: [DC_TABLE[index(q + quant_deltas[0],127)],AC_TABLE[index(q,127)]],
+            y2: [DC_TABLE[index(q + quant_deltas[1],127)] * 2,if y2_ac < 8 { 8 } else { y2_ac }],
+            uv: [DC_TABLE[index(q + quant_deltas[3],117)],AC_TABLE[index(q + quant_deltas[4],127)]],
+        };
+    }
+
+    // filter strengths per segment, for 16x16 and 4x4 prediction
+    let mut strengths = [[FilterInfo { limit: 0,interior: 0,hev_threshold: 0,inner: false, }; 2]; 4];
+    for s in 0..4 {
+        for i4x4 in 0..2 {
+            let mut level = if segments { segment_level[s] + if absolute { 0 } else { level } } else { level };
+            if deltas {
+                level += ref_deltas[0];
+                if i4x4 == 1 {
+                    level += mode_deltas[0];
+                }
+            }
+            let level = if level < 0 { 0 } else if level > 63 { 63 } else { level };
+            let info = &mut strengths[s][i4x4];
+            info.inner = i4x4 == 1;
+            if level > 0 {
+                let mut interior = level;
+                if sharpness > 0 {
+                    interior >>= if sharpness > 4 { 2 } else { 1 };
+                    if interior > 9 - sharpness {
+                        interior = 9 - sharpness;
+                    }
+                }
+                if interior < 1 {
+                    interior = 1;
+                }
+                info.limit = 2 * level + interior;
+                info.interior = interior;
+                info.hev_threshold = if level >= 40 { 2 } else if level >= 15 { 1 } else { 0 };
+            }
+        }
+    }
+
+    // coefficient probabilities
+    let _refresh = reader.flag();
+    let mut probs = COEFF_PROBS;
+    for t in 0..4 {
+        for b in 0..8 {
+            for c in 0..3 {
+                for p in 0..11 {
+                    if reader.bit(COEFF_UPDATE_PROBS[t][b][c][p]) {
+                        probs[t][b][c][p] = reader.literal(8) as u8;
+                    }
+                }
+            }
+        }
+    }
+    let skip_prob = if reader.flag() { Some(reader.literal(8) as u8) } else { None };
+
+    // macroblocks, reconstructed without loop filter
+    let y_stride = mb_width * 16;
+    let uv_stride = mb_width * 8;
+    let mut y_plane = vec![0u8; y_stride * mb_height * 16];
+    let mut u_plane = vec![0u8; uv_stride * mb_height * 8];
+    let mut v_plane = vec![0u8; uv_stride * mb_height * 8];
+    let mut filter_infos: Vec<FilterInfo> = Vec::with_capacity(mb_width * mb_height);
+    let mut intra_top = vec![0u8; mb_width * 4];
+    let mut nz_top = vec![[0u8; 9]; mb_width];
+    let mut ws_y = [0u8; BPS * 17];
+    let mut ws_u = [0u8; BPS * 9];
+    let mut ws_v = [0u8; BPS * 9];
+    for mb_y in 0..mb_height {
+        let tokens = &mut partitions[mb_y & (count - 1)];
+        let mut intra_left = [0u8; 4];
+        let mut nz_left = [0u8; 9];
+        for mb_x in 0..mb_width {
+
+            // modes
+            let segment = if update_map {
+                if !reader.bit(segment_probs[0]) { reader.bit(segment_probs[1]) as usize } else { 2 + reader.bit(segment_probs[2]) as usize }
+            }
+            else {
+                0
+            };
+            let skip = if let Some(prob) = skip_prob { reader.bit(prob) } else { false };
+            let i4x4 = !reader.bit(145);
+            let mut modes = [0u8; 16];
+            if !i4x4 {
+                modes[0] = if reader.bit(156) {
+                    if reader.bit(128) { 1 } else { 3 }
+                }
+                else {
+                    if reader.bit(163) { 2 } else { 0 }
+                };
+                for i in 0..4 {
+                    intra_top[mb_x * 4 + i] = modes[0];
+                    intra_left[i] = modes[0];
+                }
+            }
+            else {
+                for y in 0..4 {
+                    let mut mode = intra_left[y];
+                    for x in 0..4 {
+                        let prob = &BMODE_PROBS[intra_top[mb_x * 4 + x] as usize][mode as usize];
+                        let mut i = BMODE_TREE[reader.bit(prob[0]) as usize];
+                        while i > 0 {
+                            i = BMODE_TREE[2 * i as usize + reader.bit(prob[i as usize]) as usize];
+                        }
+                        mode = -i as u8;
+                        intra_top[mb_x * 4 + x] = mode;
+                        modes[y * 4 + x] = mode;
+                    }
+                    intra_left[y] = mode;
+                }
+            }
+            let uv_mode = if !reader.bit(142) { 0 } else if !reader.bit(114) { 2 } else if reader.bit(183) { 1 } else { 3 };
+
+            // residuals
+            let mut coeffs = [0i16; 384];
+            let non_zero = if !skip {
+                read_residuals(tokens,&probs,&quants[segment],i4x4,&mut nz_top[mb_x],&mut nz_left,&mut coeffs)
+            }
+            else {
+                let y2_top = nz_top[mb_x][8];
+                let y2_left = nz_left[8];
+                nz_top[mb_x] = [0; 9];
+                nz_left = [0; 9];
+                if i4x4 {
+                    nz_top[mb_x][8] = y2_top;
+                    nz_left[8] = y2_left;
+                }
+                false
+            };
+            if reader.eof() || tokens.eof() {
+                return None;
+            }
+            let mut info = strengths[segment][i4x4 as usize];
+            info.inner |= non_zero;
+            filter_infos.push(info);
+
+            // edges of the work areas, top is 127, left is 129
+            let top_left = if mb_y == 0 { 127 } else if mb_x == 0 { 129 } else { y_plane[(mb_y * 16 - 1) * y_stride + mb_x * 16 - 1] };
+            ws_y[0] = top_left;
+            for x in 0..20 {
+                ws_y[1 + x] = if mb_y == 0 {
+                    127
+                }
+                else {
+                    let line = (mb_y * 16 - 1) * y_stride;
+                    if (x < 16) || (mb_x + 1 < mb_width) {
+                        y_plane[line + mb_x * 16 + x]
+                    }
+                    else {
+                        y_plane[line + mb_x * 16 + 15]
+                    }
+                };
+            }
+            for y in 0..16 {
+                ws_y[(1 + y) * BPS] = if mb_x == 0 { 129 } else { y_plane[(mb_y * 16 + y) * y_stride + mb_x * 16 - 1] };
+            }
+
+            // the top right pixels are also used below
+            for y in [4,8,12].iter() {
+                for x in 17..21 {
+                    ws_y[y * BPS + x] = ws_y[x];
+                }
+            }
+            for (ws,plane) in [(&mut ws_u,&u_plane),(&mut ws_v,&v_plane)].iter_mut() {
+                ws[0] = if mb_y == 0 { 127 } else if mb_x == 0 { 129 } else { plane[(mb_y * 8 - 1) * uv_stride + mb_x * 8 - 1] };
+                for x in 0..8 {
+                    ws[1 + x] = if mb_y == 0 { 127 } else { plane[(mb_y * 8 - 1) * uv_stride + mb_x * 8 + x] };
+                }
+                for y in 0..8 {
+                    ws[(1 + y) * BPS] = if mb_x == 0 { 129 } else { plane[(mb_y * 8 + y) * uv_stride + mb_x * 8 - 1] };
+                }
+            }
+
+            // prediction and residuals
+            let o = BPS + 1;
+            if i4x4 {
+                for n in 0..16 {
+                    let bo = o + (n >> 2) * 4 * BPS + (n & 3) * 4;
+                    predict_subblock(&mut ws_y,bo,modes[n]);
+                    inverse_dct(&coeffs[n * 16..],&mut ws_y,bo);
+                }
+            }
+            else {
+                predict_block(&mut ws_y,o,16,modes[0],mb_y > 0,mb_x > 0);
+                for n in 0..16 {
+                    inverse_dct(&coeffs[n * 16..],&mut ws_y,o + (n >> 2) * 4 * BPS + (n & 3) * 4);
+                }
+            }
+            predict_block(&mut ws_u,o,8,uv_mode,mb_y > 0,mb_x > 0);
+            predict_block(&mut ws_v,o,8,uv_mode,mb_y > 0,mb_x > 0);
+            for n in 0..4 {
+                let bo = o + (n >> 1) * 4 * BPS + (n & 1) * 4;
+                inverse_dct(&coeffs[256 + n * 16..],&mut ws_u,bo);
+                inverse_dct(&coeffs[320 + n * 16..],&mut ws_v,bo);
+            }
+            for y in 0..16 {
+                let line = (mb_y * 16 + y) * y_stride + mb_x * 16;
+                y_plane[line..line + 16].copy_from_slice(&ws_y[o + y * BPS..o + y * BPS + 16]);
+            }
+            for y in 0..8 {
+                let line = (mb_y * 8 + y) * uv_stride + mb_x * 8;
+                u_plane[line..line + 8].copy_from_slice(&ws_u[o + y * BPS..o + y * BPS + 8]);
+                v_plane[line..line + 8].copy_from_slice(&ws_v[o + y * BPS..o + y * BPS + 8]);
+            }
+        }
+    }
+
+    // loop filter, left edge, inner vertical edges, top edge, inner horizontal edges
+    if level > 0 {
+        for mb_y in 0..mb_height {
+            for mb_x in 0..mb_width {
+                let info = filter_infos[mb_y * mb_width + mb_x];
+                if info.limit == 0 {
+                    continue;
+                }
+                let yo = mb_y * 16 * y_stride + mb_x * 16;
+                let uvo = mb_y * 8 * uv_stride + mb_x * 8;
+                if simple {
+                    if mb_x > 0 {
+                        simple_filter(&mut y_plane,yo,1,y_stride,info.limit + 4);
+                    }
+                    if info.inner {
+                        for x in [4,8,12].iter() {
+                            simple_filter(&mut y_plane,yo + x,1,y_stride,info.limit);
+                        }
+                    }
+                    if mb_y > 0 {
+                        simple_filter(&mut y_plane,yo,y_stride,1,info.limit + 4);
+                    }
+                    if info.inner {
+                        for y in [4,8,12].iter() {
+                            simple_filter(&mut y_plane,yo + y * y_stride,y_stride,1,info.limit);
+                        }
+                    }
+                }
+                else {
+                    if mb_x > 0 {
+                        normal_filter(&mut y_plane,yo,1,y_stride,16,info.limit + 4,&info,true);
+                        normal_filter(&mut u_plane,uvo,1,uv_stride,8,info.limit + 4,&info,true);
+                        normal_filter(&mut v_plane,uvo,1,uv_stride,8,info.limit + 4,&info,true);
+                    }
+                    if info.inner {
+                        for x in [4,8,12].iter() {
+                            normal_filter(&mut y_plane,yo + x,1,y_stride,16,info.limit,&info,false);
+                        }
+                        normal_filter(&mut u_plane,uvo + 4,1,uv_stride,8,info.limit,&info,false);
+                        normal_filter(&mut v_plane,uvo + 4,1,uv_stride,8,info.limit,&info,false);
+                    }
+                    if mb_y > 0 {
+                        normal_filter(&mut y_plane,yo,y_stride,1,16,info.limit + 4,&info,true);
+                        normal_filter(&mut u_plane,uvo,uv_stride,1,8,info.limit + 4,&info,true);
+                        normal_filter(&mut v_plane,uvo,uv_stride,1,8,info.limit + 4,&info,true);
+                    }
+                    if info.inner {
+                        for y in [4,8,12].iter() {
+                            normal_filter(&mut y_plane,yo + y * y_stride,y_stride,1,16,info.limit,&info,false);
+                        }
+                        normal_filter(&mut u_plane,uvo + 4 * uv_stride,uv_stride,1,8,info.limit,&info,false);
+                        normal_filter(&mut v_plane,uvo + 4 * uv_stride,uv_stride,1,8,info.limit,&info,false);
+                    }
+                }
+            }
+        }
+    }
+
+    // convert to RGB, interpolating chroma between the nearest two rows
+    let uv_height = (height + 1) / 2;
+    let mut data = vec![0u32; width * height];
+    for y in 0..height {
+        let near = y / 2;
+        let far = if y & 1 == 1 {
+            if near + 1 < uv_height { near + 1 } else { near }
+        }
+        else {
+            if near > 0 { near - 1 } else { 0 }
+        };
+        let (u_near,u_far) = (&u_plane[near * uv_stride..],&u_plane[far * uv_stride..]);
+        let (v_near,v_far) = (&v_plane[near * uv_stride..],&v_plane[far * uv_stride..]);
+        for x in 0..width {
+            let u = upsample(u_near,u_far,x,width);
+            let v = upsample(v_near,v_far,x,width);
+            data[y * width + x] = yuv_to_argb(y_plane[y * y_stride + x],u,v);
+        }
+    }
+    Some((width,height,data))
+}
+
+// ALPH chunk, alpha values for a lossy image
+fn decode_alpha(src: &[u8],width: usize,height: usize) -> Option<Vec<u8>> {
+    if src.len() < 1 {
+        return None;
+    }
+    let filter = (src[0] >> 2) & 3;
+    let mut alpha = match src[0] & 3 {
+        0 => {
+            if src.len() < 1 + width * height {
+                return None;
+            }
+            src[1..1 + width * height].to_vec()
+        },
+        1 => {
+            let mut reader = BitReader::new(&src[1..]);
+            decode_stream(&mut reader,width,height)?.iter().map(|p| (p >> 8) as u8).collect()
+        },
+        _ => { return None; },
+    };
+
+    // undo the filter, the first row predicts from the left and the first column from above
+    if filter != 0 {
+        for y in 0..height {
+            for x in 0..width {
+                let i = y * width + x;
+                let prediction = if (x == 0) && (y == 0) {
+                    0
+                }
+                else if y == 0 {
+                    alpha[i - 1]
+                }
+                else if x == 0 {
+                    alpha[i - width]
+                }
+                else {
+                    match filter {
+                        1 => alpha[i - 1],
+                        2 => alpha[i - width],
+                        _ => {
+                            let v = (alpha[i - 1] as i32) + (alpha[i - width] as i32) - (alpha[i - width - 1] as i32);
+                            if v < 0 { 0 } else if v > 255 { 255 } else { v as u8 }
+                        },
+                    }
+                };
+                alpha[i] = alpha[i].wrapping_add(prediction);
+            }
+        }
+    }
+    Some(alpha)
+}
+
+// chunks of a WebP file or of an animation frame, with the offsets of their data
+struct Chunks<'a> {
+    canvas: Option<(usize,usize)>,  // from VP8X
+    features: u8,  // from VP8X
+    iccp: Option<&'a [u8]>,
+    loops: u32,  // from ANIM
+    frames: Vec<(usize,&'a [u8])>,  // ANMF chunks
+    alpha: Option<(usize,&'a [u8])>,
+    vp8: Option<(usize,&'a [u8])>,
+    vp8l: Option<(usize,&'a [u8])>,
+}
+
+fn read_chunks<'a>(src: &'a [u8],mut sp: usize,end: usize) -> Result<Chunks<'a>,ImageError> {
+    let mut chunks = Chunks {
+        canvas: None,
+        features: 0,
+        iccp: None,
+        loops: 0,
+        frames: Vec::new(),
+        alpha: None,
+        vp8: None,
+        vp8l: None,
+    };
+    while sp + 8 <= end {
+        let size = from_le32(&src[sp + 4..sp + 8]) as usize;
+        let data = &src[sp + 8..if sp + 8 + size > end { end } else { sp + 8 + size }];
+        match &src[sp..sp + 4] {
+            b"VP8X" => {
+                if data.len() < 10 {
+                    return Err(ImageError::Corrupt(sp + 8,"invalid VP8X"));
+                }
+                chunks.features = data[0];
+                chunks.canvas = Some((from_le24(&data[4..7]) as usize + 1,from_le24(&data[7..10]) as usize + 1));
+            },
+            b"ICCP" => { chunks.iccp = Some(data); },
+            b"ANIM" => {
+                if data.len() < 6 {
+                    return Err(ImageError::Corrupt(sp + 8,"invalid ANIM"));
+                }
+                chunks.loops = from_le16(&data[4..6]);
+            },
+            b"ANMF" => { chunks.frames.push((sp + 8,data)); },
+            b"ALPH" => { chunks.alpha = Some((sp + 8,data)); },
+            b"VP8 " => { chunks.vp8 = Some((sp + 8,data)); },
+            b"VP8L" => { chunks.vp8l = Some((sp + 8,data)); },
+            _ => { },
+        }
+        sp += 8 + size + (size & 1);
+    }
+    Ok(chunks)
+}
+
+fn parse_chunks<'a>(src: &'a [u8]) -> Result<Chunks<'a>,ImageError> {
+    if (src.len() < 12) || (&src[0..4] != b"RIFF") || (&src[8..12] != b"WEBP") {
+        return Err(ImageError::UnknownFormat);
+    }
+    let end = 8 + from_le32(&src[4..8]) as usize;
+    let end = if end > src.len() { src.len() } else { end };
+    let chunks = read_chunks(src,12,end)?;
+    if chunks.vp8.is_none() && chunks.vp8l.is_none() && chunks.frames.is_empty() {
+        return Err(ImageError::Corrupt(end,"missing VP8 or VP8L chunk"));
+    }
+    Ok(chunks)
+}
+
+pub fn test(src: &[u8]) -> Result<(u32,u32),ImageError> {
+    let chunks = parse_chunks(src)?;
+    if let Some((width,height)) = chunks.canvas {
+        return Ok((width as u32,height as u32));
+    }
+    if let Some((offset,vp8)) = chunks.vp8 {
+        if (vp8.len() < 10) || (vp8[3..6] != [0x9D,0x01,0x2A]) {
+            return Err(ImageError::Corrupt(offset,"invalid VP8 header"));
+        }
+        return Ok((from_le16(&vp8[6..8]) & 0x3FFF,from_le16(&vp8[8..10]) & 0x3FFF));
+    }
+    if let Some((offset,vp8l)) = chunks.vp8l {
+        if (vp8l.len() < 5) || (vp8l[0] != 0x2F) {
+            return Err(ImageError::Corrupt(offset,"invalid VP8L header"));
+        }
+        let bits = from_le32(&vp8l[1..5]);
+        return Ok(((bits & 0x3FFF) + 1,((bits >> 14) & 0x3FFF) + 1));
+    }
+    Err(ImageError::Corrupt(12,"missing VP8 or VP8L chunk"))
+}
+
+pub fn info(src: &[u8]) -> Result<ImageInfo,ImageError> {
+    let (width,height) = test(src)?;
+    let chunks = parse_chunks(src)?;
+    let alpha = if !chunks.frames.is_empty() {
+        (chunks.features & 0x10) != 0
+    }
+    else if let Some((_,vp8l)) = chunks.vp8l {
+        (vp8l.len() >= 5) && ((from_le32(&vp8l[1..5]) & 0x10000000) != 0)  // alpha_is_used
+    }
+    else {
+        chunks.alpha.is_some()
+    };
+    let mut info = ImageInfo::new(ImageFormat::Webp,width as usize,height as usize,8,if alpha { ChannelLayout::Rgba } else { ChannelLayout::Rgb });
+    if let Some(iccp) = chunks.iccp {
+        info.icc_profile = Some(iccp.to_vec());
+    }
+    if !chunks.frames.is_empty() {
+        info.frames = chunks.frames.len();
+    }
+    Ok(info)
+}
+
+// ARGB pixels of a still image or animation frame
+fn decode_frame(chunks: &Chunks,offset: usize) -> Result<(usize,usize,Vec<u32>),ImageError> {
+    if let Some((offset,vp8l)) = chunks.vp8l {
+        decode_vp8l(vp8l).ok_or(ImageError::Corrupt(offset,"invalid VP8L data"))
+    }
+    else if let Some((offset,vp8)) = chunks.vp8 {
+        let (width,height,mut data) = decode_vp8(vp8).ok_or(ImageError::Corrupt(offset,"invalid VP8 data"))?;
+        if let Some((offset,alpha)) = chunks.alpha {
+            let alpha = decode_alpha(alpha,width,height).ok_or(ImageError::Corrupt(offset,"invalid ALPH data"))?;
+            for i in 0..width * height {
+                data[i] = (data[i] & 0x00FFFFFF) | ((alpha[i] as u32) << 24);
+            }
+        }
+        Ok((width,height,data))
+    }
+    else {
+        Err(ImageError::Corrupt(offset,"missing VP8 or VP8L chunk"))
+    }
+}
+
+fn to_mat<T: pixel::Pixel>(width: usize,height: usize,data: &[u32]) -> Mat<T> {
+    let mut image = Mat::<T>::new(vec2!(width,height));
+    for y in 0..height {
+        for x in 0..width {
+            let p = data[y * width + x];
+            image[(x,y)].set((p >> 16) as u8,(p >> 8) as u8,p as u8,(p >> 24) as u8);
+        }
+    }
+    image
+}
+
+// composite the ANMF frames on the canvas, or only the first one
+fn decode_frames<T: pixel::Pixel>(src: &[u8],chunks: &Chunks,all: bool) -> Result<Animation<T>,ImageError> {
+    let (width,height) = chunks.canvas.ok_or(ImageError::Corrupt(12,"missing VP8X chunk"))?;
+    check_dimensions(width,height)?;
+
+    // the background color from ANIM is only a hint, the canvas starts and is disposed to transparent
+    let mut compositor = Compositor::<T>::new(vec2!(width,height));
+    for &(offset,data) in chunks.frames.iter() {
+        if data.len() < 16 {
+            return Err(ImageError::Corrupt(offset,"invalid ANMF"));
+        }
+        let x = 2 * from_le24(&data[0..3]) as usize;
+        let y = 2 * from_le24(&data[3..6]) as usize;
+        let frame_width = from_le24(&data[6..9]) as usize + 1;
+        let frame_height = from_le24(&data[9..12]) as usize + 1;
+        let duration = from_le24(&data[12..15]);
+        let disposal = if (data[15] & 0x01) != 0 { Disposal::Background } else { Disposal::None };
+        let blend = if (data[15] & 0x02) != 0 { FrameBlend::Source } else { FrameBlend::Over };
+        let frame = read_chunks(src,offset + 16,offset + data.len())?;
+        let (decoded_width,decoded_height,pixels) = decode_frame(&frame,offset + 16)?;
+        if (decoded_width != frame_width) || (decoded_height != frame_height) {
+            return Err(ImageError::Corrupt(offset,"frame size does not match ANMF"));
+        }
+        compositor.begin(rect!(x,y,frame_width,frame_height),disposal);
+        compositor.draw(&to_mat(frame_width,frame_height,&pixels),blend);
+        compositor.end(duration);
+        if !all {
+            break;
+        }
+    }
+    if compositor.frames.is_empty() {
+        return Err(ImageError::Corrupt(12,"animation has no frames"));
+    }
+    Ok(compositor.finish(chunks.loops))
+}
+
+pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Result<Mat<T>,ImageError> {
+    let (width,height) = test(src)?;
+    check_dimensions(width as usize,height as usize)?;
+    let chunks = parse_chunks(src)?;
+    if chunks.vp8.is_none() && chunks.vp8l.is_none() {
+        // animated, the first frame
+        let mut animation = decode_frames::<T>(src,&chunks,false)?;
+        return Ok(animation.frames.remove(0).image);
+    }
+    let (width,height,data) = decode_frame(&chunks,12)?;
+    Ok(to_mat(width,height,&data))
+}
+
+pub fn decode_animation<T: pixel::Pixel>(src: &[u8]) -> Result<Animation<T>,ImageError> {
+    let chunks = parse_chunks(src)?;
+    if chunks.vp8.is_none() && chunks.vp8l.is_none() {
+        return decode_frames(src,&chunks,true);
+    }
+
+    // not animated, the image is the only frame
+    let image = decode::<T>(src)?;
+    Ok(Animation {
+        size: image.size,
+        frames: vec![Frame { image: image,delay: 0, }],
+        loops: 1,
+    })
+}
+
+pub fn encode<T: pixel::Pixel>(_src: &Mat<T>) -> Result<Vec<u8>,ImageError> {
+    Err(ImageError::Unsupported("WebP encoding"))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        pixel::Pixel,
+    };
+
+    // made by cwebp -lossless, pixels as in pattern()
+    const VP8L_12X8: [u8; 188] = [
+        82,73,70,70,180,0,0,0,87,69,66,80,86,80,56,76,168,0,0,0,47,11,192,1,16,153,50,68,244,63,54,17,
+        209,255,80,32,104,219,54,102,82,254,48,183,145,232,175,169,112,90,219,182,26,189,112,94,33,145,95,134,211,64,36,150,
+        144,34,126,1,33,11,227,166,129,47,135,60,23,129,146,121,36,174,69,144,109,211,57,92,246,246,4,147,52,213,118,12,
+        9,193,41,92,216,25,128,63,16,59,32,8,48,118,64,252,193,32,9,80,118,8,20,138,93,152,10,98,137,151,0,51,
+        70,96,240,192,69,10,31,39,148,208,237,193,160,129,66,143,26,55,32,36,156,4,128,128,194,243,248,162,140,12,21,8,
+        222,24,217,131,131,142,18,157,232,97,7,196,11,93,1,216,193,68,138,218,167,23,11,108,113,195,24,59,
+    ];
+
+    // made by cwebp -q 80, pixels as decoded by dwebp
+    const VP8_12X8: [u8; 92] = [
+        82,73,70,70,84,0,0,0,87,69,66,80,86,80,56,32,72,0,0,0,176,1,0,157,1,42,12,0,8,0,1,64,
+        38,37,176,2,116,1,14,187,4,128,0,254,252,114,142,62,135,215,36,237,88,205,226,37,124,195,185,133,216,255,46,63,
+        24,79,201,117,250,167,253,244,64,200,127,246,237,116,128,250,13,63,254,83,62,248,26,235,88,0,0,0,
+    ];
+
+    const VP8_12X8_PIXELS: [u8; 288] = [
+        0,4,121,13,7,125,34,8,126,56,7,126,79,5,123,100,3,122,122,5,124,140,5,121,161,7,123,181,7,123,202,7,128,216,9,131,
+        7,27,129,18,29,132,38,30,133,60,29,133,85,27,131,105,25,127,126,28,129,145,26,128,166,29,130,185,30,130,208,29,135,221,31,138,
+        7,62,132,19,65,136,39,66,139,61,65,137,86,63,134,107,61,133,128,63,135,147,62,134,167,65,134,186,65,136,209,65,141,223,67,144,
+        6,91,128,17,93,131,37,94,132,59,93,132,84,91,129,104,89,128,125,91,130,144,90,129,165,93,129,184,93,131,207,93,136,220,95,139,
+        11,122,126,24,126,128,43,127,129,65,126,128,90,124,128,111,122,125,132,124,127,151,123,126,172,126,128,190,126,127,213,126,132,227,128,136,
+        10,152,122,21,154,123,41,155,124,63,154,123,88,152,123,108,150,120,129,152,122,148,151,120,169,154,123,188,154,122,211,154,127,225,156,131,
+        10,187,125,23,190,129,42,191,130,64,190,129,89,188,127,110,185,126,131,188,128,150,187,126,171,190,127,189,190,128,212,190,131,226,192,135,
+        16,210,133,27,212,136,48,213,137,70,212,136,93,210,134,114,208,133,136,210,135,154,209,134,175,212,134,195,212,133,216,212,138,230,214,142,
+    ];
+
+    const VP8X_ALPH_12X8: [u8; 190] = [
+        82,73,70,70,182,0,0,0,87,69,66,80,86,80,56,88,10,0,0,0,16,0,0,0,11,0,0,7,0,0,65,76,
+        80,72,31,0,0,0,1,153,50,68,244,63,160,168,109,27,40,229,80,178,67,127,63,7,32,98,2,38,32,99,7,219,
+        56,4,251,13,0,0,86,80,56,32,112,0,0,0,16,2,0,157,1,42,12,0,8,0,1,64,38,37,176,2,116,1,
+        11,101,101,12,218,216,128,0,254,253,38,81,30,189,148,109,6,194,244,93,138,53,214,224,15,119,106,119,37,42,58,119,
+        176,54,119,93,179,5,133,140,45,239,48,159,133,220,246,127,221,176,150,211,220,121,101,133,253,255,225,25,59,250,122,57,
+        254,198,251,97,25,251,223,131,227,18,225,255,233,239,229,86,242,190,127,252,166,119,123,177,245,134,184,0,0,0,
+    ];
+
+    const VP8X_ALPH_12X8_PIXELS: [u8; 384] = [
+        1,6,61,255,15,8,65,255,39,6,70,255,58,5,78,255,68,2,86,255,85,2,92,255,112,5,91,120,134,4,87,140,159,9,87,160,174,7,70,180,199,14,58,200,208,14,47,220,
+        9,27,69,255,22,28,74,255,46,27,79,255,65,26,85,255,82,31,92,255,99,32,94,255,125,33,94,121,145,32,96,141,158,27,92,161,185,33,98,181,195,22,80,201,216,32,87,221,
+        16,59,76,255,30,60,82,255,53,59,91,255,71,60,89,255,91,70,84,255,102,65,71,255,117,57,66,122,130,49,72,142,161,60,106,162,176,54,120,182,220,72,156,202,231,70,166,222,
+        13,87,80,255,26,88,83,255,49,88,84,255,67,88,84,255,76,87,77,255,98,91,82,255,127,95,95,123,150,95,107,143,184,111,138,163,202,108,153,183,214,94,160,203,227,94,168,223,
+        7,116,90,255,21,120,84,255,45,122,69,255,60,120,74,255,73,115,107,255,113,133,149,255,146,139,167,124,146,120,145,144,175,128,139,164,195,129,131,184,203,115,113,204,227,128,125,224,
+        11,152,100,255,26,155,91,255,47,158,66,255,65,154,78,255,89,157,141,255,119,167,178,255,129,153,163,125,152,155,152,145,167,150,126,165,193,158,122,185,198,141,103,205,241,173,132,225,
+        8,175,89,255,23,180,84,255,44,179,63,255,66,182,84,255,105,200,164,255,108,182,161,255,135,191,141,126,140,179,109,146,146,168,90,166,180,180,112,186,228,201,155,206,230,190,156,226,
+        22,203,101,255,35,206,94,255,57,206,76,255,89,216,107,255,97,202,157,255,115,203,166,255,126,199,117,127,141,196,93,147,194,233,129,167,211,228,142,187,227,217,166,207,228,204,171,227,
+    ];
+
+    fn pattern(x: usize,y: usize) -> (u8,u8,u8,u8) {
+        (((x * 21) & 255) as u8,((y * 31) & 255) as u8,(((x * y * 7) ^ 0x55) & 255) as u8,if x < 6 { 255 } else { ((x * 20 + y) & 255) as u8 })
+    }
+
+    #[test]
+    fn decode_lossless() {
+        let image = decode::<pixel::RGBA8UN>(&VP8L_12X8).unwrap();
+        assert_eq!(image.size,vec2!(12usize,8));
+        for y in 0..8 {
+            for x in 0..12 {
+                assert_eq!(image[(x,y)].get(),pattern(x,y));
+            }
+        }
+        let info = info(&VP8L_12X8).unwrap();
+        assert_eq!((info.size,info.channels),(vec2!(12usize,8),ChannelLayout::Rgba));
+    }
+
+    #[test]
+    fn decode_truncated() {
+        for length in 0..VP8L_12X8.len() {
+            assert!(decode::<pixel::RGBA8UN>(&VP8L_12X8[0..length]).is_err());
+        }
+    }
+
+    fn compare(image: &Mat<pixel::RGBA8UN>,pixels: &[u8],alpha: bool) {
+        let depth = if alpha { 4 } else { 3 };
+        assert_eq!(image.size,vec2!(12usize,8));
+        for (i,p) in image.data().iter().enumerate() {
+            let s = &pixels[i * depth..(i + 1) * depth];
+            assert_eq!(p.get(),(s[0],s[1],s[2],if alpha { s[3] } else { 255 }));
+        }
+    }
+
+    #[test]
+    fn decode_lossy() {
+        compare(&decode::<pixel::RGBA8UN>(&VP8_12X8).unwrap(),&VP8_12X8_PIXELS,false);
+    }
+
+    #[test]
+    fn decode_lossy_alpha() {
+        compare(&decode::<pixel::RGBA8UN>(&VP8X_ALPH_12X8).unwrap(),&VP8X_ALPH_12X8_PIXELS,true);
+    }
+
+    fn chunk(tag: &[u8],data: &[u8]) -> Vec<u8> {
+        let mut dst = tag.to_vec();
+        dst.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        dst.extend_from_slice(data);
+        if (data.len() & 1) != 0 {
+            dst.push(0);
+        }
+        dst
+    }
+
+    // animation frame with the chunks of a still image after its header, and VP8X if there is one
+    fn anmf(x: usize,y: usize,duration: u32,flags: u8,still: &[u8]) -> Vec<u8> {
+        let (width,height) = test(still).unwrap();
+        let mut data: Vec<u8> = Vec::new();
+        for v in [(x / 2) as u32,(y / 2) as u32,width - 1,height - 1,duration] {
+            data.extend_from_slice(&v.to_le_bytes()[0..3]);
+        }
+        data.push(flags);
+        data.extend_from_slice(&still[if &still[12..16] == b"VP8X" { 30 } else { 12 }..]);
+        chunk(b"ANMF",&data)
+    }
+
+    // 24x10 canvas, looping 3 times, and the frames as (x,y,duration,dispose,no blend,still image)
+    const FRAMES: [(usize,usize,u32,bool,bool,&[u8]); 4] = [
+        (0,0,100,false,true,&VP8L_12X8),
+        (8,2,50,false,false,&VP8X_ALPH_12X8),
+        (12,0,70,true,false,&VP8L_12X8),
+        (4,2,30,false,true,&VP8X_ALPH_12X8),
+    ];
+
+    fn animated() -> Vec<u8> {
+        let mut data: Vec<u8> = b"WEBP".to_vec();
+        data.extend_from_slice(&chunk(b"VP8X",&[0x12,0,0,0,23,0,0,9,0,0]));
+        data.extend_from_slice(&chunk(b"ANIM",&[255,255,255,255,3,0]));
+        for &(x,y,duration,dispose,source,still) in FRAMES.iter() {
+            data.extend_from_slice(&anmf(x,y,duration,if dispose { 1 } else { 0 } | if source { 2 } else { 0 },still));
+        }
+        let mut src = chunk(b"RIFF",&data);
+        src.truncate(8 + data.len());
+        src
+    }
+
+    #[test]
+    fn decode_animated() {
+        let src = animated();
+
+        // the same frames, composited directly
+        let mut compositor = Compositor::<pixel::RGBA8UN>::new(vec2!(24usize,10));
+        for &(x,y,duration,dispose,source,still) in FRAMES.iter() {
+            let image = decode::<pixel::RGBA8UN>(still).unwrap();
+            compositor.begin(rect!(x,y,image.size.x,image.size.y),if dispose { Disposal::Background } else { Disposal::None });
+            compositor.draw(&image,if source { FrameBlend::Source } else { FrameBlend::Over });
+            compositor.end(duration);
+        }
+        let expected = compositor.finish(3);
+
+        let animation = crate::decode_animation::<pixel::RGBA8UN>(&src).unwrap();
+        assert_eq!((animation.size,animation.loops,animation.frames.len()),(vec2!(24usize,10),3,4));
+        for (frame,expected) in animation.frames.iter().zip(expected.frames.iter()) {
+            assert_eq!(frame.delay,expected.delay);
+            assert!(frame.image.data().iter().zip(expected.image.data().iter()).all(|(a,b)| a.get() == b.get()));
+        }
+
+        // the third frame is cleared before the fourth
+        assert_eq!(animation.frames[3].image[(23,0)].get(),(0,0,0,0));
+        assert_ne!(animation.frames[2].image[(23,0)].get(),(0,0,0,0));
+
+        let first = decode::<pixel::RGBA8UN>(&src).unwrap();
+        assert!(first.data().iter().zip(expected.frames[0].image.data().iter()).all(|(a,b)| a.get() == b.get()));
+        let info = info(&src).unwrap();
+        assert_eq!((info.size,info.channels,info.frames),(vec2!(24usize,10),ChannelLayout::Rgba,4));
+    }
+
+    #[test]
+    fn decode_animated_truncated() {
+        let src = animated();
+        for length in 0..src.len() {
+            let _ = decode_animation::<pixel::RGBA8UN>(&src[0..length]);
+        }
+    }
+
+    #[test]
+    fn decode_animation_still() {
+        let animation = decode_animation::<pixel::RGBA8UN>(&VP8L_12X8).unwrap();
+        assert_eq!((animation.size,animation.loops,animation.frames.len()),(vec2!(12usize,8),1,1));
+    }
 }
@@ -5,14 +5,287 @@
 
 use crate::*;
 
-pub fn test(_src: &[u8]) -> Option<(u32,u32)> {
-    None
+struct Header {
+    width: usize,
+    height: usize,
+    hotspot: Option<Vec2<usize>>,
+    short: bool,  // X10 style 16-bit words
+    sp: usize,  // right after the opening brace
 }
 
-pub fn decode<T: pixel::Pixel>(_src: &[u8]) -> Option<Mat<T>> {
-    None
+// skip whitespace and C comments
+fn skip(src: &[u8],mut sp: usize) -> usize {
+    while sp < src.len() {
+        if src[sp].is_ascii_whitespace() {
+            sp += 1;
+        }
+        else if src[sp..].starts_with(b"/*") {
+            sp += 2;
+            while (sp < src.len()) && !src[sp..].starts_with(b"*/") {
+                sp += 1;
+            }
+            sp += 2;
+        }
+        else if src[sp..].starts_with(b"//") {
+            while (sp < src.len()) && (src[sp] != b'\n') {
+                sp += 1;
+            }
+        }
+        else {
+            break;
+        }
+    }
+    if sp > src.len() { src.len() } else { sp }
 }
 
-pub fn encode<T: pixel::Pixel>(_src: &Mat<T>) -> Option<Vec<u8>> {
-    None
+// identifier, keyword or number
+fn word(src: &[u8],sp: &mut usize) -> Option<String> {
+    *sp = skip(src,*sp);
+    let start = *sp;
+    while (*sp < src.len()) && (src[*sp].is_ascii_alphanumeric() || (src[*sp] == b'_')) {
+        *sp += 1;
+    }
+    if *sp == start {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&src[start..*sp]).to_string())
+}
+
+fn number(text: &str) -> Option<usize> {
+    if text.starts_with("0x") || text.starts_with("0X") {
+        usize::from_str_radix(&text[2..],16).ok()
+    }
+    else {
+        text.parse::<usize>().ok()
+    }
+}
+
+// XBM is C source, so anything that does not look like the expected declarations is not XBM
+fn parse_header(src: &[u8]) -> Result<Header,ImageError> {
+    let mut width: Option<usize> = None;
+    let mut height: Option<usize> = None;
+    let mut x_hot: Option<usize> = None;
+    let mut y_hot: Option<usize> = None;
+    let mut sp = skip(src,0);
+
+    // defines
+    while src[sp..].starts_with(b"#define") {
+        sp += 7;
+        let name = word(src,&mut sp).ok_or(ImageError::UnknownFormat)?;
+        let value = word(src,&mut sp).and_then(|value| number(&value)).ok_or(ImageError::UnknownFormat)?;
+        if name.ends_with("width") {
+            width = Some(value);
+        }
+        else if name.ends_with("height") {
+            height = Some(value);
+        }
+        else if name.ends_with("x_hot") {
+            x_hot = Some(value);
+        }
+        else if name.ends_with("y_hot") {
+            y_hot = Some(value);
+        }
+        sp = skip(src,sp);
+    }
+    let (width,height) = match (width,height) {
+        (Some(width),Some(height)) => (width,height),
+        _ => { return Err(ImageError::UnknownFormat); },
+    };
+    if (width == 0) || (height == 0) {
+        return Err(ImageError::Dimensions(width,height));
+    }
+
+    // static [unsigned] char|short name_bits[] = {
+    let mut short = false;
+    loop {
+        let keyword = word(src,&mut sp).ok_or(ImageError::UnknownFormat)?;
+        match keyword.as_str() {
+            "static" | "unsigned" | "const" | "char" => { },
+            "short" => { short = true; },
+            _ => {
+                if !keyword.ends_with("bits") {
+                    return Err(ImageError::UnknownFormat);
+                }
+                break;
+            },
+        }
+    }
+    sp = skip(src,sp);
+    for c in b"[]=".iter() {
+        if (sp >= src.len()) || (src[sp] != *c) {
+            return Err(ImageError::UnknownFormat);
+        }
+        sp = skip(src,sp + 1);
+    }
+    if (sp >= src.len()) || (src[sp] != b'{') {
+        return Err(ImageError::UnknownFormat);
+    }
+    let hotspot = match (x_hot,y_hot) {
+        (Some(x),Some(y)) => Some(vec2!(x,y)),
+        _ => None,
+    };
+    Ok(Header {
+        width: width,
+        height: height,
+        hotspot: hotspot,
+        short: short,
+        sp: sp + 1,
+    })
+}
+
+pub fn test(src: &[u8]) -> Result<(u32,u32),ImageError> {
+    let header = parse_header(src)?;
+    Ok((header.width as u32,header.height as u32))
+}
+
+pub fn info(src: &[u8]) -> Result<ImageInfo,ImageError> {
+    let header = parse_header(src)?;
+    Ok(ImageInfo::new(ImageFormat::Xbm,header.width,header.height,1,ChannelLayout::Gray))
+}
+
+/// Get the hotspot of an XBM file.
+/// # Arguments
+/// * `src` - XBM source.
+/// # Returns
+/// * `None` - The file is not XBM or has no hotspot.
+/// * `Some(hotspot)` - Position of the hotspot, for use as a cursor.
+pub fn hotspot(src: &[u8]) -> Option<Vec2<usize>> {
+    parse_header(src).ok()?.hotspot
+}
+
+pub fn decode<T: pixel::Pixel>(src: &[u8]) -> Result<Mat<T>,ImageError> {
+    let header = parse_header(src)?;
+    let width = header.width;
+    let height = header.height;
+    check_dimensions(width,height)?;
+    let bits = if header.short { 16 } else { 8 };
+    let stride = (width + bits - 1) / bits;
+    if stride.checked_mul(height).ok_or(ImageError::Dimensions(width,height))? > src.len() - header.sp {
+        return Err(ImageError::Corrupt(src.len(),"truncated bitmap data"));
+    }
+
+    // array values
+    let mut values: Vec<u16> = Vec::with_capacity(stride * height);
+    let mut sp = header.sp;
+    while values.len() < stride * height {
+        let start = skip(src,sp);
+        values.push(word(src,&mut sp).and_then(|value| number(&value)).ok_or(ImageError::Corrupt(start,"invalid array value"))? as u16);
+        sp = skip(src,sp);
+        if (sp < src.len()) && (src[sp] == b',') {
+            sp += 1;
+        }
+    }
+
+    // least significant bit is leftmost, set bits are black
+    let mut image = Mat::<T>::new(vec2!(width,height));
+    for y in 0..height {
+        for x in 0..width {
+            let l = if values[y * stride + x / bits] & (1 << (x % bits)) != 0 { 0 } else { 255 };
+            image[(x,y)].set(l,l,l,255);
+        }
+    }
+    Ok(image)
+}
+
+/// XBM encoder settings.
+#[derive(Clone,Debug)]
+pub struct EncodeOptions {
+    /// Prefix of the C identifiers.
+    pub name: String,
+    /// Hotspot to store, if any.
+    pub hotspot: Option<Vec2<usize>>,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> EncodeOptions {
+        EncodeOptions {
+            name: "image".to_string(),
+            hotspot: None,
+        }
+    }
+}
+
+pub fn encode<T: pixel::Pixel>(image: &Mat<T>) -> Result<Vec<u8>,ImageError> {
+    encode_with_options(image,&EncodeOptions::default())
+}
+
+pub fn encode_with_options<T: pixel::Pixel>(image: &Mat<T>,options: &EncodeOptions) -> Result<Vec<u8>,ImageError> {
+    let width = image.size.x;
+    let height = image.size.y;
+    if (width == 0) || (height == 0) {
+        return Err(ImageError::Dimensions(width,height));
+    }
+    let name = &options.name;
+    let mut text = format!("#define {}_width {}\n#define {}_height {}\n",name,width,name,height);
+    if let Some(hotspot) = options.hotspot {
+        text += &format!("#define {}_x_hot {}\n#define {}_y_hot {}\n",name,hotspot.x,name,hotspot.y);
+    }
+    text += &format!("static unsigned char {}_bits[] = {{",name);
+
+    // dark, opaque pixels become set bits
+    let stride = (width + 7) / 8;
+    for y in 0..height {
+        for i in 0..stride {
+            let mut byte = 0u8;
+            for bit in 0..8 {
+                let x = i * 8 + bit;
+                if x < width {
+                    let (r,g,b,a) = image[(x,y)].get();
+                    let l = (r as u32 * 77 + g as u32 * 150 + b as u32 * 29 + 128) >> 8;
+                    if (l < 128) && (a >= 128) {
+                        byte |= 1 << bit;
+                    }
+                }
+            }
+            let n = y * stride + i;
+            text += if n % 12 == 0 { "\n   " } else { " " };
+            text += &format!("0x{:02x}",byte);
+            if n + 1 < stride * height {
+                text += ",";
+            }
+        }
+    }
+    text += " };\n";
+    Ok(text.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        pixel::Pixel,
+    };
+
+    // the classic X11 example, 16 bits per array value
+    const X10: &[u8] = b"#define x10_width 11\n#define x10_height 2\nstatic short x10_bits[] = {\n   0x0401, 0x00ff};\n";
+
+    #[test]
+    fn decode_x10_short() {
+        let image = decode::<pixel::RGB8UN>(X10).unwrap();
+        assert_eq!(image.size,vec2!(11usize,2));
+        for x in 0..11 {
+            let first = if (x == 0) || (x == 10) { 0 } else { 255 };
+            let second = if x < 8 { 0 } else { 255 };
+            assert_eq!(image[(x,0)].get(),(first,first,first,255));
+            assert_eq!(image[(x,1)].get(),(second,second,second,255));
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut image = Mat::<pixel::RGB8UN>::new(vec2!(13,5));
+        for y in 0..5 {
+            for x in 0..13 {
+                let l = if ((x * x + y) % 3) == 0 { 0 } else { 255 };
+                image[(x,y)].set(l,l,l,255);
+            }
+        }
+        let options = EncodeOptions { name: "cursor".to_string(),hotspot: Some(vec2!(4,2)), };
+        let data = encode_with_options(&image,&options).unwrap();
+        assert_eq!(hotspot(&data),Some(vec2!(4usize,2)));
+        let decoded = decode::<pixel::RGB8UN>(&data).unwrap();
+        for (a,b) in image.data().iter().zip(decoded.data().iter()) {
+            assert_eq!(a.get(),b.get());
+        }
+    }
 }
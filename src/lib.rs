@@ -11,4 +11,6 @@ mod gpu;
 pub use gpu::*;
 
 mod image;
-pub use image::*;
\ No newline at end of file
+pub use image::*;
+
+pub mod compress;